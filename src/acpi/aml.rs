@@ -0,0 +1,263 @@
+//! Encoding the bits of AML (ACPI Machine Language) that a static DSDT
+//! needs: scopes, devices, named objects, and resource templates.
+//!
+//! Each function returns the encoded bytes of one term; terms nest by
+//! passing the encoded children in.  Paths are written as in ASL, e.g.
+//! `\_SB.COM1`, with segments shorter than four characters padded with
+//! `_`.
+//!
+//! This is only for the crate's own DSDT, which is built from constants:
+//! the functions panic on input that can't be encoded, rather than
+//! reporting it.
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const DEVICE_OP: u8 = 0x82;
+const ONES_OP: u8 = 0xff;
+
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+
+// small resource descriptors.
+const IRQ_DESCRIPTOR: u8 = 0x22;
+const IO_DESCRIPTOR: u8 = 0x47;
+const END_TAG: u8 = 0x79;
+
+/// `Name(path, value)`.
+pub fn name(path: &str, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![NAME_OP];
+    out.extend(name_string(path));
+    out.extend_from_slice(value);
+    out
+}
+
+/// `Scope(path) { terms }`.
+pub fn scope(path: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = name_string(path);
+    body.extend(terms.concat());
+    with_pkg_length(&[SCOPE_OP], &body)
+}
+
+/// `Device(path) { terms }`.
+pub fn device(path: &str, terms: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = name_string(path);
+    body.extend(terms.concat());
+    with_pkg_length(&[EXT_OP_PREFIX, DEVICE_OP], &body)
+}
+
+/// An integer, in the shortest encoding.
+pub fn integer(value: u64) -> Vec<u8> {
+    match value {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        v if v == u64::MAX => vec![ONES_OP],
+        v if v <= 0xff => vec![BYTE_PREFIX, v as u8],
+        v if v <= 0xffff => prefixed(WORD_PREFIX, &(v as u16).to_le_bytes()),
+        v if v <= 0xffff_ffff => prefixed(DWORD_PREFIX, &(v as u32).to_le_bytes()),
+        v => prefixed(QWORD_PREFIX, &v.to_le_bytes()),
+    }
+}
+
+/// `EisaId(id)`: a seven character id like `PNP0501`, compressed into a
+/// 32-bit integer.
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let id = id.as_bytes();
+    assert!(id.len() == 7, "an EISA id is seven characters");
+
+    let letter = |c: u8| {
+        assert!(
+            c.is_ascii_uppercase(),
+            "an EISA id starts with capital letters"
+        );
+        u32::from(c - b'@')
+    };
+    let hex = |c: u8| {
+        (c as char)
+            .to_digit(16)
+            .expect("an EISA id ends in hex digits")
+    };
+    let vendor = letter(id[0]) << 10 | letter(id[1]) << 5 | letter(id[2]);
+    let product = id[3..].iter().fold(0, |v, &c| v << 4 | hex(c));
+    // the id is stored big endian.
+    let value = (vendor << 16 | product).swap_bytes();
+    prefixed(DWORD_PREFIX, &value.to_le_bytes())
+}
+
+/// `Buffer() { bytes }`.
+pub fn buffer(bytes: &[u8]) -> Vec<u8> {
+    let mut body = integer(bytes.len() as u64);
+    body.extend_from_slice(bytes);
+    with_pkg_length(&[BUFFER_OP], &body)
+}
+
+/// `ResourceTemplate() { descriptors }`, from descriptors made by
+/// [`io`] and [`irq`].
+pub fn resource_template(descriptors: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = descriptors.concat();
+    // a checksum of 0 means the template isn't checksummed.
+    bytes.extend_from_slice(&[END_TAG, 0]);
+    buffer(&bytes)
+}
+
+/// `IO(Decode16, base, base, 1, len)`: a fixed range of I/O ports.
+pub fn io(base: u16, len: u8) -> Vec<u8> {
+    let [lo, hi] = base.to_le_bytes();
+    vec![IO_DESCRIPTOR, 1, lo, hi, lo, hi, 1, len]
+}
+
+/// `IRQNoFlags() { irq }`: an edge triggered, active high ISA interrupt,
+/// from 0 to 15.
+pub fn irq(irq: u8) -> Vec<u8> {
+    assert!(irq < 16, "an ISA interrupt is below 16");
+    let [lo, hi] = (1u16 << irq).to_le_bytes();
+    vec![IRQ_DESCRIPTOR, lo, hi]
+}
+
+fn prefixed(prefix: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![prefix];
+    out.extend_from_slice(bytes);
+    out
+}
+
+/// The opcode, then the length of the body (including the length itself),
+/// then the body.
+fn with_pkg_length(op: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut out = op.to_vec();
+    out.extend(pkg_length(body.len()));
+    out.extend_from_slice(body);
+    out
+}
+
+fn pkg_length(body: usize) -> Vec<u8> {
+    // a single byte holds 6 bits; each extra byte adds 8, above 4 bits
+    // in the lead byte.
+    if body + 1 < 0x40 {
+        return vec![(body + 1) as u8];
+    }
+
+    let extra = if body + 2 < 1 << 12 {
+        1
+    } else if body + 3 < 1 << 20 {
+        2
+    } else {
+        assert!(body + 4 < 1 << 28, "AML packages are under 256 MiB");
+        3
+    };
+    let len = body + 1 + extra;
+    let mut out = vec![(extra << 6) as u8 | (len & 0xf) as u8];
+    for i in 0..extra {
+        out.push((len >> (4 + 8 * i)) as u8);
+    }
+
+    out
+}
+
+fn name_string(path: &str) -> Vec<u8> {
+    let mut out = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&c, tail)) = rest.split_first() {
+        if c != ROOT_CHAR && c != PARENT_PREFIX_CHAR {
+            break;
+        }
+        out.push(c);
+        rest = tail;
+    }
+
+    let segments = rest
+        .split(|&c| c == b'.')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    match segments.len() {
+        0 => out.push(ZERO_OP),
+        1 => (),
+        2 => out.push(DUAL_NAME_PREFIX),
+        n => out.extend_from_slice(&[MULTI_NAME_PREFIX, n as u8]),
+    }
+    for segment in segments {
+        assert!(segment.len() <= 4, "AML name segments are four characters");
+        out.extend_from_slice(segment);
+        out.resize(out.len() + 4 - segment.len(), b'_');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkg_length_boundaries() {
+        // the length counts its own bytes.
+        assert_eq!(pkg_length(0), [0x01]);
+        assert_eq!(pkg_length(0x3e), [0x3f]);
+        assert_eq!(pkg_length(0x3f), [0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), [0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), [0x81, 0x00, 0x01]);
+        assert_eq!(pkg_length(0xf_fffc), [0x8f, 0xff, 0xff]);
+        assert_eq!(pkg_length(0xf_fffd), [0xc1, 0x00, 0x00, 0x01]);
+        assert_eq!(pkg_length(0xfff_fffb), [0xcf, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pkg_length_too_long() {
+        pkg_length(0xfff_fffc);
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(integer(0), [ZERO_OP]);
+        assert_eq!(integer(1), [ONE_OP]);
+        assert_eq!(integer(0xff), [BYTE_PREFIX, 0xff]);
+        assert_eq!(integer(0x100), [WORD_PREFIX, 0, 1]);
+        assert_eq!(integer(0x1_0000), [DWORD_PREFIX, 0, 0, 1, 0]);
+        assert_eq!(integer(1 << 32), [QWORD_PREFIX, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(integer(u64::MAX), [ONES_OP]);
+
+        assert_eq!(eisa_id("PNP0501"), [DWORD_PREFIX, 0x41, 0xd0, 0x05, 0x01]);
+        assert_eq!(name_string("\\_SB.COM1"), b"\\\x2e_SB_COM1".to_vec());
+        assert_eq!(name_string("^A.B.C"), b"^\x2f\x03A___B___C___".to_vec());
+        assert_eq!(name("_UID", &integer(0)), b"\x08_UID\x00".to_vec());
+
+        assert_eq!(irq(4), [IRQ_DESCRIPTOR, 0x10, 0]);
+        assert_eq!(irq(15), [IRQ_DESCRIPTOR, 0, 0x80]);
+        assert_eq!(io(0x3f8, 8), [IO_DESCRIPTOR, 1, 0xf8, 3, 0xf8, 3, 1, 8]);
+        assert_eq!(
+            resource_template(&[irq(1)]),
+            [
+                BUFFER_OP,
+                8,
+                BYTE_PREFIX,
+                5,
+                IRQ_DESCRIPTOR,
+                2,
+                0,
+                END_TAG,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn eisa_id_lowercase() {
+        eisa_id("pnp0501");
+    }
+
+    #[test]
+    #[should_panic]
+    fn irq_out_of_range() {
+        irq(16);
+    }
+}
@@ -0,0 +1,359 @@
+//! Generating ACPI tables for a guest.
+//!
+//! The tables describe a hardware-reduced ACPI platform with an in-kernel
+//! irqchip (see [`kvm_create_irqchip`](../fn.kvm_create_irqchip.html)):
+//!
+//! - the RSDP, pointing to the XSDT;
+//! - the XSDT, listing the FADT and the MADT;
+//! - the FADT, pointing to the DSDT;
+//! - the MADT, with a local APIC for each vCPU, the IOAPIC, and the
+//!   interrupt source override of IRQ 0 onto GSI 2, the way the in-kernel
+//!   irqchip wires the PIT;
+//! - the DSDT, with the first serial port and a power button.
+//!
+//! [`write`] lays these out in guest memory, by default at [`RSDP_ADDR`]
+//! where guests scan for the RSDP.  Every table is checksummed.
+
+use super::memory::GuestMemory;
+use super::vm::Vm;
+use nix;
+use nix::errno::Errno;
+
+mod aml;
+
+/// Where the tables go by default: in the BIOS area that guests scan for
+/// the RSDP.
+pub const RSDP_ADDR: u64 = 0xe_0000;
+/// The most room the tables take at [`RSDP_ADDR`]; this is the range to
+/// declare as ACPI in a [`MemoryMap`](../memory/struct.MemoryMap.html).
+pub const TABLES_MAX: u64 = 0x2_0000;
+
+/// Where the in-kernel local APICs are mapped.
+pub const LAPIC_ADDR: u32 = 0xfee0_0000;
+/// Where the in-kernel IOAPIC is mapped.
+pub const IOAPIC_ADDR: u32 = 0xfec0_0000;
+/// The id of the in-kernel IOAPIC, as it comes out of reset.
+pub const IOAPIC_ID: u8 = 0;
+
+/// The OEM id in every table.
+pub const OEM_ID: [u8; 6] = *b"KVMSYS";
+const OEM_TABLE_ID: [u8; 8] = *b"KVMSYS  ";
+const CREATOR_ID: [u8; 4] = *b"KVMS";
+
+const HEADER_SIZE: usize = 36;
+const RSDP_SIZE: usize = 36;
+const FADT_SIZE: usize = 276;
+
+// FADT fields.
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INT: usize = 46;
+const FADT_IAPC_BOOT_ARCH: usize = 109;
+const FADT_FLAGS: usize = 112;
+const FADT_MINOR_VERSION: usize = 131;
+const FADT_X_DSDT: usize = 140;
+const FADT_HYPERVISOR_ID: usize = 268;
+
+/// The power button is a control method device, not a fixed feature.
+const FADT_PWR_BUTTON: u32 = 1 << 4;
+/// There is no fixed feature sleep button.
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+const IAPC_LEGACY_DEVICES: u16 = 1 << 0;
+const IAPC_8042: u16 = 1 << 1;
+
+// MADT entries.
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_ENABLED: u32 = 1;
+
+/// The value that makes the bytes add up to zero.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b))
+        .wrapping_neg()
+}
+
+/// A system description table with the standard header: the signature, the
+/// revision, and then `body`.  The checksum is filled in.
+pub fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+    out.extend_from_slice(signature);
+    out.extend_from_slice(&((HEADER_SIZE + body.len()) as u32).to_le_bytes());
+    out.push(revision);
+    out.push(0);
+    out.extend_from_slice(&OEM_ID);
+    out.extend_from_slice(&OEM_TABLE_ID);
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&CREATOR_ID);
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(body);
+
+    out[9] = checksum(&out);
+    out
+}
+
+/// The ACPI 2.0 RSDP, pointing to the XSDT.
+pub fn rsdp(xsdt: u64) -> Vec<u8> {
+    let mut out = b"RSD PTR ".to_vec();
+    out.push(0);
+    out.extend_from_slice(&OEM_ID);
+    out.push(2);
+    // no RSDT; the XSDT supersedes it.
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(RSDP_SIZE as u32).to_le_bytes());
+    out.extend_from_slice(&xsdt.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+
+    // the first checksum covers the ACPI 1.0 part, the second all of it.
+    out[8] = checksum(&out[..20]);
+    out[32] = checksum(&out);
+    out
+}
+
+/// The XSDT, listing the other tables (besides the DSDT).
+pub fn xsdt(tables: &[u64]) -> Vec<u8> {
+    let body = tables
+        .iter()
+        .flat_map(|t| t.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    table(b"XSDT", 1, &body)
+}
+
+/// The FADT of a hardware-reduced platform with legacy devices and an
+/// 8042, pointing to the DSDT.
+pub fn fadt(dsdt: u64) -> Vec<u8> {
+    let mut body = vec![0; FADT_SIZE - HEADER_SIZE];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        let at = offset - HEADER_SIZE;
+        body[at..at + bytes.len()].copy_from_slice(bytes);
+    };
+
+    put(FADT_DSDT, &(dsdt as u32).to_le_bytes());
+    put(FADT_SCI_INT, &9u16.to_le_bytes());
+    put(
+        FADT_IAPC_BOOT_ARCH,
+        &(IAPC_LEGACY_DEVICES | IAPC_8042).to_le_bytes(),
+    );
+    put(
+        FADT_FLAGS,
+        &(FADT_PWR_BUTTON | FADT_SLP_BUTTON | FADT_HW_REDUCED_ACPI).to_le_bytes(),
+    );
+    put(FADT_MINOR_VERSION, &[0]);
+    put(FADT_X_DSDT, &dsdt.to_le_bytes());
+    put(FADT_HYPERVISOR_ID, b"KVMKVMKV");
+    table(b"FACP", 6, &body)
+}
+
+/// The MADT, with a local APIC for each of `cpus` vCPUs (with APIC ids,
+/// and processor ids, 0 and up), the IOAPIC, and the interrupt source
+/// override for the PIT.
+pub fn madt(cpus: u8) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&LAPIC_ADDR.to_le_bytes());
+    body.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+
+    for id in 0..cpus {
+        body.extend_from_slice(&[MADT_LOCAL_APIC, 8, id, id]);
+        body.extend_from_slice(&MADT_ENABLED.to_le_bytes());
+    }
+
+    body.extend_from_slice(&[MADT_IOAPIC, 12, IOAPIC_ID, 0]);
+    body.extend_from_slice(&IOAPIC_ADDR.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+
+    // ISA IRQ 0 is on GSI 2, with the bus's default polarity and trigger.
+    body.extend_from_slice(&[MADT_INTERRUPT_OVERRIDE, 10, 0, 0]);
+    body.extend_from_slice(&2u32.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+
+    // LINT1 of every processor is the NMI.
+    body.extend_from_slice(&[MADT_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+    table(b"APIC", 5, &body)
+}
+
+/// The DSDT, describing COM1 and a power button.
+pub fn dsdt() -> Vec<u8> {
+    let com1 = aml::device(
+        "COM1",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0501")),
+            aml::name("_UID", &aml::integer(0)),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[aml::io(0x3f8, 8), aml::irq(4)]),
+            ),
+        ],
+    );
+    let power_button = aml::device(
+        "PWRB",
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0C0C")),
+            aml::name("_UID", &aml::integer(0)),
+        ],
+    );
+
+    table(b"DSDT", 2, &aml::scope("\\_SB", &[com1, power_button]))
+}
+
+/// Writes the tables for `cpus` vCPUs into guest memory, with the RSDP at
+/// `addr` (which must be 16-byte aligned to be found by a scan) and the
+/// rest following it.  Returns the end of the tables.  Fails with `EINVAL`
+/// for more vCPUs than the MADT can describe.
+pub fn write(memory: &GuestMemory, addr: u64, cpus: usize) -> nix::Result<u64> {
+    if cpus == 0 || cpus > 255 {
+        return Err(nix::Error::Sys(Errno::EINVAL));
+    }
+
+    let align = |a: u64| (a + 15) & !15;
+    let xsdt_addr = align(addr + RSDP_SIZE as u64);
+    // the XSDT has two entries.
+    let fadt_addr = align(xsdt_addr + HEADER_SIZE as u64 + 16);
+    let madt_addr = align(fadt_addr + FADT_SIZE as u64);
+    let madt = madt(cpus as u8);
+    let dsdt_addr = align(madt_addr + madt.len() as u64);
+    let dsdt = dsdt();
+
+    memory.write(addr, &rsdp(xsdt_addr))?;
+    memory.write(xsdt_addr, &xsdt(&[fadt_addr, madt_addr]))?;
+    memory.write(fadt_addr, &fadt(dsdt_addr))?;
+    memory.write(madt_addr, &madt)?;
+    memory.write(dsdt_addr, &dsdt)?;
+    Ok(dsdt_addr + dsdt.len() as u64)
+}
+
+impl Vm {
+    /// Writes the ACPI tables for the vCPUs of the VM at [`RSDP_ADDR`].
+    /// See [`write`].
+    pub fn write_acpi(&self, memory: &GuestMemory) -> nix::Result<u64> {
+        write(memory, RSDP_ADDR, self.vcpus().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::anonymous_memory;
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    /// Checks the header of a table, and that it sums to zero.
+    fn check_table(bytes: &[u8], signature: &[u8; 4], revision: u8) {
+        assert_eq!(&bytes[..4], signature);
+        assert_eq!(le32(bytes, 4) as usize, bytes.len());
+        assert_eq!(bytes[8], revision);
+        assert_eq!(&bytes[10..16], &OEM_ID);
+        assert_eq!(sum(bytes), 0, "{:?} checksum", signature);
+    }
+
+    fn le32(bytes: &[u8], at: usize) -> u32 {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(&bytes[at..at + 4]);
+        u32::from_le_bytes(buf)
+    }
+
+    fn le64(bytes: &[u8], at: usize) -> u64 {
+        u64::from(le32(bytes, at)) | u64::from(le32(bytes, at + 4)) << 32
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[1, 2, 0xff]), 0xfe);
+
+        let rsdp = rsdp(0x1234_5678_9abc);
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(rsdp.len(), RSDP_SIZE);
+        assert_eq!(sum(&rsdp[..20]), 0);
+        assert_eq!(sum(&rsdp), 0);
+        assert_eq!(le64(&rsdp, 24), 0x1234_5678_9abc);
+
+        let xsdt = xsdt(&[0x1000, 0x2000]);
+        check_table(&xsdt, b"XSDT", 1);
+        assert_eq!(xsdt.len(), HEADER_SIZE + 16);
+        assert_eq!(le64(&xsdt, HEADER_SIZE + 8), 0x2000);
+
+        let fadt = fadt(0xe_1000);
+        check_table(&fadt, b"FACP", 6);
+        assert_eq!(fadt.len(), FADT_SIZE);
+        assert_eq!(le32(&fadt, FADT_DSDT), 0xe_1000);
+        assert_eq!(le64(&fadt, FADT_X_DSDT), 0xe_1000);
+        assert_ne!(le32(&fadt, FADT_FLAGS) & FADT_HW_REDUCED_ACPI, 0);
+
+        for &cpus in &[1, 2, 255] {
+            let madt = madt(cpus);
+            check_table(&madt, b"APIC", 5);
+            // the local APICs, then the IOAPIC, override and NMI entries.
+            assert_eq!(
+                madt.len(),
+                HEADER_SIZE + 8 + 8 * cpus as usize + 12 + 10 + 6
+            );
+            let last = HEADER_SIZE + 8 + 8 * (cpus as usize - 1);
+            assert_eq!(
+                &madt[last..last + 4],
+                &[MADT_LOCAL_APIC, 8, cpus - 1, cpus - 1]
+            );
+        }
+
+        check_table(&dsdt(), b"DSDT", 2);
+    }
+
+    #[test]
+    fn write_tables() {
+        let memory = anonymous_memory(0x10_0000);
+        let end = write(&memory, RSDP_ADDR, 4).unwrap();
+        assert!(end <= RSDP_ADDR + TABLES_MAX);
+
+        let read = |addr: u64, len: usize| {
+            let mut buf = vec![0; len];
+            memory.read(addr, &mut buf).unwrap();
+            buf
+        };
+        let table = |addr: u64| {
+            let len = memory.read_u32(addr + 4).unwrap();
+            read(addr, len as usize)
+        };
+
+        let rsdp = read(RSDP_ADDR, RSDP_SIZE);
+        assert_eq!(sum(&rsdp[..20]), 0);
+        assert_eq!(sum(&rsdp), 0);
+
+        let xsdt = table(le64(&rsdp, 24));
+        check_table(&xsdt, b"XSDT", 1);
+        let fadt = table(le64(&xsdt, HEADER_SIZE));
+        check_table(&fadt, b"FACP", 6);
+        let madt = table(le64(&xsdt, HEADER_SIZE + 8));
+        check_table(&madt, b"APIC", 5);
+        assert_eq!(madt, super::madt(4));
+
+        let dsdt_addr = le64(&fadt, FADT_X_DSDT);
+        assert_eq!(u64::from(le32(&fadt, FADT_DSDT)), dsdt_addr);
+        let dsdt = table(dsdt_addr);
+        check_table(&dsdt, b"DSDT", 2);
+        assert_eq!(dsdt_addr + dsdt.len() as u64, end);
+
+        // every table is 16-byte aligned, and they don't overlap.
+        let mut addrs = vec![RSDP_ADDR, le64(&rsdp, 24), le64(&xsdt, HEADER_SIZE)];
+        addrs.extend(&[le64(&xsdt, HEADER_SIZE + 8), dsdt_addr]);
+        let lens = [RSDP_SIZE, xsdt.len(), fadt.len(), madt.len(), dsdt.len()];
+        for (i, &addr) in addrs.iter().enumerate() {
+            assert!(addr.is_multiple_of(16));
+            if i > 0 {
+                assert!(addrs[i - 1] + lens[i - 1] as u64 <= addr);
+            }
+        }
+
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        assert_eq!(write(&memory, RSDP_ADDR, 0), Err(einval));
+        assert_eq!(write(&memory, RSDP_ADDR, 256), Err(einval));
+        assert_eq!(
+            write(&memory, 0xf_ff00, 1),
+            Err(nix::Error::Sys(Errno::EFAULT))
+        );
+    }
+}
@@ -24,6 +24,7 @@ pub const KVM_CAP_JOIN_MEMORY_REGIONS_WORKS: i32 = 30;
 pub const KVM_CAP_IRQFD: i32 = 32;
 pub const KVM_CAP_PIT2: i32 = 33;
 pub const KVM_CAP_SET_BOOT_CPU_ID: i32 = 34;
+pub const KVM_CAP_PIT_STATE2: i32 = 35;
 pub const KVM_CAP_IOEVENTFD: i32 = 36;
 pub const KVM_CAP_SET_IDENTITY_MAP_ADDR: i32 = 37;
 pub const KVM_CAP_XEN_HVM: i32 = 38;
@@ -37,6 +38,7 @@ pub const KVM_CAP_HYPERV_SPIN: i32 = 46;
 pub const KVM_CAP_PCI_SEGMENT: i32 = 47;
 pub const KVM_CAP_PPC_PAIRED_SINGLES: i32 = 48;
 pub const KVM_CAP_INTR_SHADOW: i32 = 49;
+pub const KVM_CAP_DEBUGREGS: i32 = 50;
 pub const KVM_CAP_X86_ROBUST_SINGLESTEP: i32 = 51;
 pub const KVM_CAP_PPC_OSI: i32 = 52;
 pub const KVM_CAP_PPC_UNSET_IRQ: i32 = 53;
@@ -139,6 +141,8 @@ pub const KVM_CAP_HYPERV_VP_INDEX: i32 = 149;
 pub const KVM_CAP_S390_AIS_MIGRATION: i32 = 150;
 pub const KVM_CAP_PPC_GET_CPU_CHAR: i32 = 151;
 pub const KVM_CAP_S390_BPB: i32 = 152;
+pub const KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2: i32 = 168;
+pub const KVM_CAP_DIRTY_LOG_RING: i32 = 192;
 
 pub const KVM_S390_RESET_POR: u64 = 1;
 pub const KVM_S390_RESET_CLEAR: u64 = 2;
@@ -178,6 +182,7 @@ pub const KVM_EXIT_SYSTEM_EVENT: u32 = 24;
 pub const KVM_EXIT_S390_STSI: u32 = 25;
 pub const KVM_EXIT_IOAPIC_EOI: u32 = 26;
 pub const KVM_EXIT_HYPERV: u32 = 27;
+pub const KVM_EXIT_DIRTY_RING_FULL: u32 = 31;
 
 pub const KVM_CLOCK_TSC_STABLE: u32 = 2;
 pub const KVM_PIT_SPEAKER_DUMMY: u32 = 1;
@@ -211,3 +216,29 @@ pub const KVM_IOEVENTFD_FLAG_VIRTIO_CCW_NOTIFY: u32 = 1 << 3;
 
 pub const KVM_IRQFD_FLAG_DEASSIGN: u32 = 1 << 0;
 pub const KVM_IRQFD_FLAG_RESAMPLE: u32 = 1 << 1;
+
+/// Set in the `flags` of a `kvm_msi`, or of an MSI routing entry, when its
+/// `devid` is valid.
+pub const KVM_MSI_VALID_DEVID: u32 = 1 << 0;
+
+pub const KVM_IRQ_ROUTING_IRQCHIP: u32 = 1;
+pub const KVM_IRQ_ROUTING_MSI: u32 = 2;
+
+/// Passed in `args[0]` when enabling [`KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2`];
+/// `kvm_get_dirty_log` stops write protecting the pages it reports.
+pub const KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE: u64 = 1 << 0;
+/// Passed in `args[0]` when enabling [`KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2`];
+/// newly created dirty-logged slots start with every page marked dirty.
+pub const KVM_DIRTY_LOG_INITIALLY_SET: u64 = 1 << 1;
+
+/// The page offset, in the vCPU file descriptor, at which the dirty ring is
+/// mapped.  This is the value for x86.
+pub const KVM_DIRTY_LOG_PAGE_OFFSET: i64 = 64;
+/// Set by KVM on a dirty ring entry once it has been filled in.
+pub const KVM_DIRTY_GFN_F_DIRTY: u32 = 1 << 0;
+/// Set by userspace on a dirty ring entry once it has been harvested.
+pub const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
+pub const KVM_IRQCHIP_PIC_MASTER: u32 = 0;
+pub const KVM_IRQCHIP_PIC_SLAVE: u32 = 1;
+pub const KVM_IRQCHIP_IOAPIC: u32 = 2;
@@ -1,10 +1,14 @@
-//! `KVM_GET_VCPU_EVENTS`, `KVM_SET_VCPU_EVENTS`
-//! `KVM_GET_DEBUGREGS`, and `KVM_SET_DEBUGREGS` are unsupported.
+// Each wrapper is a single ioctl, whose requirements on the file
+// descriptor and the pointed-to data are those of the ioctl itself; they
+// are documented under `# Support` instead.
+#![allow(clippy::missing_safety_doc)]
 
 pub const KVMIO: u8 = 0xAE;
 
 use libc::ioctl;
 use nix;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::mem::size_of;
 use std::os::unix::io::RawFd;
 
@@ -34,6 +38,35 @@ pub struct DirtyLog {
     pub value: DirtyLogValue,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+/// From the struct `kvm_clear_dirty_log`.
+pub struct ClearDirtyLog {
+    pub slot: u32,
+    /// The number of pages to clear.  This must be a multiple of 64, unless
+    /// the range reaches the end of the memory slot.
+    pub num_pages: u32,
+    /// The first page to clear.  This must be a multiple of 64.
+    pub first_page: u64,
+    /// The bitmap of pages to clear, where bit 0 corresponds to
+    /// `first_page`.  See [`DirtyLog`] for the layout of the union.
+    pub value: DirtyLogValue,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// From the struct `kvm_dirty_gfn`.  These are the entries of the per-vCPU
+/// dirty ring.
+pub struct DirtyGfn {
+    /// Either [`KVM_DIRTY_GFN_F_DIRTY`] (set by KVM) or
+    /// [`KVM_DIRTY_GFN_F_RESET`] (set by userspace once harvested).
+    pub flags: u32,
+    /// The memory slot, with the address space in bits 16-31.
+    pub slot: u32,
+    /// The page offset into the memory slot.
+    pub offset: u64,
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// From the struct `kvm_interrupt`.
@@ -43,23 +76,27 @@ pub struct Interrupt {
 
 #[repr(C)]
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_fpu`.
 pub struct Fpu {
     pub fpr: [[u8; 16]; 8],
     pub fcw: u16,
     pub fsw: u16,
     pub ftwx: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub pad1: u8,
     pub last_opcode: u16,
     pub last_ip: u64,
     pub last_dp: u64,
     pub xmm: [[u8; 16]; 16],
     pub mxcsr: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub pad2: u32,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_cpuid_entry`.
 pub struct CpuIdEntry {
     pub function: u32,
@@ -67,6 +104,7 @@ pub struct CpuIdEntry {
     pub ebx: u32,
     pub ecx: u32,
     pub edx: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub _pad: u32,
 }
 
@@ -109,10 +147,12 @@ pub struct XenHvmConfig {
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_clock_data`.
 pub struct ClockData {
     pub clock: u64,
     pub flags: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub _pad: [u32; 9],
 }
 
@@ -146,6 +186,7 @@ pub struct EnableCap {
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_mp_state`
 pub struct MpState {
     pub mp_state: u32,
@@ -167,9 +208,11 @@ pub struct Translation {
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_msr_entry`.
 pub struct MsrEntry {
     pub index: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub reserved: u32,
     pub data: u64,
 }
@@ -214,6 +257,251 @@ pub struct IrqFd {
     pub _pad: [u8; 16],
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// From the struct `kvm_msi`.
+pub struct Msi {
+    pub address_lo: u32,
+    pub address_hi: u32,
+    pub data: u32,
+    pub flags: u32,
+    pub devid: u32,
+    pub pad: [u8; 12],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// From the struct `kvm_irq_routing_entry`.  `u` is the union of the
+/// routing types: for [`KVM_IRQ_ROUTING_IRQCHIP`] it starts with the
+/// irqchip and pin, and for [`KVM_IRQ_ROUTING_MSI`] with the `address_lo`,
+/// `address_hi`, `data`, and `devid` of the message.
+pub struct IrqRoutingEntry {
+    pub gsi: u32,
+    // This is actually `type` in the kernel code.
+    pub kind: u32,
+    pub flags: u32,
+    pub pad: u32,
+    pub u: [u32; 8],
+}
+
+#[repr(C)]
+/// From the struct `kvm_irq_routing`.
+pub struct IrqRouting {
+    pub nr: u32,
+    pub flags: u32,
+    pub entries: [IrqRoutingEntry; 0],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_lapic_state`.  This is the raw register page of the
+/// local APIC.
+pub struct LapicState {
+    #[cfg_attr(feature = "serde", serde(with = "big_array"))]
+    pub regs: [u8; 1024],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_irqchip`.
+pub struct Irqchip {
+    /// One of [`KVM_IRQCHIP_PIC_MASTER`], [`KVM_IRQCHIP_PIC_SLAVE`], or
+    /// [`KVM_IRQCHIP_IOAPIC`].
+    pub chip_id: u32,
+    pub pad: u32,
+    /// This is meant to be a union of `struct kvm_pic_state` and
+    /// `struct kvm_ioapic_state`, depending on `chip_id`; we keep the raw
+    /// bytes.
+    #[cfg_attr(feature = "serde", serde(with = "big_array"))]
+    pub chip: [u8; 512],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_pit_channel_state`.
+pub struct PitChannelState {
+    pub count: u32,
+    pub latched_count: u16,
+    pub count_latched: u8,
+    pub status_latched: u8,
+    pub status: u8,
+    pub read_state: u8,
+    pub write_state: u8,
+    pub write_latch: u8,
+    pub rw_mode: u8,
+    pub mode: u8,
+    pub bcd: u8,
+    pub gate: u8,
+    pub count_load_time: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_pit_state2`.
+pub struct PitState2 {
+    pub channels: [PitChannelState; 3],
+    pub flags: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reserved: [u32; 9],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_xsave`.
+pub struct Xsave {
+    #[cfg_attr(feature = "serde", serde(with = "big_array"))]
+    pub region: [u32; 1024],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_xcr`.
+pub struct Xcr {
+    pub xcr: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reserved: u32,
+    pub value: u64,
+}
+
+/// The most extended control registers in [`Xcrs`].
+pub const KVM_MAX_XCRS: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_xcrs`.
+pub struct Xcrs {
+    /// The number of entries of `xcrs` in use.
+    pub nr_xcrs: u32,
+    pub flags: u32,
+    pub xcrs: [Xcr; KVM_MAX_XCRS],
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub padding: [u64; 16],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_debugregs`.
+pub struct DebugRegs {
+    /// `dr0` through `dr3`.
+    pub db: [u64; 4],
+    pub dr6: u64,
+    pub dr7: u64,
+    pub flags: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reserved: [u64; 9],
+}
+
+/// [`VcpuEvents::nmi`]`.pending` is valid.
+pub const KVM_VCPUEVENT_VALID_NMI_PENDING: u32 = 1u32 << 0;
+/// [`VcpuEvents::sipi_vector`] is valid.
+pub const KVM_VCPUEVENT_VALID_SIPI_VECTOR: u32 = 1u32 << 1;
+/// [`VcpuEvents::interrupt`]`.shadow` is valid.
+pub const KVM_VCPUEVENT_VALID_SHADOW: u32 = 1u32 << 2;
+/// [`VcpuEvents::smi`] is valid.
+pub const KVM_VCPUEVENT_VALID_SMM: u32 = 1u32 << 3;
+/// The exception payload fields of [`VcpuEvents`] are valid.
+pub const KVM_VCPUEVENT_VALID_PAYLOAD: u32 = 1u32 << 4;
+/// The pending triple fault in [`VcpuEvents`] is valid.
+pub const KVM_VCPUEVENT_VALID_TRIPLE_FAULT: u32 = 1u32 << 5;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The `exception` member of the struct `kvm_vcpu_events`.
+pub struct ExceptionEvent {
+    pub injected: u8,
+    pub nr: u8,
+    pub has_error_code: u8,
+    pub pending: u8,
+    pub error_code: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The `interrupt` member of the struct `kvm_vcpu_events`.
+pub struct InterruptEvent {
+    pub injected: u8,
+    pub nr: u8,
+    pub soft: u8,
+    pub shadow: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The `nmi` member of the struct `kvm_vcpu_events`.
+pub struct NmiEvent {
+    pub injected: u8,
+    pub pending: u8,
+    pub masked: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub pad: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The `smi` member of the struct `kvm_vcpu_events`.
+pub struct SmiEvent {
+    pub smm: u8,
+    pub pending: u8,
+    pub smm_inside_nmi: u8,
+    pub latched_init: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// From the struct `kvm_vcpu_events`.  `flags` is a combination of the
+/// `KVM_VCPUEVENT_VALID_*` constants, saying which fields are meaningful.
+pub struct VcpuEvents {
+    pub exception: ExceptionEvent,
+    pub interrupt: InterruptEvent,
+    pub nmi: NmiEvent,
+    pub sipi_vector: u32,
+    pub flags: u32,
+    pub smi: SmiEvent,
+    /// This is meant to be a union of reserved bytes and
+    /// `struct { __u8 pending; } triple_fault`; the first byte is the
+    /// pending triple fault, if [`KVM_VCPUEVENT_VALID_TRIPLE_FAULT`] is set.
+    pub reserved: [u8; 27],
+    pub exception_has_payload: u8,
+    pub exception_payload: u64,
+}
+
+/// Serializes arrays too long for serde's own implementations as
+/// sequences.
+#[cfg(feature = "serde")]
+mod big_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::convert::TryFrom;
+
+    pub fn serialize<S: Serializer, T: Serialize, const N: usize>(
+        array: &[T; N],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_seq(array.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(
+        d: D,
+    ) -> Result<[T; N], D::Error> {
+        let items = Vec::<T>::deserialize(d)?;
+        let len = items.len();
+        <[T; N]>::try_from(items).map_err(|_| D::Error::invalid_length(len, &"a full array"))
+    }
+}
+
 pub(crate) fn ehandle(out: i32) -> nix::Result<i32> {
     nix::errno::Errno::result(out)
 }
@@ -352,6 +640,47 @@ pub unsafe fn kvm_get_dirty_log(fd: RawFd, log: *const DirtyLog) -> nix::Result<
     ehandle(ioctl(fd, iow!(KVMIO, 0x42, size_of::<DirtyLog>()), log))
 }
 
+/// Clears the dirty status of a set of pages in a memory slot, and write
+/// protects them again.  Bit 0 of the bitmap corresponds to `first_page`
+/// in the memory slot; every page that has its bit set is cleared.  The
+/// `first_page` must be a multiple of 64, and `num_pages` must be a
+/// multiple of 64 as well unless `first_page + num_pages` is the size of
+/// the memory slot.
+///
+/// This is only useful once manual dirty log protection has been enabled
+/// using [`kvm_enable_cap`] with [`KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2`].
+/// Afterwards, [`kvm_get_dirty_log`] no longer write protects the pages it
+/// reports, and it is up to userspace to clear them (in as small of chunks
+/// as it likes) before reading their contents.
+///
+/// As with [`kvm_get_dirty_log`], bits 16-31 of `slot` specify the address
+/// space if [`KVM_CAP_MULTI_ADDRESS_SPACE`] is available.
+///
+/// # Support
+/// This ioctl is supported only by the x86, ARM, and arm64 architectures,
+/// and requires the [`KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2`] capability.  This
+/// is available only on the VM file descriptor.
+pub unsafe fn kvm_clear_dirty_log(fd: RawFd, log: *const ClearDirtyLog) -> nix::Result<i32> {
+    ehandle(ioctl(
+        fd,
+        iorw!(KVMIO, 0xc0, size_of::<ClearDirtyLog>()),
+        log,
+    ))
+}
+
+/// Resets the dirty ring entries of every vCPU in the VM that userspace has
+/// marked with [`KVM_DIRTY_GFN_F_RESET`], write protecting the pages they
+/// refer to again and freeing the entries for reuse.  Returns the number of
+/// entries that were reset.
+///
+/// # Support
+/// This ioctl is supported only by the x86 and arm64 architectures, and
+/// requires the [`KVM_CAP_DIRTY_LOG_RING`] capability to have been enabled.
+/// This is available only on the VM file descriptor.
+pub unsafe fn kvm_reset_dirty_rings(fd: RawFd) -> nix::Result<i32> {
+    ehandle(ioctl(fd, io!(KVMIO, 0xc7), 0))
+}
+
 /// This ioctl is used to run a guest virtual cpu.  While there are no
 /// explicit parameters, there is an implicit parameter block that can be
 /// obtained by `mmap`ing the vCPU file descriptor at offset 0, with the size
@@ -412,6 +741,18 @@ pub unsafe fn kvm_get_msrs(fd: RawFd, msrs: *mut Msrs) -> nix::Result<i32> {
     ehandle(ioctl(fd, iorw!(KVMIO, 0x88, size_of::<Msrs>()), msrs))
 }
 
+/// Writes model-specific registers to the vCPU.  Supported MSR indicies can
+/// be obtained using `kvm_get_msr_index_list`.  Returns the number of MSRs
+/// that were successfully written; writing stops at the first MSR that
+/// could not be set.
+///
+/// # Support
+/// This ioctl is supported only by the x86 architecture, and is a basic
+/// capability.  This is available only on the vCPU file descriptor.
+pub unsafe fn kvm_set_msrs(fd: RawFd, msrs: *const Msrs) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0x89, size_of::<Msrs>()), msrs))
+}
+
 /// Defines the vCPU responses to the CPUID instruction.
 ///
 /// # Support
@@ -454,6 +795,117 @@ pub unsafe fn kvm_set_fpu(fd: RawFd, fpu: *const Fpu) -> nix::Result<i32> {
     ehandle(ioctl(fd, iow!(KVMIO, 0x8d, size_of::<Fpu>()), fpu))
 }
 
+/// Reads the local APIC registers of the vCPU.  The data format and layout
+/// are the same as documented in the architecture manual.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the
+/// [`KVM_CAP_IRQCHIP`] capability (and an in-kernel irqchip).  This is
+/// available only on the vCPU file descriptor.
+pub unsafe fn kvm_get_lapic(fd: RawFd, lapic: *mut LapicState) -> nix::Result<i32> {
+    ehandle(ioctl(fd, ior!(KVMIO, 0x8e, size_of::<LapicState>()), lapic))
+}
+
+/// Writes the local APIC registers of the vCPU.  The data format and
+/// layout are the same as documented in the architecture manual.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the
+/// [`KVM_CAP_IRQCHIP`] capability (and an in-kernel irqchip).  This is
+/// available only on the vCPU file descriptor.
+pub unsafe fn kvm_set_lapic(fd: RawFd, lapic: *const LapicState) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0x8f, size_of::<LapicState>()), lapic))
+}
+
+/// Reads the xsave area of the vCPU, which is a superset of the state
+/// returned by [`kvm_get_fpu`].
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the [`KVM_CAP_XSAVE`]
+/// capability.  This is available only on the vCPU file descriptor.
+pub unsafe fn kvm_get_xsave(fd: RawFd, xsave: *mut Xsave) -> nix::Result<i32> {
+    ehandle(ioctl(fd, ior!(KVMIO, 0xa4, size_of::<Xsave>()), xsave))
+}
+
+/// Writes the xsave area of the vCPU.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the [`KVM_CAP_XSAVE`]
+/// capability.  This is available only on the vCPU file descriptor.
+pub unsafe fn kvm_set_xsave(fd: RawFd, xsave: *const Xsave) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0xa5, size_of::<Xsave>()), xsave))
+}
+
+/// Reads the extended control registers (such as `XCR0`) of the vCPU.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the [`KVM_CAP_XCRS`]
+/// capability.  This is available only on the vCPU file descriptor.
+pub unsafe fn kvm_get_xcrs(fd: RawFd, xcrs: *mut Xcrs) -> nix::Result<i32> {
+    ehandle(ioctl(fd, ior!(KVMIO, 0xa6, size_of::<Xcrs>()), xcrs))
+}
+
+/// Writes the extended control registers of the vCPU.  Only the first
+/// `nr_xcrs` entries are used.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the [`KVM_CAP_XCRS`]
+/// capability.  This is available only on the vCPU file descriptor.
+pub unsafe fn kvm_set_xcrs(fd: RawFd, xcrs: *const Xcrs) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0xa7, size_of::<Xcrs>()), xcrs))
+}
+
+/// Reads the pending and injected exceptions, interrupts, and NMIs of the
+/// vCPU, along with the interrupt shadow and SMM state.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the
+/// [`KVM_CAP_VCPU_EVENTS`] capability.  This is available only on the vCPU
+/// file descriptor.
+pub unsafe fn kvm_get_vcpu_events(fd: RawFd, events: *mut VcpuEvents) -> nix::Result<i32> {
+    ehandle(ioctl(
+        fd,
+        ior!(KVMIO, 0x9f, size_of::<VcpuEvents>()),
+        events,
+    ))
+}
+
+/// Writes the pending and injected events of the vCPU.  The NMI pending
+/// state, SIPI vector, interrupt shadow, and SMM state are only written if
+/// the matching `KVM_VCPUEVENT_VALID_*` flag is set.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the
+/// [`KVM_CAP_VCPU_EVENTS`] capability.  This is available only on the vCPU
+/// file descriptor.
+pub unsafe fn kvm_set_vcpu_events(fd: RawFd, events: *const VcpuEvents) -> nix::Result<i32> {
+    ehandle(ioctl(
+        fd,
+        iow!(KVMIO, 0xa0, size_of::<VcpuEvents>()),
+        events,
+    ))
+}
+
+/// Reads the debug registers of the vCPU.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the
+/// [`KVM_CAP_DEBUGREGS`] capability.  This is available only on the vCPU
+/// file descriptor.
+pub unsafe fn kvm_get_debugregs(fd: RawFd, regs: *mut DebugRegs) -> nix::Result<i32> {
+    ehandle(ioctl(fd, ior!(KVMIO, 0xa1, size_of::<DebugRegs>()), regs))
+}
+
+/// Writes the debug registers of the vCPU.  `flags` must be zero.
+///
+/// # Support
+/// This ioctl is supported only by x86, and requires the
+/// [`KVM_CAP_DEBUGREGS`] capability.  This is available only on the vCPU
+/// file descriptor.
+pub unsafe fn kvm_set_debugregs(fd: RawFd, regs: *const DebugRegs) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0xa2, size_of::<DebugRegs>()), regs))
+}
+
 /// Creates an interrupt control model in the kernel.  For x86,
 /// it creates a virtual ioapic, a virtual PIC (two PICs, nested), and
 /// sets up future vCPUs to have a local APIC.  IRQ routing for GSIs 0-15
@@ -493,6 +945,31 @@ pub unsafe fn kvm_irq_line_status(fd: RawFd, irq: *mut IrqLevel) -> nix::Result<
     ehandle(ioctl(fd, iorw!(KVMIO, 0x67, size_of::<IrqLevel>()), irq))
 }
 
+/// Reads the state of a kernel interrupt controller created with
+/// [`kvm_create_irqchip`] into a buffer provided by the caller.  The
+/// `chip_id` of the buffer selects which controller is read.
+///
+/// # Support
+/// This ioctl is only supported by the x86 architecture, and requires the
+/// [`KVM_CAP_IRQCHIP`] capability.  This is available only on the VM file
+/// descriptor.
+pub unsafe fn kvm_get_irqchip(fd: RawFd, chip: *mut Irqchip) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iorw!(KVMIO, 0x62, size_of::<Irqchip>()), chip))
+}
+
+/// Sets the state of a kernel interrupt controller created with
+/// [`kvm_create_irqchip`] from a buffer provided by the caller.  The
+/// `chip_id` of the buffer selects which controller is written.
+///
+/// # Support
+/// This ioctl is only supported by the x86 architecture, and requires the
+/// [`KVM_CAP_IRQCHIP`] capability.  This is available only on the VM file
+/// descriptor.
+pub unsafe fn kvm_set_irqchip(fd: RawFd, chip: *const Irqchip) -> nix::Result<i32> {
+    // yes, this is `_IOR` in the kernel headers.
+    ehandle(ioctl(fd, ior!(KVMIO, 0x63, size_of::<Irqchip>()), chip))
+}
+
 /// Sets the MSR that the Xen HVM guest uses to initialize its hypercall
 /// page, and provides the starting address and size of the hypercall
 /// blobs in userspace.  When the guest writes the MSR, kvm copies one
@@ -742,6 +1219,28 @@ pub unsafe fn kvm_create_pit2(fd: RawFd, pit: *const PitConfig) -> nix::Result<i
     ehandle(ioctl(fd, iow!(KVMIO, 0x77, size_of::<PitConfig>()), pit))
 }
 
+/// Retrieves the state of the in-kernel PIT model created with
+/// [`kvm_create_pit2`].
+///
+/// # Support
+/// This ioctl is supported by only the x86 architecture, and requires the
+/// [`KVM_CAP_PIT_STATE2`] capability.  This is only available on the VM file
+/// descriptor.
+pub unsafe fn kvm_get_pit2(fd: RawFd, pit: *mut PitState2) -> nix::Result<i32> {
+    ehandle(ioctl(fd, ior!(KVMIO, 0x9f, size_of::<PitState2>()), pit))
+}
+
+/// Sets the state of the in-kernel PIT model created with
+/// [`kvm_create_pit2`].
+///
+/// # Support
+/// This ioctl is supported by only the x86 architecture, and requires the
+/// [`KVM_CAP_PIT_STATE2`] capability.  This is only available on the VM file
+/// descriptor.
+pub unsafe fn kvm_set_pit2(fd: RawFd, pit: *const PitState2) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0xa0, size_of::<PitState2>()), pit))
+}
+
 /// Allows setting an eventfd to directly trigger a guest interrupt.
 /// `kvm_irqfd.fd` specifies the file descriptor to use as the eventfd and
 /// `kvm_irqfd.gsi` specifies the irqchip pin toggled by this event.  When
@@ -778,3 +1277,36 @@ pub unsafe fn kvm_create_pit2(fd: RawFd, pit: *const PitConfig) -> nix::Result<i
 pub unsafe fn kvm_irqfd(fd: RawFd, io: *const IrqFd) -> nix::Result<i32> {
     ehandle(ioctl(fd, iow!(KVMIO, 0x76, size_of::<IrqFd>()), io))
 }
+
+/// Directly injects an MSI message.  `flags` may have
+/// [`KVM_MSI_VALID_DEVID`] set, when the `devid` field holds a valid
+/// unique device identifier for the originator of the message; some
+/// interrupt controllers (the GICv3 ITS) require it.  Returns 0 if the
+/// guest blocked the MSI, or a positive number if it was delivered; fails
+/// with `EPERM` if no vCPU was its destination.
+///
+/// # Support
+/// This ioctl is supported by the x86 and arm64 architectures, and requires
+/// the [`KVM_CAP_SIGNAL_MSI`] capability, and an in-kernel irqchip.  This
+/// is available only on the VM file descriptor.
+pub unsafe fn kvm_signal_msi(fd: RawFd, msi: *const Msi) -> nix::Result<i32> {
+    ehandle(ioctl(fd, iow!(KVMIO, 0xa5, size_of::<Msi>()), msi))
+}
+
+/// Sets the GSI routing table entries, overwriting any previously set
+/// entries.  `nr` is the number of entries following the header; a GSI may
+/// have several entries, each of which is signalled when the GSI is.  The
+/// table replaces the default routing set up by [`kvm_create_irqchip`], so
+/// a table with MSI routes must also carry the irqchip routes still in use.
+///
+/// # Support
+/// This ioctl is supported by the x86, s390, arm, and arm64 architectures,
+/// and requires the [`KVM_CAP_IRQ_ROUTING`] capability.  This is available
+/// only on the VM file descriptor.
+pub unsafe fn kvm_set_gsi_routing(fd: RawFd, routing: *const IrqRouting) -> nix::Result<i32> {
+    ehandle(ioctl(
+        fd,
+        iow!(KVMIO, 0x6a, size_of::<IrqRouting>()),
+        routing,
+    ))
+}
@@ -0,0 +1,778 @@
+//! An emulated MC146818 real time clock, with its CMOS NVRAM.
+//!
+//! The clock runs off the host's wall clock, plus whatever offset the
+//! guest sets it to.  Update-ended, alarm and periodic interrupts go out on
+//! [`IRQ`]; they need something to call [`Cmos::poll`] when they're due,
+//! which [`spawn_timer`] does.  Without it, the interrupt flags are still
+//! brought up to date whenever the guest reads register C, which is how
+//! guests that poll the clock see them.
+
+use super::{BusDevice, Interrupt};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The index port; the data port follows it.
+pub const PORT: u16 = 0x70;
+/// The number of ports.
+pub const PORTS: u64 = 2;
+/// The ISA IRQ of the clock.
+pub const IRQ: u32 = 8;
+
+// time of day registers.
+const SECONDS: usize = 0x00;
+const SECONDS_ALARM: usize = 0x01;
+const MINUTES: usize = 0x02;
+const MINUTES_ALARM: usize = 0x03;
+const HOURS: usize = 0x04;
+const HOURS_ALARM: usize = 0x05;
+const DAY_OF_WEEK: usize = 0x06;
+const DAY_OF_MONTH: usize = 0x07;
+const MONTH: usize = 0x08;
+const YEAR: usize = 0x09;
+const CENTURY: usize = 0x32;
+
+const REG_A: usize = 0x0a;
+const REG_B: usize = 0x0b;
+const REG_C: usize = 0x0c;
+const REG_D: usize = 0x0d;
+
+const A_UIP: u8 = 1 << 7;
+const A_DIVIDER: u8 = 0x70;
+/// The divider setting that runs the 32.768 kHz time base.
+const A_DIVIDER_RUN: u8 = 0x20;
+const A_RATE: u8 = 0x0f;
+
+const B_SET: u8 = 1 << 7;
+const B_PIE: u8 = 1 << 6;
+const B_AIE: u8 = 1 << 5;
+const B_UIE: u8 = 1 << 4;
+const B_BINARY: u8 = 1 << 2;
+const B_24H: u8 = 1 << 1;
+
+const C_IRQF: u8 = 1 << 7;
+const C_PF: u8 = 1 << 6;
+const C_AF: u8 = 1 << 5;
+const C_UF: u8 = 1 << 4;
+
+const D_VRT: u8 = 1 << 7;
+
+// the memory size bytes.
+const BASE_MEMORY: usize = 0x15;
+const EXTENDED_MEMORY: usize = 0x17;
+const EXTENDED_MEMORY_2: usize = 0x30;
+const MEMORY_ABOVE_16M: usize = 0x34;
+const MEMORY_ABOVE_4G: usize = 0x5b;
+// the checksum over 0x10 to 0x2d, big endian.
+const CHECKSUM: usize = 0x2e;
+
+/// An alarm register with its top two bits set matches any value.
+const ALARM_DONT_CARE: u8 = 0xc0;
+/// How long before an update UIP is set for.
+const UPDATE_CYCLE: u32 = 244_000;
+
+/// The RTC, to be registered over [`PORTS`] ports at [`PORT`].
+pub struct Cmos {
+    index: usize,
+    nmi_masked: bool,
+    regs: [u8; 128],
+    // the guest's time less the host's, in seconds.
+    offset: i64,
+    // the last second that update-ended flags were raised for.
+    last_update: i64,
+    next_periodic: Option<Instant>,
+    irq: Option<Box<dyn Interrupt>>,
+    level: bool,
+    timer: Option<Thread>,
+}
+
+impl Cmos {
+    /// Creates the RTC, running and set to the host's time, in BCD and 24
+    /// hour mode, and raising `irq`, if any, for interrupts.
+    pub fn new(irq: Option<Box<dyn Interrupt>>) -> Cmos {
+        let mut regs = [0; 128];
+        // 32.768 kHz, and a periodic rate of 1024 Hz.
+        regs[REG_A] = A_DIVIDER_RUN | 0x06;
+        regs[REG_B] = B_24H;
+        regs[REG_D] = D_VRT;
+
+        let mut cmos = Cmos {
+            index: 0,
+            nmi_masked: false,
+            regs,
+            offset: 0,
+            last_update: 0,
+            next_periodic: None,
+            irq,
+            level: false,
+            timer: None,
+        };
+        cmos.last_update = cmos.now().0;
+        cmos.refresh();
+        cmos
+    }
+
+    /// Whether the guest masked NMIs, with bit 7 of the index port.
+    pub fn nmi_masked(&self) -> bool {
+        self.nmi_masked
+    }
+
+    /// Fills in the memory size bytes, and the checksum, for `low` bytes
+    /// of RAM below 4 GiB (including the first megabyte) and `high` bytes
+    /// above it.
+    pub fn set_memory_size(&mut self, low: u64, high: u64) {
+        const KIB: u64 = 1 << 10;
+        const MIB: u64 = 1 << 20;
+
+        let put16 = |regs: &mut [u8; 128], at: usize, value: u64| {
+            let value = value.min(0xffff) as u16;
+            regs[at..at + 2].copy_from_slice(&value.to_le_bytes());
+        };
+        put16(&mut self.regs, BASE_MEMORY, 640);
+        let extended = low.saturating_sub(MIB) / KIB;
+        put16(&mut self.regs, EXTENDED_MEMORY, extended);
+        put16(&mut self.regs, EXTENDED_MEMORY_2, extended);
+        // in 64 KiB units.
+        put16(
+            &mut self.regs,
+            MEMORY_ABOVE_16M,
+            low.saturating_sub(16 * MIB) >> 16,
+        );
+        let above_4g = (high >> 16).min(0xff_ffff) as u32;
+        self.regs[MEMORY_ABOVE_4G..MEMORY_ABOVE_4G + 3]
+            .copy_from_slice(&above_4g.to_le_bytes()[..3]);
+
+        let sum = self.regs[0x10..CHECKSUM]
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(u16::from(b)));
+        self.regs[CHECKSUM..CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    /// Brings the clock and the interrupt flags up to date, raising the
+    /// interrupt if one is due.  Returns how long until the next event
+    /// (the next update, or periodic interrupt), or `None` if the clock is
+    /// stopped.
+    pub fn poll(&mut self) -> Option<Duration> {
+        if !self.running() {
+            return None;
+        }
+
+        let (secs, nanos) = self.now();
+        if secs != self.last_update {
+            self.last_update = secs;
+            self.refresh();
+            let mut flags = C_UF;
+            if self.alarm_matches() {
+                flags |= C_AF;
+            }
+            self.raise(flags);
+        }
+        let mut next = Duration::new(0, 1_000_000_000 - nanos);
+
+        let now = Instant::now();
+        match (self.periodic(), self.next_periodic) {
+            (Some(period), Some(due)) => {
+                if now >= due {
+                    self.raise(C_PF);
+                    // missed periods are dropped.
+                    let due = due + period;
+                    self.next_periodic = Some(if due <= now { now + period } else { due });
+                }
+                if let Some(due) = self.next_periodic {
+                    next = next.min(due.saturating_duration_since(now));
+                }
+            }
+            (Some(period), None) => {
+                self.next_periodic = Some(now + period);
+                next = next.min(period);
+            }
+            (None, _) => self.next_periodic = None,
+        }
+
+        Some(next)
+    }
+
+    /// The host's time, plus the guest's offset.
+    fn now(&self) -> (i64, u32) {
+        let host = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (host.as_secs() as i64 + self.offset, host.subsec_nanos())
+    }
+
+    fn running(&self) -> bool {
+        self.regs[REG_A] & A_DIVIDER == A_DIVIDER_RUN && self.regs[REG_B] & B_SET == 0
+    }
+
+    /// The period of the periodic interrupt, if it is enabled.
+    fn periodic(&self) -> Option<Duration> {
+        let rate = self.regs[REG_A] & A_RATE;
+        if self.regs[REG_B] & B_PIE == 0 || rate == 0 {
+            return None;
+        }
+
+        // rates 1 and 2 are the same as 8 and 9.
+        let rate = if rate <= 2 { rate + 7 } else { rate };
+        Some(Duration::from_nanos(
+            (1_000_000_000u64 << (rate - 1)) / 32768,
+        ))
+    }
+
+    fn alarm_matches(&self) -> bool {
+        [
+            (SECONDS_ALARM, SECONDS),
+            (MINUTES_ALARM, MINUTES),
+            (HOURS_ALARM, HOURS),
+        ]
+        .iter()
+        .all(|&(alarm, time)| {
+            let alarm = self.regs[alarm];
+            alarm & ALARM_DONT_CARE == ALARM_DONT_CARE || alarm == self.regs[time]
+        })
+    }
+
+    /// Sets flags in register C, and raises the interrupt if one of them is
+    /// enabled.
+    fn raise(&mut self, flags: u8) {
+        self.regs[REG_C] |= flags;
+        let enabled = (self.regs[REG_B] & (B_PIE | B_AIE | B_UIE)) & self.regs[REG_C];
+        if enabled != 0 {
+            self.regs[REG_C] |= C_IRQF;
+        }
+        self.update_interrupt();
+    }
+
+    fn update_interrupt(&mut self) {
+        let level = self.regs[REG_C] & C_IRQF != 0;
+        if level == self.level {
+            return;
+        }
+
+        self.level = level;
+        if let Some(ref mut irq) = self.irq {
+            irq.set_level(level);
+        }
+    }
+
+    fn binary(&self) -> bool {
+        self.regs[REG_B] & B_BINARY != 0
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.binary() {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.binary() {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0xf)
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.regs[REG_B] & B_24H != 0 {
+            return self.encode(hour);
+        }
+
+        // in 12 hour mode, bit 7 is PM.
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        match hour % 12 {
+            0 => self.encode(12) | pm,
+            hour => self.encode(hour) | pm,
+        }
+    }
+
+    fn decode_hour(&self, value: u8) -> u8 {
+        if self.regs[REG_B] & B_24H != 0 {
+            return self.decode(value);
+        }
+
+        let pm = if value & 0x80 != 0 { 12 } else { 0 };
+        self.decode(value & 0x7f) % 12 + pm
+    }
+
+    /// Writes the current time into the time of day registers, unless
+    /// the guest is setting them.
+    fn refresh(&mut self) {
+        if self.regs[REG_B] & B_SET != 0 {
+            return;
+        }
+
+        let secs = self.now().0;
+        let days = secs.div_euclid(86400);
+        let time = secs.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        // January 1st, 1970 was a Thursday; Sunday is 1.
+        let day_of_week = ((days + 4).rem_euclid(7) + 1) as u8;
+
+        self.regs[SECONDS] = self.encode((time % 60) as u8);
+        self.regs[MINUTES] = self.encode((time / 60 % 60) as u8);
+        self.regs[HOURS] = self.encode_hour((time / 3600) as u8);
+        self.regs[DAY_OF_WEEK] = self.encode(day_of_week);
+        self.regs[DAY_OF_MONTH] = self.encode(day);
+        self.regs[MONTH] = self.encode(month);
+        self.regs[YEAR] = self.encode(year.rem_euclid(100) as u8);
+        self.regs[CENTURY] = self.encode(year.div_euclid(100) as u8);
+    }
+
+    /// Sets the clock to the time in the time of day registers, and
+    /// rewrites them from it, since what the guest wrote may not be a
+    /// valid time.
+    fn set_time(&mut self) {
+        let year = i64::from(self.decode(self.regs[CENTURY])) * 100
+            + i64::from(self.decode(self.regs[YEAR]));
+        let month = self.decode(self.regs[MONTH]).clamp(1, 12);
+        let day = self.decode(self.regs[DAY_OF_MONTH]).max(1);
+        let time = i64::from(self.decode_hour(self.regs[HOURS])) * 3600
+            + i64::from(self.decode(self.regs[MINUTES])) * 60
+            + i64::from(self.decode(self.regs[SECONDS]));
+
+        let secs = days_from_civil(year, month, day) * 86400 + time;
+        self.offset = 0;
+        self.offset = secs - self.now().0;
+        self.last_update = secs;
+        self.refresh();
+    }
+
+    fn read_register(&mut self, index: usize) -> u8 {
+        match index {
+            SECONDS | MINUTES | HOURS | DAY_OF_WEEK | DAY_OF_MONTH | MONTH | YEAR | CENTURY => {
+                self.refresh();
+                self.regs[index]
+            }
+            REG_A => {
+                let (_, nanos) = self.now();
+                if self.running() && nanos >= 1_000_000_000 - UPDATE_CYCLE {
+                    self.regs[REG_A] | A_UIP
+                } else {
+                    self.regs[REG_A]
+                }
+            }
+            REG_C => {
+                self.poll();
+                let flags = self.regs[REG_C];
+                self.regs[REG_C] = 0;
+                self.update_interrupt();
+                flags
+            }
+            _ => self.regs[index],
+        }
+    }
+
+    fn write_register(&mut self, index: usize, value: u8) {
+        match index {
+            SECONDS | MINUTES | HOURS | DAY_OF_WEEK | DAY_OF_MONTH | MONTH | YEAR | CENTURY => {
+                self.regs[index] = value;
+                // outside of SET, the clock carries on from the new time.
+                if self.regs[REG_B] & B_SET == 0 {
+                    self.set_time();
+                }
+            }
+            REG_A => {
+                self.regs[REG_A] = value & !A_UIP;
+                self.next_periodic = None;
+                self.wake_timer();
+            }
+            REG_B => {
+                let set = self.regs[REG_B] & B_SET != 0;
+                self.regs[REG_B] = value;
+                if set && value & B_SET == 0 {
+                    self.set_time();
+                }
+                self.next_periodic = None;
+                self.wake_timer();
+            }
+            // these are read only.
+            REG_C | REG_D => {}
+            _ => self.regs[index] = value,
+        }
+    }
+
+    fn wake_timer(&self) {
+        if let Some(ref timer) = self.timer {
+            timer.unpark();
+        }
+    }
+}
+
+impl BusDevice for Cmos {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        data[0] = match offset {
+            // the index port is write only.
+            0 => 0xff,
+            _ => {
+                let index = self.index;
+                self.read_register(index)
+            }
+        };
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        match offset {
+            0 => {
+                self.nmi_masked = data[0] & 0x80 != 0;
+                self.index = usize::from(data[0] & 0x7f);
+            }
+            _ => {
+                let index = self.index;
+                self.write_register(index, data[0]);
+            }
+        }
+    }
+}
+
+/// Spawns a thread that polls the RTC whenever an interrupt is due (see
+/// [`Cmos::poll`]).  It runs for as long as the RTC does.
+pub fn spawn_timer(cmos: Arc<Mutex<Cmos>>) -> JoinHandle<()> {
+    let handle = {
+        let cmos = Arc::downgrade(&cmos);
+        thread::spawn(move || {
+            while let Some(cmos) = cmos.upgrade() {
+                let next = cmos.lock().unwrap_or_else(|e| e.into_inner()).poll();
+                drop(cmos);
+                // a stopped clock is woken by the guest starting it.
+                thread::park_timeout(next.unwrap_or(Duration::from_secs(1)));
+            }
+        })
+    };
+
+    cmos.lock().unwrap_or_else(|e| e.into_inner()).timer = Some(handle.thread().clone());
+    handle
+}
+
+/// The days since 1970-01-01 of a date.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Levels(Arc<Mutex<Vec<bool>>>);
+
+    impl Interrupt for Levels {
+        fn set_level(&mut self, level: bool) {
+            self.0.lock().unwrap().push(level);
+        }
+    }
+
+    fn cmos() -> (Cmos, Levels) {
+        let levels = Levels::default();
+        (Cmos::new(Some(Box::new(levels.clone()))), levels)
+    }
+
+    fn read(cmos: &mut Cmos, index: usize) -> u8 {
+        let mut data = [0];
+        cmos.write(0, 0, &[index as u8]);
+        cmos.read(0, 1, &mut data);
+        data[0]
+    }
+
+    fn write(cmos: &mut Cmos, index: usize, value: u8) {
+        cmos.write(0, 0, &[index as u8]);
+        cmos.write(0, 1, &[value]);
+    }
+
+    /// Sets the time of day registers in SET mode, in the mode `mode`
+    /// (register B without SET), then lets the clock run.
+    fn set(cmos: &mut Cmos, mode: u8, time: &[(usize, u8)]) {
+        write(cmos, REG_B, mode | B_SET);
+        for &(index, value) in time {
+            write(cmos, index, value);
+        }
+        write(cmos, REG_B, mode);
+    }
+
+    // 2023-02-28, a Tuesday, at 23:59:30, in BCD.
+    const TIME: [(usize, u8); 7] = [
+        (CENTURY, 0x20),
+        (YEAR, 0x23),
+        (MONTH, 0x02),
+        (DAY_OF_MONTH, 0x28),
+        (HOURS, 0x23),
+        (MINUTES, 0x59),
+        (SECONDS, 0x30),
+    ];
+
+    #[test]
+    fn bcd_and_binary() {
+        let mut cmos = Cmos::new(None);
+        set(&mut cmos, B_24H, &TIME);
+        for &(index, value) in &TIME[..6] {
+            assert_eq!(read(&mut cmos, index), value);
+        }
+        assert!(read(&mut cmos, SECONDS) >= 0x30);
+        assert_eq!(read(&mut cmos, DAY_OF_WEEK), 3);
+
+        // switching to binary doesn't move the clock.
+        write(&mut cmos, REG_B, B_24H | B_BINARY);
+        assert_eq!(read(&mut cmos, HOURS), 23);
+        assert_eq!(read(&mut cmos, MINUTES), 59);
+        assert_eq!(read(&mut cmos, YEAR), 23);
+        assert_eq!(read(&mut cmos, CENTURY), 20);
+
+        set(
+            &mut cmos,
+            B_24H | B_BINARY,
+            &[(YEAR, 99), (MONTH, 12), (DAY_OF_MONTH, 31), (HOURS, 10)],
+        );
+        write(&mut cmos, REG_B, B_24H);
+        assert_eq!(read(&mut cmos, YEAR), 0x99);
+        assert_eq!(read(&mut cmos, MONTH), 0x12);
+        assert_eq!(read(&mut cmos, DAY_OF_MONTH), 0x31);
+        assert_eq!(read(&mut cmos, HOURS), 0x10);
+    }
+
+    #[test]
+    fn hour_modes() {
+        let mut cmos = Cmos::new(None);
+        for &(hour, twelve) in &[(0x00, 0x12), (0x01, 0x01), (0x12, 0x92), (0x23, 0x91)] {
+            set(&mut cmos, B_24H, &[(HOURS, hour), (MINUTES, 0)]);
+            write(&mut cmos, REG_B, 0);
+            assert_eq!(read(&mut cmos, HOURS), twelve);
+            write(&mut cmos, REG_B, B_24H);
+            assert_eq!(read(&mut cmos, HOURS), hour);
+        }
+
+        // 1 PM, set in 12 hour binary mode.
+        set(&mut cmos, B_BINARY, &[(HOURS, 0x81)]);
+        write(&mut cmos, REG_B, B_24H | B_BINARY);
+        assert_eq!(read(&mut cmos, HOURS), 13);
+    }
+
+    #[test]
+    fn set_mode() {
+        let mut cmos = Cmos::new(None);
+        write(&mut cmos, REG_B, B_24H | B_SET);
+        write(&mut cmos, SECONDS, 0x45);
+        write(&mut cmos, MINUTES, 0x99);
+        // the registers hold what was written while SET is on.
+        assert_eq!(read(&mut cmos, SECONDS), 0x45);
+        assert_eq!(read(&mut cmos, MINUTES), 0x99);
+        assert_eq!(cmos.poll(), None);
+        // there are no updates in progress either, and UIP isn't writable.
+        write(&mut cmos, REG_A, A_UIP | A_DIVIDER_RUN | 0x06);
+        assert_eq!(read(&mut cmos, REG_A), A_DIVIDER_RUN | 0x06);
+
+        // outside of SET, a time that isn't valid is carried over at once.
+        set(&mut cmos, B_24H, &TIME);
+        write(&mut cmos, HOURS, 0x24);
+        assert_eq!(cmos.regs[HOURS], 0x00);
+        assert_eq!(cmos.regs[DAY_OF_MONTH], 0x01);
+        assert_eq!(cmos.regs[MONTH], 0x03);
+        assert_eq!(read(&mut cmos, DAY_OF_WEEK), 4);
+    }
+
+    #[test]
+    fn update_in_progress() {
+        let mut cmos = Cmos::new(None);
+        // UIP is set for the last 244 us of each second.
+        let start = Instant::now();
+        let mut seen = false;
+        while !seen && start.elapsed() < Duration::from_secs(2) {
+            seen = read(&mut cmos, REG_A) & A_UIP != 0;
+        }
+        assert!(seen);
+
+        // a stopped divider doesn't update.
+        write(&mut cmos, REG_A, 0x06);
+        assert_eq!(cmos.poll(), None);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(100) {
+            assert_eq!(read(&mut cmos, REG_A), 0x06);
+        }
+    }
+
+    #[test]
+    fn alarm() {
+        let mut cmos = Cmos::new(None);
+        set(&mut cmos, B_24H, &TIME);
+        write(&mut cmos, REG_B, B_24H | B_SET);
+        for &(alarm, value) in &[
+            (SECONDS_ALARM, 0x30),
+            (MINUTES_ALARM, 0x59),
+            (HOURS_ALARM, 0x23),
+        ] {
+            write(&mut cmos, alarm, value);
+        }
+        assert!(cmos.alarm_matches());
+        write(&mut cmos, SECONDS_ALARM, 0x31);
+        assert!(!cmos.alarm_matches());
+        // with the top two bits set, an alarm register matches anything.
+        write(&mut cmos, SECONDS_ALARM, ALARM_DONT_CARE);
+        assert!(cmos.alarm_matches());
+        write(&mut cmos, HOURS_ALARM, 0xff);
+        assert!(cmos.alarm_matches());
+        write(&mut cmos, MINUTES_ALARM, 0x58);
+        assert!(!cmos.alarm_matches());
+    }
+
+    #[test]
+    fn alarm_interrupt() {
+        let (mut cmos, levels) = cmos();
+        // an alarm that matches every second, and one that never does.
+        for &alarm in &[SECONDS_ALARM, MINUTES_ALARM, HOURS_ALARM] {
+            write(&mut cmos, alarm, ALARM_DONT_CARE);
+        }
+        write(&mut cmos, REG_B, B_24H | B_AIE);
+        cmos.last_update -= 1;
+        cmos.poll();
+        assert_eq!(cmos.regs[REG_C], C_IRQF | C_AF | C_UF);
+        assert_eq!(*levels.0.lock().unwrap(), [true]);
+        // reading register C clears it, and lowers the interrupt.
+        assert_eq!(read(&mut cmos, REG_C) & (C_IRQF | C_AF), C_IRQF | C_AF);
+        assert_eq!(cmos.regs[REG_C], 0);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+
+        write(&mut cmos, SECONDS_ALARM, 0x60);
+        cmos.last_update -= 1;
+        cmos.poll();
+        assert_eq!(cmos.regs[REG_C], C_UF);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn periodic() {
+        let mut cmos = Cmos::new(None);
+        // no rate, or no PIE, is no periodic interrupt.
+        assert_eq!(cmos.periodic(), None);
+        write(&mut cmos, REG_B, B_24H | B_PIE);
+        let period = |cmos: &mut Cmos, rate: u8| {
+            write(cmos, REG_A, A_DIVIDER_RUN | rate);
+            cmos.periodic()
+        };
+        assert_eq!(period(&mut cmos, 0), None);
+        assert_eq!(period(&mut cmos, 6), Some(Duration::from_nanos(976_562)));
+        assert_eq!(period(&mut cmos, 3), Some(Duration::from_nanos(122_070)));
+        assert_eq!(period(&mut cmos, 15), Some(Duration::from_millis(500)));
+        // rates 1 and 2 are the same as 8 and 9.
+        assert_eq!(period(&mut cmos, 1), period(&mut cmos, 8));
+        assert_eq!(period(&mut cmos, 2), period(&mut cmos, 9));
+    }
+
+    #[test]
+    fn periodic_interrupt() {
+        let (mut cmos, levels) = cmos();
+        write(&mut cmos, REG_A, A_DIVIDER_RUN | 3);
+        write(&mut cmos, REG_B, B_24H | B_PIE);
+        // the first poll starts the period, and waits for it at most.
+        let period = Duration::from_nanos(122_070);
+        assert!(cmos.poll().unwrap() <= period);
+        assert!(cmos.next_periodic.is_some());
+
+        thread::sleep(Duration::from_millis(1));
+        assert!(cmos.poll().unwrap() <= period);
+        assert_eq!(cmos.regs[REG_C] & (C_IRQF | C_PF), C_IRQF | C_PF);
+        assert_eq!(*levels.0.lock().unwrap(), [true]);
+        assert_eq!(read(&mut cmos, REG_C) & (C_IRQF | C_PF), C_IRQF | C_PF);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+
+        // turning it off stops the period.
+        write(&mut cmos, REG_B, B_24H);
+        cmos.poll();
+        assert_eq!(cmos.next_periodic, None);
+        assert_eq!(read(&mut cmos, REG_C) & C_PF, 0);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn update_interrupt() {
+        let (mut cmos, levels) = cmos();
+        // without UIE, the flag is set but the interrupt isn't raised.
+        cmos.last_update -= 1;
+        cmos.poll();
+        assert_eq!(cmos.regs[REG_C] & (C_IRQF | C_UF), C_UF);
+        assert!(levels.0.lock().unwrap().is_empty());
+
+        write(&mut cmos, REG_B, B_24H | B_UIE);
+        cmos.last_update -= 1;
+        cmos.poll();
+        assert_eq!(cmos.regs[REG_C] & (C_IRQF | C_UF), C_IRQF | C_UF);
+        assert_eq!(*levels.0.lock().unwrap(), [true]);
+        // it stays raised until register C is read.
+        cmos.last_update -= 1;
+        cmos.poll();
+        assert_eq!(*levels.0.lock().unwrap(), [true]);
+        read(&mut cmos, REG_C);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn nmi_mask() {
+        let mut cmos = Cmos::new(None);
+        assert!(!cmos.nmi_masked());
+        cmos.write(0, 0, &[0x80 | REG_B as u8]);
+        assert!(cmos.nmi_masked());
+        // the index is the rest of the byte.
+        let mut data = [0];
+        cmos.read(0, 1, &mut data);
+        assert_eq!(data[0], B_24H);
+        cmos.write(0, 0, &[REG_B as u8]);
+        assert!(!cmos.nmi_masked());
+    }
+
+    #[test]
+    fn memory_size() {
+        const MIB: u64 = 1 << 20;
+        let mut cmos = Cmos::new(None);
+        let bytes = |cmos: &mut Cmos, at: usize, len: usize| {
+            (at..at + len)
+                .map(|index| read(cmos, index))
+                .collect::<Vec<_>>()
+        };
+
+        cmos.set_memory_size(8 * MIB, 0);
+        assert_eq!(bytes(&mut cmos, BASE_MEMORY, 2), [0x80, 0x02]);
+        assert_eq!(bytes(&mut cmos, EXTENDED_MEMORY, 2), [0x00, 0x1c]);
+        assert_eq!(bytes(&mut cmos, EXTENDED_MEMORY_2, 2), [0x00, 0x1c]);
+        assert_eq!(bytes(&mut cmos, MEMORY_ABOVE_16M, 2), [0, 0]);
+        assert_eq!(bytes(&mut cmos, MEMORY_ABOVE_4G, 3), [0, 0, 0]);
+        // the checksum of 0x10 to 0x2d is big endian.
+        assert_eq!(bytes(&mut cmos, CHECKSUM, 2), [0x00, 0x9e]);
+
+        // the extended memory saturates; the rest is in 64 KiB units.
+        cmos.set_memory_size(512 * MIB, 4096 * MIB);
+        assert_eq!(bytes(&mut cmos, EXTENDED_MEMORY, 2), [0xff, 0xff]);
+        assert_eq!(bytes(&mut cmos, EXTENDED_MEMORY_2, 2), [0xff, 0xff]);
+        assert_eq!(bytes(&mut cmos, MEMORY_ABOVE_16M, 2), [0x00, 0x1f]);
+        assert_eq!(bytes(&mut cmos, MEMORY_ABOVE_4G, 3), [0x00, 0x00, 0x01]);
+        assert_eq!(bytes(&mut cmos, CHECKSUM, 2), [0x02, 0x80]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Ports for guests under test: the Bochs debug console, and QEMU's
+//! `isa-debug-exit`.
+//!
+//! Whatever the guest writes to the debug console port goes straight to
+//! the host.  A write to the exit port ends the run with a status code,
+//! through a [`VmEvent::Exit`]: the run loop (see
+//! [`MappedRun::run`](../../run/struct.MappedRun.html#method.run)) returns
+//! it as a [`RunResult::Exited`](../../run/enum.RunResult.html).  As with
+//! QEMU, the code is the value written shifted left by one, with bit 0 set,
+//! so that no write exits with a 0 that could be mistaken for success.
+
+use super::{BusDevice, VmEvent};
+use std::io::Write;
+use std::sync::mpsc::Sender;
+
+/// The port of the debug console.
+pub const CONSOLE_PORT: u16 = 0xe9;
+/// Where QEMU puts the exit port by default.
+pub const EXIT_PORT: u16 = 0x501;
+/// The number of ports QEMU's exit port takes by default.
+pub const EXIT_PORTS: u64 = 2;
+
+/// The debug console, to be registered over the one port at
+/// [`CONSOLE_PORT`].  Reads return the port number, which is how guests
+/// detect it.
+pub struct DebugConsole {
+    out: Box<dyn Write + Send>,
+}
+
+impl DebugConsole {
+    /// Creates a debug console writing to `out`.
+    pub fn new(out: Box<dyn Write + Send>) -> DebugConsole {
+        DebugConsole { out }
+    }
+}
+
+impl BusDevice for DebugConsole {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b = CONSOLE_PORT as u8;
+        }
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) {
+        if let Err(e) = self.out.write_all(data).and_then(|_| self.out.flush()) {
+            warn!("couldn't write debug console output: {}", e);
+        }
+    }
+}
+
+/// The exit port, to be registered over any number of ports (QEMU's is
+/// [`EXIT_PORTS`] at [`EXIT_PORT`]).  A write of up to four bytes, at any
+/// offset, exits.
+pub struct DebugExit {
+    events: Sender<VmEvent>,
+}
+
+impl DebugExit {
+    /// Creates an exit port, sending the exits to `events`.
+    pub fn new(events: Sender<VmEvent>) -> DebugExit {
+        DebugExit { events }
+    }
+}
+
+impl BusDevice for DebugExit {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b = 0;
+        }
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) {
+        let value = data
+            .iter()
+            .take(4)
+            .rev()
+            .fold(0u32, |value, &b| value << 8 | u32::from(b));
+        let code = value << 1 | 1;
+
+        debug!("debug exit with {:#x}", code);
+        if self.events.send(VmEvent::Exit(code)).is_err() {
+            warn!("no one to exit the VM");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn console() {
+        let out = Output::default();
+        let mut console = DebugConsole::new(Box::new(out.clone()));
+        let port = u64::from(CONSOLE_PORT);
+        console.write(port, 0, b"hi");
+        console.write(port, 0, b"!\n");
+        assert_eq!(*out.0.lock().unwrap(), b"hi!\n");
+
+        let mut data = [0; 2];
+        console.read(port, 0, &mut data);
+        assert_eq!(data, [0xe9; 2]);
+    }
+
+    #[test]
+    fn exit() {
+        let (tx, rx) = mpsc::channel();
+        let mut exit = DebugExit::new(tx);
+        let port = u64::from(EXIT_PORT);
+        exit.write(port, 0, &[0]);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Exit(1)));
+        exit.write(port, 0, &[0x2a]);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Exit(0x55)));
+        exit.write(port, 1, &[0x34, 0x12]);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Exit(0x2469)));
+        exit.write(port, 0, &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Exit(0x2468_acf1)));
+        // the top bit of a four-byte value is shifted out.
+        exit.write(port, 0, &[0xff; 4]);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Exit(!0)));
+        assert!(rx.try_recv().is_err());
+
+        let mut data = [0xff; 2];
+        exit.read(port, 0, &mut data);
+        assert_eq!(data, [0; 2]);
+    }
+}
@@ -0,0 +1,552 @@
+//! QEMU's firmware configuration device, `fw_cfg`, as found on x86.
+//!
+//! Firmware like SeaBIOS and OVMF reads its configuration from numbered
+//! items, selected by writing the item's key to the selector port.  The
+//! selected item is then either read a byte at a time from the data port,
+//! or copied into guest memory by the DMA interface, which is given the
+//! guest physical address of a `FWCfgDmaAccess` structure.  Besides the
+//! standard keys, items can be added as named files, which are listed in
+//! the file directory item.  Every item is read only.
+
+use super::BusDevice;
+use memory::{E820Entry, GuestMemory};
+use nix;
+use nix::errno::Errno;
+use std::collections::BTreeMap;
+
+/// The selector port, which is 16 bits wide; the data port follows it.
+pub const PORT: u16 = 0x510;
+/// The number of ports at [`PORT`].
+pub const PORTS: u64 = 2;
+/// The DMA address port, which is 64 bits wide and big endian.
+pub const DMA_PORT: u16 = 0x514;
+/// The number of ports at [`DMA_PORT`].
+pub const DMA_PORTS: u64 = 8;
+
+/// The `QEMU` signature.
+pub const FW_CFG_SIGNATURE: u16 = 0x00;
+/// The features of the interface.
+pub const FW_CFG_ID: u16 = 0x01;
+/// The VM's UUID, as 16 bytes.
+pub const FW_CFG_UUID: u16 = 0x02;
+/// The size of RAM, as 64 bits.
+pub const FW_CFG_RAM_SIZE: u16 = 0x03;
+/// Whether there is no display, as 16 bits.
+pub const FW_CFG_NOGRAPHIC: u16 = 0x04;
+/// The number of vCPUs, as 16 bits.
+pub const FW_CFG_NB_CPUS: u16 = 0x05;
+/// The machine type, as 32 bits.
+pub const FW_CFG_MACHINE_ID: u16 = 0x06;
+/// Where the kernel goes, as 32 bits.
+pub const FW_CFG_KERNEL_ADDR: u16 = 0x07;
+/// The size of the kernel, as 32 bits.
+pub const FW_CFG_KERNEL_SIZE: u16 = 0x08;
+/// Where the command line goes, as 32 bits, for firmware that predates
+/// [`FW_CFG_CMDLINE_ADDR`].
+pub const FW_CFG_KERNEL_CMDLINE: u16 = 0x09;
+/// Where the initrd goes, as 32 bits.
+pub const FW_CFG_INITRD_ADDR: u16 = 0x0a;
+/// The size of the initrd, as 32 bits.
+pub const FW_CFG_INITRD_SIZE: u16 = 0x0b;
+/// The boot device, as 16 bits.
+pub const FW_CFG_BOOT_DEVICE: u16 = 0x0c;
+/// The NUMA topology, as 64-bit node numbers and sizes.
+pub const FW_CFG_NUMA: u16 = 0x0d;
+/// Whether to show the boot menu, as 16 bits.
+pub const FW_CFG_BOOT_MENU: u16 = 0x0e;
+/// The most vCPUs there can be, as 16 bits.
+pub const FW_CFG_MAX_CPUS: u16 = 0x0f;
+/// The entry point of the kernel, as 32 bits.
+pub const FW_CFG_KERNEL_ENTRY: u16 = 0x10;
+/// The kernel, less its setup code.
+pub const FW_CFG_KERNEL_DATA: u16 = 0x11;
+/// The initrd.
+pub const FW_CFG_INITRD_DATA: u16 = 0x12;
+/// Where the command line goes, as 32 bits.
+pub const FW_CFG_CMDLINE_ADDR: u16 = 0x13;
+/// The size of the command line, with its NUL, as 32 bits.
+pub const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+/// The command line, NUL terminated.
+pub const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+/// Where the real mode setup code goes, as 32 bits.
+pub const FW_CFG_SETUP_ADDR: u16 = 0x16;
+/// The size of the setup code, as 32 bits.
+pub const FW_CFG_SETUP_SIZE: u16 = 0x17;
+/// The real mode setup code of a `bzImage`.
+pub const FW_CFG_SETUP_DATA: u16 = 0x18;
+/// The directory of the named files.
+pub const FW_CFG_FILE_DIR: u16 = 0x19;
+/// The key of the first named file.
+pub const FW_CFG_FILE_FIRST: u16 = 0x20;
+/// Whether the PIT's IRQ 0 is overridden onto GSI 2.
+pub const FW_CFG_IRQ0_OVERRIDE: u16 = 0x8002;
+
+/// The traditional interface, through the data port.
+pub const FW_CFG_VERSION: u32 = 1 << 0;
+/// The DMA interface.
+pub const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+
+// `FWCfgDmaAccess.control`.
+const DMA_CTL_ERROR: u32 = 1 << 0;
+const DMA_CTL_READ: u32 = 1 << 1;
+const DMA_CTL_SKIP: u32 = 1 << 2;
+const DMA_CTL_SELECT: u32 = 1 << 3;
+const DMA_CTL_WRITE: u32 = 1 << 4;
+
+/// What reading the DMA address port gives, `QEMU CFG`.
+const DMA_SIGNATURE: u64 = 0x5145_4d55_2043_4647;
+/// The longest file name, not counting the terminating NUL.
+pub const FILE_NAME_MAX: usize = 55;
+/// The most named files there can be.
+pub const FILES_MAX: usize = 0x1000;
+
+/// The device, to be registered over [`PORTS`] ports at [`PORT`] and
+/// [`DMA_PORTS`] ports at [`DMA_PORT`].
+pub struct FwCfg {
+    memory: GuestMemory,
+    items: BTreeMap<u16, Vec<u8>>,
+    // the names of the files, and their keys.
+    files: BTreeMap<String, u16>,
+    selector: u16,
+    offset: usize,
+    dma_addr: u64,
+}
+
+impl FwCfg {
+    /// Creates the device with the signature, interface id and an empty
+    /// file directory, doing DMA to and from `memory`.
+    pub fn new(memory: GuestMemory) -> FwCfg {
+        let mut fw_cfg = FwCfg {
+            memory,
+            items: BTreeMap::new(),
+            files: BTreeMap::new(),
+            selector: 0,
+            offset: 0,
+            dma_addr: 0,
+        };
+
+        fw_cfg.add_item(FW_CFG_SIGNATURE, b"QEMU".to_vec());
+        fw_cfg.add_u32(FW_CFG_ID, FW_CFG_VERSION | FW_CFG_VERSION_DMA);
+        fw_cfg.add_u32(FW_CFG_IRQ0_OVERRIDE, 1);
+        fw_cfg.update_file_dir();
+        fw_cfg
+    }
+
+    /// Sets an item, replacing any item with the same key.  Replacing a
+    /// named file updates its size in the file directory.
+    pub fn add_item(&mut self, key: u16, data: Vec<u8>) {
+        self.items.insert(key, data);
+        if key >= FW_CFG_FILE_FIRST && self.files.values().any(|&k| k == key) {
+            self.update_file_dir();
+        }
+    }
+
+    /// Sets an item to a little endian 16-bit value.
+    pub fn add_u16(&mut self, key: u16, value: u16) {
+        self.add_item(key, value.to_le_bytes().to_vec());
+    }
+
+    /// Sets an item to a little endian 32-bit value.
+    pub fn add_u32(&mut self, key: u16, value: u32) {
+        self.add_item(key, value.to_le_bytes().to_vec());
+    }
+
+    /// Sets an item to a little endian 64-bit value.
+    pub fn add_u64(&mut self, key: u16, value: u64) {
+        self.add_item(key, value.to_le_bytes().to_vec());
+    }
+
+    /// Adds a named file, like `etc/e820`, and returns its key.  Fails
+    /// with `EINVAL` if the name is too long, `EEXIST` if there is a file
+    /// with the name already, or `ENOSPC` if there are too many files.
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> nix::Result<u16> {
+        if name.is_empty() || name.len() > FILE_NAME_MAX {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if self.files.contains_key(name) {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+        if self.files.len() >= FILES_MAX {
+            return Err(nix::Error::Sys(Errno::ENOSPC));
+        }
+
+        let key = FW_CFG_FILE_FIRST + self.files.len() as u16;
+        self.files.insert(name.to_owned(), key);
+        self.add_item(key, data);
+        Ok(key)
+    }
+
+    /// Sets the size of RAM.
+    pub fn set_ram_size(&mut self, size: u64) {
+        self.add_u64(FW_CFG_RAM_SIZE, size);
+    }
+
+    /// Sets the number of vCPUs, both present and at most.
+    pub fn set_cpus(&mut self, cpus: u16) {
+        self.add_u16(FW_CFG_NB_CPUS, cpus);
+        self.add_u16(FW_CFG_MAX_CPUS, cpus);
+    }
+
+    /// Sets the kernel for the firmware to boot, to be loaded at `addr`.
+    pub fn set_kernel(&mut self, addr: u32, kernel: Vec<u8>) {
+        self.add_u32(FW_CFG_KERNEL_ADDR, addr);
+        self.add_u32(FW_CFG_KERNEL_SIZE, kernel.len() as u32);
+        self.add_item(FW_CFG_KERNEL_DATA, kernel);
+    }
+
+    /// Sets the real mode setup code of a `bzImage` kernel, which the
+    /// firmware loads separately from the rest of it, at `addr`.
+    pub fn set_setup(&mut self, addr: u32, setup: Vec<u8>) {
+        self.add_u32(FW_CFG_SETUP_ADDR, addr);
+        self.add_u32(FW_CFG_SETUP_SIZE, setup.len() as u32);
+        self.add_item(FW_CFG_SETUP_DATA, setup);
+    }
+
+    /// Sets the initrd for the kernel, to be loaded at `addr`.
+    pub fn set_initrd(&mut self, addr: u32, initrd: Vec<u8>) {
+        self.add_u32(FW_CFG_INITRD_ADDR, addr);
+        self.add_u32(FW_CFG_INITRD_SIZE, initrd.len() as u32);
+        self.add_item(FW_CFG_INITRD_DATA, initrd);
+    }
+
+    /// Sets the kernel's command line, to be copied to `addr`.
+    pub fn set_cmdline(&mut self, addr: u32, cmdline: &str) {
+        let mut data = cmdline.as_bytes().to_vec();
+        data.push(0);
+        self.add_u32(FW_CFG_CMDLINE_ADDR, addr);
+        self.add_u32(FW_CFG_CMDLINE_SIZE, data.len() as u32);
+        self.add_item(FW_CFG_CMDLINE_DATA, data);
+    }
+
+    /// Adds the `etc/e820` file, the memory map as SeaBIOS and OVMF read
+    /// it.
+    pub fn set_e820(&mut self, e820: &[E820Entry]) -> nix::Result<u16> {
+        let mut data = Vec::with_capacity(e820.len() * 20);
+        for entry in e820 {
+            data.extend_from_slice(&entry.addr.to_le_bytes());
+            data.extend_from_slice(&entry.size.to_le_bytes());
+            data.extend_from_slice(&entry.kind.to_le_bytes());
+        }
+        self.add_file("etc/e820", data)
+    }
+
+    /// The directory lists the files sorted by name, with big endian
+    /// fields.
+    fn update_file_dir(&mut self) {
+        let mut dir = (self.files.len() as u32).to_be_bytes().to_vec();
+        for (name, &key) in &self.files {
+            let size = self.items.get(&key).map_or(0, |item| item.len());
+            dir.extend_from_slice(&(size as u32).to_be_bytes());
+            dir.extend_from_slice(&key.to_be_bytes());
+            dir.extend_from_slice(&[0; 2]);
+            let mut field = [0; FILE_NAME_MAX + 1];
+            field[..name.len()].copy_from_slice(name.as_bytes());
+            dir.extend_from_slice(&field);
+        }
+        self.items.insert(FW_CFG_FILE_DIR, dir);
+    }
+
+    fn select(&mut self, key: u16) {
+        self.selector = key;
+        self.offset = 0;
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let byte = self
+            .items
+            .get(&self.selector)
+            .and_then(|item| item.get(self.offset))
+            .cloned();
+        match byte {
+            Some(byte) => {
+                self.offset += 1;
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    /// Carries out the `FWCfgDmaAccess` at `addr`, and writes back its
+    /// control field.
+    fn dma(&mut self, addr: u64) {
+        let control = match self.dma_transfer(addr) {
+            Ok(()) => 0,
+            Err(e) => {
+                debug!("fw_cfg DMA at {:#x} failed: {}", addr, e);
+                DMA_CTL_ERROR
+            }
+        };
+
+        if self.memory.write(addr, &control.to_be_bytes()).is_err() {
+            warn!("fw_cfg DMA access at {:#x} isn't in guest memory", addr);
+        }
+    }
+
+    fn dma_transfer(&mut self, addr: u64) -> nix::Result<()> {
+        let mut access = [0; 16];
+        self.memory.read(addr, &mut access)?;
+        let be32 = |at: usize| {
+            let mut buf = [0; 4];
+            buf.copy_from_slice(&access[at..at + 4]);
+            u32::from_be_bytes(buf)
+        };
+        let control = be32(0);
+        let len = be32(4) as usize;
+        let target = u64::from(be32(8)) << 32 | u64::from(be32(12));
+
+        if control & DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+
+        if control & DMA_CTL_WRITE != 0 {
+            // every item is read only.
+            return Err(nix::Error::Sys(Errno::EPERM));
+        } else if control & DMA_CTL_READ != 0 {
+            let item = self.items.get(&self.selector).map_or(&[][..], |i| &i[..]);
+            let start = self.offset.min(item.len());
+            let available = (item.len() - start).min(len);
+            self.memory.write(target, &item[start..start + available])?;
+            // past the end of the item, it reads as zeroes.
+            self.memory
+                .zero(target + available as u64, (len - available) as u64)?;
+        } else if control & DMA_CTL_SKIP == 0 {
+            return Ok(());
+        }
+
+        self.offset = self.offset.saturating_add(len);
+        Ok(())
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        match (base as u16, offset) {
+            (PORT, 1) => {
+                for b in data.iter_mut() {
+                    *b = self.read_byte();
+                }
+            }
+            (DMA_PORT, _) => {
+                let signature = DMA_SIGNATURE.to_be_bytes();
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = signature.get(offset as usize + i).cloned().unwrap_or(0);
+                }
+            }
+            _ => {
+                for b in data.iter_mut() {
+                    *b = 0;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        match (base as u16, offset, data.len()) {
+            (PORT, 0, 2) => self.select(u16::from_le_bytes([data[0], data[1]])),
+            // the address is written as two big endian halves, with the
+            // low half starting the transfer; or all at once.
+            (DMA_PORT, 0, 4) => {
+                let mut buf = [0; 4];
+                buf.copy_from_slice(data);
+                self.dma_addr = u64::from(u32::from_be_bytes(buf)) << 32;
+            }
+            (DMA_PORT, 4, 4) => {
+                let mut buf = [0; 4];
+                buf.copy_from_slice(data);
+                let addr = self.dma_addr | u64::from(u32::from_be_bytes(buf));
+                self.dma_addr = 0;
+                self.dma(addr);
+            }
+            (DMA_PORT, 0, 8) => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(data);
+                self.dma(u64::from_be_bytes(buf));
+            }
+            // writes through the data port have been dropped since QEMU 2.4.
+            _ => debug!("ignored fw_cfg write of {:?} at {:#x}", data, base + offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::anonymous_memory;
+
+    fn select(fw_cfg: &mut FwCfg, key: u16) {
+        fw_cfg.write(u64::from(PORT), 0, &key.to_le_bytes());
+    }
+
+    fn read_data(fw_cfg: &mut FwCfg, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        fw_cfg.read(u64::from(PORT), 1, &mut data);
+        data
+    }
+
+    /// Writes a `FWCfgDmaAccess` at `addr` and starts it, through the two
+    /// halves of the address port.
+    fn dma(fw_cfg: &mut FwCfg, addr: u64, control: u32, len: u32, target: u64) -> u32 {
+        let memory = fw_cfg.memory.clone();
+        memory.write(addr, &control.to_be_bytes()).unwrap();
+        memory.write(addr + 4, &len.to_be_bytes()).unwrap();
+        memory.write(addr + 8, &target.to_be_bytes()).unwrap();
+        fw_cfg.write(u64::from(DMA_PORT), 0, &((addr >> 32) as u32).to_be_bytes());
+        fw_cfg.write(u64::from(DMA_PORT), 4, &(addr as u32).to_be_bytes());
+
+        let mut control = [0; 4];
+        memory.read(addr, &mut control).unwrap();
+        u32::from_be_bytes(control)
+    }
+
+    #[test]
+    fn selector_and_data_ports() {
+        let mut fw_cfg = FwCfg::new(anonymous_memory(0x1000));
+        select(&mut fw_cfg, FW_CFG_SIGNATURE);
+        assert_eq!(read_data(&mut fw_cfg, 1), b"Q");
+        assert_eq!(read_data(&mut fw_cfg, 3), b"EMU");
+        // past the end of the item, it reads as zeroes.
+        assert_eq!(read_data(&mut fw_cfg, 2), [0, 0]);
+
+        // selecting starts over, even with the same key.
+        select(&mut fw_cfg, FW_CFG_SIGNATURE);
+        assert_eq!(read_data(&mut fw_cfg, 4), b"QEMU");
+        select(&mut fw_cfg, FW_CFG_ID);
+        assert_eq!(read_data(&mut fw_cfg, 4), [3, 0, 0, 0]);
+
+        fw_cfg.set_cpus(4);
+        select(&mut fw_cfg, FW_CFG_MAX_CPUS);
+        assert_eq!(read_data(&mut fw_cfg, 2), [4, 0]);
+        select(&mut fw_cfg, FW_CFG_UUID);
+        assert_eq!(read_data(&mut fw_cfg, 2), [0, 0]);
+
+        // the data port can't be written, and the selector reads as zero.
+        fw_cfg.write(u64::from(PORT), 1, &[0xff]);
+        select(&mut fw_cfg, FW_CFG_SIGNATURE);
+        assert_eq!(read_data(&mut fw_cfg, 1), b"Q");
+        let mut selector = [0xff; 2];
+        fw_cfg.read(u64::from(PORT), 0, &mut selector);
+        assert_eq!(selector, [0, 0]);
+    }
+
+    #[test]
+    fn file_directory() {
+        let mut fw_cfg = FwCfg::new(anonymous_memory(0x1000));
+        assert_eq!(
+            fw_cfg.add_file("etc/e820", vec![1; 40]),
+            Ok(FW_CFG_FILE_FIRST)
+        );
+        assert_eq!(
+            fw_cfg.add_file("bootorder", vec![2; 3]),
+            Ok(FW_CFG_FILE_FIRST + 1)
+        );
+
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        let name = "n".repeat(FILE_NAME_MAX + 1);
+        assert_eq!(fw_cfg.add_file("", vec![]), Err(einval));
+        assert_eq!(fw_cfg.add_file(&name, vec![]), Err(einval));
+        assert!(fw_cfg.add_file(&name[1..], vec![]).is_ok());
+        assert_eq!(
+            fw_cfg.add_file("bootorder", vec![]),
+            Err(nix::Error::Sys(Errno::EEXIST))
+        );
+
+        // replacing a file's data updates its size in the directory.
+        fw_cfg.add_item(FW_CFG_FILE_FIRST, vec![1; 60]);
+        select(&mut fw_cfg, FW_CFG_FILE_DIR);
+        assert_eq!(read_data(&mut fw_cfg, 4), [0, 0, 0, 3]);
+        // sorted by name: `bootorder`, `etc/e820`, then the long one.
+        let entry = read_data(&mut fw_cfg, 64);
+        assert_eq!(&entry[..8], &[0, 0, 0, 3, 0, 0x21, 0, 0]);
+        assert_eq!(&entry[8..18], b"bootorder\0");
+        let entry = read_data(&mut fw_cfg, 64);
+        assert_eq!(&entry[..8], &[0, 0, 0, 60, 0, 0x20, 0, 0]);
+        assert_eq!(&entry[8..17], b"etc/e820\0");
+        let entry = read_data(&mut fw_cfg, 64);
+        assert_eq!(&entry[..8], &[0, 0, 0, 0, 0, 0x22, 0, 0]);
+        assert_eq!(entry[8 + FILE_NAME_MAX], 0);
+        assert_eq!(read_data(&mut fw_cfg, 1), [0]);
+
+        let e820 = [E820Entry {
+            addr: 0x10_0000,
+            size: 0x20_0000,
+            kind: 1,
+        }];
+        assert_eq!(fw_cfg.set_e820(&e820), Err(nix::Error::Sys(Errno::EEXIST)));
+    }
+
+    #[test]
+    fn kernel_items() {
+        let mut fw_cfg = FwCfg::new(anonymous_memory(0x1000));
+        fw_cfg.set_kernel(0x10_0000, vec![1; 3]);
+        fw_cfg.set_setup(0x9_0000, vec![2; 2]);
+        fw_cfg.set_initrd(0x800_0000, vec![3; 5]);
+        fw_cfg.set_cmdline(0x2_0000, "ro");
+
+        let mut item = |key: u16, len: usize| {
+            select(&mut fw_cfg, key);
+            read_data(&mut fw_cfg, len)
+        };
+        assert_eq!(item(FW_CFG_KERNEL_ADDR, 4), [0, 0, 0x10, 0]);
+        assert_eq!(item(FW_CFG_KERNEL_SIZE, 4), [3, 0, 0, 0]);
+        assert_eq!(item(FW_CFG_KERNEL_DATA, 4), [1, 1, 1, 0]);
+        assert_eq!(item(FW_CFG_SETUP_ADDR, 4), [0, 0, 9, 0]);
+        assert_eq!(item(FW_CFG_SETUP_SIZE, 4), [2, 0, 0, 0]);
+        assert_eq!(item(FW_CFG_SETUP_DATA, 2), [2, 2]);
+        assert_eq!(item(FW_CFG_INITRD_ADDR, 4), [0, 0, 0, 8]);
+        assert_eq!(item(FW_CFG_INITRD_SIZE, 4), [5, 0, 0, 0]);
+        assert_eq!(item(FW_CFG_INITRD_DATA, 5), [3; 5]);
+        assert_eq!(item(FW_CFG_CMDLINE_ADDR, 4), [0, 0, 2, 0]);
+        assert_eq!(item(FW_CFG_CMDLINE_SIZE, 4), [3, 0, 0, 0]);
+        assert_eq!(item(FW_CFG_CMDLINE_DATA, 4), *b"ro\0\0");
+    }
+
+    #[test]
+    fn dma_reads() {
+        let mut fw_cfg = FwCfg::new(anonymous_memory(0x1_0000));
+        let memory = fw_cfg.memory.clone();
+        let mut signature = [0; 8];
+        fw_cfg.read(u64::from(DMA_PORT), 0, &mut signature);
+        assert_eq!(&signature, b"QEMU CFG");
+
+        // selecting and reading past the end of the item, which zero fills.
+        memory.write(0x2000, &[0xff; 8]).unwrap();
+        let control = u32::from(FW_CFG_SIGNATURE) << 16 | DMA_CTL_SELECT | DMA_CTL_READ;
+        assert_eq!(dma(&mut fw_cfg, 0x1000, control, 8, 0x2000), 0);
+        let mut data = [0; 8];
+        memory.read(0x2000, &mut data).unwrap();
+        assert_eq!(&data, b"QEMU\0\0\0\0");
+
+        // skipping, then reading on from the same item.
+        fw_cfg.set_kernel(0x10_0000, (0..16).collect());
+        let control = u32::from(FW_CFG_KERNEL_DATA) << 16 | DMA_CTL_SELECT | DMA_CTL_SKIP;
+        assert_eq!(dma(&mut fw_cfg, 0x1000, control, 4, 0), 0);
+        assert_eq!(dma(&mut fw_cfg, 0x1000, DMA_CTL_READ, 4, 0x2000), 0);
+        memory.read(0x2000, &mut data[..4]).unwrap();
+        assert_eq!(&data[..4], &[4, 5, 6, 7]);
+        // the data port carries on from where DMA left off.
+        assert_eq!(read_data(&mut fw_cfg, 2), [8, 9]);
+
+        // the whole address at once.
+        memory.write(0x1000, &DMA_CTL_READ.to_be_bytes()).unwrap();
+        memory.write(0x1004, &2u32.to_be_bytes()).unwrap();
+        memory.write(0x1008, &0x3000u64.to_be_bytes()).unwrap();
+        fw_cfg.write(u64::from(DMA_PORT), 0, &0x1000u64.to_be_bytes());
+        assert_eq!(memory.read_u16(0x3000), Ok(0x0b0a));
+        assert_eq!(memory.read_u32(0x1000), Ok(0));
+    }
+
+    #[test]
+    fn dma_errors() {
+        let mut fw_cfg = FwCfg::new(anonymous_memory(0x1_0000));
+        let control = u32::from(FW_CFG_SIGNATURE) << 16 | DMA_CTL_SELECT | DMA_CTL_WRITE;
+        assert_eq!(dma(&mut fw_cfg, 0x1000, control, 4, 0x2000), DMA_CTL_ERROR);
+
+        // a target outside of guest memory.
+        let control = u32::from(FW_CFG_SIGNATURE) << 16 | DMA_CTL_SELECT | DMA_CTL_READ;
+        assert_eq!(dma(&mut fw_cfg, 0x1000, control, 8, 0xfffc), DMA_CTL_ERROR);
+        assert_eq!(dma(&mut fw_cfg, 0x1000, control, 4, 1 << 40), DMA_CTL_ERROR);
+
+        // an access structure outside of guest memory is dropped.
+        fw_cfg.write(u64::from(DMA_PORT), 0, &0x1_0000u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,511 @@
+//! An emulated i8042 keyboard controller, with a keyboard attached.
+//!
+//! Besides the keyboard, the controller drives the A20 gate and the CPU
+//! reset line; guests commonly reset themselves by pulsing the latter with
+//! command 0xfe.  That is surfaced as a [`VmEvent::Reset`] on the channel
+//! the controller is given, for whatever runs the VM to act on.  There is
+//! no mouse on the auxiliary port.
+
+use super::{BusDevice, Interrupt, VmEvent};
+use std::collections::VecDeque;
+use std::sync::mpsc::Sender;
+
+/// The data port.
+pub const DATA_PORT: u16 = 0x60;
+/// The status (when read) and command (when written) port.
+pub const COMMAND_PORT: u16 = 0x64;
+/// The ISA IRQ of the keyboard.
+pub const KEYBOARD_IRQ: u32 = 1;
+/// The ISA IRQ of the auxiliary (mouse) port.
+pub const AUX_IRQ: u32 = 12;
+
+const STATUS_OBF: u8 = 1 << 0;
+const STATUS_SYS: u8 = 1 << 2;
+const STATUS_COMMAND: u8 = 1 << 3;
+const STATUS_UNLOCKED: u8 = 1 << 4;
+const STATUS_AUX_OBF: u8 = 1 << 5;
+
+const CONFIG_KEYBOARD_INT: u8 = 1 << 0;
+const CONFIG_AUX_INT: u8 = 1 << 1;
+const CONFIG_SYS: u8 = 1 << 2;
+const CONFIG_KEYBOARD_DISABLED: u8 = 1 << 4;
+const CONFIG_AUX_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const OUTPUT_RESET: u8 = 1 << 0;
+const OUTPUT_A20: u8 = 1 << 1;
+
+// controller commands.
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_AUX: u8 = 0xa7;
+const CMD_ENABLE_AUX: u8 = 0xa8;
+const CMD_TEST_AUX: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_KEYBOARD: u8 = 0xab;
+const CMD_DISABLE_KEYBOARD: u8 = 0xad;
+const CMD_ENABLE_KEYBOARD: u8 = 0xae;
+const CMD_READ_INPUT: u8 = 0xc0;
+const CMD_READ_OUTPUT: u8 = 0xd0;
+const CMD_WRITE_OUTPUT: u8 = 0xd1;
+const CMD_WRITE_KEYBOARD_BUFFER: u8 = 0xd2;
+const CMD_WRITE_AUX_BUFFER: u8 = 0xd3;
+const CMD_WRITE_AUX: u8 = 0xd4;
+const CMD_PULSE: u8 = 0xf0;
+
+// keyboard commands, and its responses.
+const KBD_SET_LEDS: u8 = 0xed;
+const KBD_ECHO: u8 = 0xee;
+const KBD_SCANCODE_SET: u8 = 0xf0;
+const KBD_IDENTIFY: u8 = 0xf2;
+const KBD_TYPEMATIC: u8 = 0xf3;
+const KBD_ENABLE: u8 = 0xf4;
+const KBD_DISABLE: u8 = 0xf5;
+const KBD_DEFAULTS: u8 = 0xf6;
+const KBD_RESET: u8 = 0xff;
+const KBD_ACK: u8 = 0xfa;
+const KBD_SELF_TEST_OK: u8 = 0xaa;
+const KBD_RESEND: u8 = 0xfe;
+
+/// The most bytes the keyboard buffers before dropping keystrokes.
+const QUEUE_MAX: usize = 64;
+
+/// What the next byte written to the data port is for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pending {
+    Keyboard,
+    Config,
+    Output,
+    KeyboardBuffer,
+    AuxBuffer,
+    Aux,
+    KeyboardArgument(u8),
+}
+
+/// The controller, to be registered over one port at each of
+/// [`DATA_PORT`] and [`COMMAND_PORT`].
+pub struct I8042 {
+    status: u8,
+    config: u8,
+    output_port: u8,
+    pending: Pending,
+    // the output buffer, and whether each byte is from the auxiliary port.
+    queue: VecDeque<(u8, bool)>,
+    scanning: bool,
+    scancode_set: u8,
+    events: Sender<VmEvent>,
+    keyboard_irq: Option<Box<dyn Interrupt>>,
+    aux_irq: Option<Box<dyn Interrupt>>,
+    levels: (bool, bool),
+}
+
+impl I8042 {
+    /// Creates the controller, as the firmware leaves it: with the A20
+    /// gate open, translation on, and the keyboard interrupt enabled.
+    /// Resets are sent to `events`; `keyboard_irq` and `aux_irq`, if any,
+    /// are raised when there is data for the guest.
+    pub fn new(
+        events: Sender<VmEvent>,
+        keyboard_irq: Option<Box<dyn Interrupt>>,
+        aux_irq: Option<Box<dyn Interrupt>>,
+    ) -> I8042 {
+        I8042 {
+            status: STATUS_SYS | STATUS_UNLOCKED,
+            config: CONFIG_KEYBOARD_INT | CONFIG_SYS | CONFIG_AUX_DISABLED | CONFIG_TRANSLATE,
+            output_port: OUTPUT_RESET | OUTPUT_A20,
+            pending: Pending::Keyboard,
+            queue: VecDeque::new(),
+            scanning: true,
+            scancode_set: 2,
+            events,
+            keyboard_irq,
+            aux_irq,
+            levels: (false, false),
+        }
+    }
+
+    /// Queues scancodes from the keyboard, as the guest is to read them
+    /// (in set 1 if translation is on, which it is by default).  They are
+    /// dropped if the keyboard isn't scanning, or its buffer is full.
+    pub fn queue_scancodes(&mut self, scancodes: &[u8]) {
+        if !self.scanning || self.config & CONFIG_KEYBOARD_DISABLED != 0 {
+            return;
+        }
+        if self.queue.len() + scancodes.len() > QUEUE_MAX {
+            warn!("keyboard buffer full; dropping {:?}", scancodes);
+            return;
+        }
+
+        self.queue.extend(scancodes.iter().map(|&b| (b, false)));
+        self.update_interrupts();
+    }
+
+    /// Whether the A20 gate is open.
+    pub fn a20(&self) -> bool {
+        self.output_port & OUTPUT_A20 != 0
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.queue.push_back((byte, false));
+    }
+
+    fn reset_cpu(&mut self) {
+        debug!("i8042 reset");
+        if self.events.send(VmEvent::Reset).is_err() {
+            warn!("no one to reset the VM");
+        }
+    }
+
+    fn update_interrupts(&mut self) {
+        let (keyboard, aux) = match self.queue.front() {
+            Some(&(_, false)) => (self.config & CONFIG_KEYBOARD_INT != 0, false),
+            Some(&(_, true)) => (false, self.config & CONFIG_AUX_INT != 0),
+            None => (false, false),
+        };
+        self.set_interrupts(keyboard, aux);
+    }
+
+    fn set_interrupts(&mut self, keyboard: bool, aux: bool) {
+        if keyboard != self.levels.0 {
+            if let Some(ref mut irq) = self.keyboard_irq {
+                irq.set_level(keyboard);
+            }
+        }
+        if aux != self.levels.1 {
+            if let Some(ref mut irq) = self.aux_irq {
+                irq.set_level(aux);
+            }
+        }
+        self.levels = (keyboard, aux);
+    }
+
+    fn status(&self) -> u8 {
+        match self.queue.front() {
+            Some(&(_, false)) => self.status | STATUS_OBF,
+            Some(&(_, true)) => self.status | STATUS_OBF | STATUS_AUX_OBF,
+            None => self.status,
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let byte = self.queue.pop_front().map_or(0, |(b, _)| b);
+        // the IRQs are edge triggered, so the lines go low for the next
+        // byte to raise them again.
+        self.set_interrupts(false, false);
+        byte
+    }
+
+    fn command(&mut self, command: u8) {
+        self.status |= STATUS_COMMAND;
+        self.pending = Pending::Keyboard;
+
+        match command {
+            CMD_READ_CONFIG => {
+                let config = self.config;
+                self.push(config);
+            }
+            CMD_WRITE_CONFIG => self.pending = Pending::Config,
+            CMD_DISABLE_AUX => self.config |= CONFIG_AUX_DISABLED,
+            CMD_ENABLE_AUX => self.config &= !CONFIG_AUX_DISABLED,
+            CMD_TEST_AUX | CMD_TEST_KEYBOARD => self.push(0x00),
+            CMD_SELF_TEST => {
+                self.status |= STATUS_SYS;
+                self.push(0x55);
+            }
+            CMD_DISABLE_KEYBOARD => self.config |= CONFIG_KEYBOARD_DISABLED,
+            CMD_ENABLE_KEYBOARD => self.config &= !CONFIG_KEYBOARD_DISABLED,
+            CMD_READ_INPUT => self.push(0x00),
+            CMD_READ_OUTPUT => {
+                let output = self.output_port;
+                self.push(output);
+            }
+            CMD_WRITE_OUTPUT => self.pending = Pending::Output,
+            CMD_WRITE_KEYBOARD_BUFFER => self.pending = Pending::KeyboardBuffer,
+            CMD_WRITE_AUX_BUFFER => self.pending = Pending::AuxBuffer,
+            CMD_WRITE_AUX => self.pending = Pending::Aux,
+            // the low bits select the lines to pulse low, bit 0 being the
+            // reset line.
+            c if c & CMD_PULSE == CMD_PULSE => {
+                if c & OUTPUT_RESET == 0 {
+                    self.reset_cpu();
+                }
+            }
+            c => debug!("unknown i8042 command {:#x}", c),
+        }
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        self.status &= !STATUS_COMMAND;
+        let pending = self.pending;
+        self.pending = Pending::Keyboard;
+
+        match pending {
+            Pending::Keyboard => self.keyboard_command(byte),
+            Pending::KeyboardArgument(command) => self.keyboard_argument(command, byte),
+            Pending::Config => {
+                self.config = byte;
+                self.status = self.status & !STATUS_SYS | byte & CONFIG_SYS;
+            }
+            Pending::Output => {
+                self.output_port = byte;
+                if byte & OUTPUT_RESET == 0 {
+                    self.reset_cpu();
+                }
+            }
+            Pending::KeyboardBuffer => self.push(byte),
+            Pending::AuxBuffer => self.queue.push_back((byte, true)),
+            // there is no mouse to answer.
+            Pending::Aux => {}
+        }
+    }
+
+    fn keyboard_command(&mut self, command: u8) {
+        match command {
+            KBD_SET_LEDS | KBD_SCANCODE_SET | KBD_TYPEMATIC => {
+                self.pending = Pending::KeyboardArgument(command);
+                self.push(KBD_ACK);
+            }
+            KBD_ECHO => self.push(KBD_ECHO),
+            KBD_IDENTIFY => {
+                self.push(KBD_ACK);
+                self.push(0xab);
+                self.push(0x83);
+            }
+            KBD_ENABLE => {
+                self.scanning = true;
+                self.push(KBD_ACK);
+            }
+            KBD_DISABLE | KBD_DEFAULTS => {
+                self.scanning = command == KBD_DEFAULTS;
+                self.scancode_set = 2;
+                self.push(KBD_ACK);
+            }
+            KBD_RESET => {
+                self.scanning = true;
+                self.scancode_set = 2;
+                self.queue.clear();
+                self.push(KBD_ACK);
+                self.push(KBD_SELF_TEST_OK);
+            }
+            c => {
+                debug!("unknown keyboard command {:#x}", c);
+                self.push(KBD_RESEND);
+            }
+        }
+    }
+
+    fn keyboard_argument(&mut self, command: u8, argument: u8) {
+        self.push(KBD_ACK);
+        if command == KBD_SCANCODE_SET {
+            match argument {
+                // a query.
+                0 => {
+                    let set = self.scancode_set;
+                    self.push(set);
+                }
+                1..=3 => self.scancode_set = argument,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl BusDevice for I8042 {
+    fn read(&mut self, base: u64, _offset: u64, data: &mut [u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        data[0] = match base as u16 {
+            DATA_PORT => self.read_data(),
+            _ => self.status(),
+        };
+        self.update_interrupts();
+    }
+
+    fn write(&mut self, base: u64, _offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        match base as u16 {
+            DATA_PORT => self.write_data(data[0]),
+            _ => self.command(data[0]),
+        }
+        self.update_interrupts();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Levels(Arc<Mutex<Vec<bool>>>);
+
+    impl Interrupt for Levels {
+        fn set_level(&mut self, level: bool) {
+            self.0.lock().unwrap().push(level);
+        }
+    }
+
+    fn i8042() -> (I8042, Receiver<VmEvent>, Levels, Levels) {
+        let (tx, rx) = channel();
+        let (keyboard, aux) = (Levels::default(), Levels::default());
+        let i8042 = I8042::new(
+            tx,
+            Some(Box::new(keyboard.clone())),
+            Some(Box::new(aux.clone())),
+        );
+        (i8042, rx, keyboard, aux)
+    }
+
+    fn status(i8042: &mut I8042) -> u8 {
+        let mut data = [0];
+        i8042.read(u64::from(COMMAND_PORT), 0, &mut data);
+        data[0]
+    }
+
+    fn command(i8042: &mut I8042, command: u8) {
+        i8042.write(u64::from(COMMAND_PORT), 0, &[command]);
+    }
+
+    fn read(i8042: &mut I8042) -> u8 {
+        let mut data = [0];
+        i8042.read(u64::from(DATA_PORT), 0, &mut data);
+        data[0]
+    }
+
+    fn write(i8042: &mut I8042, byte: u8) {
+        i8042.write(u64::from(DATA_PORT), 0, &[byte]);
+    }
+
+    /// Reads the output buffer until it is empty.
+    fn drain(i8042: &mut I8042) -> Vec<u8> {
+        let mut bytes = vec![];
+        while status(i8042) & STATUS_OBF != 0 {
+            bytes.push(read(i8042));
+        }
+        bytes
+    }
+
+    #[test]
+    fn controller_commands() {
+        let (mut i8042, _rx, _, _) = i8042();
+        assert_eq!(status(&mut i8042), STATUS_SYS | STATUS_UNLOCKED);
+
+        command(&mut i8042, CMD_READ_CONFIG);
+        assert_eq!(
+            status(&mut i8042),
+            STATUS_OBF | STATUS_SYS | STATUS_COMMAND | STATUS_UNLOCKED
+        );
+        assert_eq!(drain(&mut i8042), vec![0x65]);
+
+        // the next data byte is the argument, which clears the command bit.
+        command(&mut i8042, CMD_WRITE_CONFIG);
+        write(&mut i8042, CONFIG_KEYBOARD_INT | CONFIG_AUX_INT);
+        assert_eq!(status(&mut i8042), STATUS_UNLOCKED);
+        command(&mut i8042, CMD_READ_CONFIG);
+        assert_eq!(drain(&mut i8042), vec![0x03]);
+
+        command(&mut i8042, CMD_SELF_TEST);
+        assert_eq!(status(&mut i8042) & STATUS_SYS, STATUS_SYS);
+        assert_eq!(drain(&mut i8042), vec![0x55]);
+        command(&mut i8042, CMD_TEST_KEYBOARD);
+        assert_eq!(drain(&mut i8042), vec![0x00]);
+
+        command(&mut i8042, CMD_READ_OUTPUT);
+        assert_eq!(drain(&mut i8042), vec![OUTPUT_RESET | OUTPUT_A20]);
+        assert!(i8042.a20());
+        command(&mut i8042, CMD_WRITE_OUTPUT);
+        write(&mut i8042, OUTPUT_RESET);
+        assert!(!i8042.a20());
+
+        command(&mut i8042, CMD_WRITE_KEYBOARD_BUFFER);
+        write(&mut i8042, 0x1e);
+        assert_eq!(status(&mut i8042) & STATUS_AUX_OBF, 0);
+        assert_eq!(drain(&mut i8042), vec![0x1e]);
+        command(&mut i8042, CMD_WRITE_AUX_BUFFER);
+        write(&mut i8042, 0x08);
+        assert_ne!(status(&mut i8042) & STATUS_AUX_OBF, 0);
+        assert_eq!(drain(&mut i8042), vec![0x08]);
+    }
+
+    #[test]
+    fn keyboard_commands() {
+        let (mut i8042, _rx, _, _) = i8042();
+        write(&mut i8042, KBD_IDENTIFY);
+        assert_eq!(drain(&mut i8042), vec![KBD_ACK, 0xab, 0x83]);
+        write(&mut i8042, KBD_ECHO);
+        assert_eq!(drain(&mut i8042), vec![KBD_ECHO]);
+
+        write(&mut i8042, KBD_SCANCODE_SET);
+        write(&mut i8042, 0);
+        assert_eq!(drain(&mut i8042), vec![KBD_ACK, KBD_ACK, 2]);
+        write(&mut i8042, KBD_SCANCODE_SET);
+        write(&mut i8042, 1);
+        write(&mut i8042, KBD_SCANCODE_SET);
+        write(&mut i8042, 0);
+        assert_eq!(
+            drain(&mut i8042),
+            vec![KBD_ACK, KBD_ACK, KBD_ACK, KBD_ACK, 1]
+        );
+
+        write(&mut i8042, 0x42);
+        assert_eq!(drain(&mut i8042), vec![KBD_RESEND]);
+        i8042.queue_scancodes(&[0x1c]);
+        write(&mut i8042, KBD_RESET);
+        assert_eq!(drain(&mut i8042), vec![KBD_ACK, KBD_SELF_TEST_OK]);
+    }
+
+    #[test]
+    fn scancodes() {
+        let (mut i8042, _rx, keyboard, aux) = i8042();
+        i8042.queue_scancodes(&[0x1c, 0x9c]);
+        assert_eq!(*keyboard.0.lock().unwrap(), vec![true]);
+        // the line drops for each byte read, and comes back for the next.
+        assert_eq!(read(&mut i8042), 0x1c);
+        assert_eq!(*keyboard.0.lock().unwrap(), vec![true, false, true]);
+        assert_eq!(read(&mut i8042), 0x9c);
+        assert_eq!(*keyboard.0.lock().unwrap(), vec![true, false, true, false]);
+        assert!(aux.0.lock().unwrap().is_empty());
+
+        // nothing is queued while the keyboard is disabled, or not scanning.
+        command(&mut i8042, CMD_DISABLE_KEYBOARD);
+        i8042.queue_scancodes(&[0x1c]);
+        command(&mut i8042, CMD_ENABLE_KEYBOARD);
+        write(&mut i8042, KBD_DISABLE);
+        assert_eq!(drain(&mut i8042), vec![KBD_ACK]);
+        i8042.queue_scancodes(&[0x1c]);
+        assert_eq!(status(&mut i8042) & STATUS_OBF, 0);
+        write(&mut i8042, KBD_ENABLE);
+        assert_eq!(drain(&mut i8042), vec![KBD_ACK]);
+
+        // nor past what the buffer holds.
+        i8042.queue_scancodes(&[0x1e; QUEUE_MAX]);
+        i8042.queue_scancodes(&[0x1c]);
+        assert_eq!(drain(&mut i8042), vec![0x1e; QUEUE_MAX]);
+    }
+
+    #[test]
+    fn reset() {
+        let (mut i8042, rx, _, _) = i8042();
+        // pulsing anything but the reset line does nothing.
+        command(&mut i8042, 0xff);
+        assert!(rx.try_recv().is_err());
+        command(&mut i8042, 0xfe);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Reset));
+        assert!(rx.try_recv().is_err());
+
+        // so does clearing the reset line of the output port.
+        command(&mut i8042, CMD_WRITE_OUTPUT);
+        write(&mut i8042, OUTPUT_A20);
+        assert_eq!(rx.try_recv(), Ok(VmEvent::Reset));
+
+        // with no one to tell, the reset is dropped.
+        drop(rx);
+        command(&mut i8042, 0xfe);
+    }
+}
@@ -0,0 +1,422 @@
+//! Emulated devices, and the bus that routes guest port I/O and MMIO to
+//! them.
+//!
+//! Devices implement [`BusDevice`], and are registered on a [`Bus`] over
+//! ranges of ports or of guest physical addresses.  The vCPU threads then
+//! share the bus (it is `Send` and `Sync`), handing it each `KVM_EXIT_IO`
+//! and `KVM_EXIT_MMIO` exit through [`Bus::handle_io`] and
+//! [`Bus::handle_mmio`].  Each device is behind a mutex, so that a device is
+//! only ever accessed by one vCPU at a time.
+//!
+//! Devices raise interrupts through an [`Interrupt`], which is either an
+//! IRQ line of the in-kernel irqchip ([`IrqLine`]) or an irqfd
+//! ([`IrqEvent`]).  Those that act on the VM as a whole, like resetting it,
+//! send a [`VmEvent`] over a channel.
+
+use super::consts::KVM_EXIT_IO_OUT;
+use super::ctl::{kvm_irq_line, IrqLevel};
+use super::run::Run;
+use nix;
+use nix::errno::Errno;
+use nix::unistd;
+use std::collections::BTreeMap;
+use std::os::unix::io::RawFd;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub mod cmos;
+pub mod debug;
+pub mod fw_cfg;
+pub mod i8042;
+pub mod pci;
+pub mod serial;
+pub mod virtio;
+
+/// The number of I/O ports.
+pub const IO_PORTS: u64 = 0x1_0000;
+
+/// An emulated device.
+pub trait BusDevice: Send {
+    /// Reads `data.len()` bytes at `offset` into the range at `base` the
+    /// device was registered over.
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]);
+
+    /// Writes `data` at `offset` into the range at `base` the device was
+    /// registered over.
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]);
+}
+
+/// Something a device asks of the VM as a whole.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VmEvent {
+    /// The guest reset the CPU.
+    Reset,
+    /// The guest asked to exit, with a status code.
+    Exit(u32),
+}
+
+/// An interrupt line that a device drives.
+pub trait Interrupt: Send {
+    /// Raises or lowers the line.
+    fn set_level(&mut self, level: bool);
+}
+
+/// An IRQ line of the in-kernel irqchip, driven with
+/// [`kvm_irq_line`](../fn.kvm_irq_line.html).  With the irqchip's default
+/// routing, lines 0 to 15 are the ISA IRQs (on both the 8259s and the
+/// IOAPIC), and 16 to 23 the rest of the IOAPIC's pins.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IrqLine {
+    vm: RawFd,
+    irq: u32,
+}
+
+impl IrqLine {
+    /// The line `irq` of the irqchip of the VM `vm`.
+    pub fn new(vm: RawFd, irq: u32) -> IrqLine {
+        IrqLine { vm, irq }
+    }
+}
+
+impl Interrupt for IrqLine {
+    fn set_level(&mut self, level: bool) {
+        let irq = IrqLevel {
+            irq: self.irq,
+            level: level as u32,
+        };
+        if let Err(e) = unsafe { kvm_irq_line(self.vm, &irq) } {
+            warn!("couldn't set IRQ {} to {}: {}", self.irq, level, e);
+        }
+    }
+}
+
+/// An eventfd registered as an irqfd (see
+/// [`kvm_irqfd`](../fn.kvm_irqfd.html)).  An irqfd without a resample fd
+/// pulses its GSI each time it is signalled, so this signals it when the
+/// line is raised, and ignores lowering it; that suits edge triggered
+/// interrupts.  This does not take ownership of the eventfd.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IrqEvent {
+    fd: RawFd,
+}
+
+impl IrqEvent {
+    /// Wraps an eventfd that is registered as an irqfd.
+    pub fn new(fd: RawFd) -> IrqEvent {
+        IrqEvent { fd }
+    }
+
+    /// Signals the eventfd.
+    pub fn trigger(&self) -> nix::Result<()> {
+        unistd::write(self.fd, &1u64.to_ne_bytes()).map(|_| ())
+    }
+}
+
+impl Interrupt for IrqEvent {
+    fn set_level(&mut self, level: bool) {
+        if !level {
+            return;
+        }
+
+        if let Err(e) = self.trigger() {
+            warn!("couldn't signal irqfd {}: {}", self.fd, e);
+        }
+    }
+}
+
+/// A device shared between a bus and its owner.
+pub type SharedDevice = Arc<Mutex<dyn BusDevice>>;
+
+/// The two address spaces a device can be registered in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Space {
+    /// Port I/O, with addresses below [`IO_PORTS`].
+    Io,
+    /// Memory mapped I/O, at guest physical addresses.
+    Mmio,
+}
+
+struct Entry {
+    len: u64,
+    device: SharedDevice,
+}
+
+/// Routes accesses to ports and guest physical addresses to the devices
+/// registered over them.
+///
+/// The ranges are fixed once the bus is shared with the vCPUs: changing
+/// them takes `&mut self`, so a bus behind an `Arc` can't have devices
+/// added or removed.  Devices that come and go while the guest runs go
+/// behind one that stays, registered over the whole range they may use;
+/// [`pci::PciRoot`] is one, whose functions are added through its own
+/// mutex.
+#[derive(Default)]
+pub struct Bus {
+    // by space, then by base address.
+    ranges: BTreeMap<(Space, u64), Entry>,
+}
+
+impl Bus {
+    /// Creates a bus with no devices on it.
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    /// Registers a device over `len` addresses from `base`.  A device may be
+    /// registered over any number of ranges.  Fails with `EINVAL` if the
+    /// range is empty or runs past the end of the space, or with `EEXIST`
+    /// if it overlaps a range that is already registered.
+    ///
+    /// This is for setting the bus up before the vCPUs run; see [`Bus`]
+    /// for devices that are plugged in later.
+    pub fn insert(
+        &mut self,
+        device: SharedDevice,
+        space: Space,
+        base: u64,
+        len: u64,
+    ) -> nix::Result<()> {
+        let end = base
+            .checked_add(len)
+            .ok_or(nix::Error::Sys(Errno::EINVAL))?;
+        if len == 0 || (space == Space::Io && end > IO_PORTS) {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let below = self.find(space, end - 1);
+        if below.is_some_and(|(other, entry)| other + entry.len > base) {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        self.ranges.insert((space, base), Entry { len, device });
+        Ok(())
+    }
+
+    /// Unregisters the range at `base`, returning its device.
+    pub fn remove(&mut self, space: Space, base: u64) -> Option<SharedDevice> {
+        self.ranges.remove(&(space, base)).map(|entry| entry.device)
+    }
+
+    /// The range `addr` is in, as its base and length, and the device over
+    /// it.
+    pub fn get(&self, space: Space, addr: u64) -> Option<(u64, u64, &SharedDevice)> {
+        self.find(space, addr)
+            .filter(|&(base, entry)| addr - base < entry.len)
+            .map(|(base, entry)| (base, entry.len, &entry.device))
+    }
+
+    /// Reads `data.len()` bytes at `addr` from the device there.  Returns
+    /// false, filling `data` with ones (as an undriven bus reads), if no one
+    /// device covers all of it.
+    pub fn read(&self, space: Space, addr: u64, data: &mut [u8]) -> bool {
+        match self.device(space, addr, data.len()) {
+            Some((base, mut device)) => {
+                device.read(base, addr - base, data);
+                true
+            }
+            None => {
+                debug!(
+                    "unhandled {:?} read of {} at {:#x}",
+                    space,
+                    data.len(),
+                    addr
+                );
+                for b in data.iter_mut() {
+                    *b = 0xff;
+                }
+                false
+            }
+        }
+    }
+
+    /// Writes `data` at `addr` to the device there.  Returns false, and
+    /// drops the write, if no one device covers all of it.
+    pub fn write(&self, space: Space, addr: u64, data: &[u8]) -> bool {
+        match self.device(space, addr, data.len()) {
+            Some((base, mut device)) => {
+                device.write(base, addr - base, data);
+                true
+            }
+            None => {
+                debug!("unhandled {:?} write of {:?} at {:#x}", space, data, addr);
+                false
+            }
+        }
+    }
+
+    /// Handles a `KVM_EXIT_IO` exit, including string I/O with a `count`
+    /// above 1, where each element is a separate access.  Returns false if
+    /// no device handled the port.
+    ///
+    /// # Safety
+    /// `run` must point to the vCPU's mapping of `kvm_run`, and the exit
+    /// reason must be `KVM_EXIT_IO`; the data KVM points to lies past the
+    /// end of [`Run`].
+    pub unsafe fn handle_io(&self, run: *mut Run) -> bool {
+        let io = (*run).exit.io;
+        let size = usize::from(io.size);
+        if size == 0 {
+            return false;
+        }
+        let data = slice::from_raw_parts_mut(
+            (run as *mut u8).add(io.data_offset as usize),
+            size * io.count as usize,
+        );
+        let port = u64::from(io.port);
+
+        let mut handled = true;
+        for element in data.chunks_mut(size) {
+            handled &= if io.direction == KVM_EXIT_IO_OUT {
+                self.write(Space::Io, port, element)
+            } else {
+                self.read(Space::Io, port, element)
+            };
+        }
+
+        handled
+    }
+
+    /// Handles a `KVM_EXIT_MMIO` exit.  Returns false if no device handled
+    /// the address.
+    pub fn handle_mmio(&self, run: &mut Run) -> bool {
+        let mmio = unsafe { &mut run.exit.mmio };
+        let len = (mmio.len as usize).min(mmio.data.len());
+        let data = &mut mmio.data[..len];
+        if mmio.is_write != 0 {
+            self.write(Space::Mmio, mmio.phys_addr, data)
+        } else {
+            self.read(Space::Mmio, mmio.phys_addr, data)
+        }
+    }
+
+    /// The range with the highest base at or below `addr`.
+    fn find(&self, space: Space, addr: u64) -> Option<(u64, &Entry)> {
+        self.ranges
+            .range((space, 0)..=(space, addr))
+            .next_back()
+            .map(|(&(_, base), entry)| (base, entry))
+    }
+
+    /// The device covering all of `len` bytes at `addr`, locked, with the
+    /// base of its range.
+    fn device(
+        &self,
+        space: Space,
+        addr: u64,
+        len: usize,
+    ) -> Option<(u64, MutexGuard<'_, dyn BusDevice + 'static>)> {
+        let (base, size, device) = self.get(space, addr)?;
+        if addr - base + len as u64 > size {
+            return None;
+        }
+
+        // a device that panicked is left as it was.
+        let device = device.lock().unwrap_or_else(|e| e.into_inner());
+        Some((base, device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records each write with the base and offset it came with, and reads
+    /// as the low byte of the base and the offset.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<(u64, u64, Vec<u8>)>,
+    }
+
+    impl BusDevice for Recorder {
+        fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = (base as u8).wrapping_add(offset as u8 + i as u8);
+            }
+        }
+
+        fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+            self.writes.push((base, offset, data.to_vec()));
+        }
+    }
+
+    fn recorder() -> Arc<Mutex<Recorder>> {
+        Arc::new(Mutex::new(Recorder::default()))
+    }
+
+    #[test]
+    fn insert() {
+        let mut bus = Bus::new();
+        let device = recorder();
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        let eexist = nix::Error::Sys(Errno::EEXIST);
+        assert_eq!(bus.insert(device.clone(), Space::Io, 0x60, 0), Err(einval));
+        assert_eq!(
+            bus.insert(device.clone(), Space::Io, 0xfff0, 0x11),
+            Err(einval)
+        );
+        assert_eq!(
+            bus.insert(device.clone(), Space::Mmio, !0x1f, 0x20),
+            Err(einval)
+        );
+        bus.insert(device.clone(), Space::Io, 0xfff0, 0x10).unwrap();
+        bus.insert(device.clone(), Space::Mmio, !0x1f, 0x10)
+            .unwrap();
+
+        bus.insert(device.clone(), Space::Io, 0x60, 0x10).unwrap();
+        // overlapping the start, the end, the whole, or the inside of it.
+        for &(base, len) in &[(0x58, 0x10), (0x6f, 2), (0x50, 0x40), (0x64, 1)] {
+            assert_eq!(
+                bus.insert(device.clone(), Space::Io, base, len),
+                Err(eexist)
+            );
+        }
+        // next to it, or in the other space, is fine.
+        bus.insert(device.clone(), Space::Io, 0x50, 0x10).unwrap();
+        bus.insert(device.clone(), Space::Io, 0x70, 0x10).unwrap();
+        bus.insert(device.clone(), Space::Mmio, 0x60, 0x10).unwrap();
+
+        assert_eq!(
+            bus.get(Space::Io, 0x6f).map(|(b, l, _)| (b, l)),
+            Some((0x60, 0x10))
+        );
+        assert!(bus.get(Space::Io, 0x80).is_none());
+        assert!(bus.remove(Space::Io, 0x60).is_some());
+        assert!(bus.remove(Space::Io, 0x60).is_none());
+        assert!(bus.get(Space::Io, 0x6f).is_none());
+        bus.insert(device, Space::Io, 0x64, 1).unwrap();
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut bus = Bus::new();
+        let (first, second) = (recorder(), recorder());
+        bus.insert(first.clone(), Space::Io, 0x3f8, 8).unwrap();
+        bus.insert(first.clone(), Space::Io, 0x2f8, 8).unwrap();
+        bus.insert(second.clone(), Space::Io, 0x400, 8).unwrap();
+        bus.insert(second.clone(), Space::Mmio, 0x3f8, 8).unwrap();
+
+        let mut data = [0; 2];
+        assert!(bus.read(Space::Io, 0x3fa, &mut data));
+        assert_eq!(data, [0xfa, 0xfb]);
+        assert!(bus.read(Space::Io, 0x2f8, &mut data));
+        assert_eq!(data, [0xf8, 0xf9]);
+        assert!(bus.write(Space::Io, 0x3fd, &[1]));
+        assert!(bus.write(Space::Io, 0x2ff, &[2]));
+        assert!(bus.write(Space::Mmio, 0x3fd, &[3, 4]));
+        assert_eq!(
+            first.lock().unwrap().writes,
+            vec![(0x3f8, 5, vec![1]), (0x2f8, 7, vec![2])]
+        );
+        assert_eq!(second.lock().unwrap().writes, vec![(0x3f8, 5, vec![3, 4])]);
+
+        // an access that no one device covers all of is dropped, and reads
+        // as ones.
+        assert!(!bus.read(Space::Io, 0x3ff, &mut data));
+        assert_eq!(data, [0xff, 0xff]);
+        assert!(!bus.read(Space::Io, 0x500, &mut data));
+        assert!(!bus.write(Space::Io, 0x3ff, &[0; 2]));
+        assert!(!bus.write(Space::Mmio, 0x400, &[0]));
+        assert_eq!(first.lock().unwrap().writes.len(), 2);
+        assert_eq!(second.lock().unwrap().writes.len(), 1);
+    }
+}
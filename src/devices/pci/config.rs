@@ -0,0 +1,1000 @@
+//! The configuration space of a PCI function.
+//!
+//! [`PciConfig`] emulates a type 0 header: each byte is either read only,
+//! or writable by the guest, which covers the command register, BAR sizing
+//! and relocation, and the writable registers of capabilities.  On top of
+//! that it keeps the state of the function's MSI or MSI-X capability, whose
+//! messages it sends through an [`MsiInterrupt`].
+
+use super::msi::{MsiInterrupt, MsiMessage};
+use devices::Space;
+use nix;
+use nix::errno::Errno;
+
+/// The size of a function's configuration space, including the extended
+/// space that only ECAM reaches.
+pub const CONFIG_SIZE: usize = 0x1000;
+/// The number of BARs of a type 0 header.
+pub const BARS: usize = 6;
+
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const STATUS: usize = 0x06;
+pub const REVISION_ID: usize = 0x08;
+pub const CLASS_CODE: usize = 0x09;
+pub const CACHE_LINE_SIZE: usize = 0x0c;
+pub const LATENCY_TIMER: usize = 0x0d;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR0: usize = 0x10;
+pub const SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const SUBSYSTEM_ID: usize = 0x2e;
+pub const CAPABILITIES: usize = 0x34;
+pub const INTERRUPT_LINE: usize = 0x3c;
+pub const INTERRUPT_PIN: usize = 0x3d;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+// along with the above, parity and SERR# reporting.
+const COMMAND_WRITABLE: u16 = 0x0547;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Set in the header type of function 0 of a multi-function device.
+pub const HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+pub const CAP_ID_MSIX: u8 = 0x11;
+// capabilities go after the header, in the rest of the legacy space.
+const CAPS_START: usize = 0x40;
+const CAPS_END: usize = 0x100;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_64: u32 = 1 << 2;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// The most vectors MSI has.
+pub const MSI_VECTORS_MAX: u8 = 32;
+/// The most vectors MSI-X has.
+pub const MSIX_VECTORS_MAX: u16 = 2048;
+
+const MSI_CTL_ENABLE: u16 = 1 << 0;
+const MSI_CTL_64BIT: u16 = 1 << 7;
+const MSI_CTL_MASKABLE: u16 = 1 << 8;
+// offsets into the MSI capability, which is always the 64 bit layout.
+const MSI_CTL: usize = 2;
+const MSI_ADDRESS: usize = 4;
+const MSI_DATA: usize = 12;
+const MSI_MASK: usize = 16;
+const MSI_PENDING: usize = 20;
+
+const MSIX_CTL_MASK: u16 = 1 << 14;
+const MSIX_CTL_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_CONTROL: usize = 12;
+const MSIX_ENTRY_MASKED: u8 = 1 << 0;
+
+/// What a BAR maps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BarKind {
+    /// Ports.
+    Io,
+    /// Memory below 4 GiB.
+    Memory32,
+    /// Memory anywhere; the BAR takes the next BAR's register for the high
+    /// half of its address.
+    Memory64,
+}
+
+/// A BAR, as the guest last programmed it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Bar {
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    pub size: u64,
+    pub address: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct BarInfo {
+    kind: BarKind,
+    prefetchable: bool,
+    size: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Msi {
+    cap: usize,
+    vectors: u8,
+    maskable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Msix {
+    cap: usize,
+    bar: usize,
+    table: u64,
+    pba: u64,
+    // the table and the pending bits, as the guest sees them.
+    entries: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+/// The configuration space of a PCI function.
+pub struct PciConfig {
+    data: Vec<u8>,
+    // the bits of each byte the guest may write.
+    writable: Vec<u8>,
+    bars: [Option<BarInfo>; BARS],
+    last_cap: usize,
+    caps_end: usize,
+    msi: Option<Msi>,
+    msix: Option<Msix>,
+    interrupt: Option<Box<dyn MsiInterrupt>>,
+}
+
+impl PciConfig {
+    /// The configuration space of a function with the given ids, and
+    /// 24-bit class code (class, subclass, and programming interface, from
+    /// the high byte down).
+    pub fn new(vendor_id: u16, device_id: u16, class_code: u32, revision_id: u8) -> PciConfig {
+        let mut config = PciConfig {
+            data: vec![0; CONFIG_SIZE],
+            writable: vec![0; CONFIG_SIZE],
+            bars: [None; BARS],
+            last_cap: 0,
+            caps_end: CAPS_START,
+            msi: None,
+            msix: None,
+            interrupt: None,
+        };
+
+        config.set_u16(VENDOR_ID, vendor_id);
+        config.set_u16(DEVICE_ID, device_id);
+        config.data[REVISION_ID] = revision_id;
+        config.data[CLASS_CODE..CLASS_CODE + 3].copy_from_slice(&class_code.to_le_bytes()[..3]);
+        config.writable[COMMAND..COMMAND + 2].copy_from_slice(&COMMAND_WRITABLE.to_le_bytes());
+        config.writable[CACHE_LINE_SIZE] = 0xff;
+        config.writable[LATENCY_TIMER] = 0xff;
+        config.writable[INTERRUPT_LINE] = 0xff;
+        config
+    }
+
+    /// Sets the header type, which is 0 (a single function endpoint) to
+    /// begin with.
+    pub fn set_header_type(&mut self, header_type: u8) {
+        self.data[HEADER_TYPE] = header_type;
+    }
+
+    /// Sets the subsystem ids.
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set_u16(SUBSYSTEM_VENDOR_ID, vendor_id);
+        self.set_u16(SUBSYSTEM_ID, id);
+    }
+
+    /// Sets the legacy interrupt pin the function uses, from 1 (INTA#) to
+    /// 4 (INTD#), or 0 for none.
+    pub fn set_interrupt_pin(&mut self, pin: u8) {
+        self.data[INTERRUPT_PIN] = pin;
+    }
+
+    /// The command register.
+    pub fn command(&self) -> u16 {
+        self.u16(COMMAND)
+    }
+
+    /// Reads `data.len()` bytes at `offset`, as the guest does.  Bytes past
+    /// the end of the space read as ones.
+    pub fn read(&self, offset: u16, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = *self.data.get(usize::from(offset) + i).unwrap_or(&0xff);
+        }
+    }
+
+    /// Writes `data` at `offset`, as the guest does: only the writable bits
+    /// change.  Unmasking an MSI or MSI-X vector sends the message pending
+    /// on it.
+    pub fn write(&mut self, offset: u16, data: &[u8]) {
+        for (i, &b) in data.iter().enumerate() {
+            let offset = usize::from(offset) + i;
+            if offset >= CONFIG_SIZE {
+                break;
+            }
+            let mask = self.writable[offset];
+            self.data[offset] = self.data[offset] & !mask | b & mask;
+        }
+
+        self.send_pending();
+    }
+
+    /// Declares BAR `index`, of `size` bytes, which must be a power of two
+    /// of at least 4 bytes for ports, up to 256, and of at least 16 bytes
+    /// for memory.  Its address is 0 until it is set.  A 64 bit BAR takes
+    /// the next index too.  Fails with `EINVAL` if the BAR isn't valid,
+    /// or with `EEXIST` if the index is taken.
+    pub fn add_bar(
+        &mut self,
+        index: usize,
+        kind: BarKind,
+        size: u64,
+        prefetchable: bool,
+    ) -> nix::Result<()> {
+        let last = if kind == BarKind::Memory64 {
+            index + 1
+        } else {
+            index
+        };
+        let valid = match kind {
+            BarKind::Io => (4..=0x100).contains(&size) && !prefetchable,
+            BarKind::Memory32 => (16..=1 << 31).contains(&size),
+            BarKind::Memory64 => size >= 16,
+        };
+        if last >= BARS || !size.is_power_of_two() || !valid {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if (index..=last).any(|i| self.bars[i].is_some() || self.is_upper_half(i)) {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        self.bars[index] = Some(BarInfo {
+            kind,
+            prefetchable,
+            size,
+        });
+        let offset = BAR0 + index * 4;
+        let (flags, address_mask) = match kind {
+            BarKind::Io => (BAR_IO, !0x3),
+            BarKind::Memory32 => (0, !0xf),
+            BarKind::Memory64 => (BAR_MEMORY_64, !0xf),
+        };
+        let flags = if prefetchable {
+            flags | BAR_PREFETCHABLE
+        } else {
+            flags
+        };
+        let size_mask = !(size - 1);
+        self.set_u32(offset, flags);
+        self.set_writable_u32(offset, size_mask as u32 & address_mask);
+        if kind == BarKind::Memory64 {
+            self.set_writable_u32(offset + 4, (size_mask >> 32) as u32);
+        }
+
+        Ok(())
+    }
+
+    /// BAR `index`, if it was declared.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        let info = (*self.bars.get(index)?)?;
+        let offset = BAR0 + index * 4;
+        let low = u64::from(self.u32(offset));
+        let address = match info.kind {
+            BarKind::Io => low & !0x3,
+            BarKind::Memory32 => low & !0xf,
+            BarKind::Memory64 => low & !0xf | u64::from(self.u32(offset + 4)) << 32,
+        };
+
+        Some(Bar {
+            kind: info.kind,
+            prefetchable: info.prefetchable,
+            size: info.size,
+            address,
+        })
+    }
+
+    /// Moves BAR `index` to `address`, as firmware would.  Fails with
+    /// `EINVAL` if there is no such BAR, or if the address isn't aligned
+    /// to its size, or doesn't fit in it.
+    pub fn set_bar_address(&mut self, index: usize, address: u64) -> nix::Result<()> {
+        let bar = self.bar(index).ok_or(nix::Error::Sys(Errno::EINVAL))?;
+        let fits = match bar.kind {
+            BarKind::Io => address < 0x1_0000,
+            BarKind::Memory32 => address < 1 << 32,
+            BarKind::Memory64 => true,
+        };
+        if !address.is_multiple_of(bar.size) || !fits {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let offset = BAR0 + index * 4;
+        let flags = self.u32(offset)
+            & match bar.kind {
+                BarKind::Io => 0x3,
+                _ => 0xf,
+            };
+        self.set_u32(offset, address as u32 | flags);
+        if bar.kind == BarKind::Memory64 {
+            self.set_u32(offset + 4, (address >> 32) as u32);
+        }
+
+        Ok(())
+    }
+
+    /// The BAR that decodes `addr`, and the offset into it, if the command
+    /// register enables decoding its space.
+    pub fn bar_at(&self, space: Space, addr: u64) -> Option<(usize, u64)> {
+        let command = self.command();
+        (0..BARS).find_map(|index| {
+            let bar = self.bar(index)?;
+            let enabled = match bar.kind {
+                BarKind::Io => space == Space::Io && command & COMMAND_IO != 0,
+                _ => space == Space::Mmio && command & COMMAND_MEMORY != 0,
+            };
+            let offset = addr.wrapping_sub(bar.address);
+            if enabled && offset < bar.size {
+                Some((index, offset))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Adds a capability with the id `id`, and the registers `body`, which
+    /// follow the id and next pointer.  `writable` has the bits of each
+    /// byte of `body` that the guest may write.  Returns the offset of the
+    /// capability.  Fails with `EINVAL` if `writable` isn't as long as
+    /// `body`, or with `ENOSPC` if there isn't room for it.
+    pub fn add_capability(&mut self, id: u8, body: &[u8], writable: &[u8]) -> nix::Result<u8> {
+        if writable.len() != body.len() {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        let offset = (self.caps_end + 3) & !3;
+        let end = offset + 2 + body.len();
+        if end > CAPS_END {
+            return Err(nix::Error::Sys(Errno::ENOSPC));
+        }
+
+        self.data[offset] = id;
+        self.data[offset + 1] = 0;
+        self.data[offset + 2..end].copy_from_slice(body);
+        self.writable[offset + 2..end].copy_from_slice(writable);
+        if self.last_cap == 0 {
+            self.data[CAPABILITIES] = offset as u8;
+            let status = self.u16(STATUS) | STATUS_CAPABILITIES;
+            self.set_u16(STATUS, status);
+        } else {
+            self.data[self.last_cap + 1] = offset as u8;
+        }
+        self.last_cap = offset;
+        self.caps_end = end;
+        Ok(offset as u8)
+    }
+
+    /// Adds an MSI capability with `vectors` vectors, a power of two up to
+    /// [`MSI_VECTORS_MAX`], and 64 bit addresses.  Returns the offset of
+    /// the capability.  Fails with `EINVAL` if the number of vectors isn't
+    /// valid, with `EEXIST` if there is already an MSI capability, or as
+    /// [`add_capability`](#method.add_capability) does.
+    pub fn add_msi(&mut self, vectors: u8, maskable: bool) -> nix::Result<u8> {
+        if !vectors.is_power_of_two() || vectors > MSI_VECTORS_MAX {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if self.msi.is_some() {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        let mut control = (vectors.trailing_zeros() as u16) << 1 | MSI_CTL_64BIT;
+        if maskable {
+            control |= MSI_CTL_MASKABLE;
+        }
+        // control, then the address, data, and (if maskable) the mask and
+        // pending bits; the enable bit and the number of vectors enabled are
+        // writable, as are the mask bits.
+        let mut body = vec![0; if maskable { 22 } else { 12 }];
+        body[..2].copy_from_slice(&control.to_le_bytes());
+        let mut writable = vec![
+            0x71, 0x00, 0xfc, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        if maskable {
+            writable.extend_from_slice(&[0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        }
+
+        let cap = self.add_capability(CAP_ID_MSI, &body, &writable)?;
+        self.msi = Some(Msi {
+            cap: usize::from(cap),
+            vectors,
+            maskable,
+        });
+        Ok(cap)
+    }
+
+    /// Adds an MSI-X capability with `vectors` vectors, up to
+    /// [`MSIX_VECTORS_MAX`], whose table is at `table` in BAR `bar`, and
+    /// pending bits at `pba`.  Those accesses are for
+    /// [`msix_read`](#method.msix_read) and
+    /// [`msix_write`](#method.msix_write) to handle.  Returns the offset of
+    /// the capability.  Fails with `EINVAL` if the number of vectors isn't
+    /// valid, or the table or pending bits aren't aligned, overlap, or don't
+    /// fit in the BAR, with `EEXIST` if there is already an MSI-X
+    /// capability, or as [`add_capability`](#method.add_capability) does.
+    pub fn add_msix(&mut self, vectors: u16, bar: usize, table: u64, pba: u64) -> nix::Result<u8> {
+        let size = self
+            .bar(bar)
+            .filter(|bar| bar.kind != BarKind::Io)
+            .map_or(0, |bar| bar.size);
+        let table_len = usize::from(vectors) * MSIX_ENTRY_SIZE;
+        let pba_len = usize::from(vectors).div_ceil(64) * 8;
+        let table_end = table + table_len as u64;
+        let pba_end = pba + pba_len as u64;
+        if vectors == 0
+            || vectors > MSIX_VECTORS_MAX
+            || !table.is_multiple_of(8)
+            || !pba.is_multiple_of(8)
+            || table_end > size
+            || pba_end > size
+            || (table < pba_end && pba < table_end)
+        {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if self.msix.is_some() {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        let mut body = vec![];
+        body.extend_from_slice(&(vectors - 1).to_le_bytes());
+        body.extend_from_slice(&(table as u32 | bar as u32).to_le_bytes());
+        body.extend_from_slice(&(pba as u32 | bar as u32).to_le_bytes());
+        let writable = [0x00, 0xc0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let cap = self.add_capability(CAP_ID_MSIX, &body, &writable)?;
+        // every vector starts out masked.
+        let mut entries = vec![0; table_len];
+        for entry in entries.chunks_mut(MSIX_ENTRY_SIZE) {
+            entry[MSIX_ENTRY_CONTROL] = MSIX_ENTRY_MASKED;
+        }
+        self.msix = Some(Msix {
+            cap: usize::from(cap),
+            bar,
+            table,
+            pba,
+            entries,
+            pending: vec![0; pba_len],
+        });
+        Ok(cap)
+    }
+
+    /// Sets where MSI and MSI-X messages go.
+    pub fn set_msi_interrupt(&mut self, interrupt: Box<dyn MsiInterrupt>) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Reads the MSI-X table or pending bits, if `data.len()` bytes at
+    /// `offset` into BAR `bar` are all in either.  Returns false, leaving
+    /// the access to the device, if they aren't.
+    pub fn msix_read(&self, bar: usize, offset: u64, data: &mut [u8]) -> bool {
+        let msix = match self.msix {
+            Some(ref msix) if msix.bar == bar => msix,
+            _ => return false,
+        };
+
+        let regions = [(msix.table, &msix.entries), (msix.pba, &msix.pending)];
+        for &(base, bytes) in regions.iter() {
+            if let Some(start) = within(offset, data.len(), base, bytes.len()) {
+                data.copy_from_slice(&bytes[start..start + data.len()]);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Writes the MSI-X table, or drops a write to the read only pending
+    /// bits, if `data.len()` bytes at `offset` into BAR `bar` are all in
+    /// either.  Returns false, leaving the access to the device, if they
+    /// aren't.  Unmasking a vector sends the message pending on it.
+    pub fn msix_write(&mut self, bar: usize, offset: u64, data: &[u8]) -> bool {
+        let msix = match self.msix {
+            Some(ref mut msix) if msix.bar == bar => msix,
+            _ => return false,
+        };
+
+        if let Some(start) = within(offset, data.len(), msix.table, msix.entries.len()) {
+            msix.entries[start..start + data.len()].copy_from_slice(data);
+            // only the mask bit of the vector control is writable.
+            let first = start / MSIX_ENTRY_SIZE;
+            let last = (start + data.len() - 1) / MSIX_ENTRY_SIZE;
+            for entry in first..=last {
+                let control = entry * MSIX_ENTRY_SIZE + MSIX_ENTRY_CONTROL;
+                msix.entries[control] &= MSIX_ENTRY_MASKED;
+                for b in &mut msix.entries[control + 1..control + 4] {
+                    *b = 0;
+                }
+            }
+        } else if within(offset, data.len(), msix.pba, msix.pending.len()).is_none() {
+            return false;
+        }
+
+        self.send_pending();
+        true
+    }
+
+    /// Sends the message of vector `vector`, through MSI-X if it is
+    /// enabled, or else through MSI.  A message for a masked vector is
+    /// left pending, and one for a function that isn't a bus master is
+    /// dropped.  Returns false if neither is enabled, for the device to
+    /// fall back on its interrupt pin.
+    pub fn signal(&mut self, vector: u16) -> bool {
+        if let Some(message) = self.msix_message(vector) {
+            if self.msix_masked(vector) {
+                self.set_msix_pending(vector, true);
+            } else {
+                self.send(vector, message);
+            }
+            true
+        } else if let Some(message) = self.msi_message(vector) {
+            if self.msi_masked(vector) {
+                self.set_msi_bit(MSI_PENDING, vector, true);
+            } else {
+                self.send(vector, message);
+            }
+            true
+        } else if self.msix_enabled() || self.msi_enabled() {
+            debug!("no MSI vector {} to signal", vector);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether MSI-X is enabled.
+    pub fn msix_enabled(&self) -> bool {
+        self.msix
+            .as_ref()
+            .is_some_and(|msix| self.u16(msix.cap + 2) & MSIX_CTL_ENABLE != 0)
+    }
+
+    /// Whether MSI is enabled.
+    pub fn msi_enabled(&self) -> bool {
+        self.msi
+            .as_ref()
+            .is_some_and(|msi| self.u16(msi.cap + MSI_CTL) & MSI_CTL_ENABLE != 0)
+    }
+
+    /// The message of MSI-X vector `vector`, if MSI-X is enabled.
+    pub fn msix_message(&self, vector: u16) -> Option<MsiMessage> {
+        let msix = self.msix.as_ref().filter(|_| self.msix_enabled())?;
+        let start = usize::from(vector) * MSIX_ENTRY_SIZE;
+        let entry = msix.entries.get(start..start + MSIX_ENTRY_SIZE)?;
+        let mut address = [0; 8];
+        address.copy_from_slice(&entry[..8]);
+        let mut data = [0; 4];
+        data.copy_from_slice(&entry[8..12]);
+
+        Some(MsiMessage {
+            address: u64::from_le_bytes(address),
+            data: u32::from_le_bytes(data),
+        })
+    }
+
+    /// The message of MSI vector `vector`, if MSI is enabled, and the guest
+    /// enabled that many vectors.
+    pub fn msi_message(&self, vector: u16) -> Option<MsiMessage> {
+        let msi = self.msi.filter(|_| self.msi_enabled())?;
+        let enabled = self.msi_vectors_enabled(msi);
+        if vector >= enabled {
+            return None;
+        }
+
+        // the low bits of the data are the vector.
+        let data = u32::from(self.u16(msi.cap + MSI_DATA));
+        Some(MsiMessage {
+            address: u64::from(self.u32(msi.cap + MSI_ADDRESS))
+                | u64::from(self.u32(msi.cap + MSI_ADDRESS + 4)) << 32,
+            data: data & !(u32::from(enabled) - 1) | u32::from(vector),
+        })
+    }
+
+    fn msi_vectors_enabled(&self, msi: Msi) -> u16 {
+        let enabled = (self.u16(msi.cap + MSI_CTL) >> 4) & 0x7;
+        (1 << enabled).min(u16::from(msi.vectors))
+    }
+
+    fn msix_masked(&self, vector: u16) -> bool {
+        let msix = match self.msix {
+            Some(ref msix) => msix,
+            None => return true,
+        };
+        let control = usize::from(vector) * MSIX_ENTRY_SIZE + MSIX_ENTRY_CONTROL;
+        self.u16(msix.cap + 2) & MSIX_CTL_MASK != 0
+            || msix.entries[control] & MSIX_ENTRY_MASKED != 0
+    }
+
+    fn set_msix_pending(&mut self, vector: u16, pending: bool) {
+        if let Some(ref mut msix) = self.msix {
+            let byte = &mut msix.pending[usize::from(vector / 8)];
+            if pending {
+                *byte |= 1 << (vector % 8);
+            } else {
+                *byte &= !(1 << (vector % 8));
+            }
+        }
+    }
+
+    fn msi_masked(&self, vector: u16) -> bool {
+        self.msi
+            .is_some_and(|msi| msi.maskable && self.msi_bit(msi.cap + MSI_MASK, vector))
+    }
+
+    fn msi_bit(&self, offset: usize, vector: u16) -> bool {
+        self.u32(offset) & 1 << vector != 0
+    }
+
+    fn set_msi_bit(&mut self, register: usize, vector: u16, set: bool) {
+        let msi = match self.msi {
+            Some(msi) if msi.maskable => msi,
+            _ => return,
+        };
+        let offset = msi.cap + register;
+        let bits = if set {
+            self.u32(offset) | 1 << vector
+        } else {
+            self.u32(offset) & !(1 << vector)
+        };
+        self.set_u32(offset, bits);
+    }
+
+    /// Sends the messages pending on vectors that are no longer masked.
+    fn send_pending(&mut self) {
+        let mut vectors = vec![];
+        if let Some(ref msix) = self.msix {
+            let count = msix.entries.len() / MSIX_ENTRY_SIZE;
+            vectors.extend(
+                (0..count as u16).filter(|&v| msix.pending[usize::from(v / 8)] & 1 << (v % 8) != 0),
+            );
+        }
+        for vector in vectors {
+            if let Some(message) = self.msix_message(vector) {
+                if !self.msix_masked(vector) {
+                    self.set_msix_pending(vector, false);
+                    self.send(vector, message);
+                }
+            }
+        }
+
+        let msi = match self.msi {
+            Some(msi) if msi.maskable => msi,
+            _ => return,
+        };
+        for vector in 0..u16::from(msi.vectors) {
+            if !self.msi_bit(msi.cap + MSI_PENDING, vector) || self.msi_masked(vector) {
+                continue;
+            }
+            if let Some(message) = self.msi_message(vector) {
+                self.set_msi_bit(MSI_PENDING, vector, false);
+                self.send(vector, message);
+            }
+        }
+    }
+
+    fn send(&mut self, vector: u16, message: MsiMessage) {
+        // the message is a memory write by the function.
+        if self.command() & COMMAND_BUS_MASTER == 0 {
+            debug!("dropped MSI vector {} without bus mastering", vector);
+            return;
+        }
+
+        match self.interrupt {
+            Some(ref mut interrupt) => interrupt.signal(vector, message),
+            None => debug!("nowhere to send MSI vector {}", vector),
+        }
+    }
+
+    /// Whether the BAR register at `index` is the high half of a 64 bit
+    /// BAR.
+    fn is_upper_half(&self, index: usize) -> bool {
+        index > 0 && self.bars[index - 1].is_some_and(|bar| bar.kind == BarKind::Memory64)
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_writable_u32(&mut self, offset: usize, mask: u32) {
+        self.writable[offset..offset + 4].copy_from_slice(&mask.to_le_bytes());
+    }
+}
+
+/// Where `len` bytes at `offset` start in the `size` bytes at `base`, if
+/// they are all in there.
+fn within(offset: u64, len: usize, base: u64, size: usize) -> Option<usize> {
+    let start = offset.checked_sub(base)?;
+    if start + len as u64 <= size as u64 {
+        Some(start as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the messages sent.
+    struct Recorder(Arc<Mutex<Vec<(u16, MsiMessage)>>>);
+
+    impl MsiInterrupt for Recorder {
+        fn signal(&mut self, vector: u16, message: MsiMessage) {
+            self.0.lock().unwrap().push((vector, message));
+        }
+    }
+
+    fn recorded(config: &mut PciConfig) -> Arc<Mutex<Vec<(u16, MsiMessage)>>> {
+        let sent = Arc::new(Mutex::new(vec![]));
+        config.set_msi_interrupt(Box::new(Recorder(sent.clone())));
+        sent
+    }
+
+    fn read_u32(config: &PciConfig, offset: usize) -> u32 {
+        let mut data = [0; 4];
+        config.read(offset as u16, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_u32(config: &mut PciConfig, offset: usize, value: u32) {
+        config.write(offset as u16, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn bar_sizing() {
+        let mut config = PciConfig::new(0x1af4, 0x1000, 0x02_00_00, 0);
+        config.add_bar(0, BarKind::Memory32, 0x1000, false).unwrap();
+        config.add_bar(1, BarKind::Io, 0x20, false).unwrap();
+        config.add_bar(4, BarKind::Memory32, 0x10, true).unwrap();
+
+        // writing ones reads back the size, and the flags.
+        for &offset in &[BAR0, BAR0 + 4, BAR0 + 16, BAR0 + 8] {
+            write_u32(&mut config, offset, !0);
+        }
+        assert_eq!(read_u32(&config, BAR0), 0xffff_f000);
+        assert_eq!(read_u32(&config, BAR0 + 4), 0xffff_ffe1);
+        assert_eq!(read_u32(&config, BAR0 + 16), 0xffff_fff8);
+        // nothing is declared at index 2.
+        assert_eq!(read_u32(&config, BAR0 + 8), 0);
+        assert_eq!(config.bar(2), None);
+
+        write_u32(&mut config, BAR0, 0xfebf_f123);
+        assert_eq!(
+            config.bar(0),
+            Some(Bar {
+                kind: BarKind::Memory32,
+                prefetchable: false,
+                size: 0x1000,
+                address: 0xfebf_f000,
+            })
+        );
+
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        let eexist = nix::Error::Sys(Errno::EEXIST);
+        assert_eq!(
+            config.add_bar(2, BarKind::Memory32, 0x30, false),
+            Err(einval)
+        );
+        assert_eq!(config.add_bar(2, BarKind::Memory32, 8, false), Err(einval));
+        assert_eq!(config.add_bar(2, BarKind::Io, 0x200, false), Err(einval));
+        assert_eq!(config.add_bar(2, BarKind::Io, 0x10, true), Err(einval));
+        assert_eq!(config.add_bar(BARS, BarKind::Io, 0x10, false), Err(einval));
+        assert_eq!(config.add_bar(1, BarKind::Io, 0x10, false), Err(eexist));
+        assert_eq!(
+            config.add_bar(3, BarKind::Memory64, 0x10, false),
+            Err(eexist)
+        );
+    }
+
+    #[test]
+    fn bar_64bit() {
+        let mut config = PciConfig::new(0x1af4, 0x1000, 0x02_00_00, 0);
+        config.add_bar(2, BarKind::Memory64, 1 << 33, true).unwrap();
+
+        // the upper half is the next register, and isn't a BAR itself.
+        assert_eq!(config.bar(3), None);
+        let eexist = nix::Error::Sys(Errno::EEXIST);
+        assert_eq!(config.add_bar(3, BarKind::Io, 0x10, false), Err(eexist));
+        assert_eq!(
+            config.add_bar(1, BarKind::Memory64, 0x10, false),
+            Err(eexist)
+        );
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        assert_eq!(
+            config.add_bar(5, BarKind::Memory64, 0x10, false),
+            Err(einval)
+        );
+
+        write_u32(&mut config, BAR0 + 8, !0);
+        write_u32(&mut config, BAR0 + 12, !0);
+        assert_eq!(read_u32(&config, BAR0 + 8), 0xc);
+        assert_eq!(read_u32(&config, BAR0 + 12), 0xffff_fffe);
+
+        config.set_bar_address(2, 0x8_0000_0000).unwrap();
+        assert_eq!(read_u32(&config, BAR0 + 8), 0xc);
+        assert_eq!(read_u32(&config, BAR0 + 12), 0x8);
+        assert_eq!(config.bar(2).unwrap().address, 0x8_0000_0000);
+        assert_eq!(config.set_bar_address(2, 1 << 32), Err(einval));
+        assert_eq!(config.set_bar_address(3, 0), Err(einval));
+
+        // decoding is off until the command register turns it on.
+        assert_eq!(config.bar_at(Space::Mmio, 0x8_0000_0010), None);
+        config.write(COMMAND as u16, &COMMAND_MEMORY.to_le_bytes());
+        assert_eq!(config.bar_at(Space::Mmio, 0x8_0000_0010), Some((2, 0x10)));
+        assert_eq!(config.bar_at(Space::Io, 0x8_0000_0010), None);
+        assert_eq!(config.bar_at(Space::Mmio, 0xa_0000_0000), None);
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut config = PciConfig::new(0x1af4, 0x1000, 0x02_00_00, 0);
+        assert_eq!(config.u16(STATUS) & STATUS_CAPABILITIES, 0);
+        assert_eq!(
+            config.add_capability(CAP_ID_VENDOR, &[3], &[]),
+            Err(nix::Error::Sys(Errno::EINVAL))
+        );
+
+        let first = config
+            .add_capability(CAP_ID_VENDOR, &[3, 0xaa], &[0, 0xff])
+            .unwrap();
+        let second = config
+            .add_capability(CAP_ID_VENDOR, &[5, 1, 2, 3], &[0; 4])
+            .unwrap();
+        assert_eq!((first, second), (0x40, 0x44));
+        assert_ne!(config.u16(STATUS) & STATUS_CAPABILITIES, 0);
+
+        // the list runs from the capabilities pointer, and ends with 0.
+        let mut next = [0; 1];
+        config.read(CAPABILITIES as u16, &mut next);
+        let mut chain = vec![];
+        while next[0] != 0 {
+            let mut cap = [0; 2];
+            config.read(u16::from(next[0]), &mut cap);
+            chain.push((next[0], cap[0]));
+            next[0] = cap[1];
+        }
+        assert_eq!(chain, vec![(0x40, CAP_ID_VENDOR), (0x44, CAP_ID_VENDOR)]);
+
+        // only the writable bits of the body change.
+        config.write(0x40, &[0xff, 0xff, 0x11, 0x22]);
+        let mut cap = [0; 4];
+        config.read(0x40, &mut cap);
+        assert_eq!(cap, [CAP_ID_VENDOR, 0x44, 3, 0x22]);
+
+        assert_eq!(
+            config.add_capability(CAP_ID_VENDOR, &[0; 0xb3], &[0; 0xb3]),
+            Err(nix::Error::Sys(Errno::ENOSPC))
+        );
+        assert_eq!(
+            config.add_capability(CAP_ID_VENDOR, &[0; 0xb2], &[0; 0xb2]),
+            Ok(0x4c)
+        );
+    }
+
+    #[test]
+    fn msi() {
+        let mut config = PciConfig::new(0x1af4, 0x1000, 0x02_00_00, 0);
+        let cap = usize::from(config.add_msi(4, true).unwrap());
+        assert_eq!(config.add_msi(4, true), Err(nix::Error::Sys(Errno::EEXIST)));
+        assert_eq!(
+            config.u16(cap + MSI_CTL),
+            2 << 1 | MSI_CTL_64BIT | MSI_CTL_MASKABLE
+        );
+        let sent = recorded(&mut config);
+        assert!(!config.signal(0));
+
+        config.write((cap + MSI_ADDRESS) as u16, &0xfee0_0000u64.to_le_bytes());
+        config.write((cap + MSI_DATA) as u16, &0x40u16.to_le_bytes());
+        // enable two of the four vectors.
+        config.write((cap + MSI_CTL) as u16, &[0x11]);
+        assert!(config.msi_enabled());
+        let message = |data| MsiMessage {
+            address: 0xfee0_0000,
+            data,
+        };
+        assert_eq!(config.msi_message(1), Some(message(0x41)));
+        assert_eq!(config.msi_message(2), None);
+
+        // without bus mastering the message is dropped.
+        assert!(config.signal(1));
+        assert!(sent.lock().unwrap().is_empty());
+        config.write(COMMAND as u16, &COMMAND_BUS_MASTER.to_le_bytes());
+        assert!(config.signal(1));
+        assert!(config.signal(2));
+        assert_eq!(*sent.lock().unwrap(), vec![(1, message(0x41))]);
+
+        // a masked vector is left pending until it is unmasked.
+        write_u32(&mut config, cap + MSI_MASK, 1);
+        assert!(config.signal(0));
+        assert_eq!(read_u32(&config, cap + MSI_PENDING), 1);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        // the pending bits are read only.
+        write_u32(&mut config, cap + MSI_PENDING, 0);
+        assert_eq!(read_u32(&config, cap + MSI_PENDING), 1);
+
+        write_u32(&mut config, cap + MSI_MASK, 0);
+        assert_eq!(read_u32(&config, cap + MSI_PENDING), 0);
+        assert_eq!(sent.lock().unwrap()[1], (0, message(0x40)));
+    }
+
+    #[test]
+    fn msix() {
+        let mut config = PciConfig::new(0x1af4, 0x1000, 0x02_00_00, 0);
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        assert_eq!(config.add_msix(4, 0, 0, 0x800), Err(einval));
+        config.add_bar(0, BarKind::Memory32, 0x1000, false).unwrap();
+        assert_eq!(config.add_msix(4, 0, 0, 0x20), Err(einval));
+        assert_eq!(config.add_msix(4, 0, 0, 0x1000), Err(einval));
+        assert_eq!(config.add_msix(0, 0, 0, 0x800), Err(einval));
+        let cap = usize::from(config.add_msix(4, 0, 0, 0x800).unwrap());
+        assert_eq!(
+            config.add_msix(4, 0, 0, 0x800),
+            Err(nix::Error::Sys(Errno::EEXIST))
+        );
+        let sent = recorded(&mut config);
+        config.write(COMMAND as u16, &COMMAND_BUS_MASTER.to_le_bytes());
+
+        // the table and pending bits are only in BAR 0.
+        let mut data = [0; 4];
+        assert!(!config.msix_read(1, 0, &mut data));
+        assert!(!config.msix_read(0, 0x40, &mut data));
+        assert!(!config.msix_write(0, 0x900, &data));
+        assert!(config.msix_read(0, 12, &mut data));
+        assert_eq!(data, [MSIX_ENTRY_MASKED, 0, 0, 0]);
+
+        let message = MsiMessage {
+            address: 0xfee0_1000,
+            data: 0x31,
+        };
+        assert!(config.msix_write(0, 16, &message.address.to_le_bytes()));
+        assert!(config.msix_write(0, 24, &message.data.to_le_bytes()));
+        assert!(!config.signal(1));
+        config.write((cap + 3) as u16, &[(MSIX_CTL_ENABLE >> 8) as u8]);
+        assert!(config.msix_enabled());
+        assert_eq!(config.msix_message(1), Some(message));
+        assert_eq!(config.msix_message(4), None);
+
+        // every vector starts out masked.
+        assert!(config.signal(1));
+        assert!(sent.lock().unwrap().is_empty());
+        let mut pending = [0; 8];
+        assert!(config.msix_read(0, 0x800, &mut pending));
+        assert_eq!(pending, [0b10, 0, 0, 0, 0, 0, 0, 0]);
+        // writes to the pending bits are dropped.
+        assert!(config.msix_write(0, 0x800, &[0; 8]));
+        assert!(config.msix_read(0, 0x800, &mut pending));
+        assert_eq!(pending[0], 0b10);
+
+        // only the mask bit of the vector control is writable.
+        assert!(config.msix_write(0, 28, &[0xfe, 0xff, 0xff, 0xff]));
+        assert!(config.msix_read(0, 28, &mut data));
+        assert_eq!(data, [0, 0, 0, 0]);
+        assert_eq!(*sent.lock().unwrap(), vec![(1, message)]);
+        assert!(config.msix_read(0, 0x800, &mut pending));
+        assert_eq!(pending[0], 0);
+
+        // so does the function mask.
+        let control = ((MSIX_CTL_ENABLE | MSIX_CTL_MASK) >> 8) as u8;
+        config.write((cap + 3) as u16, &[control]);
+        assert!(config.signal(1));
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        config.write((cap + 3) as u16, &[(MSIX_CTL_ENABLE >> 8) as u8]);
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // MSI-X takes the place of MSI.
+        config.add_msi(1, false).unwrap();
+        assert!(config.signal(1));
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,535 @@
+//! A PCI root bus: the host bridge, and the functions behind it.
+//!
+//! The guest reaches configuration space through configuration mechanism
+//! #1 (an address at [`CONFIG_ADDRESS_PORT`], and the data at
+//! [`CONFIG_DATA_PORT`]), or through ECAM at [`ECAM_ADDR`].  Only bus 0
+//! exists.  Each function implements [`PciDevice`], which mostly comes down
+//! to keeping a [`PciConfig`]; the root decodes its BARs, wherever the
+//! guest moves them within the windows the root is registered over, and
+//! hands their accesses to it.  Accesses to an MSI-X table are handled by
+//! the [`PciConfig`] before they get to the function.
+//!
+//! [`PciRoot::add`] places each BAR in the windows, the way firmware would;
+//! decoding stays off until the guest turns it on.
+
+use super::{Bus, BusDevice, Space};
+use memory::PCI_HOLE_START;
+use nix;
+use nix::errno::Errno;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+pub mod config;
+pub mod msi;
+
+pub use self::config::{Bar, BarKind, PciConfig};
+pub use self::msi::{GsiRouting, MsiEvents, MsiInterrupt, MsiMessage, SignalMsi};
+
+use self::config::{BARS, CONFIG_SIZE};
+
+/// The port of the configuration address register.
+pub const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+/// The port of the configuration data register.
+pub const CONFIG_DATA_PORT: u16 = 0xcfc;
+/// Where the ECAM window is, at the start of the PCI hole.
+pub const ECAM_ADDR: u64 = PCI_HOLE_START;
+/// The size of the ECAM window, for all 256 buses.
+pub const ECAM_SIZE: u64 = 0x1000_0000;
+/// The window that memory BARs are placed in, from the end of the ECAM
+/// window to the IOAPIC.
+pub const MMIO_WINDOW: u64 = ECAM_ADDR + ECAM_SIZE;
+/// The size of the window for memory BARs.
+pub const MMIO_WINDOW_SIZE: u64 = 0xfec0_0000 - MMIO_WINDOW;
+/// The window that port BARs are placed in.
+pub const IO_WINDOW: u64 = 0xc000;
+/// The size of the window for port BARs.
+pub const IO_WINDOW_SIZE: u64 = 0x4000;
+
+/// The number of device numbers on a bus.
+pub const DEVICES: u8 = 32;
+
+const CONFIG_ENABLE: u32 = 1 << 31;
+// the enable bit, bus, device, function, and dword-aligned register.
+const CONFIG_ADDRESS_MASK: u32 = 0x80ff_fffc;
+
+/// The device and function number of a function, as one byte.
+pub fn devfn(device: u8, function: u8) -> u8 {
+    (device & 0x1f) << 3 | function & 0x7
+}
+
+/// A PCI function.
+pub trait PciDevice: Send {
+    /// The function's configuration space.
+    fn config(&self) -> &PciConfig;
+
+    /// The function's configuration space.
+    fn config_mut(&mut self) -> &mut PciConfig;
+
+    /// Reads `data.len()` bytes of configuration space at `offset`.
+    fn read_config(&mut self, offset: u16, data: &mut [u8]) {
+        self.config().read(offset, data)
+    }
+
+    /// Writes `data` to configuration space at `offset`.
+    fn write_config(&mut self, offset: u16, data: &[u8]) {
+        self.config_mut().write(offset, data)
+    }
+
+    /// Reads `data.len()` bytes at `offset` into BAR `bar`.
+    fn read_bar(&mut self, _bar: usize, _offset: u64, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b = 0xff;
+        }
+    }
+
+    /// Writes `data` at `offset` into BAR `bar`.
+    fn write_bar(&mut self, _bar: usize, _offset: u64, _data: &[u8]) {}
+}
+
+/// A function shared between the root and its owner.
+pub type SharedPciDevice = Arc<Mutex<dyn PciDevice>>;
+
+/// The host bridge, at function 0 of device 0.
+pub struct HostBridge {
+    config: PciConfig,
+}
+
+impl HostBridge {
+    /// A host bridge, with QEMU's ids for a generic PCIe host bridge.
+    pub fn new() -> HostBridge {
+        HostBridge {
+            config: PciConfig::new(0x1b36, 0x0008, 0x06_00_00, 0),
+        }
+    }
+}
+
+impl Default for HostBridge {
+    fn default() -> HostBridge {
+        HostBridge::new()
+    }
+}
+
+impl PciDevice for HostBridge {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+}
+
+/// The root bus, to be put on a [`Bus`] with [`register`].
+pub struct PciRoot {
+    address: u32,
+    devices: BTreeMap<u8, SharedPciDevice>,
+    // where the next BARs go.
+    io_next: u64,
+    mmio_next: u64,
+}
+
+impl PciRoot {
+    /// A root bus with nothing but the host bridge on it.
+    pub fn new() -> PciRoot {
+        let mut root = PciRoot {
+            address: 0,
+            devices: BTreeMap::new(),
+            io_next: IO_WINDOW,
+            mmio_next: MMIO_WINDOW,
+        };
+        root.devices
+            .insert(devfn(0, 0), Arc::new(Mutex::new(HostBridge::new())));
+        root
+    }
+
+    /// Adds a single function device at the first free device number.
+    /// Returns its device and function number.  Fails with `ENOSPC` if the
+    /// bus is full, or as [`insert`](#method.insert) does.
+    pub fn add(&mut self, device: SharedPciDevice) -> nix::Result<u8> {
+        let devfn = (0..DEVICES)
+            .map(|device| devfn(device, 0))
+            .find(|devfn| !self.devices.contains_key(devfn))
+            .ok_or(nix::Error::Sys(Errno::ENOSPC))?;
+        self.insert(devfn, device)?;
+        Ok(devfn)
+    }
+
+    /// Adds a function at the device and function number `devfn`, and
+    /// places its BARs.  Functions other than 0 are only seen by the guest
+    /// if function 0 of the device is there, with
+    /// [`HEADER_TYPE_MULTIFUNCTION`](config/constant.HEADER_TYPE_MULTIFUNCTION.html)
+    /// set.  Fails with `EEXIST` if the number is taken, or with `ENOSPC` if
+    /// the BARs don't fit in the windows.
+    pub fn insert(&mut self, devfn: u8, device: SharedPciDevice) -> nix::Result<()> {
+        if self.devices.contains_key(&devfn) {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        {
+            let mut function = device.lock().unwrap_or_else(|e| e.into_inner());
+            let config = function.config_mut();
+            let (mut io_next, mut mmio_next) = (self.io_next, self.mmio_next);
+            let mut addresses = vec![];
+            for index in 0..BARS {
+                let bar = match config.bar(index) {
+                    Some(bar) => bar,
+                    None => continue,
+                };
+                let (next, end) = match bar.kind {
+                    BarKind::Io => (&mut io_next, IO_WINDOW + IO_WINDOW_SIZE),
+                    _ => (&mut mmio_next, MMIO_WINDOW + MMIO_WINDOW_SIZE),
+                };
+                let address = (*next + bar.size - 1) & !(bar.size - 1);
+                if address.checked_add(bar.size).is_none_or(|e| e > end) {
+                    return Err(nix::Error::Sys(Errno::ENOSPC));
+                }
+                *next = address + bar.size;
+                addresses.push((index, address));
+            }
+
+            for (index, address) in addresses {
+                config.set_bar_address(index, address)?;
+            }
+            self.io_next = io_next;
+            self.mmio_next = mmio_next;
+        }
+
+        self.devices.insert(devfn, device);
+        Ok(())
+    }
+
+    /// The function at `devfn`, if there is one.
+    pub fn device(&self, devfn: u8) -> Option<&SharedPciDevice> {
+        self.devices.get(&devfn)
+    }
+
+    /// Reads the configuration space of the function at `devfn` on `bus`;
+    /// a missing function reads as ones.
+    pub fn read_config(&self, bus: u8, devfn: u8, offset: u16, data: &mut [u8]) {
+        match self.function(bus, devfn) {
+            Some(device) => device
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .read_config(offset, data),
+            None => {
+                for b in data.iter_mut() {
+                    *b = 0xff;
+                }
+            }
+        }
+    }
+
+    /// Writes the configuration space of the function at `devfn` on `bus`;
+    /// a write to a missing function is dropped.
+    pub fn write_config(&self, bus: u8, devfn: u8, offset: u16, data: &[u8]) {
+        if let Some(device) = self.function(bus, devfn) {
+            device
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_config(offset, data);
+        }
+    }
+
+    fn function(&self, bus: u8, devfn: u8) -> Option<&SharedPciDevice> {
+        if bus != 0 || (devfn & 0x7 != 0 && !self.devices.contains_key(&(devfn & !0x7))) {
+            return None;
+        }
+        self.devices.get(&devfn)
+    }
+
+    /// The bus, function, and register that `offset` into the ECAM window
+    /// selects.
+    fn ecam(offset: u64) -> (u8, u8, u16) {
+        (
+            (offset >> 20) as u8,
+            (offset >> 12) as u8,
+            (offset as usize % CONFIG_SIZE) as u16,
+        )
+    }
+
+    /// The bus, function, and register `offset` bytes into the data
+    /// register selects, if the address register enables an access.
+    fn config_data(&self, offset: u64) -> Option<(u8, u8, u16)> {
+        if self.address & CONFIG_ENABLE == 0 {
+            return None;
+        }
+        Some((
+            (self.address >> 16) as u8,
+            (self.address >> 8) as u8,
+            (self.address & 0xfc) as u16 + offset as u16,
+        ))
+    }
+
+    /// Hands an access to the BAR that decodes `addr`.  Returns false if
+    /// there isn't one.
+    fn bar_access<F>(&self, space: Space, addr: u64, f: F) -> bool
+    where
+        F: FnOnce(&mut dyn PciDevice, usize, u64),
+    {
+        for device in self.devices.values() {
+            let mut device = device.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((bar, offset)) = device.config().bar_at(space, addr) {
+                f(&mut *device, bar, offset);
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+impl Default for PciRoot {
+    fn default() -> PciRoot {
+        PciRoot::new()
+    }
+}
+
+impl BusDevice for PciRoot {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        let handled = match base {
+            base if base == u64::from(CONFIG_ADDRESS_PORT) => {
+                // only whole dword accesses reach the address register.
+                if offset == 0 && data.len() == 4 {
+                    data.copy_from_slice(&self.address.to_le_bytes());
+                    true
+                } else {
+                    false
+                }
+            }
+            base if base == u64::from(CONFIG_DATA_PORT) => match self.config_data(offset) {
+                Some((bus, devfn, register)) => {
+                    self.read_config(bus, devfn, register, data);
+                    true
+                }
+                None => false,
+            },
+            ECAM_ADDR => {
+                let (bus, devfn, register) = PciRoot::ecam(offset);
+                self.read_config(bus, devfn, register, data);
+                true
+            }
+            IO_WINDOW => self.bar_access(Space::Io, base + offset, |device, bar, offset| {
+                if !device.config().msix_read(bar, offset, data) {
+                    device.read_bar(bar, offset, data);
+                }
+            }),
+            _ => self.bar_access(Space::Mmio, base + offset, |device, bar, offset| {
+                if !device.config().msix_read(bar, offset, data) {
+                    device.read_bar(bar, offset, data);
+                }
+            }),
+        };
+
+        if !handled {
+            for b in data.iter_mut() {
+                *b = 0xff;
+            }
+        }
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        match base {
+            base if base == u64::from(CONFIG_ADDRESS_PORT) => {
+                if offset == 0 && data.len() == 4 {
+                    let address = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    self.address = address & CONFIG_ADDRESS_MASK;
+                }
+            }
+            base if base == u64::from(CONFIG_DATA_PORT) => {
+                if let Some((bus, devfn, register)) = self.config_data(offset) {
+                    self.write_config(bus, devfn, register, data);
+                }
+            }
+            ECAM_ADDR => {
+                let (bus, devfn, register) = PciRoot::ecam(offset);
+                self.write_config(bus, devfn, register, data);
+            }
+            IO_WINDOW => {
+                self.bar_access(Space::Io, base + offset, |device, bar, offset| {
+                    if !device.config_mut().msix_write(bar, offset, data) {
+                        device.write_bar(bar, offset, data);
+                    }
+                });
+            }
+            _ => {
+                self.bar_access(Space::Mmio, base + offset, |device, bar, offset| {
+                    if !device.config_mut().msix_write(bar, offset, data) {
+                        device.write_bar(bar, offset, data);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Registers `root` on `bus` over the configuration ports, the ECAM window,
+/// and the windows for BARs.
+pub fn register(root: &Arc<Mutex<PciRoot>>, bus: &mut Bus) -> nix::Result<()> {
+    bus.insert(root.clone(), Space::Io, u64::from(CONFIG_ADDRESS_PORT), 4)?;
+    bus.insert(root.clone(), Space::Io, u64::from(CONFIG_DATA_PORT), 4)?;
+    bus.insert(root.clone(), Space::Io, IO_WINDOW, IO_WINDOW_SIZE)?;
+    bus.insert(root.clone(), Space::Mmio, ECAM_ADDR, ECAM_SIZE)?;
+    bus.insert(root.clone(), Space::Mmio, MMIO_WINDOW, MMIO_WINDOW_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config::{COMMAND, COMMAND_IO, COMMAND_MEMORY, INTERRUPT_LINE};
+    use super::*;
+
+    struct TestFunction {
+        config: PciConfig,
+        writes: Vec<(usize, u64, Vec<u8>)>,
+    }
+
+    impl PciDevice for TestFunction {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+
+        fn config_mut(&mut self) -> &mut PciConfig {
+            &mut self.config
+        }
+
+        fn read_bar(&mut self, bar: usize, offset: u64, data: &mut [u8]) {
+            for b in data.iter_mut() {
+                *b = bar as u8 | (offset as u8) << 4;
+            }
+        }
+
+        fn write_bar(&mut self, bar: usize, offset: u64, data: &[u8]) {
+            self.writes.push((bar, offset, data.to_vec()));
+        }
+    }
+
+    fn function(device_id: u16) -> Arc<Mutex<TestFunction>> {
+        let mut config = PciConfig::new(0x1af4, device_id, 0xff_00_00, 0);
+        config.add_bar(0, BarKind::Io, 0x20, false).unwrap();
+        config.add_bar(1, BarKind::Memory32, 0x1000, false).unwrap();
+        Arc::new(Mutex::new(TestFunction {
+            config,
+            writes: vec![],
+        }))
+    }
+
+    fn read(root: &mut PciRoot, base: u64, offset: u64, len: usize) -> u32 {
+        let mut data = [0; 4];
+        root.read(base, offset, &mut data[..len]);
+        u32::from_le_bytes(data)
+    }
+
+    fn select(root: &mut PciRoot, bus: u8, devfn: u8, register: u8) {
+        let address =
+            CONFIG_ENABLE | u32::from(bus) << 16 | u32::from(devfn) << 8 | u32::from(register);
+        root.write(u64::from(CONFIG_ADDRESS_PORT), 0, &address.to_le_bytes());
+    }
+
+    fn ecam(bus: u8, devfn: u8, register: u16) -> u64 {
+        u64::from(bus) << 20 | u64::from(devfn) << 12 | u64::from(register)
+    }
+
+    #[test]
+    fn config_mechanisms() {
+        let mut root = PciRoot::new();
+        assert_eq!(root.add(function(0x1041)), Ok(devfn(1, 0)));
+        root.insert(devfn(1, 2), function(0x1042)).unwrap();
+        root.insert(devfn(2, 1), function(0x1043)).unwrap();
+        assert_eq!(
+            root.insert(devfn(1, 0), function(0x1044)),
+            Err(nix::Error::Sys(Errno::EEXIST))
+        );
+
+        let data = u64::from(CONFIG_DATA_PORT);
+        // the address register keeps only the bits that select something.
+        root.write(u64::from(CONFIG_ADDRESS_PORT), 0, &[0xff; 4]);
+        assert_eq!(
+            read(&mut root, u64::from(CONFIG_ADDRESS_PORT), 0, 4),
+            0x80ff_fffc
+        );
+        assert_eq!(
+            read(&mut root, u64::from(CONFIG_ADDRESS_PORT), 0, 2),
+            0xffff
+        );
+        root.write(u64::from(CONFIG_ADDRESS_PORT), 0, &[0; 2]);
+        assert_eq!(
+            read(&mut root, u64::from(CONFIG_ADDRESS_PORT), 0, 4),
+            0x80ff_fffc
+        );
+
+        let ids = |device: u32| device << 16 | 0x1af4;
+        for &(devfn, expected) in &[
+            (devfn(0, 0), 0x0008_1b36),
+            (devfn(1, 0), ids(0x1041)),
+            (devfn(1, 2), ids(0x1042)),
+            // function 1 of device 2 is hidden without function 0.
+            (devfn(2, 1), !0),
+            (devfn(3, 0), !0),
+        ] {
+            select(&mut root, 0, devfn, 0);
+            assert_eq!(read(&mut root, data, 0, 4), expected);
+            assert_eq!(read(&mut root, ECAM_ADDR, ecam(0, devfn, 0), 4), expected);
+        }
+
+        // sub-dword accesses are at the offset into the data register.
+        select(&mut root, 0, devfn(1, 0), 0);
+        assert_eq!(read(&mut root, data, 2, 2), 0x1041);
+        assert_eq!(read(&mut root, data, 1, 1), 0x1a);
+        // only bus 0 exists.
+        select(&mut root, 1, devfn(1, 0), 0);
+        assert_eq!(read(&mut root, data, 0, 4), !0);
+        assert_eq!(read(&mut root, ECAM_ADDR, ecam(1, devfn(1, 0), 0), 4), !0);
+        // without the enable bit, the data register reads as ones.
+        root.write(
+            u64::from(CONFIG_ADDRESS_PORT),
+            0,
+            &(devfn(1, 0) as u32 * 256).to_le_bytes(),
+        );
+        assert_eq!(read(&mut root, data, 0, 4), !0);
+
+        // writes go through either mechanism, and only ECAM reaches the
+        // extended space.
+        select(&mut root, 0, devfn(1, 0), INTERRUPT_LINE as u8);
+        root.write(data, 0, &[11]);
+        assert_eq!(
+            read(&mut root, ECAM_ADDR, ecam(0, devfn(1, 0), 0x3c), 1),
+            11
+        );
+        root.write(ECAM_ADDR, ecam(0, devfn(1, 0), 0x3c), &[12]);
+        assert_eq!(read(&mut root, data, 0, 1), 12);
+        assert_eq!(
+            read(&mut root, ECAM_ADDR, ecam(0, devfn(1, 0), 0x100), 4),
+            0
+        );
+    }
+
+    #[test]
+    fn bars() {
+        let mut root = PciRoot::new();
+        let first = function(0x1041);
+        let second = function(0x1042);
+        root.add(first.clone()).unwrap();
+        root.add(second.clone()).unwrap();
+
+        // the BARs are placed in the windows, in order.
+        let bar = |function: &Arc<Mutex<TestFunction>>, index| {
+            function.lock().unwrap().config.bar(index).unwrap().address
+        };
+        assert_eq!(bar(&first, 0), IO_WINDOW);
+        assert_eq!(bar(&first, 1), MMIO_WINDOW);
+        assert_eq!(bar(&second, 0), IO_WINDOW + 0x20);
+        assert_eq!(bar(&second, 1), MMIO_WINDOW + 0x1000);
+
+        // nothing decodes until the command register says so.
+        assert_eq!(read(&mut root, IO_WINDOW, 0x24, 1), 0xff);
+        let command = COMMAND_IO | COMMAND_MEMORY;
+        root.write_config(0, devfn(2, 0), COMMAND as u16, &command.to_le_bytes());
+        assert_eq!(read(&mut root, IO_WINDOW, 0x4, 1), 0xff);
+        assert_eq!(read(&mut root, IO_WINDOW, 0x24, 1), 0x40);
+        assert_eq!(read(&mut root, MMIO_WINDOW, 0x1003, 1), 0x31);
+
+        root.write(MMIO_WINDOW, 0x1008, &[1, 2]);
+        root.write(IO_WINDOW, 0x8, &[3]);
+        assert_eq!(second.lock().unwrap().writes, vec![(1, 8, vec![1, 2])]);
+        assert!(first.lock().unwrap().writes.is_empty());
+    }
+}
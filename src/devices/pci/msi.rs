@@ -0,0 +1,357 @@
+//! Delivering message signalled interrupts.
+//!
+//! An MSI is a memory write by the device, of the message's data to the
+//! message's address, which the interrupt controller turns into an
+//! interrupt.  KVM takes the message either directly, through
+//! [`kvm_signal_msi`](../../../fn.kvm_signal_msi.html) ([`SignalMsi`]), or
+//! through an irqfd whose GSI is routed to the message ([`MsiEvents`],
+//! with the routes kept in a [`GsiRouting`]).
+
+use consts::{
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_IRQ_ROUTING_IRQCHIP,
+    KVM_IRQ_ROUTING_MSI, KVM_MSI_VALID_DEVID,
+};
+use ctl::{kvm_set_gsi_routing, kvm_signal_msi, IrqRouting, IrqRoutingEntry, Msi};
+use devices::IrqEvent;
+use nix;
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+/// The number of IOAPIC pins, which the default routing gives the first
+/// GSIs.
+const IOAPIC_PINS: u32 = 24;
+
+/// An MSI message, as programmed by the guest.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// Where a device's MSIs go.
+pub trait MsiInterrupt: Send {
+    /// Delivers `message` for the device's vector `vector`.
+    fn signal(&mut self, vector: u16, message: MsiMessage);
+}
+
+/// Delivers MSIs with [`kvm_signal_msi`](../../../fn.kvm_signal_msi.html).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SignalMsi {
+    vm: RawFd,
+    devid: Option<u32>,
+}
+
+impl SignalMsi {
+    /// Delivers MSIs to the VM `vm`, with the device id `devid`, if any,
+    /// for interrupt controllers that need one.
+    pub fn new(vm: RawFd, devid: Option<u32>) -> SignalMsi {
+        SignalMsi { vm, devid }
+    }
+}
+
+impl MsiInterrupt for SignalMsi {
+    fn signal(&mut self, vector: u16, message: MsiMessage) {
+        let msi = Msi {
+            address_lo: message.address as u32,
+            address_hi: (message.address >> 32) as u32,
+            data: message.data,
+            flags: if self.devid.is_some() {
+                KVM_MSI_VALID_DEVID
+            } else {
+                0
+            },
+            devid: self.devid.unwrap_or(0),
+            pad: [0; 12],
+        };
+        if let Err(e) = unsafe { kvm_signal_msi(self.vm, &msi) } {
+            warn!("couldn't signal MSI vector {}: {}", vector, e);
+        }
+    }
+}
+
+/// The GSI routing table of a VM.  Setting a table replaces the whole of
+/// it, so everything that routes GSIs has to share one of these.  It
+/// starts out with the routes the in-kernel irqchip sets up on x86: GSIs 0
+/// to 15 to both the 8259s and the IOAPIC, and the rest of the IOAPIC's
+/// pins after them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GsiRouting {
+    vm: RawFd,
+    entries: Vec<IrqRoutingEntry>,
+}
+
+impl GsiRouting {
+    /// The default routing of the VM `vm`.  Nothing is set until a route
+    /// is changed.
+    pub fn new(vm: RawFd) -> GsiRouting {
+        let irqchip = |gsi: u32, chip: u32, pin: u32| IrqRoutingEntry {
+            gsi,
+            kind: KVM_IRQ_ROUTING_IRQCHIP,
+            flags: 0,
+            pad: 0,
+            u: [chip, pin, 0, 0, 0, 0, 0, 0],
+        };
+
+        let mut entries = vec![];
+        for gsi in 0..IOAPIC_PINS {
+            if gsi < 8 {
+                entries.push(irqchip(gsi, KVM_IRQCHIP_PIC_MASTER, gsi));
+            } else if gsi < 16 {
+                entries.push(irqchip(gsi, KVM_IRQCHIP_PIC_SLAVE, gsi - 8));
+            }
+            entries.push(irqchip(gsi, KVM_IRQCHIP_IOAPIC, gsi));
+        }
+
+        GsiRouting { vm, entries }
+    }
+
+    /// The routes, in the order they are set.
+    pub fn entries(&self) -> &[IrqRoutingEntry] {
+        &self.entries
+    }
+
+    /// Routes `gsi` to `message` alone, and sets the table.  The routes
+    /// are left as they were if that fails.
+    pub fn set_msi(&mut self, gsi: u32, message: MsiMessage) -> nix::Result<()> {
+        let mut entries = self.without(gsi);
+        entries.push(IrqRoutingEntry {
+            gsi,
+            kind: KVM_IRQ_ROUTING_MSI,
+            flags: 0,
+            pad: 0,
+            u: [
+                message.address as u32,
+                (message.address >> 32) as u32,
+                message.data,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        });
+        self.commit(entries)
+    }
+
+    /// Removes the routes of `gsi`, and sets the table.  The routes are
+    /// left as they were if that fails.
+    pub fn remove(&mut self, gsi: u32) -> nix::Result<()> {
+        let entries = self.without(gsi);
+        self.commit(entries)
+    }
+
+    /// Sets the table.
+    pub fn set(&self) -> nix::Result<()> {
+        set_routing(self.vm, &self.entries)
+    }
+
+    // the routes of every GSI but `gsi`.
+    fn without(&self, gsi: u32) -> Vec<IrqRoutingEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.gsi != gsi)
+            .copied()
+            .collect()
+    }
+
+    fn commit(&mut self, entries: Vec<IrqRoutingEntry>) -> nix::Result<()> {
+        set_routing(self.vm, &entries)?;
+        self.entries = entries;
+        Ok(())
+    }
+}
+
+fn set_routing(vm: RawFd, entries: &[IrqRoutingEntry]) -> nix::Result<()> {
+    // the header is one word, and the entries a whole number of them.
+    let words = size_of::<IrqRoutingEntry>() / 8;
+    let mut buf = vec![0u64; 1 + entries.len() * words];
+    unsafe {
+        let routing = buf.as_mut_ptr() as *mut IrqRouting;
+        (*routing).nr = entries.len() as u32;
+        let dest = (*routing).entries.as_mut_ptr();
+        for (i, entry) in entries.iter().enumerate() {
+            *dest.add(i) = *entry;
+        }
+        kvm_set_gsi_routing(vm, routing).map(|_| ())
+    }
+}
+
+/// Delivers MSIs through irqfds, one for each vector.  The GSI of a vector
+/// is routed to its message the first time it is signalled, and again
+/// whenever the guest changes the message.
+pub struct MsiEvents {
+    routing: Arc<Mutex<GsiRouting>>,
+    vectors: Vec<(u32, IrqEvent, Option<MsiMessage>)>,
+}
+
+impl MsiEvents {
+    /// Delivers vector `n` through `vectors[n]`: an irqfd, and the GSI it
+    /// is registered with, which is routed in `routing`.
+    pub fn new(routing: Arc<Mutex<GsiRouting>>, vectors: Vec<(u32, IrqEvent)>) -> MsiEvents {
+        MsiEvents {
+            routing,
+            vectors: vectors
+                .into_iter()
+                .map(|(gsi, event)| (gsi, event, None))
+                .collect(),
+        }
+    }
+}
+
+impl MsiInterrupt for MsiEvents {
+    fn signal(&mut self, vector: u16, message: MsiMessage) {
+        let (gsi, event, routed) = match self.vectors.get_mut(usize::from(vector)) {
+            Some(&mut (gsi, ref event, ref mut routed)) => (gsi, event, routed),
+            None => {
+                warn!("no irqfd for MSI vector {}", vector);
+                return;
+            }
+        };
+
+        if *routed != Some(message) {
+            let result = self
+                .routing
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .set_msi(gsi, message);
+            if let Err(e) = result {
+                warn!("couldn't route GSI {} to {:?}: {}", gsi, message, e);
+                return;
+            }
+            *routed = Some(message);
+        }
+
+        if let Err(e) = event.trigger() {
+            warn!("couldn't signal MSI vector {}: {}", vector, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ctl::kvm_create_irqchip;
+    use nix::fcntl::{self, OFlag};
+    use nix::sys::eventfd::{eventfd, EfdFlags};
+    use nix::sys::stat::Mode;
+    use nix::unistd;
+    use vm::Vm;
+
+    const MESSAGE: MsiMessage = MsiMessage {
+        address: 0x1_fee0_0000,
+        data: 0x4041,
+    };
+
+    // the routes of `gsi`, as (kind, first three words).
+    fn routes(routing: &GsiRouting, gsi: u32) -> Vec<(u32, [u32; 3])> {
+        routing
+            .entries()
+            .iter()
+            .filter(|entry| entry.gsi == gsi)
+            .map(|entry| (entry.kind, [entry.u[0], entry.u[1], entry.u[2]]))
+            .collect()
+    }
+
+    // a VM with an in-kernel irqchip, whose routes can be set; never
+    // closed.
+    fn irqchip_vm() -> Option<RawFd> {
+        let kvm = fcntl::open("/dev/kvm", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty()).ok()?;
+        unsafe {
+            let vm = Vm::create(kvm).unwrap();
+            kvm_create_irqchip(vm.fd()).unwrap();
+            Some(vm.fd())
+        }
+    }
+
+    #[test]
+    fn default_routes() {
+        let routing = GsiRouting::new(-1);
+        let irqchip = KVM_IRQ_ROUTING_IRQCHIP;
+        assert_eq!(routing.entries().len(), 16 * 2 + 8);
+        assert_eq!(
+            routes(&routing, 0),
+            [
+                (irqchip, [KVM_IRQCHIP_PIC_MASTER, 0, 0]),
+                (irqchip, [KVM_IRQCHIP_IOAPIC, 0, 0])
+            ]
+        );
+        assert_eq!(
+            routes(&routing, 10),
+            [
+                (irqchip, [KVM_IRQCHIP_PIC_SLAVE, 2, 0]),
+                (irqchip, [KVM_IRQCHIP_IOAPIC, 10, 0])
+            ]
+        );
+        assert_eq!(
+            routes(&routing, 23),
+            [(irqchip, [KVM_IRQCHIP_IOAPIC, 23, 0])]
+        );
+        assert!(routes(&routing, 24).is_empty());
+    }
+
+    #[test]
+    fn set_msi() {
+        let mut routing = match irqchip_vm() {
+            Some(vm) => GsiRouting::new(vm),
+            None => return,
+        };
+        routing.set().unwrap();
+
+        // the MSI route replaces the irqchip ones.
+        let msi = (KVM_IRQ_ROUTING_MSI, [0xfee0_0000, 1, 0x4041]);
+        routing.set_msi(5, MESSAGE).unwrap();
+        assert_eq!(routes(&routing, 5), [msi]);
+        routing.set_msi(30, MESSAGE).unwrap();
+        assert_eq!(routes(&routing, 30), [msi]);
+        let other = MsiMessage {
+            data: 0x4042,
+            ..MESSAGE
+        };
+        routing.set_msi(30, other).unwrap();
+        assert_eq!(
+            routes(&routing, 30),
+            [(KVM_IRQ_ROUTING_MSI, [0xfee0_0000, 1, 0x4042])]
+        );
+        routing.remove(30).unwrap();
+        assert!(routes(&routing, 30).is_empty());
+        assert_eq!(routing.entries().len(), 16 * 2 + 8 - 1);
+    }
+
+    #[test]
+    fn failures_keep_routes() {
+        // there is no VM to set the table on.
+        let mut routing = GsiRouting::new(-1);
+        let before = routing.clone();
+        assert!(routing.set_msi(5, MESSAGE).is_err());
+        assert!(routing.remove(0).is_err());
+        assert_eq!(routing, before);
+    }
+
+    #[test]
+    fn events() {
+        let vm = match irqchip_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        let routing = Arc::new(Mutex::new(GsiRouting::new(vm)));
+        let event = eventfd(0, EfdFlags::EFD_NONBLOCK).unwrap();
+        let mut events = MsiEvents::new(routing.clone(), vec![(24, IrqEvent::new(event))]);
+
+        // vectors without an irqfd are dropped.
+        events.signal(1, MESSAGE);
+        assert!(routes(&routing.lock().unwrap(), 24).is_empty());
+        let mut count = [0; 8];
+        assert!(unistd::read(event, &mut count).is_err());
+
+        // the first signal routes the GSI, and triggers the irqfd.
+        events.signal(0, MESSAGE);
+        assert_eq!(
+            routes(&routing.lock().unwrap(), 24),
+            [(KVM_IRQ_ROUTING_MSI, [0xfee0_0000, 1, 0x4041])]
+        );
+        assert_eq!(events.vectors[0].2, Some(MESSAGE));
+        assert_eq!(unistd::read(event, &mut count), Ok(8));
+        assert_eq!(u64::from_le_bytes(count), 1);
+    }
+}
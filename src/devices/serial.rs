@@ -0,0 +1,523 @@
+//! An emulated 16550A UART.
+//!
+//! The UART transmits instantly: whatever the guest writes to the transmit
+//! holding register goes straight to the output stream, and the transmitter
+//! is always empty.  Input is queued by the host with
+//! [`Serial::queue_input`] (or from a stream with [`spawn_input`]), and is
+//! never dropped; the receive FIFO just holds on to it until the guest
+//! reads it.  There is no timing, so a character timeout interrupt is
+//! reported as soon as there is less input than the FIFO's trigger level.
+//! As on a PC, the IRQ line is only driven while the guest sets OUT2 in the
+//! MCR, and not in loopback mode.
+
+use super::{BusDevice, Interrupt};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// The ports of the first serial port.
+pub const COM1: u16 = 0x3f8;
+/// The ports of the second serial port.
+pub const COM2: u16 = 0x2f8;
+/// The ports of the third serial port.
+pub const COM3: u16 = 0x3e8;
+/// The ports of the fourth serial port.
+pub const COM4: u16 = 0x2e8;
+/// The IRQ of the first and third serial ports.
+pub const COM1_IRQ: u32 = 4;
+/// The IRQ of the second and fourth serial ports.
+pub const COM2_IRQ: u32 = 3;
+/// The number of ports of a serial port.
+pub const PORTS: u64 = 8;
+
+// register offsets.
+const DATA: u64 = 0;
+const IER: u64 = 1;
+const IIR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_MS: u8 = 1 << 3;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_MS: u8 = 0x00;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_TRIGGER: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+const MSR_DELTA: u8 = 0x0f;
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+const FIFO_SIZE: usize = 16;
+
+/// A 16550A UART, to be registered over [`PORTS`] ports at one of the
+/// `COM` bases.
+pub struct Serial {
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    msr: u8,
+    scr: u8,
+    divisor: u16,
+    // whether the transmitter empty interrupt is pending; it is cleared by
+    // reading the IIR, or writing the THR.
+    thre: bool,
+    rx: VecDeque<u8>,
+    out: Box<dyn Write + Send>,
+    irq: Option<Box<dyn Interrupt>>,
+    level: bool,
+}
+
+impl Serial {
+    /// Creates a UART, as it comes out of reset, writing what the guest
+    /// transmits to `out`, and raising `irq`, if any, when an interrupt is
+    /// pending.
+    pub fn new(out: Box<dyn Write + Send>, irq: Option<Box<dyn Interrupt>>) -> Serial {
+        Serial {
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            // a host is always connected.
+            msr: MSR_DCD | MSR_DSR | MSR_CTS,
+            scr: 0,
+            // 115200 baud.
+            divisor: 1,
+            thre: false,
+            rx: VecDeque::new(),
+            out,
+            irq,
+            level: false,
+        }
+    }
+
+    /// Queues input for the guest to read.
+    pub fn queue_input(&mut self, bytes: &[u8]) {
+        // a UART in loopback mode is disconnected from the outside.
+        if self.mcr & MCR_LOOP != 0 {
+            return;
+        }
+
+        self.rx.extend(bytes);
+        self.update_interrupt();
+    }
+
+    /// The number of queued bytes the guest hasn't read.
+    pub fn pending_input(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// The divisor the guest set, from which the baud rate is
+    /// `115200 / divisor`.
+    pub fn divisor(&self) -> u16 {
+        self.divisor
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    fn trigger_level(&self) -> usize {
+        match self.fcr & FCR_TRIGGER {
+            0x00 => 1,
+            0x40 => 4,
+            0x80 => 8,
+            _ => 14,
+        }
+    }
+
+    /// The highest priority pending interrupt, as IIR reports it.
+    fn interrupt(&self) -> u8 {
+        let received = self.rx.len().min(FIFO_SIZE);
+        // input is never dropped, so there are no receiver line status
+        // interrupts.
+        if self.ier & IER_RDA != 0 && received > 0 {
+            if !self.fifo_enabled() || received >= self.trigger_level() {
+                IIR_RDA
+            } else {
+                IIR_TIMEOUT
+            }
+        } else if self.ier & IER_THRE != 0 && self.thre {
+            IIR_THRE
+        } else if self.ier & IER_MS != 0 && self.msr & MSR_DELTA != 0 {
+            IIR_MS
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn update_interrupt(&mut self) {
+        // OUT2 gates the interrupt onto the bus, and is disconnected from
+        // it in loopback mode.
+        let enabled = self.mcr & (MCR_OUT2 | MCR_LOOP) == MCR_OUT2;
+        let level = enabled && self.interrupt() != IIR_NONE;
+        if level == self.level {
+            return;
+        }
+
+        self.level = level;
+        if let Some(ref mut irq) = self.irq {
+            irq.set_level(level);
+        }
+    }
+
+    /// Sets the modem status lines, and the bits for those that changed
+    /// (the trailing edge of RI, for it).
+    fn set_modem_status(&mut self, status: u8) {
+        let old = self.msr;
+        let mut delta = (old ^ status) >> 4 & !(MSR_RI >> 4);
+        if old & MSR_RI != 0 && status & MSR_RI == 0 {
+            delta |= MSR_RI >> 4;
+        }
+        self.msr = status & !MSR_DELTA | (old | delta) & MSR_DELTA;
+    }
+
+    /// The modem status lines the modem control lines feed in loopback
+    /// mode.
+    fn loopback_status(&self) -> u8 {
+        let mut status = 0;
+        if self.mcr & MCR_RTS != 0 {
+            status |= MSR_CTS;
+        }
+        if self.mcr & MCR_DTR != 0 {
+            status |= MSR_DSR;
+        }
+        if self.mcr & MCR_OUT1 != 0 {
+            status |= MSR_RI;
+        }
+        if self.mcr & MCR_OUT2 != 0 {
+            status |= MSR_DCD;
+        }
+        status
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.rx.push_back(byte);
+        } else if let Err(e) = self.out.write_all(&[byte]).and_then(|_| self.out.flush()) {
+            warn!("couldn't write serial output: {}", e);
+        }
+        // the byte goes out right away.
+        self.thre = true;
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            DATA if dlab => self.divisor as u8,
+            DATA => self.rx.pop_front().unwrap_or(0),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR => {
+                let interrupt = self.interrupt();
+                if interrupt == IIR_THRE {
+                    self.thre = false;
+                }
+                if self.fifo_enabled() {
+                    interrupt | IIR_FIFO
+                } else {
+                    interrupt
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            // the transmitter is always empty.
+            LSR if self.rx.is_empty() => LSR_THRE | LSR_TEMT,
+            LSR => LSR_THRE | LSR_TEMT | LSR_DR,
+            MSR => {
+                let msr = self.msr;
+                self.msr &= !MSR_DELTA;
+                msr
+            }
+            SCR => self.scr,
+            _ => 0xff,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            DATA if dlab => self.divisor = self.divisor & 0xff00 | u16::from(value),
+            DATA => self.transmit(value),
+            IER if dlab => self.divisor = self.divisor & 0xff | u16::from(value) << 8,
+            IER => {
+                // enabling the interrupt with the transmitter empty raises
+                // it.
+                if self.ier & IER_THRE == 0 && value & IER_THRE != 0 {
+                    self.thre = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR => {
+                if value & FCR_CLEAR_RX != 0 || (value ^ self.fcr) & FCR_ENABLE != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value & (FCR_ENABLE | FCR_TRIGGER);
+            }
+            LCR => self.lcr = value,
+            MCR => {
+                let was_loop = self.mcr & MCR_LOOP != 0;
+                self.mcr = value & MCR_MASK;
+                if self.mcr & MCR_LOOP != 0 {
+                    let status = self.loopback_status();
+                    self.set_modem_status(status);
+                } else if was_loop {
+                    self.set_modem_status(MSR_DCD | MSR_DSR | MSR_CTS);
+                }
+            }
+            // the LSR and MSR are read only.
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Serial {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        // the registers are bytes; wider accesses read the next ones.
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.read_register(offset + i as u64);
+        }
+        self.update_interrupt();
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        for (i, &b) in data.iter().enumerate() {
+            self.write_register(offset + i as u64, b);
+        }
+        self.update_interrupt();
+    }
+}
+
+/// Spawns a thread that feeds everything read from `input` to the UART,
+/// until the end of the stream or an error.
+pub fn spawn_input<R>(serial: Arc<Mutex<Serial>>, mut input: R) -> JoinHandle<io::Result<()>>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0; 64];
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            serial
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .queue_input(&buf[..n]);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Levels(Arc<Mutex<Vec<bool>>>);
+
+    impl Interrupt for Levels {
+        fn set_level(&mut self, level: bool) {
+            self.0.lock().unwrap().push(level);
+        }
+    }
+
+    fn serial() -> (Serial, Output, Levels) {
+        let (out, levels) = (Output::default(), Levels::default());
+        let serial = Serial::new(Box::new(out.clone()), Some(Box::new(levels.clone())));
+        (serial, out, levels)
+    }
+
+    fn read(serial: &mut Serial, offset: u64) -> u8 {
+        let mut data = [0];
+        serial.read(u64::from(COM1), offset, &mut data);
+        data[0]
+    }
+
+    fn write(serial: &mut Serial, offset: u64, value: u8) {
+        serial.write(u64::from(COM1), offset, &[value]);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let (mut serial, out, _) = serial();
+        write(&mut serial, LCR, LCR_DLAB | 0x03);
+        write(&mut serial, DATA, 0x0c);
+        write(&mut serial, IER, 0x01);
+        assert_eq!(serial.divisor(), 0x010c);
+        assert_eq!(read(&mut serial, DATA), 0x0c);
+        assert_eq!(read(&mut serial, IER), 0x01);
+
+        // with DLAB clear, the same ports are the data and IER again.
+        write(&mut serial, LCR, 0x03);
+        write(&mut serial, IER, IER_RDA);
+        write(&mut serial, DATA, b'x');
+        assert_eq!(serial.divisor(), 0x010c);
+        assert_eq!(read(&mut serial, IER), IER_RDA);
+        assert_eq!(*out.0.lock().unwrap(), b"x");
+    }
+
+    #[test]
+    fn receive_fifo() {
+        let (mut serial, _, _) = serial();
+        assert_eq!(read(&mut serial, LSR), LSR_THRE | LSR_TEMT);
+
+        serial.queue_input(b"ab");
+        assert_eq!(serial.pending_input(), 2);
+        assert_eq!(read(&mut serial, LSR), LSR_THRE | LSR_TEMT | LSR_DR);
+        assert_eq!(read(&mut serial, DATA), b'a');
+        assert_eq!(read(&mut serial, DATA), b'b');
+        assert_eq!(read(&mut serial, LSR), LSR_THRE | LSR_TEMT);
+        assert_eq!(read(&mut serial, DATA), 0);
+
+        // clearing the FIFO drops what wasn't read.
+        serial.queue_input(b"cd");
+        write(&mut serial, IIR, FCR_ENABLE | FCR_CLEAR_RX);
+        assert_eq!(serial.pending_input(), 0);
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        let (mut serial, _, _) = serial();
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+
+        write(&mut serial, IER, IER_RDA | IER_THRE | IER_MS);
+        assert_eq!(read(&mut serial, IIR), IIR_THRE);
+        // reading the IIR cleared the transmitter empty interrupt.
+        assert_eq!(read(&mut serial, IIR), IIR_NONE);
+
+        write(&mut serial, DATA, b'x');
+        serial.queue_input(b"y");
+        assert_eq!(read(&mut serial, IIR), IIR_RDA);
+        assert_eq!(read(&mut serial, DATA), b'y');
+        assert_eq!(read(&mut serial, IIR), IIR_THRE);
+
+        // with the FIFO on, less input than the trigger level times out.
+        write(&mut serial, IIR, FCR_ENABLE | FCR_TRIGGER);
+        serial.queue_input(b"z");
+        assert_eq!(read(&mut serial, IIR), IIR_TIMEOUT | IIR_FIFO);
+        serial.queue_input(&[0; 13]);
+        assert_eq!(read(&mut serial, IIR), IIR_RDA | IIR_FIFO);
+        while serial.pending_input() > 0 {
+            read(&mut serial, DATA);
+        }
+
+        // a modem status change is the lowest priority.
+        write(&mut serial, MCR, MCR_LOOP);
+        assert_eq!(read(&mut serial, IIR), IIR_MS | IIR_FIFO);
+        read(&mut serial, MSR);
+        assert_eq!(read(&mut serial, IIR), IIR_NONE | IIR_FIFO);
+    }
+
+    #[test]
+    fn loopback() {
+        let (mut serial, out, _) = serial();
+        write(&mut serial, MCR, MCR_LOOP | MCR_RTS | MCR_DTR);
+        // DCD dropped, and CTS and DSR follow RTS and DTR.
+        assert_eq!(read(&mut serial, MSR), MSR_CTS | MSR_DSR | MSR_DCD >> 4);
+        assert_eq!(read(&mut serial, MSR), MSR_CTS | MSR_DSR);
+        write(&mut serial, MCR, MCR_LOOP | MCR_OUT1 | MCR_OUT2);
+        read(&mut serial, MSR);
+        write(&mut serial, MCR, MCR_LOOP | MCR_OUT2);
+        // RI reports its trailing edge.
+        assert_eq!(read(&mut serial, MSR), MSR_DCD | MSR_RI >> 4);
+
+        // what is transmitted comes back, and the outside is disconnected.
+        write(&mut serial, DATA, b'z');
+        serial.queue_input(b"ignored");
+        assert!(out.0.lock().unwrap().is_empty());
+        assert_eq!(read(&mut serial, LSR) & LSR_DR, LSR_DR);
+        assert_eq!(read(&mut serial, DATA), b'z');
+        assert_eq!(serial.pending_input(), 0);
+
+        write(&mut serial, MCR, 0);
+        assert_eq!(
+            read(&mut serial, MSR) & !MSR_DELTA,
+            MSR_DCD | MSR_DSR | MSR_CTS
+        );
+    }
+
+    #[test]
+    fn irq_level() {
+        let (mut serial, _, levels) = serial();
+        write(&mut serial, IER, IER_RDA);
+        serial.queue_input(b"a");
+        assert!(levels.0.lock().unwrap().is_empty());
+
+        write(&mut serial, MCR, MCR_OUT2);
+        assert_eq!(*levels.0.lock().unwrap(), [true]);
+        read(&mut serial, DATA);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+
+        serial.queue_input(b"b");
+        assert_eq!(*levels.0.lock().unwrap(), [true, false, true]);
+        write(&mut serial, IER, 0);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false, true, false]);
+    }
+
+    #[test]
+    fn irq_gated() {
+        let (mut serial, _, levels) = serial();
+        write(&mut serial, MCR, MCR_OUT2);
+        write(&mut serial, IER, IER_THRE);
+        assert_eq!(*levels.0.lock().unwrap(), [true]);
+
+        // the interrupt stays pending with OUT2 clear, or in loopback mode.
+        write(&mut serial, MCR, 0);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+        write(&mut serial, MCR, MCR_OUT2 | MCR_LOOP);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false]);
+        write(&mut serial, MCR, MCR_OUT2);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false, true]);
+        assert_eq!(read(&mut serial, IIR), IIR_THRE);
+        assert_eq!(*levels.0.lock().unwrap(), [true, false, true, false]);
+    }
+}
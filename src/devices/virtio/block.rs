@@ -0,0 +1,1005 @@
+//! A virtio-blk device, backed by a raw image file (or a block device).
+//!
+//! A [`Block`] serves reads, writes, flushes, discards, and the device id.
+//! Once activated, each queue the driver enabled gets a thread of its own,
+//! which waits on the queue's eventfd and handles the requests on the queue
+//! with positioned reads and writes of the file, so the queues don't get in
+//! each other's way.  With [`BlockConfig::direct`] the file is opened with
+//! `O_DIRECT`; the data goes through a page aligned buffer, but the driver
+//! still has to keep requests aligned to the file's logical block size,
+//! which [`BlockConfig::block_size`] tells it.  Discards punch holes in the
+//! file.
+
+use super::queue::{Chain, Queue, Reader, Writer};
+use super::{
+    Activation, VirtioDevice, VirtioInterrupt, TYPE_BLOCK, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_RING_PACKED,
+};
+use libc;
+use nix;
+use nix::errno::Errno;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::unistd;
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::slice;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// The size of a sector, which requests address the disk in.
+pub const SECTOR_SIZE: u64 = 512;
+/// The size of the device id.
+pub const ID_BYTES: usize = 20;
+
+// feature bits.
+const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
+const VIRTIO_BLK_F_RO: u32 = 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_MQ: u32 = 12;
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+
+// request types.
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const REQ_FLUSH: u32 = 4;
+const REQ_GET_ID: u32 = 8;
+const REQ_DISCARD: u32 = 11;
+
+// request status.
+const STATUS_OK: u8 = 0;
+const STATUS_IOERR: u8 = 1;
+const STATUS_UNSUPP: u8 = 2;
+
+// configuration space offsets.
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SEG_MAX: usize = 12;
+const CONFIG_BLK_SIZE: usize = 20;
+const CONFIG_NUM_QUEUES: usize = 34;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+const CONFIG_SIZE: usize = 60;
+
+const REQUEST_HEADER_SIZE: usize = 16;
+const DISCARD_SEGMENT_SIZE: u64 = 16;
+const DISCARD_F_UNMAP: u32 = 1 << 0;
+const MAX_DISCARD_SECTORS: u32 = 1 << 22;
+const MAX_DISCARD_SEGMENTS: u32 = 32;
+
+const PAGE_SIZE: usize = 4096;
+// the most data read or written at once.
+const CHUNK_SIZE: usize = 256 * PAGE_SIZE;
+
+/// How a [`Block`] device behaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockConfig {
+    /// Whether the disk is read-only.  The file is opened read-only, and
+    /// the driver is told the disk is.
+    pub read_only: bool,
+    /// Whether the file is opened with `O_DIRECT`, bypassing the host's
+    /// page cache.
+    pub direct: bool,
+    /// The number of queues, each served by a thread of its own.
+    pub queues: u16,
+    /// The most entries each queue allows; a power of two, of at least 4.
+    pub queue_size: u16,
+    /// The logical block size the driver is told about; a power of two
+    /// from 512 to 4096.  With `direct`, it should be (at least) the file's
+    /// logical block size.
+    pub block_size: u32,
+    /// The id the driver gets, which Linux shows as the disk's serial.  At
+    /// most [`ID_BYTES`] bytes of it are used.
+    pub id: String,
+}
+
+impl Default for BlockConfig {
+    fn default() -> BlockConfig {
+        BlockConfig {
+            read_only: false,
+            direct: false,
+            queues: 1,
+            queue_size: 256,
+            block_size: 512,
+            id: String::new(),
+        }
+    }
+}
+
+/// The disk, as the queues share it.
+struct Disk {
+    file: File,
+    read_only: bool,
+    // in sectors.
+    capacity: u64,
+    id: [u8; ID_BYTES],
+}
+
+/// A virtio-blk device.
+pub struct Block {
+    disk: Arc<Disk>,
+    config: BlockConfig,
+    workers: Vec<JoinHandle<()>>,
+    // signalled to stop the workers.
+    stop: Option<RawFd>,
+}
+
+impl Block {
+    /// A disk backed by the file at `path`, whose size (rounded down to
+    /// whole sectors) is the capacity.  Fails if the file can't be opened,
+    /// or with `InvalidInput` if `config` isn't valid.
+    pub fn open<P: AsRef<Path>>(path: P, config: BlockConfig) -> io::Result<Block> {
+        if config.queues == 0
+            || !config.queue_size.is_power_of_two()
+            || config.queue_size < 4
+            || !config.block_size.is_power_of_two()
+            || config.block_size < SECTOR_SIZE as u32
+            || config.block_size > PAGE_SIZE as u32
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid virtio-blk configuration",
+            ));
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(!config.read_only);
+        if config.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let mut file = options.open(path)?;
+        // the metadata of a block device doesn't have its size.
+        let capacity = file.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+
+        let mut id = [0; ID_BYTES];
+        let len = cmp::min(config.id.len(), ID_BYTES);
+        id[..len].copy_from_slice(&config.id.as_bytes()[..len]);
+
+        Ok(Block {
+            disk: Arc::new(Disk {
+                file,
+                read_only: config.read_only,
+                capacity,
+                id,
+            }),
+            config,
+            workers: vec![],
+            stop: None,
+        })
+    }
+
+    /// The size of the disk, in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.disk.capacity
+    }
+
+    /// Whether the device is activated, and its queues served.
+    pub fn is_active(&self) -> bool {
+        self.stop.is_some()
+    }
+
+    fn config_space(&self) -> [u8; CONFIG_SIZE] {
+        let mut config = [0; CONFIG_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            config[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(CONFIG_CAPACITY, &self.disk.capacity.to_le_bytes());
+        // the request header and status take a descriptor each.
+        let seg_max = u32::from(self.config.queue_size) - 2;
+        put(CONFIG_SEG_MAX, &seg_max.to_le_bytes());
+        put(CONFIG_BLK_SIZE, &self.config.block_size.to_le_bytes());
+        put(CONFIG_NUM_QUEUES, &self.config.queues.to_le_bytes());
+        put(
+            CONFIG_MAX_DISCARD_SECTORS,
+            &MAX_DISCARD_SECTORS.to_le_bytes(),
+        );
+        put(CONFIG_MAX_DISCARD_SEG, &MAX_DISCARD_SEGMENTS.to_le_bytes());
+        let alignment = self.config.block_size / SECTOR_SIZE as u32;
+        put(CONFIG_DISCARD_SECTOR_ALIGNMENT, &alignment.to_le_bytes());
+        config
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![self.config.queue_size; usize::from(self.config.queues)]
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = 1 << VIRTIO_F_RING_INDIRECT_DESC
+            | 1 << VIRTIO_F_RING_EVENT_IDX
+            | 1 << VIRTIO_F_RING_PACKED
+            | 1 << VIRTIO_BLK_F_SEG_MAX
+            | 1 << VIRTIO_BLK_F_BLK_SIZE
+            | 1 << VIRTIO_BLK_F_FLUSH;
+        if self.config.read_only {
+            features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            features |= 1 << VIRTIO_BLK_F_DISCARD;
+        }
+        if self.config.queues > 1 {
+            features |= 1 << VIRTIO_BLK_F_MQ;
+        }
+        features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config_space();
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = offset
+                .checked_add(i as u64)
+                .and_then(|o| config.get(o as usize))
+                .map_or(0, |&b| b);
+        }
+    }
+
+    fn activate(&mut self, activation: Activation) -> nix::Result<()> {
+        let mut queues = vec![];
+        for (index, config) in activation.queues.iter().enumerate() {
+            if config.ready {
+                let queue = Queue::new(activation.memory.clone(), config, activation.features)?;
+                queues.push((index as u16, queue, activation.queue_events[index]));
+            }
+        }
+
+        let stop = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?;
+        for (index, queue, event) in queues {
+            let mut worker = Worker {
+                disk: self.disk.clone(),
+                queue,
+                index,
+                interrupt: activation.interrupt.clone(),
+                buffer: Buffer(vec![]),
+            };
+            self.workers
+                .push(thread::spawn(move || worker.run(event, stop)));
+        }
+
+        self.stop = Some(stop);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            if let Err(e) = unistd::write(stop, &1u64.to_ne_bytes()) {
+                warn!("couldn't stop virtio-blk queues: {}", e);
+            }
+            for worker in self.workers.drain(..) {
+                let _ = worker.join();
+            }
+            let _ = unistd::close(stop);
+        }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+/// A page aligned buffer, as `O_DIRECT` wants.
+struct Buffer(Vec<Page>);
+
+#[repr(C, align(4096))]
+#[derive(Copy, Clone)]
+struct Page([u8; PAGE_SIZE]);
+
+impl Buffer {
+    /// The first `len` bytes of the buffer, which grows to fit them.
+    fn get(&mut self, len: usize) -> &mut [u8] {
+        let pages = len.div_ceil(PAGE_SIZE);
+        if self.0.len() < pages {
+            self.0.resize(pages, Page([0; PAGE_SIZE]));
+        }
+        // the pages are contiguous, and at least `len` bytes long.
+        unsafe { slice::from_raw_parts_mut(self.0.as_mut_ptr() as *mut u8, len) }
+    }
+}
+
+/// Serves a queue.
+struct Worker {
+    disk: Arc<Disk>,
+    queue: Queue,
+    index: u16,
+    interrupt: Arc<dyn VirtioInterrupt>,
+    buffer: Buffer,
+}
+
+impl Worker {
+    /// Handles requests whenever the driver notifies `event`, until `stop`
+    /// is signalled, or the queue breaks, which the driver is told as the
+    /// device needing a reset.
+    fn run(&mut self, event: RawFd, stop: RawFd) {
+        loop {
+            let mut fds = [
+                PollFd::new(event, EventFlags::POLLIN),
+                PollFd::new(stop, EventFlags::POLLIN),
+            ];
+            match poll(&mut fds, -1) {
+                Ok(_) => {}
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => {
+                    warn!("couldn't wait on virtio-blk queue {}: {}", self.index, e);
+                    self.interrupt.signal_needs_reset();
+                    return;
+                }
+            }
+            if fds[1].revents().is_some_and(|r| !r.is_empty()) {
+                return;
+            }
+
+            let _ = unistd::read(event, &mut [0; 8]);
+            if let Err(e) = self.process_queue() {
+                warn!("virtio-blk queue {} is broken: {}", self.index, e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        }
+    }
+
+    /// Handles every request on the queue.
+    fn process_queue(&mut self) -> nix::Result<()> {
+        loop {
+            self.queue.disable_notification()?;
+            while let Some(chain) = self.queue.pop()? {
+                let len = self.handle(&chain);
+                self.queue.add_used(&chain, len)?;
+            }
+            if self.queue.needs_notification()? {
+                self.interrupt.signal_used_queue(self.index);
+            }
+            // requests that came in before notifications were back on
+            // won't be notified.
+            if !self.queue.enable_notification()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handles the request in `chain`, and returns how much of it was
+    /// written.
+    fn handle(&mut self, chain: &Chain) -> u32 {
+        let memory = self.queue.memory().clone();
+        // the status is the last byte the device writes.
+        let status_addr = match chain
+            .descriptors()
+            .iter()
+            .rev()
+            .find(|d| d.writable && d.len != 0)
+        {
+            Some(d) => d.addr + u64::from(d.len) - 1,
+            None => {
+                debug!("virtio-blk request {} without a status", chain.id());
+                return 0;
+            }
+        };
+
+        let mut reader = chain.reader(&memory);
+        let mut writer = chain.writer(&memory);
+        let data_len = writer.available() - 1;
+        let status = match self.request(&mut reader, &mut writer, data_len) {
+            Ok(status) => status,
+            Err(e) => {
+                debug!("virtio-blk request {} failed: {}", chain.id(), e);
+                STATUS_IOERR
+            }
+        };
+
+        if let Err(e) = memory.write_u8(status_addr, status) {
+            debug!("virtio-blk request {} status: {}", chain.id(), e);
+        }
+        writer.written() + 1
+    }
+
+    /// Carries out the request read from `reader`, whose data (of
+    /// `data_len` bytes, if any) goes to `writer`.
+    fn request(
+        &mut self,
+        reader: &mut Reader,
+        writer: &mut Writer,
+        data_len: u64,
+    ) -> io::Result<u8> {
+        let mut header = [0; REQUEST_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let kind = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let mut sector = [0; 8];
+        sector.copy_from_slice(&header[8..]);
+        let sector = u64::from_le_bytes(sector);
+
+        match kind {
+            REQ_IN => {
+                let mut offset = self.offset(sector, data_len)?;
+                let mut left = data_len;
+                while left != 0 {
+                    let len = cmp::min(left, CHUNK_SIZE as u64) as usize;
+                    let buf = self.buffer.get(len);
+                    self.disk.file.read_exact_at(buf, offset)?;
+                    writer.write_all(buf)?;
+                    offset += len as u64;
+                    left -= len as u64;
+                }
+            }
+            REQ_OUT if self.disk.read_only => return Ok(STATUS_IOERR),
+            REQ_OUT => {
+                // the rest of what the driver wrote is the data.
+                let mut left = reader.available();
+                let mut offset = self.offset(sector, left)?;
+                while left != 0 {
+                    let len = cmp::min(left, CHUNK_SIZE as u64) as usize;
+                    let buf = self.buffer.get(len);
+                    reader.read_exact(buf)?;
+                    self.disk.file.write_all_at(buf, offset)?;
+                    offset += len as u64;
+                    left -= len as u64;
+                }
+            }
+            REQ_FLUSH => self.disk.file.sync_data()?,
+            REQ_GET_ID => {
+                let len = cmp::min(data_len, ID_BYTES as u64) as usize;
+                writer.write_all(&self.disk.id[..len])?;
+            }
+            REQ_DISCARD if self.disk.read_only => return Ok(STATUS_IOERR),
+            REQ_DISCARD => return self.discard(reader),
+            _ => return Ok(STATUS_UNSUPP),
+        }
+
+        Ok(STATUS_OK)
+    }
+
+    /// Punches holes for the segments of a discard request.
+    fn discard(&mut self, reader: &mut Reader) -> io::Result<u8> {
+        let count = reader.available() / DISCARD_SEGMENT_SIZE;
+        if count == 0
+            || !reader.available().is_multiple_of(DISCARD_SEGMENT_SIZE)
+            || count > u64::from(MAX_DISCARD_SEGMENTS)
+        {
+            return Ok(STATUS_UNSUPP);
+        }
+
+        let mut segments = vec![];
+        for _ in 0..count {
+            let mut segment = [0; DISCARD_SEGMENT_SIZE as usize];
+            reader.read_exact(&mut segment)?;
+            let mut sector = [0; 8];
+            sector.copy_from_slice(&segment[..8]);
+            let sectors = u32::from_le_bytes([segment[8], segment[9], segment[10], segment[11]]);
+            let flags = u32::from_le_bytes([segment[12], segment[13], segment[14], segment[15]]);
+            if flags & DISCARD_F_UNMAP != 0 || sectors > MAX_DISCARD_SECTORS {
+                return Ok(STATUS_UNSUPP);
+            }
+            let len = u64::from(sectors) * SECTOR_SIZE;
+            segments.push((self.offset(u64::from_le_bytes(sector), len)?, len));
+        }
+
+        for (offset, len) in segments {
+            let ret = unsafe {
+                libc::fallocate(
+                    self.disk.file.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                // a discard is only a hint.
+                if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(STATUS_OK)
+    }
+
+    /// The offset in the file of `len` bytes at `sector`.  Fails with
+    /// `EINVAL` if `len` isn't whole sectors, or they aren't all on the
+    /// disk.
+    fn offset(&self, sector: u64, len: u64) -> io::Result<u64> {
+        let end = sector.checked_add(len / SECTOR_SIZE);
+        if !len.is_multiple_of(SECTOR_SIZE) || end.is_none_or(|end| end > self.disk.capacity) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(sector * SECTOR_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::virtio::QueueConfig;
+    use memory::{anonymous_memory, GuestMemory};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    const DESC: u64 = 0x1000;
+    const DRIVER: u64 = 0x2000;
+    const DEVICE: u64 = 0x3000;
+    const HEADER: u64 = 0x4000;
+    const DATA: u64 = 0x5000;
+    const STATUS: u64 = 0x8000;
+    const QUEUE_SIZE: u16 = 16;
+
+    const SECTORS: u64 = 16;
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(contents: &[u8]) -> TempFile {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "kvm-sys-block-{}-{}",
+                process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            );
+            let path = env::temp_dir().join(name);
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[derive(Default)]
+    struct Signals {
+        used: AtomicU32,
+        needs_reset: AtomicBool,
+    }
+
+    impl VirtioInterrupt for Signals {
+        fn signal_used_queue(&self, _queue: u16) {
+            self.used.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn signal_config(&self) {}
+
+        fn signal_needs_reset(&self) {
+            self.needs_reset.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// A disk of [`SECTORS`] sectors, each filled with its number.
+    fn disk() -> TempFile {
+        let contents: Vec<u8> = (0..SECTORS)
+            .flat_map(|sector| vec![sector as u8; SECTOR_SIZE as usize])
+            .collect();
+        TempFile::new(&contents)
+    }
+
+    fn queue_config() -> QueueConfig {
+        QueueConfig {
+            ready: true,
+            desc_table: DESC,
+            driver_area: DRIVER,
+            device_area: DEVICE,
+            ..QueueConfig::new(QUEUE_SIZE)
+        }
+    }
+
+    fn worker(block: &Block, memory: &GuestMemory, signals: &Arc<Signals>) -> Worker {
+        Worker {
+            disk: block.disk.clone(),
+            queue: Queue::new(memory.clone(), &queue_config(), 0).unwrap(),
+            index: 0,
+            interrupt: signals.clone(),
+            buffer: Buffer(vec![]),
+        }
+    }
+
+    fn desc(memory: &GuestMemory, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = DESC + 16 * u64::from(index);
+        memory.write_u64(desc, addr).unwrap();
+        memory.write_u32(desc + 8, len).unwrap();
+        memory.write_u16(desc + 12, flags).unwrap();
+        memory.write_u16(desc + 14, next).unwrap();
+    }
+
+    /// Makes a request available: its header, then `data.0` bytes at
+    /// [`DATA`] (writable if `data.1`), then the status.
+    fn post(memory: &GuestMemory, kind: u32, sector: u64, data: Option<(u32, bool)>) {
+        memory.write_u32(HEADER, kind).unwrap();
+        memory.write_u32(HEADER + 4, 0).unwrap();
+        memory.write_u64(HEADER + 8, sector).unwrap();
+        memory.write_u8(STATUS, 0xff).unwrap();
+
+        desc(memory, 0, HEADER, 16, 1, 1);
+        let mut next = 1;
+        if let Some((len, writable)) = data {
+            desc(memory, 1, DATA, len, 1 | (writable as u16) << 1, 2);
+            next = 2;
+        }
+        desc(memory, next, STATUS, 1, 2, 0);
+
+        let idx = memory.read_u16(DRIVER + 2).unwrap();
+        let slot = DRIVER + 4 + 2 * u64::from(idx % QUEUE_SIZE);
+        memory.write_u16(slot, 0).unwrap();
+        memory.write_u16(DRIVER + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    /// Carries out a request, returning its status and used length.
+    fn request(
+        worker: &mut Worker,
+        kind: u32,
+        sector: u64,
+        data: Option<(u32, bool)>,
+    ) -> (u8, u32) {
+        let memory = worker.queue.memory().clone();
+        post(&memory, kind, sector, data);
+        worker.process_queue().unwrap();
+        let idx = memory.read_u16(DEVICE + 2).unwrap();
+        let slot = DEVICE + 4 + 8 * u64::from(idx.wrapping_sub(1) % QUEUE_SIZE);
+        let used = memory.read_u32(slot + 4).unwrap();
+        (memory.read_u8(STATUS).unwrap(), used)
+    }
+
+    fn discard_segment(memory: &GuestMemory, index: u64, sector: u64, sectors: u32, flags: u32) {
+        let segment = DATA + DISCARD_SEGMENT_SIZE * index;
+        memory.write_u64(segment, sector).unwrap();
+        memory.write_u32(segment + 8, sectors).unwrap();
+        memory.write_u32(segment + 12, flags).unwrap();
+    }
+
+    #[test]
+    fn open_checks_config() {
+        let file = disk();
+        let open = |config: BlockConfig| Block::open(&file.0, config).map(|_| ());
+        assert!(open(BlockConfig::default()).is_ok());
+        for config in &[
+            BlockConfig {
+                queues: 0,
+                ..BlockConfig::default()
+            },
+            BlockConfig {
+                queue_size: 12,
+                ..BlockConfig::default()
+            },
+            BlockConfig {
+                block_size: 256,
+                ..BlockConfig::default()
+            },
+            BlockConfig {
+                block_size: 8192,
+                ..BlockConfig::default()
+            },
+        ] {
+            let e = open(config.clone()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn config_space() {
+        let file = disk();
+        let config = BlockConfig {
+            queues: 4,
+            queue_size: 64,
+            block_size: 4096,
+            ..BlockConfig::default()
+        };
+        let block = Block::open(&file.0, config).unwrap();
+        assert_eq!(block.capacity(), SECTORS);
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+        assert_eq!(block.queue_max_sizes(), [64; 4]);
+        let features = block.features();
+        assert_ne!(features & 1 << VIRTIO_BLK_F_MQ, 0);
+        assert_ne!(features & 1 << VIRTIO_BLK_F_DISCARD, 0);
+        assert_eq!(features & 1 << VIRTIO_BLK_F_RO, 0);
+
+        let mut config = [0; CONFIG_SIZE];
+        block.read_config(0, &mut config);
+        assert_eq!(
+            config[CONFIG_CAPACITY..CONFIG_CAPACITY + 8],
+            SECTORS.to_le_bytes()
+        );
+        assert_eq!(
+            config[CONFIG_SEG_MAX..CONFIG_SEG_MAX + 4],
+            62u32.to_le_bytes()
+        );
+        assert_eq!(
+            config[CONFIG_BLK_SIZE..CONFIG_BLK_SIZE + 4],
+            4096u32.to_le_bytes()
+        );
+        assert_eq!(
+            config[CONFIG_NUM_QUEUES..CONFIG_NUM_QUEUES + 2],
+            4u16.to_le_bytes()
+        );
+        let alignment = CONFIG_DISCARD_SECTOR_ALIGNMENT;
+        assert_eq!(config[alignment..alignment + 4], 8u32.to_le_bytes());
+
+        // reads past the end are zeroes.
+        let mut tail = [0xff; 8];
+        block.read_config(CONFIG_SIZE as u64 - 4, &mut tail);
+        assert_eq!(tail, [0; 8]);
+    }
+
+    #[test]
+    fn read_write() {
+        let file = disk();
+        let block = Block::open(&file.0, BlockConfig::default()).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        assert_eq!(
+            request(&mut worker, REQ_IN, 3, Some((1024, true))),
+            (STATUS_OK, 1025)
+        );
+        let mut data = vec![0; 1024];
+        memory.read(DATA, &mut data).unwrap();
+        assert!(data[..512].iter().all(|&b| b == 3));
+        assert!(data[512..].iter().all(|&b| b == 4));
+        assert_eq!(signals.used.load(Ordering::SeqCst), 1);
+
+        memory.write(DATA, &[0xab; 1024]).unwrap();
+        assert_eq!(
+            request(&mut worker, REQ_OUT, 8, Some((1024, false))),
+            (STATUS_OK, 1)
+        );
+        let contents = file.contents();
+        assert_eq!(contents.len() as u64, SECTORS * SECTOR_SIZE);
+        assert!(contents[8 * 512..10 * 512].iter().all(|&b| b == 0xab));
+        assert!(contents[7 * 512..8 * 512].iter().all(|&b| b == 7));
+        assert!(contents[10 * 512..11 * 512].iter().all(|&b| b == 10));
+
+        memory.zero(DATA, 1024).unwrap();
+        assert_eq!(
+            request(&mut worker, REQ_IN, 8, Some((1024, true))),
+            (STATUS_OK, 1025)
+        );
+        memory.read(DATA, &mut data).unwrap();
+        assert!(data.iter().all(|&b| b == 0xab));
+
+        assert_eq!(request(&mut worker, REQ_FLUSH, 0, None), (STATUS_OK, 1));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let file = disk();
+        let block = Block::open(&file.0, BlockConfig::default()).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        // past the end, partly past it, and not whole sectors.
+        let ioerr = (STATUS_IOERR, 1);
+        assert_eq!(
+            request(&mut worker, REQ_IN, SECTORS, Some((512, true))),
+            ioerr
+        );
+        assert_eq!(
+            request(&mut worker, REQ_IN, SECTORS - 1, Some((1024, true))),
+            ioerr
+        );
+        assert_eq!(request(&mut worker, REQ_IN, !0, Some((512, true))), ioerr);
+        assert_eq!(
+            request(&mut worker, REQ_OUT, SECTORS - 1, Some((1024, false))),
+            ioerr
+        );
+        assert_eq!(request(&mut worker, REQ_IN, 0, Some((100, true))), ioerr);
+        assert_eq!(file.contents(), disk().contents());
+
+        assert_eq!(request(&mut worker, 99, 0, None), (STATUS_UNSUPP, 1));
+    }
+
+    #[test]
+    fn get_id() {
+        let file = disk();
+        let config = BlockConfig {
+            id: "a-rather-long-disk-serial".into(),
+            ..BlockConfig::default()
+        };
+        let block = Block::open(&file.0, config).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        let len = ID_BYTES as u32;
+        assert_eq!(
+            request(&mut worker, REQ_GET_ID, 0, Some((len, true))),
+            (STATUS_OK, len + 1)
+        );
+        let mut id = [0; ID_BYTES];
+        memory.read(DATA, &mut id).unwrap();
+        assert_eq!(&id, b"a-rather-long-disk-s");
+
+        // a shorter buffer gets what fits.
+        memory.zero(DATA, 32).unwrap();
+        assert_eq!(
+            request(&mut worker, REQ_GET_ID, 0, Some((8, true))),
+            (STATUS_OK, 9)
+        );
+        memory.read(DATA, &mut id).unwrap();
+        assert_eq!(&id[..9], b"a-rather\0");
+
+        let block = Block::open(&file.0, BlockConfig::default()).unwrap();
+        let mut worker = self::worker(&block, &memory, &signals);
+        memory.write(DATA, &[0xff; ID_BYTES]).unwrap();
+        assert_eq!(
+            request(&mut worker, REQ_GET_ID, 0, Some((len, true))),
+            (STATUS_OK, len + 1)
+        );
+        memory.read(DATA, &mut id).unwrap();
+        assert_eq!(id, [0; ID_BYTES]);
+    }
+
+    #[test]
+    fn discard() {
+        let file = disk();
+        let block = Block::open(&file.0, BlockConfig::default()).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        discard_segment(&memory, 0, 2, 2, 0);
+        discard_segment(&memory, 1, 12, 1, 0);
+        assert_eq!(
+            request(&mut worker, REQ_DISCARD, 0, Some((32, false))),
+            (STATUS_OK, 1)
+        );
+        let contents = file.contents();
+        assert_eq!(contents.len() as u64, SECTORS * SECTOR_SIZE);
+        for sector in 0..SECTORS as usize {
+            let discarded = sector == 2 || sector == 3 || sector == 12;
+            let expected = if discarded { 0 } else { sector as u8 };
+            let data = &contents[sector * 512..(sector + 1) * 512];
+            assert!(data.iter().all(|&b| b == expected), "sector {}", sector);
+        }
+
+        // the unmap flag is for write zeroes, and segments are whole.
+        discard_segment(&memory, 0, 4, 1, DISCARD_F_UNMAP);
+        let unsupp = (STATUS_UNSUPP, 1);
+        assert_eq!(
+            request(&mut worker, REQ_DISCARD, 0, Some((16, false))),
+            unsupp
+        );
+        assert_eq!(
+            request(&mut worker, REQ_DISCARD, 0, Some((24, false))),
+            unsupp
+        );
+        assert_eq!(request(&mut worker, REQ_DISCARD, 0, None), unsupp);
+        discard_segment(&memory, 0, SECTORS - 1, 2, 0);
+        let ioerr = (STATUS_IOERR, 1);
+        assert_eq!(
+            request(&mut worker, REQ_DISCARD, 0, Some((16, false))),
+            ioerr
+        );
+        let contents = file.contents();
+        assert!(contents[4 * 512..5 * 512].iter().all(|&b| b == 4));
+        assert!(contents[15 * 512..].iter().all(|&b| b == 15));
+    }
+
+    #[test]
+    fn read_only() {
+        let file = disk();
+        let config = BlockConfig {
+            read_only: true,
+            ..BlockConfig::default()
+        };
+        let block = Block::open(&file.0, config).unwrap();
+        assert_ne!(block.features() & 1 << VIRTIO_BLK_F_RO, 0);
+        assert_eq!(block.features() & 1 << VIRTIO_BLK_F_DISCARD, 0);
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        memory.write(DATA, &[0xab; 512]).unwrap();
+        let ioerr = (STATUS_IOERR, 1);
+        assert_eq!(request(&mut worker, REQ_OUT, 0, Some((512, false))), ioerr);
+        discard_segment(&memory, 0, 0, 1, 0);
+        assert_eq!(
+            request(&mut worker, REQ_DISCARD, 0, Some((16, false))),
+            ioerr
+        );
+        assert_eq!(file.contents(), disk().contents());
+
+        assert_eq!(
+            request(&mut worker, REQ_IN, 5, Some((512, true))),
+            (STATUS_OK, 513)
+        );
+        assert_eq!(memory.read_u8(DATA + 511).unwrap(), 5);
+        assert_eq!(request(&mut worker, REQ_FLUSH, 0, None), (STATUS_OK, 1));
+    }
+
+    #[test]
+    fn without_status() {
+        let file = disk();
+        let block = Block::open(&file.0, BlockConfig::default()).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        // a request with nowhere to put the status is used, but not
+        // carried out.
+        post(&memory, REQ_OUT, 0, Some((512, false)));
+        desc(&memory, 1, DATA, 512, 0, 0);
+        worker.process_queue().unwrap();
+        assert_eq!(memory.read_u16(DEVICE + 2).unwrap(), 1);
+        assert_eq!(memory.read_u32(DEVICE + 8).unwrap(), 0);
+        assert_eq!(file.contents(), disk().contents());
+    }
+
+    #[test]
+    fn broken_queue_needs_reset() {
+        let file = disk();
+        let block = Block::open(&file.0, BlockConfig::default()).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let mut worker = worker(&block, &memory, &signals);
+
+        // more requests than the queue has room for.
+        memory.write_u16(DRIVER + 2, QUEUE_SIZE + 1).unwrap();
+        let event = eventfd(1, EfdFlags::EFD_NONBLOCK).unwrap();
+        let stop = eventfd(0, EfdFlags::EFD_NONBLOCK).unwrap();
+        worker.run(event, stop);
+        assert!(signals.needs_reset.load(Ordering::SeqCst));
+        let _ = unistd::close(event);
+        let _ = unistd::close(stop);
+    }
+
+    #[test]
+    fn activate() {
+        let file = disk();
+        let config = BlockConfig {
+            queues: 2,
+            queue_size: QUEUE_SIZE,
+            ..BlockConfig::default()
+        };
+        let mut block = Block::open(&file.0, config).unwrap();
+        let memory = anonymous_memory(0x10000);
+        let signals = Arc::new(Signals::default());
+        let events: Vec<_> = (0..2)
+            .map(|_| eventfd(0, EfdFlags::EFD_NONBLOCK).unwrap())
+            .collect();
+        // only the first queue is enabled.
+        block
+            .activate(Activation {
+                memory: memory.clone(),
+                features: 0,
+                queues: vec![queue_config(), QueueConfig::new(QUEUE_SIZE)],
+                queue_events: events.clone(),
+                interrupt: signals.clone(),
+            })
+            .unwrap();
+        assert!(block.is_active());
+
+        post(&memory, REQ_IN, 6, Some((512, true)));
+        unistd::write(events[0], &1u64.to_ne_bytes()).unwrap();
+        let start = Instant::now();
+        while memory.read_u16(DEVICE + 2).unwrap() != 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(memory.read_u8(STATUS).unwrap(), STATUS_OK);
+        assert_eq!(memory.read_u8(DATA).unwrap(), 6);
+
+        block.reset();
+        assert!(!block.is_active());
+        assert!(!signals.needs_reset.load(Ordering::SeqCst));
+        for fd in events {
+            let _ = unistd::close(fd);
+        }
+    }
+}
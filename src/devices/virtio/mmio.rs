@@ -0,0 +1,818 @@
+//! The virtio-mmio transport, version 2 (the virtio 1.x layout).
+//!
+//! An [`MmioTransport`] is registered on the bus over [`MMIO_SIZE`] bytes
+//! of MMIO.  The guest learns where it is from the kernel command line (see
+//! [`cmdline_param`]), or the firmware.  Each queue of the device has an
+//! eventfd that is signalled when the driver notifies the queue.  With
+//! [`MmioTransport::register_ioeventfds`] the notifications go straight to
+//! those eventfds from KVM; otherwise the transport signals them when it
+//! handles the write.  The interrupt is an [`Interrupt`], which
+//! [`MmioTransport::register_irqfd`] makes an irqfd; it is raised when the
+//! interrupt status has bits set, and lowered when the driver acknowledges
+//! all of them.
+
+use super::{
+    Activation, QueueConfig, VirtioDevice, VirtioInterrupt, STATUS_DEVICE_NEEDS_RESET,
+    STATUS_DRIVER_OK, STATUS_FAILED, STATUS_FEATURES_OK, VIRTIO_F_VERSION_1,
+};
+use consts::{KVM_IOEVENTFD_FLAG_DATAMATCH, KVM_IOEVENTFD_FLAG_DEASSIGN, KVM_IRQFD_FLAG_DEASSIGN};
+use ctl::{kvm_ioeventfd, kvm_irqfd, IoEventFd, IrqFd};
+use devices::{BusDevice, Interrupt, IrqEvent};
+use memory::GuestMemory;
+use nix;
+use nix::errno::Errno;
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::unistd;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The size of the registers and configuration space of a device.
+pub const MMIO_SIZE: u64 = 0x200;
+
+/// Set in the interrupt status when the device used buffers.
+pub const INTERRUPT_VRING: u32 = 1 << 0;
+/// Set in the interrupt status when the configuration space changed.
+pub const INTERRUPT_CONFIG: u32 = 1 << 1;
+
+// "virt"
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+// "QEMU"
+const VENDOR_ID: u32 = 0x554d_4551;
+
+// register offsets.
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION_REG: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID_REG: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const SHM_LEN_LOW: u64 = 0x0b0;
+const SHM_LEN_HIGH: u64 = 0x0b4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+/// The `virtio_mmio.device` kernel parameter that tells Linux about a
+/// device at `base`, interrupting on `irq`.
+pub fn cmdline_param(base: u64, irq: u32) -> String {
+    format!("virtio_mmio.device={}@{:#x}:{}", MMIO_SIZE, base, irq)
+}
+
+/// The interrupt status, shared with the device.
+struct MmioInterrupt {
+    status: AtomicU32,
+    generation: AtomicU32,
+    needs_reset: AtomicBool,
+    // the status changes with the line held, so that the line follows it.
+    irq: Mutex<Box<dyn Interrupt>>,
+}
+
+impl MmioInterrupt {
+    fn raise(&self, bits: u32) {
+        let mut irq = self.irq.lock().unwrap_or_else(|e| e.into_inner());
+        self.status.fetch_or(bits, Ordering::SeqCst);
+        irq.set_level(true);
+    }
+
+    fn ack(&self, bits: u32) {
+        let mut irq = self.irq.lock().unwrap_or_else(|e| e.into_inner());
+        if self.status.fetch_and(!bits, Ordering::SeqCst) & !bits == 0 {
+            irq.set_level(false);
+        }
+    }
+}
+
+impl VirtioInterrupt for MmioInterrupt {
+    fn signal_used_queue(&self, _queue: u16) {
+        self.raise(INTERRUPT_VRING);
+    }
+
+    fn signal_config(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.raise(INTERRUPT_CONFIG);
+    }
+
+    fn signal_needs_reset(&self) {
+        if !self.needs_reset.swap(true, Ordering::SeqCst) {
+            self.signal_config();
+        }
+    }
+}
+
+/// A virtio device behind the MMIO transport.
+pub struct MmioTransport {
+    device: Box<dyn VirtioDevice>,
+    memory: GuestMemory,
+    interrupt: Arc<MmioInterrupt>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<QueueConfig>,
+    queue_events: Vec<RawFd>,
+    status: u32,
+    active: bool,
+    // the VM and base address the ioeventfds are registered with.
+    ioeventfds: Option<(RawFd, u64)>,
+    // the VM, GSI, and eventfd of the irqfd.
+    irqfd: Option<(RawFd, u32, RawFd)>,
+}
+
+impl MmioTransport {
+    /// Puts `device` behind the transport, with its queues in `memory`,
+    /// and its interrupt on `irq`.  Fails if the eventfds for the queues
+    /// can't be created.
+    pub fn new(
+        device: Box<dyn VirtioDevice>,
+        memory: GuestMemory,
+        irq: Box<dyn Interrupt>,
+    ) -> nix::Result<MmioTransport> {
+        let queues: Vec<_> = device
+            .queue_max_sizes()
+            .into_iter()
+            .map(QueueConfig::new)
+            .collect();
+        let mut queue_events = vec![];
+        for _ in 0..queues.len() {
+            match eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC) {
+                Ok(fd) => queue_events.push(fd),
+                Err(e) => {
+                    for fd in queue_events {
+                        let _ = unistd::close(fd);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(MmioTransport {
+            device,
+            memory,
+            interrupt: Arc::new(MmioInterrupt {
+                status: AtomicU32::new(0),
+                generation: AtomicU32::new(0),
+                needs_reset: AtomicBool::new(false),
+                irq: Mutex::new(irq),
+            }),
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+            queue_events,
+            status: 0,
+            active: false,
+            ioeventfds: None,
+            irqfd: None,
+        })
+    }
+
+    /// The device status, as the driver set it, and with
+    /// `STATUS_DEVICE_NEEDS_RESET` once the device asked for a reset.
+    pub fn status(&self) -> u32 {
+        if self.interrupt.needs_reset.load(Ordering::SeqCst) {
+            self.status | STATUS_DEVICE_NEEDS_RESET
+        } else {
+            self.status
+        }
+    }
+
+    /// Whether the device is activated.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The eventfd of each queue.
+    pub fn queue_events(&self) -> &[RawFd] {
+        &self.queue_events
+    }
+
+    /// Registers the eventfd of each queue as an ioeventfd, matching the
+    /// queue's index written to the queue notify register of the device at
+    /// `base`, so that notifications don't exit to userspace.  They are
+    /// unregistered when the transport is dropped.  Fails with `EEXIST` if
+    /// they are already registered.
+    ///
+    /// # Safety
+    /// `vm` must be the file descriptor of the VM the transport is on, and
+    /// must stay open for as long as the transport exists, since dropping
+    /// it unregisters them.
+    ///
+    /// # Support
+    /// See [`kvm_ioeventfd`](../../../fn.kvm_ioeventfd.html).  `vm` is the VM
+    /// file descriptor.
+    pub unsafe fn register_ioeventfds(&mut self, vm: RawFd, base: u64) -> nix::Result<()> {
+        if self.ioeventfds.is_some() {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        for (index, &fd) in self.queue_events.iter().enumerate() {
+            let result = kvm_ioeventfd(vm, &notify_ioeventfd(base, index, fd, 0));
+            if let Err(e) = result {
+                for (index, &fd) in self.queue_events[..index].iter().enumerate() {
+                    let deassign = notify_ioeventfd(base, index, fd, KVM_IOEVENTFD_FLAG_DEASSIGN);
+                    let _ = kvm_ioeventfd(vm, &deassign);
+                }
+                return Err(e);
+            }
+        }
+
+        self.ioeventfds = Some((vm, base));
+        Ok(())
+    }
+
+    /// Replaces the interrupt with an irqfd for `gsi`, whose eventfd the
+    /// transport owns.  It is unregistered when the transport is dropped.
+    /// Fails with `EEXIST` if there already is one.
+    ///
+    /// # Safety
+    /// `vm` must be the file descriptor of the VM the transport is on, and
+    /// must stay open for as long as the transport exists, since dropping
+    /// it unregisters the irqfd.
+    ///
+    /// # Support
+    /// See [`kvm_irqfd`](../../../fn.kvm_irqfd.html).  `vm` is the VM file
+    /// descriptor.
+    pub unsafe fn register_irqfd(&mut self, vm: RawFd, gsi: u32) -> nix::Result<()> {
+        if self.irqfd.is_some() {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        let fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?;
+        if let Err(e) = kvm_irqfd(vm, &irqfd(fd, gsi, 0)) {
+            let _ = unistd::close(fd);
+            return Err(e);
+        }
+
+        *self.interrupt.irq.lock().unwrap_or_else(|e| e.into_inner()) = Box::new(IrqEvent::new(fd));
+        self.irqfd = Some((vm, gsi, fd));
+        Ok(())
+    }
+
+    /// The features the device offers through the transport.
+    fn device_features(&self) -> u64 {
+        self.device.features() | 1 << VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&self) -> Option<&QueueConfig> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    fn queue_mut(&mut self) -> Option<&mut QueueConfig> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_type(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.queue().map_or(0, |q| u32::from(q.max_size)),
+            QUEUE_READY => self.queue().map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt.status.load(Ordering::SeqCst),
+            STATUS => self.status(),
+            // there are no shared memory regions.
+            SHM_LEN_LOW | SHM_LEN_HIGH => !0,
+            CONFIG_GENERATION => self.interrupt.generation.load(Ordering::SeqCst),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        let set_low = |v: &mut u64| *v = *v & !0xffff_ffff | u64::from(value);
+        let set_high = |v: &mut u64| *v = *v & 0xffff_ffff | u64::from(value) << 32;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            // the features are set once the device accepts them.
+            DRIVER_FEATURES if self.status & STATUS_FEATURES_OK != 0 => {}
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features),
+                1 => set_high(&mut self.driver_features),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NOTIFY => self.notify(value),
+            INTERRUPT_ACK => self.interrupt.ack(value),
+            STATUS => self.set_status(value),
+            // the queues can't change under an active device.
+            _ if self.active => debug!("write of {:#x} to {:#x} while active", value, offset),
+            QUEUE_NUM if value > u32::from(u16::MAX) => {
+                debug!("virtio queue size {:#x} is too big", value)
+            }
+            QUEUE_NUM => {
+                if let Some(q) = self.queue_mut() {
+                    q.size = value as u16;
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.queue_mut() {
+                    q.ready = value == 1;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.queue_mut() {
+                    let address = match offset {
+                        QUEUE_DESC_LOW | QUEUE_DESC_HIGH => &mut q.desc_table,
+                        QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => &mut q.driver_area,
+                        _ => &mut q.device_area,
+                    };
+                    if offset & 0x4 == 0 {
+                        set_low(address);
+                    } else {
+                        set_high(address);
+                    }
+                }
+            }
+            _ => debug!(
+                "write of {:#x} to virtio-mmio register {:#x}",
+                value, offset
+            ),
+        }
+    }
+
+    fn notify(&mut self, queue: u32) {
+        match self.queue_events.get(queue as usize) {
+            Some(&fd) => {
+                if let Err(e) = unistd::write(fd, &1u64.to_ne_bytes()) {
+                    warn!("couldn't notify virtio queue {}: {}", queue, e);
+                }
+            }
+            None => debug!("notify of missing virtio queue {}", queue),
+        }
+    }
+
+    fn set_status(&mut self, value: u32) {
+        if value == 0 {
+            self.reset();
+            return;
+        }
+
+        // the device sets DEVICE_NEEDS_RESET, not the driver.
+        let mut status = value & !STATUS_DEVICE_NEEDS_RESET;
+        // the device doesn't accept features it doesn't offer, and without
+        // VIRTIO_F_VERSION_1 the driver expects the legacy layout.
+        let features_ok = self.driver_features & !self.device_features() == 0
+            && self.driver_features & 1 << VIRTIO_F_VERSION_1 != 0;
+        if status & !self.status & STATUS_FEATURES_OK != 0 && !features_ok {
+            status &= !STATUS_FEATURES_OK;
+        }
+        self.status = status;
+
+        let live = STATUS_DRIVER_OK | STATUS_FEATURES_OK;
+        if !self.active && status & live == live && status & STATUS_FAILED == 0 {
+            self.activate();
+        }
+    }
+
+    fn activate(&mut self) {
+        let valid = self
+            .queues
+            .iter()
+            .all(|q| !q.ready || (q.size != 0 && q.size <= q.max_size));
+        let result = if valid {
+            self.device.activate(Activation {
+                memory: self.memory.clone(),
+                features: self.driver_features,
+                queues: self.queues.clone(),
+                queue_events: self.queue_events.clone(),
+                interrupt: self.interrupt.clone(),
+            })
+        } else {
+            Err(nix::Error::Sys(Errno::EINVAL))
+        };
+
+        match result {
+            Ok(()) => self.active = true,
+            Err(e) => {
+                warn!("couldn't activate virtio device: {}", e);
+                self.interrupt.signal_needs_reset();
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.active {
+            self.device.reset();
+            self.active = false;
+        }
+
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        for q in &mut self.queues {
+            *q = QueueConfig::new(q.max_size);
+        }
+        self.status = 0;
+        self.interrupt.needs_reset.store(false, Ordering::SeqCst);
+        self.interrupt.ack(!0);
+        // notifications from before the reset mean nothing now.
+        for &fd in &self.queue_events {
+            let _ = unistd::read(fd, &mut [0; 8]);
+        }
+    }
+}
+
+impl BusDevice for MmioTransport {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= CONFIG {
+            self.device.read_config(offset - CONFIG, data);
+            return;
+        }
+
+        // the registers only take aligned 32 bit accesses.
+        if data.len() == 4 && offset.is_multiple_of(4) {
+            data.copy_from_slice(&self.read_register(offset).to_le_bytes());
+        } else {
+            debug!("bad virtio-mmio read of {} at {:#x}", data.len(), offset);
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if offset >= CONFIG {
+            self.device.write_config(offset - CONFIG, data);
+            return;
+        }
+
+        if data.len() == 4 && offset.is_multiple_of(4) {
+            let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            self.write_register(offset, value);
+        } else {
+            debug!("bad virtio-mmio write of {:?} at {:#x}", data, offset);
+        }
+    }
+}
+
+impl Drop for MmioTransport {
+    fn drop(&mut self) {
+        if self.active {
+            self.device.reset();
+        }
+
+        if let Some((vm, base)) = self.ioeventfds {
+            for (index, &fd) in self.queue_events.iter().enumerate() {
+                let deassign = notify_ioeventfd(base, index, fd, KVM_IOEVENTFD_FLAG_DEASSIGN);
+                let _ = unsafe { kvm_ioeventfd(vm, &deassign) };
+            }
+        }
+        if let Some((vm, gsi, fd)) = self.irqfd {
+            let _ = unsafe { kvm_irqfd(vm, &irqfd(fd, gsi, KVM_IRQFD_FLAG_DEASSIGN)) };
+            let _ = unistd::close(fd);
+        }
+        for &fd in &self.queue_events {
+            let _ = unistd::close(fd);
+        }
+    }
+}
+
+/// The ioeventfd for notifications of queue `index`.
+fn notify_ioeventfd(base: u64, index: usize, fd: RawFd, flags: u32) -> IoEventFd {
+    IoEventFd {
+        datamatch: index as u64,
+        addr: base + QUEUE_NOTIFY,
+        len: 4,
+        fd,
+        flags: KVM_IOEVENTFD_FLAG_DATAMATCH | flags,
+        _pad: [0; 36],
+    }
+}
+
+fn irqfd(fd: RawFd, gsi: u32, flags: u32) -> IrqFd {
+    IrqFd {
+        fd,
+        gsi,
+        flags,
+        resampled: 0,
+        _pad: [0; 16],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::virtio::{STATUS_ACKNOWLEDGE, STATUS_DRIVER, TYPE_BLOCK};
+    use memory::anonymous_memory;
+
+    const FEATURES: u64 = 1 << 5 | 1 << 9;
+    const ALL: u32 = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK;
+
+    /// What the device went through.
+    #[derive(Default)]
+    struct Log {
+        activations: Vec<(u64, Vec<QueueConfig>)>,
+        interrupt: Option<Arc<dyn VirtioInterrupt>>,
+        resets: usize,
+    }
+
+    struct TestDevice(Arc<Mutex<Log>>);
+
+    impl VirtioDevice for TestDevice {
+        fn device_type(&self) -> u32 {
+            TYPE_BLOCK
+        }
+
+        fn queue_max_sizes(&self) -> Vec<u16> {
+            vec![8, 16]
+        }
+
+        fn features(&self) -> u64 {
+            FEATURES
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b = offset as u8 + i as u8;
+            }
+        }
+
+        fn activate(&mut self, activation: Activation) -> nix::Result<()> {
+            let mut log = self.0.lock().unwrap();
+            log.activations
+                .push((activation.features, activation.queues));
+            log.interrupt = Some(activation.interrupt);
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            let mut log = self.0.lock().unwrap();
+            log.resets += 1;
+            log.interrupt = None;
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Levels(Arc<Mutex<Vec<bool>>>);
+
+    impl Interrupt for Levels {
+        fn set_level(&mut self, level: bool) {
+            self.0.lock().unwrap().push(level);
+        }
+    }
+
+    fn transport() -> (MmioTransport, Arc<Mutex<Log>>, Levels) {
+        let (log, levels) = (Arc::new(Mutex::new(Log::default())), Levels::default());
+        let transport = MmioTransport::new(
+            Box::new(TestDevice(log.clone())),
+            anonymous_memory(0x1000),
+            Box::new(levels.clone()),
+        )
+        .unwrap();
+        (transport, log, levels)
+    }
+
+    fn read(transport: &mut MmioTransport, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        transport.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write(transport: &mut MmioTransport, offset: u64, value: u32) {
+        transport.write(0, offset, &value.to_le_bytes());
+    }
+
+    fn set_features(transport: &mut MmioTransport, features: u64) {
+        write(transport, DRIVER_FEATURES_SEL, 0);
+        write(transport, DRIVER_FEATURES, features as u32);
+        write(transport, DRIVER_FEATURES_SEL, 1);
+        write(transport, DRIVER_FEATURES, (features >> 32) as u32);
+        write(transport, STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        write(transport, STATUS, ALL & !STATUS_DRIVER_OK);
+    }
+
+    /// Activates the device, with queue 0 of 4 entries.
+    fn activate(transport: &mut MmioTransport) {
+        set_features(transport, FEATURES | 1 << VIRTIO_F_VERSION_1);
+        write(transport, QUEUE_SEL, 0);
+        write(transport, QUEUE_NUM, 4);
+        write(transport, QUEUE_DESC_LOW, 0x1000);
+        write(transport, QUEUE_DESC_HIGH, 1);
+        write(transport, QUEUE_DRIVER_LOW, 0x2000);
+        write(transport, QUEUE_DEVICE_LOW, 0x3000);
+        write(transport, QUEUE_READY, 1);
+        write(transport, STATUS, ALL);
+    }
+
+    #[test]
+    fn registers() {
+        let (mut transport, _, _) = transport();
+        assert_eq!(read(&mut transport, MAGIC_VALUE), MAGIC);
+        assert_eq!(read(&mut transport, VERSION_REG), 2);
+        assert_eq!(read(&mut transport, DEVICE_ID), TYPE_BLOCK);
+        assert_eq!(read(&mut transport, VENDOR_ID_REG), VENDOR_ID);
+
+        assert_eq!(read(&mut transport, DEVICE_FEATURES), FEATURES as u32);
+        write(&mut transport, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(read(&mut transport, DEVICE_FEATURES), 1);
+        write(&mut transport, DEVICE_FEATURES_SEL, 2);
+        assert_eq!(read(&mut transport, DEVICE_FEATURES), 0);
+
+        for &(queue, max) in &[(0, 8), (1, 16), (2, 0)] {
+            write(&mut transport, QUEUE_SEL, queue);
+            assert_eq!(read(&mut transport, QUEUE_NUM_MAX), max);
+        }
+
+        // the registers only take aligned 32 bit accesses.
+        let mut data = [0xff; 2];
+        transport.read(0, MAGIC_VALUE, &mut data);
+        assert_eq!(data, [0, 0]);
+        let mut config = [0; 3];
+        transport.read(0, CONFIG + 4, &mut config);
+        assert_eq!(config, [4, 5, 6]);
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let (mut transport, log, _) = transport();
+
+        // without VERSION_1, the driver wants the legacy layout.
+        set_features(&mut transport, FEATURES);
+        assert_eq!(
+            read(&mut transport, STATUS),
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER
+        );
+        write(&mut transport, STATUS, 0);
+
+        // nor are features the device doesn't offer accepted.
+        set_features(&mut transport, 1 << 6 | 1 << VIRTIO_F_VERSION_1);
+        assert_eq!(
+            read(&mut transport, STATUS),
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER
+        );
+        write(&mut transport, STATUS, ALL);
+        assert!(!transport.is_active());
+        write(&mut transport, STATUS, 0);
+
+        set_features(&mut transport, 1 << 5 | 1 << VIRTIO_F_VERSION_1);
+        assert_eq!(read(&mut transport, STATUS), ALL & !STATUS_DRIVER_OK);
+        // once accepted, the features stay put.
+        write(&mut transport, DRIVER_FEATURES_SEL, 0);
+        write(&mut transport, DRIVER_FEATURES, !0);
+        write(&mut transport, STATUS, ALL);
+        assert!(transport.is_active());
+        let features = log.lock().unwrap().activations[0].0;
+        assert_eq!(features, 1 << 5 | 1 << VIRTIO_F_VERSION_1);
+    }
+
+    #[test]
+    fn queues() {
+        let (mut transport, log, _) = transport();
+        write(&mut transport, QUEUE_SEL, 1);
+        write(&mut transport, QUEUE_NUM, 0x1_0004);
+        write(&mut transport, QUEUE_SEL, 3);
+        write(&mut transport, QUEUE_READY, 1);
+        activate(&mut transport);
+        assert!(transport.is_active());
+        assert_eq!(read(&mut transport, QUEUE_READY), 1);
+
+        let queues = log.lock().unwrap().activations[0].1.clone();
+        assert_eq!(
+            queues,
+            vec![
+                QueueConfig {
+                    max_size: 8,
+                    size: 4,
+                    ready: true,
+                    desc_table: 0x1_0000_1000,
+                    driver_area: 0x2000,
+                    device_area: 0x3000,
+                },
+                // a size that doesn't fit the register is dropped.
+                QueueConfig::new(16),
+            ]
+        );
+
+        // the queues can't change under an active device.
+        write(&mut transport, QUEUE_READY, 0);
+        write(&mut transport, QUEUE_NUM, 2);
+        write(&mut transport, QUEUE_DESC_LOW, 0x5000);
+        assert_eq!(read(&mut transport, QUEUE_READY), 1);
+        assert_eq!(transport.queues[0], queues[0]);
+
+        // but the driver can still notify them.
+        write(&mut transport, QUEUE_NOTIFY, 1);
+        let mut count = [0; 8];
+        assert_eq!(unistd::read(transport.queue_events()[1], &mut count), Ok(8));
+        assert_eq!(u64::from_ne_bytes(count), 1);
+        assert!(unistd::read(transport.queue_events()[0], &mut count).is_err());
+    }
+
+    #[test]
+    fn reset() {
+        let (mut transport, log, levels) = transport();
+        activate(&mut transport);
+        write(&mut transport, QUEUE_NOTIFY, 0);
+        let interrupt = log.lock().unwrap().interrupt.clone().unwrap();
+        interrupt.signal_used_queue(0);
+        assert_eq!(*levels.0.lock().unwrap(), vec![true]);
+
+        write(&mut transport, STATUS, 0);
+        assert!(!transport.is_active());
+        assert_eq!(log.lock().unwrap().resets, 1);
+        assert_eq!(read(&mut transport, STATUS), 0);
+        assert_eq!(read(&mut transport, INTERRUPT_STATUS), 0);
+        assert_eq!(*levels.0.lock().unwrap(), vec![true, false]);
+        assert_eq!(transport.driver_features, 0);
+        assert_eq!(
+            transport.queues,
+            vec![QueueConfig::new(8), QueueConfig::new(16)]
+        );
+        assert!(unistd::read(transport.queue_events()[0], &mut [0; 8]).is_err());
+
+        // the device may be activated again.
+        activate(&mut transport);
+        assert!(transport.is_active());
+        assert_eq!(log.lock().unwrap().activations.len(), 2);
+    }
+
+    #[test]
+    fn interrupts() {
+        let (mut transport, log, levels) = transport();
+        activate(&mut transport);
+        let interrupt = log.lock().unwrap().interrupt.clone().unwrap();
+
+        interrupt.signal_used_queue(0);
+        interrupt.signal_config();
+        assert_eq!(
+            read(&mut transport, INTERRUPT_STATUS),
+            INTERRUPT_VRING | INTERRUPT_CONFIG
+        );
+        assert_eq!(read(&mut transport, CONFIG_GENERATION), 1);
+
+        // the line stays up until every bit is acknowledged.
+        write(&mut transport, INTERRUPT_ACK, INTERRUPT_VRING);
+        assert_eq!(read(&mut transport, INTERRUPT_STATUS), INTERRUPT_CONFIG);
+        assert_eq!(*levels.0.lock().unwrap(), vec![true, true]);
+        write(&mut transport, INTERRUPT_ACK, INTERRUPT_CONFIG);
+        assert_eq!(read(&mut transport, INTERRUPT_STATUS), 0);
+        assert_eq!(*levels.0.lock().unwrap(), vec![true, true, false]);
+    }
+
+    #[test]
+    fn needs_reset() {
+        let (mut transport, log, levels) = transport();
+        // the driver can't set DEVICE_NEEDS_RESET.
+        write(
+            &mut transport,
+            STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DEVICE_NEEDS_RESET,
+        );
+        assert_eq!(read(&mut transport, STATUS), STATUS_ACKNOWLEDGE);
+        write(&mut transport, STATUS, 0);
+        levels.0.lock().unwrap().clear();
+
+        activate(&mut transport);
+        let interrupt = log.lock().unwrap().interrupt.clone().unwrap();
+        interrupt.signal_needs_reset();
+        interrupt.signal_needs_reset();
+        assert_eq!(
+            read(&mut transport, STATUS),
+            ALL | STATUS_DEVICE_NEEDS_RESET
+        );
+        assert_eq!(read(&mut transport, INTERRUPT_STATUS), INTERRUPT_CONFIG);
+        assert_eq!(read(&mut transport, CONFIG_GENERATION), 1);
+        assert_eq!(*levels.0.lock().unwrap(), vec![true]);
+
+        write(&mut transport, STATUS, 0);
+        assert_eq!(read(&mut transport, STATUS), 0);
+
+        // a queue bigger than the device allows fails the activation.
+        set_features(&mut transport, FEATURES | 1 << VIRTIO_F_VERSION_1);
+        write(&mut transport, QUEUE_NUM, 9);
+        write(&mut transport, QUEUE_READY, 1);
+        write(&mut transport, STATUS, ALL);
+        assert!(!transport.is_active());
+        assert_eq!(
+            read(&mut transport, STATUS),
+            ALL | STATUS_DEVICE_NEEDS_RESET
+        );
+        assert_eq!(log.lock().unwrap().activations.len(), 1);
+    }
+}
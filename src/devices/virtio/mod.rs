@@ -0,0 +1,144 @@
+//! Virtio devices, and the transports that put them in front of the guest.
+//!
+//! A device implements [`VirtioDevice`]: it offers features, has a
+//! configuration space, and, once the driver has set it up, is activated
+//! with the queues the driver configured.  From then on it runs on its own,
+//! usually on a thread of its own: it learns that the driver put buffers on
+//! a queue from the queue's eventfd, takes them off it with a
+//! [`queue::Queue`], and tells the driver it used them through a
+//! [`VirtioInterrupt`].  The transport (for now only
+//! [`mmio::MmioTransport`]) handles everything up to the activation, and
+//! resets.
+
+use memory::GuestMemory;
+use nix;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+pub mod block;
+pub mod mmio;
+pub mod queue;
+
+/// The driver found the device.
+pub const STATUS_ACKNOWLEDGE: u32 = 1 << 0;
+/// The driver knows how to drive the device.
+pub const STATUS_DRIVER: u32 = 1 << 1;
+/// The driver is set up, and the device is live.
+pub const STATUS_DRIVER_OK: u32 = 1 << 2;
+/// The driver and device agreed on the features.
+pub const STATUS_FEATURES_OK: u32 = 1 << 3;
+/// The device ran into an error it can only recover from with a reset.
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 1 << 6;
+/// The driver gave up on the device.
+pub const STATUS_FAILED: u32 = 1 << 7;
+
+/// Descriptors may point to indirect descriptor tables.
+pub const VIRTIO_F_RING_INDIRECT_DESC: u32 = 28;
+/// The rings carry the event indexes that suppress notifications and
+/// interrupts.
+pub const VIRTIO_F_RING_EVENT_IDX: u32 = 29;
+/// The device is a virtio 1.0 (or later) device; the modern transports
+/// require it.
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+/// The device is behind an IOMMU, or some other address translation.
+pub const VIRTIO_F_ACCESS_PLATFORM: u32 = 33;
+/// The queues are packed rather than split.
+pub const VIRTIO_F_RING_PACKED: u32 = 34;
+/// Buffers are used in the order they were made available.
+pub const VIRTIO_F_IN_ORDER: u32 = 35;
+
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_CONSOLE: u32 = 3;
+pub const TYPE_RNG: u32 = 4;
+
+/// A queue, as the driver configured it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct QueueConfig {
+    /// The most entries the device allows.
+    pub max_size: u16,
+    /// The number of entries.
+    pub size: u16,
+    /// Whether the driver enabled the queue.
+    pub ready: bool,
+    /// The guest physical address of the descriptor table (or ring, for a
+    /// packed queue).
+    pub desc_table: u64,
+    /// The guest physical address of the driver area: the available ring,
+    /// or the driver's event suppression structure of a packed queue.
+    pub driver_area: u64,
+    /// The guest physical address of the device area: the used ring, or
+    /// the device's event suppression structure of a packed queue.
+    pub device_area: u64,
+}
+
+impl QueueConfig {
+    /// A queue with up to `max_size` entries, which is how big it is until
+    /// the driver makes it smaller.
+    pub fn new(max_size: u16) -> QueueConfig {
+        QueueConfig {
+            max_size,
+            size: max_size,
+            ..QueueConfig::default()
+        }
+    }
+}
+
+/// How a device interrupts the driver.
+pub trait VirtioInterrupt: Send + Sync {
+    /// Tells the driver the device used buffers from queue `queue`.
+    fn signal_used_queue(&self, queue: u16);
+
+    /// Tells the driver the configuration space changed.
+    fn signal_config(&self);
+
+    /// Tells the driver the device ran into an error it can only recover
+    /// from with a reset, such as a malformed queue: the transport sets
+    /// [`STATUS_DEVICE_NEEDS_RESET`] in the device status until the next
+    /// reset, and signals a configuration change.
+    fn signal_needs_reset(&self);
+}
+
+/// What a device is activated with.
+pub struct Activation {
+    /// The guest's memory, which the queues are in.
+    pub memory: GuestMemory,
+    /// The features the driver accepted.
+    pub features: u64,
+    /// Each of the device's queues, whether or not the driver enabled it.
+    pub queues: Vec<QueueConfig>,
+    /// An eventfd for each queue, signalled when the driver notifies the
+    /// device of new buffers on it.  The transport owns them, and keeps
+    /// them open for as long as it lives.
+    pub queue_events: Vec<RawFd>,
+    pub interrupt: Arc<dyn VirtioInterrupt>,
+}
+
+/// A virtio device.
+pub trait VirtioDevice: Send {
+    /// The device type, one of the `TYPE_*` constants.
+    fn device_type(&self) -> u32;
+
+    /// The most entries each of the device's queues allows; there are as
+    /// many queues as entries.
+    fn queue_max_sizes(&self) -> Vec<u16>;
+
+    /// The features the device offers, as bits numbered the way the `VIRTIO_F_*`
+    /// constants are.  The transport offers [`VIRTIO_F_VERSION_1`] whether
+    /// or not this does.
+    fn features(&self) -> u64;
+
+    /// Reads `data.len()` bytes of the configuration space at `offset`.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    /// Writes `data` to the configuration space at `offset`.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Starts the device, once the driver set it up.  The device is marked
+    /// as needing a reset if this fails.
+    fn activate(&mut self, activation: Activation) -> nix::Result<()>;
+
+    /// Stops the device, and forgets what it was activated with; it may be
+    /// activated again.
+    fn reset(&mut self);
+}
@@ -0,0 +1,1293 @@
+//! Virtqueues: the rings in guest memory that the driver makes buffers
+//! available on, and the device hands them back on once it used them.
+//!
+//! A [`Queue`] is built from a [`QueueConfig`] a device was activated with,
+//! and is split or packed as the negotiated features say.  Nothing in the
+//! rings is trusted.  Every access goes through [`GuestMemory`], which fails
+//! with `EFAULT` outside of guest memory, and so does each buffer of a
+//! chain, before the device sees it.  A malformed chain (one that loops, is
+//! longer than the queue, has device readable buffers after writable ones,
+//! or nests indirect tables) fails with `EINVAL`.  Once popping a chain
+//! fails, the queue can't be trusted to be in step with the driver, and the
+//! device should ask for a reset (see
+//! [`VirtioInterrupt::signal_needs_reset`](super::VirtioInterrupt::signal_needs_reset)).
+
+use super::{
+    QueueConfig, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_RING_PACKED,
+};
+use memory::GuestMemory;
+use nix;
+use nix::errno::Errno;
+use state::io_error;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{fence, Ordering};
+
+/// The most entries a queue has.
+pub const QUEUE_SIZE_MAX: u16 = 32768;
+
+const DESC_SIZE: u64 = 16;
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+const DESC_F_INDIRECT: u16 = 1 << 2;
+// packed descriptors only.
+const DESC_F_AVAIL: u16 = 1 << 7;
+const DESC_F_USED: u16 = 1 << 15;
+
+// split queues.
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+const USED_F_NO_NOTIFY: u16 = 1 << 0;
+
+// the event suppression structures of packed queues.
+const EVENT_FLAG_ENABLE: u16 = 0;
+const EVENT_FLAG_DISABLE: u16 = 1;
+const EVENT_FLAG_DESC: u16 = 2;
+
+/// A buffer: one descriptor of a chain.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Descriptor {
+    /// The guest physical address of the buffer.
+    pub addr: u64,
+    pub len: u32,
+    /// Whether the device writes the buffer, rather than reads it.
+    pub writable: bool,
+}
+
+/// A chain of buffers the driver made available, with any indirect table
+/// flattened into it.  The device readable buffers come first, then the
+/// device writable ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    id: u16,
+    // the number of descriptors it took in a packed ring.
+    ring_len: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+    /// The id the chain is handed back with: the index of its head in a
+    /// split queue, or its buffer id in a packed one.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// The buffers of the chain.
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// Reads the device readable buffers, in order.
+    pub fn reader<'a>(&'a self, memory: &'a GuestMemory) -> Reader<'a> {
+        let readable = self.descriptors.iter().take_while(|d| !d.writable).count();
+        Reader {
+            memory,
+            buffers: &self.descriptors[..readable],
+            offset: 0,
+        }
+    }
+
+    /// Writes the device writable buffers, in order.
+    pub fn writer<'a>(&'a self, memory: &'a GuestMemory) -> Writer<'a> {
+        let readable = self.descriptors.iter().take_while(|d| !d.writable).count();
+        Writer {
+            memory,
+            buffers: &self.descriptors[readable..],
+            offset: 0,
+            written: 0,
+        }
+    }
+}
+
+/// Reads a chain's device readable buffers as one stream.
+pub struct Reader<'a> {
+    memory: &'a GuestMemory,
+    buffers: &'a [Descriptor],
+    // into the first buffer.
+    offset: u64,
+}
+
+impl<'a> Reader<'a> {
+    /// The number of bytes left to read.
+    pub fn available(&self) -> u64 {
+        self.buffers.iter().map(|d| u64::from(d.len)).sum::<u64>() - self.offset
+    }
+}
+
+impl<'a> Read for Reader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let desc = match self.buffers.first() {
+                Some(&desc) => desc,
+                None => break,
+            };
+            let left = u64::from(desc.len) - self.offset;
+            if left == 0 {
+                self.buffers = &self.buffers[1..];
+                self.offset = 0;
+                continue;
+            }
+
+            let len = left.min((buf.len() - done) as u64) as usize;
+            self.memory
+                .read(desc.addr + self.offset, &mut buf[done..done + len])
+                .map_err(io_error)?;
+            self.offset += len as u64;
+            done += len;
+        }
+
+        Ok(done)
+    }
+}
+
+/// Writes a chain's device writable buffers as one stream.
+pub struct Writer<'a> {
+    memory: &'a GuestMemory,
+    buffers: &'a [Descriptor],
+    // into the first buffer.
+    offset: u64,
+    written: u32,
+}
+
+impl<'a> Writer<'a> {
+    /// The number of bytes left to write.
+    pub fn available(&self) -> u64 {
+        self.buffers.iter().map(|d| u64::from(d.len)).sum::<u64>() - self.offset
+    }
+
+    /// The number of bytes written, which is what the chain is used with.
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+}
+
+impl<'a> Write for Writer<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            let desc = match self.buffers.first() {
+                Some(&desc) => desc,
+                None => break,
+            };
+            let left = u64::from(desc.len) - self.offset;
+            if left == 0 {
+                self.buffers = &self.buffers[1..];
+                self.offset = 0;
+                continue;
+            }
+
+            let len = left.min((buf.len() - done) as u64) as usize;
+            self.memory
+                .write(desc.addr + self.offset, &buf[done..done + len])
+                .map_err(io_error)?;
+            self.offset += len as u64;
+            self.written = self.written.saturating_add(len as u32);
+            done += len;
+        }
+
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The device's side of a virtqueue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queue {
+    memory: GuestMemory,
+    size: u16,
+    desc_table: u64,
+    driver_area: u64,
+    device_area: u64,
+    packed: bool,
+    event_idx: bool,
+    indirect: bool,
+    next_avail: u16,
+    next_used: u16,
+    // the wrap counters of a packed queue.
+    avail_wrap: bool,
+    used_wrap: bool,
+    // the number of descriptors used, which unlike the ring position of a
+    // packed queue doesn't wrap at the size.
+    used_count: u16,
+    // what it was when the driver was last considered for an interrupt.
+    signalled_used: Option<u16>,
+}
+
+impl Queue {
+    /// The queue `config` describes, in `memory`, with the ring layout and
+    /// features of `features`.  Fails with `EINVAL` if the size isn't valid
+    /// for the layout, or the rings aren't aligned, or with `EFAULT` if
+    /// they aren't all in memory.
+    pub fn new(memory: GuestMemory, config: &QueueConfig, features: u64) -> nix::Result<Queue> {
+        let packed = features & 1 << VIRTIO_F_RING_PACKED != 0;
+        let size = config.size;
+        let valid_size = if packed {
+            size <= QUEUE_SIZE_MAX
+        } else {
+            size.is_power_of_two() && size <= QUEUE_SIZE_MAX
+        };
+        let (driver_len, device_len, driver_align) = if packed {
+            (4, 4, 4)
+        } else {
+            (6 + 2 * u64::from(size), 6 + 8 * u64::from(size), 2)
+        };
+        if size == 0
+            || size > config.max_size
+            || !valid_size
+            || !config.desc_table.is_multiple_of(DESC_SIZE)
+            || !config.driver_area.is_multiple_of(driver_align)
+            || !config.device_area.is_multiple_of(4)
+        {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if !memory.contains(config.desc_table, DESC_SIZE * u64::from(size))
+            || !memory.contains(config.driver_area, driver_len)
+            || !memory.contains(config.device_area, device_len)
+        {
+            return Err(nix::Error::Sys(Errno::EFAULT));
+        }
+
+        Ok(Queue {
+            memory,
+            size,
+            desc_table: config.desc_table,
+            driver_area: config.driver_area,
+            device_area: config.device_area,
+            packed,
+            event_idx: features & 1 << VIRTIO_F_RING_EVENT_IDX != 0,
+            indirect: features & 1 << VIRTIO_F_RING_INDIRECT_DESC != 0,
+            next_avail: 0,
+            next_used: 0,
+            avail_wrap: true,
+            used_wrap: true,
+            used_count: 0,
+            signalled_used: None,
+        })
+    }
+
+    /// The number of entries.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Whether the queue is packed, rather than split.
+    pub fn is_packed(&self) -> bool {
+        self.packed
+    }
+
+    /// The guest memory the queue is in.
+    pub fn memory(&self) -> &GuestMemory {
+        &self.memory
+    }
+
+    /// Takes the next chain the driver made available, if there is one.
+    pub fn pop(&mut self) -> nix::Result<Option<Chain>> {
+        if self.packed {
+            self.pop_packed()
+        } else {
+            self.pop_split()
+        }
+    }
+
+    /// Hands `chain` back to the driver, saying the device wrote `len`
+    /// bytes to it.
+    pub fn add_used(&mut self, chain: &Chain, len: u32) -> nix::Result<()> {
+        if self.packed {
+            let desc = self.desc_table + DESC_SIZE * u64::from(self.next_used);
+            self.memory.write_u32(desc + 8, len)?;
+            self.memory.write_u16(desc + 12, chain.id)?;
+            let mut flags = if self.used_wrap {
+                DESC_F_AVAIL | DESC_F_USED
+            } else {
+                0
+            };
+            if len != 0 {
+                flags |= DESC_F_WRITE;
+            }
+            // the flags hand the descriptor over, so they go last.
+            fence(Ordering::Release);
+            self.memory.write_u16(desc + 14, flags)?;
+
+            self.next_used += chain.ring_len;
+            self.used_count = self.used_count.wrapping_add(chain.ring_len);
+            if self.next_used >= self.size {
+                self.next_used -= self.size;
+                self.used_wrap = !self.used_wrap;
+            }
+        } else {
+            let slot = self.device_area + 4 + 8 * u64::from(self.next_used % self.size);
+            self.memory.write_u32(slot, u32::from(chain.id))?;
+            self.memory.write_u32(slot + 4, len)?;
+            self.next_used = self.next_used.wrapping_add(1);
+            self.used_count = self.next_used;
+            fence(Ordering::Release);
+            self.memory
+                .write_u16(self.device_area + 2, self.next_used)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since it
+    /// was last asked.
+    pub fn needs_notification(&mut self) -> nix::Result<bool> {
+        // the used ring has to be seen before the driver's wishes are read.
+        fence(Ordering::SeqCst);
+        // where the used index was, in the same terms as where it is: for
+        // a packed queue that may be before the start of the ring, in which
+        // case the event is too.
+        let new = self.next_used;
+        let old = self
+            .signalled_used
+            .replace(self.used_count)
+            .map(|old| new.wrapping_sub(self.used_count.wrapping_sub(old)));
+
+        if self.packed {
+            let flags = self.memory.read_u16(self.driver_area + 2)?;
+            match flags {
+                EVENT_FLAG_DISABLE => Ok(false),
+                EVENT_FLAG_DESC if self.event_idx => {
+                    let off_wrap = self.memory.read_u16(self.driver_area)?;
+                    let mut event = off_wrap & 0x7fff;
+                    if (off_wrap >> 15 != 0) != self.used_wrap {
+                        event = event.wrapping_sub(self.size);
+                    }
+                    Ok(old.is_none_or(|old| need_event(event, new, old)))
+                }
+                _ => Ok(true),
+            }
+        } else if self.event_idx {
+            let used_event = self.driver_area + 4 + 2 * u64::from(self.size);
+            let event = self.memory.read_u16(used_event)?;
+            Ok(old.is_none_or(|old| need_event(event, new, old)))
+        } else {
+            let flags = self.memory.read_u16(self.driver_area)?;
+            Ok(flags & AVAIL_F_NO_INTERRUPT == 0)
+        }
+    }
+
+    /// Asks the driver not to notify the device of new chains.  This is a
+    /// hint; the driver may still do it.
+    pub fn disable_notification(&mut self) -> nix::Result<()> {
+        if self.packed {
+            self.memory
+                .write_u16(self.device_area + 2, EVENT_FLAG_DISABLE)
+        } else if self.event_idx {
+            // the driver notifies once it passes the avail event, which
+            // stays where it was.
+            Ok(())
+        } else {
+            self.memory.write_u16(self.device_area, USED_F_NO_NOTIFY)
+        }
+    }
+
+    /// Asks the driver to notify the device of new chains.  Returns whether
+    /// chains were made available before the driver could see that, which
+    /// the device has to take care of without a notification.
+    pub fn enable_notification(&mut self) -> nix::Result<bool> {
+        if self.packed {
+            if self.event_idx {
+                let off_wrap = self.next_avail | (self.avail_wrap as u16) << 15;
+                self.memory.write_u16(self.device_area, off_wrap)?;
+                self.memory
+                    .write_u16(self.device_area + 2, EVENT_FLAG_DESC)?;
+            } else {
+                self.memory
+                    .write_u16(self.device_area + 2, EVENT_FLAG_ENABLE)?;
+            }
+        } else if self.event_idx {
+            let avail_event = self.device_area + 4 + 8 * u64::from(self.size);
+            self.memory.write_u16(avail_event, self.next_avail)?;
+        } else {
+            self.memory.write_u16(self.device_area, 0)?;
+        }
+
+        fence(Ordering::SeqCst);
+        self.has_available()
+    }
+
+    /// Whether the driver made chains available that haven't been popped.
+    pub fn has_available(&self) -> nix::Result<bool> {
+        if self.packed {
+            let desc = self.desc_table + DESC_SIZE * u64::from(self.next_avail);
+            let flags = self.memory.read_u16(desc + 14)?;
+            Ok(is_available(flags, self.avail_wrap))
+        } else {
+            let avail_idx = self.memory.read_u16(self.driver_area + 2)?;
+            Ok(avail_idx != self.next_avail)
+        }
+    }
+
+    fn pop_split(&mut self) -> nix::Result<Option<Chain>> {
+        let avail_idx = self.memory.read_u16(self.driver_area + 2)?;
+        // the ring entries are only read once the index says they're there.
+        fence(Ordering::Acquire);
+        let pending = avail_idx.wrapping_sub(self.next_avail);
+        if pending == 0 {
+            return Ok(None);
+        }
+        if pending > self.size {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let slot = self.driver_area + 4 + 2 * u64::from(self.next_avail % self.size);
+        let head = self.memory.read_u16(slot)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        if head >= self.size {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let mut descriptors = vec![];
+        let mut index = head;
+        // a chain can't be longer than the queue; one that is, loops.
+        for _ in 0..self.size {
+            let (addr, len, flags, next) = self.read_descriptor(self.desc_table, index)?;
+            if flags & DESC_F_INDIRECT != 0 {
+                if !self.indirect || flags & DESC_F_NEXT != 0 {
+                    return Err(nix::Error::Sys(Errno::EINVAL));
+                }
+                self.push_split_indirect(&mut descriptors, addr, len)?;
+                return Ok(Some(Chain {
+                    id: head,
+                    ring_len: 1,
+                    descriptors,
+                }));
+            }
+
+            self.push(&mut descriptors, addr, len, flags)?;
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(Some(Chain {
+                    id: head,
+                    ring_len: 1,
+                    descriptors,
+                }));
+            }
+            if next >= self.size {
+                return Err(nix::Error::Sys(Errno::EINVAL));
+            }
+            index = next;
+        }
+
+        Err(nix::Error::Sys(Errno::EINVAL))
+    }
+
+    /// Adds the chain in the indirect table at `addr` of a split queue.
+    fn push_split_indirect(
+        &self,
+        descriptors: &mut Vec<Descriptor>,
+        addr: u64,
+        len: u32,
+    ) -> nix::Result<()> {
+        let count = self.indirect_count(addr, len)?;
+        let mut index = 0;
+        for _ in 0..count {
+            let (addr, len, flags, next) = self.read_descriptor_at(addr, index)?;
+            if flags & DESC_F_INDIRECT != 0 {
+                return Err(nix::Error::Sys(Errno::EINVAL));
+            }
+            self.push(descriptors, addr, len, flags)?;
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(());
+            }
+            if u64::from(next) >= count {
+                return Err(nix::Error::Sys(Errno::EINVAL));
+            }
+            index = u64::from(next);
+        }
+
+        Err(nix::Error::Sys(Errno::EINVAL))
+    }
+
+    fn pop_packed(&mut self) -> nix::Result<Option<Chain>> {
+        if !self.has_available()? {
+            return Ok(None);
+        }
+        // the rest of the descriptor is only read once its flags say it's
+        // there.
+        fence(Ordering::Acquire);
+
+        let mut descriptors = vec![];
+        let (mut index, mut wrap) = (self.next_avail, self.avail_wrap);
+        for ring_len in 1..=self.size {
+            let (addr, len, id, flags) = self.read_descriptor(self.desc_table, index)?;
+            if !is_available(flags, wrap) {
+                return Err(nix::Error::Sys(Errno::EINVAL));
+            }
+            index += 1;
+            if index == self.size {
+                index = 0;
+                wrap = !wrap;
+            }
+
+            if flags & DESC_F_INDIRECT != 0 {
+                if !self.indirect || flags & DESC_F_NEXT != 0 {
+                    return Err(nix::Error::Sys(Errno::EINVAL));
+                }
+                self.push_packed_indirect(&mut descriptors, addr, len)?;
+            } else {
+                self.push(&mut descriptors, addr, len, flags)?;
+            }
+
+            // the buffer id is in the last descriptor.
+            if flags & DESC_F_NEXT == 0 {
+                self.next_avail = index;
+                self.avail_wrap = wrap;
+                return Ok(Some(Chain {
+                    id,
+                    ring_len,
+                    descriptors,
+                }));
+            }
+        }
+
+        Err(nix::Error::Sys(Errno::EINVAL))
+    }
+
+    /// Adds the buffers in the indirect table at `addr` of a packed queue,
+    /// which are all of its entries, in order.
+    fn push_packed_indirect(
+        &self,
+        descriptors: &mut Vec<Descriptor>,
+        addr: u64,
+        len: u32,
+    ) -> nix::Result<()> {
+        for index in 0..self.indirect_count(addr, len)? {
+            let (addr, len, _, flags) = self.read_descriptor_at(addr, index)?;
+            if flags & DESC_F_INDIRECT != 0 {
+                return Err(nix::Error::Sys(Errno::EINVAL));
+            }
+            self.push(descriptors, addr, len, flags)?;
+        }
+
+        Ok(())
+    }
+
+    /// The number of entries of the indirect table at `addr`, of `len`
+    /// bytes.
+    fn indirect_count(&self, addr: u64, len: u32) -> nix::Result<u64> {
+        let len = u64::from(len);
+        let count = len / DESC_SIZE;
+        // next fields are 16 bits, so no table has more entries than that.
+        if count == 0 || !len.is_multiple_of(DESC_SIZE) || count > 1 << 16 {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if !self.memory.contains(addr, len) {
+            return Err(nix::Error::Sys(Errno::EFAULT));
+        }
+
+        Ok(count)
+    }
+
+    /// Adds a buffer to a chain, after checking it.
+    fn push(
+        &self,
+        descriptors: &mut Vec<Descriptor>,
+        addr: u64,
+        len: u32,
+        flags: u16,
+    ) -> nix::Result<()> {
+        let writable = flags & DESC_F_WRITE != 0;
+        if !writable && descriptors.last().is_some_and(|d| d.writable) {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if !self.memory.contains(addr, u64::from(len)) {
+            return Err(nix::Error::Sys(Errno::EFAULT));
+        }
+
+        descriptors.push(Descriptor {
+            addr,
+            len,
+            writable,
+        });
+        Ok(())
+    }
+
+    /// Reads entry `index` of the descriptor table at `table`.
+    fn read_descriptor(&self, table: u64, index: u16) -> nix::Result<(u64, u32, u16, u16)> {
+        self.read_descriptor_at(table, u64::from(index))
+    }
+
+    /// Reads entry `index` of the table at `table`, as the address, the
+    /// length, and the two 16 bit fields after them: the flags and next
+    /// index of a split descriptor, or the buffer id and flags of a packed
+    /// one.
+    fn read_descriptor_at(&self, table: u64, index: u64) -> nix::Result<(u64, u32, u16, u16)> {
+        let mut desc = [0; DESC_SIZE as usize];
+        self.memory.read(table + DESC_SIZE * index, &mut desc)?;
+        let mut addr = [0; 8];
+        addr.copy_from_slice(&desc[..8]);
+        let len = u32::from_le_bytes([desc[8], desc[9], desc[10], desc[11]]);
+        let a = u16::from_le_bytes([desc[12], desc[13]]);
+        let b = u16::from_le_bytes([desc[14], desc[15]]);
+        Ok((u64::from_le_bytes(addr), len, a, b))
+    }
+}
+
+/// Whether a packed descriptor with `flags` is available, in the lap of
+/// the ring with the wrap counter `wrap`.
+fn is_available(flags: u16, wrap: bool) -> bool {
+    (flags & DESC_F_AVAIL != 0) == wrap && (flags & DESC_F_USED != 0) != wrap
+}
+
+/// Whether moving the used index from `old` to `new` passed `event`.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::anonymous_memory;
+
+    const MEMORY_SIZE: u64 = 0x2_0000;
+    const DESC: u64 = 0x1000;
+    const DRIVER: u64 = 0x2000;
+    const DEVICE: u64 = 0x3000;
+    const TABLE: u64 = 0x4000;
+    const DATA: u64 = 0x8000;
+
+    const INDIRECT: u64 = 1 << VIRTIO_F_RING_INDIRECT_DESC;
+    const EVENT_IDX: u64 = 1 << VIRTIO_F_RING_EVENT_IDX;
+    const PACKED: u64 = 1 << VIRTIO_F_RING_PACKED;
+
+    fn queue(memory: &GuestMemory, size: u16, features: u64) -> Queue {
+        let config = QueueConfig {
+            size,
+            desc_table: DESC,
+            driver_area: DRIVER,
+            device_area: DEVICE,
+            ..QueueConfig::new(QUEUE_SIZE_MAX)
+        };
+        Queue::new(memory.clone(), &config, features).unwrap()
+    }
+
+    fn error(result: nix::Result<Option<Chain>>) -> Errno {
+        match result {
+            Err(nix::Error::Sys(errno)) => errno,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    /// Writes a descriptor: `a` and `b` are the flags and next index of a
+    /// split descriptor, or the buffer id and flags of a packed one.
+    fn desc(memory: &GuestMemory, table: u64, index: u16, addr: u64, len: u32, a: u16, b: u16) {
+        let desc = table + DESC_SIZE * u64::from(index);
+        memory.write_u64(desc, addr).unwrap();
+        memory.write_u32(desc + 8, len).unwrap();
+        memory.write_u16(desc + 12, a).unwrap();
+        memory.write_u16(desc + 14, b).unwrap();
+    }
+
+    /// Makes the chain at `head` available at `idx` of a split queue.
+    fn avail(memory: &GuestMemory, size: u16, idx: u16, head: u16) {
+        let slot = DRIVER + 4 + 2 * u64::from(idx % size);
+        memory.write_u16(slot, head).unwrap();
+        memory.write_u16(DRIVER + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    /// The flags of a packed descriptor made available in the lap `wrap`.
+    fn avail_flags(wrap: bool, flags: u16) -> u16 {
+        flags | if wrap { DESC_F_AVAIL } else { DESC_F_USED }
+    }
+
+    fn descriptor(addr: u64, len: u32, writable: bool) -> Descriptor {
+        Descriptor {
+            addr,
+            len,
+            writable,
+        }
+    }
+
+    #[test]
+    fn new_checks_config() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let config = QueueConfig {
+            size: 8,
+            desc_table: DESC,
+            driver_area: DRIVER,
+            device_area: DEVICE,
+            ..QueueConfig::new(8)
+        };
+        assert!(Queue::new(memory.clone(), &config, 0).is_ok());
+
+        let check = |config: QueueConfig, features: u64| match Queue::new(
+            memory.clone(),
+            &config,
+            features,
+        ) {
+            Err(nix::Error::Sys(errno)) => Some(errno),
+            _ => None,
+        };
+        assert_eq!(
+            check(QueueConfig { size: 6, ..config }, 0),
+            Some(Errno::EINVAL)
+        );
+        assert_eq!(check(QueueConfig { size: 6, ..config }, PACKED), None);
+        assert_eq!(
+            check(QueueConfig { size: 0, ..config }, 0),
+            Some(Errno::EINVAL)
+        );
+        assert_eq!(
+            check(QueueConfig { size: 16, ..config }, 0),
+            Some(Errno::EINVAL)
+        );
+        let misaligned = QueueConfig {
+            desc_table: DESC + 8,
+            ..config
+        };
+        assert_eq!(check(misaligned, 0), Some(Errno::EINVAL));
+        let misaligned = QueueConfig {
+            device_area: DEVICE + 2,
+            ..config
+        };
+        assert_eq!(check(misaligned, 0), Some(Errno::EINVAL));
+        let outside = QueueConfig {
+            device_area: MEMORY_SIZE - 16,
+            ..config
+        };
+        assert_eq!(check(outside, 0), Some(Errno::EFAULT));
+        assert_eq!(check(outside, PACKED), None);
+    }
+
+    #[test]
+    fn split_pop() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let mut queue = queue(&memory, 8, 0);
+        assert_eq!(queue.pop(), Ok(None));
+
+        memory.write(DATA, b"hello, world").unwrap();
+        desc(&memory, DESC, 3, DATA, 5, DESC_F_NEXT, 5);
+        desc(&memory, DESC, 5, DATA + 5, 7, DESC_F_NEXT, 1);
+        desc(
+            &memory,
+            DESC,
+            1,
+            DATA + 0x100,
+            4,
+            DESC_F_WRITE | DESC_F_NEXT,
+            2,
+        );
+        desc(&memory, DESC, 2, DATA + 0x200, 4, DESC_F_WRITE, 0);
+        avail(&memory, 8, 0, 3);
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 3);
+        assert_eq!(
+            chain.descriptors(),
+            [
+                descriptor(DATA, 5, false),
+                descriptor(DATA + 5, 7, false),
+                descriptor(DATA + 0x100, 4, true),
+                descriptor(DATA + 0x200, 4, true),
+            ]
+        );
+        assert_eq!(queue.pop(), Ok(None));
+
+        let mut reader = chain.reader(&memory);
+        assert_eq!(reader.available(), 12);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"hello, world");
+        assert_eq!(reader.available(), 0);
+
+        let mut writer = chain.writer(&memory);
+        assert_eq!(writer.available(), 8);
+        assert_eq!(
+            writer.write_all(b"0123456789").unwrap_err().kind(),
+            io::ErrorKind::WriteZero
+        );
+        assert_eq!(writer.written(), 8);
+        assert_eq!(memory.read_u32(DATA + 0x100).unwrap(), 0x3332_3130);
+        assert_eq!(memory.read_u32(DATA + 0x200).unwrap(), 0x3736_3534);
+
+        queue.add_used(&chain, writer.written()).unwrap();
+        assert_eq!(memory.read_u16(DEVICE + 2).unwrap(), 1);
+        assert_eq!(memory.read_u32(DEVICE + 4).unwrap(), 3);
+        assert_eq!(memory.read_u32(DEVICE + 8).unwrap(), 8);
+    }
+
+    #[test]
+    fn split_indirect() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        desc(&memory, TABLE, 0, DATA, 16, DESC_F_NEXT, 2);
+        desc(&memory, TABLE, 2, DATA + 0x100, 32, DESC_F_WRITE, 0);
+        desc(&memory, DESC, 0, DATA + 0x400, 8, DESC_F_NEXT, 1);
+        desc(&memory, DESC, 1, TABLE, 48, DESC_F_INDIRECT, 0);
+
+        // not without the feature.
+        let mut queue = self::queue(&memory, 4, 0);
+        avail(&memory, 4, 0, 0);
+        assert_eq!(error(queue.pop()), Errno::EINVAL);
+
+        let mut queue = self::queue(&memory, 4, INDIRECT);
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 0);
+        assert_eq!(
+            chain.descriptors(),
+            [
+                descriptor(DATA + 0x400, 8, false),
+                descriptor(DATA, 16, false),
+                descriptor(DATA + 0x100, 32, true),
+            ]
+        );
+
+        let bad = |flags: u16, addr: u64, len: u32| {
+            desc(&memory, DESC, 1, addr, len, flags, 0);
+            let mut queue = self::queue(&memory, 4, INDIRECT);
+            error(queue.pop())
+        };
+        // an indirect descriptor with a next one, or of a partial table.
+        assert_eq!(bad(DESC_F_INDIRECT | DESC_F_NEXT, TABLE, 48), Errno::EINVAL);
+        assert_eq!(bad(DESC_F_INDIRECT, TABLE, 40), Errno::EINVAL);
+        assert_eq!(bad(DESC_F_INDIRECT, TABLE, 0), Errno::EINVAL);
+        assert_eq!(bad(DESC_F_INDIRECT, MEMORY_SIZE - 16, 32), Errno::EFAULT);
+
+        // nested tables, and a next index outside the table.
+        desc(&memory, TABLE, 2, TABLE, 48, DESC_F_INDIRECT, 0);
+        assert_eq!(bad(DESC_F_INDIRECT, TABLE, 48), Errno::EINVAL);
+        desc(&memory, TABLE, 2, DATA, 16, DESC_F_WRITE | DESC_F_NEXT, 3);
+        assert_eq!(bad(DESC_F_INDIRECT, TABLE, 48), Errno::EINVAL);
+        // a loop within the table.
+        desc(&memory, TABLE, 2, DATA, 16, DESC_F_NEXT, 0);
+        assert_eq!(bad(DESC_F_INDIRECT, TABLE, 48), Errno::EINVAL);
+    }
+
+    #[test]
+    fn split_malformed() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let bad = |head: u16| {
+            let mut queue = self::queue(&memory, 4, 0);
+            avail(&memory, 4, 0, head);
+            error(queue.pop())
+        };
+
+        // a loop, one that takes the whole queue, and a head outside it.
+        desc(&memory, DESC, 0, DATA, 1, DESC_F_NEXT, 0);
+        assert_eq!(bad(0), Errno::EINVAL);
+        for index in 0..4 {
+            desc(&memory, DESC, index, DATA, 1, DESC_F_NEXT, (index + 1) % 4);
+        }
+        assert_eq!(bad(2), Errno::EINVAL);
+        assert_eq!(bad(4), Errno::EINVAL);
+
+        // a next index outside the queue.
+        desc(&memory, DESC, 0, DATA, 1, DESC_F_NEXT, 4);
+        assert_eq!(bad(0), Errno::EINVAL);
+
+        // readable after writable.
+        desc(&memory, DESC, 0, DATA, 1, DESC_F_WRITE | DESC_F_NEXT, 1);
+        desc(&memory, DESC, 1, DATA, 1, 0, 0);
+        assert_eq!(bad(0), Errno::EINVAL);
+
+        // buffers outside of memory, or running off its end.
+        desc(&memory, DESC, 0, MEMORY_SIZE, 1, 0, 0);
+        assert_eq!(bad(0), Errno::EFAULT);
+        desc(&memory, DESC, 0, MEMORY_SIZE - 4, 8, 0, 0);
+        assert_eq!(bad(0), Errno::EFAULT);
+        desc(&memory, DESC, 0, !0 - 4, 8, 0, 0);
+        assert_eq!(bad(0), Errno::EFAULT);
+
+        // more available than the queue holds.
+        let mut queue = self::queue(&memory, 4, 0);
+        memory.write_u16(DRIVER + 2, 5).unwrap();
+        assert_eq!(error(queue.pop()), Errno::EINVAL);
+    }
+
+    #[test]
+    fn split_wrap() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let mut queue = queue(&memory, 4, 0);
+        queue.next_avail = 0xfffe;
+        queue.next_used = 0xfffe;
+        for index in 0..4 {
+            desc(&memory, DESC, index, DATA, 1, DESC_F_WRITE, 0);
+        }
+
+        for idx in 0xfffe..0xfffe + 6 {
+            let idx = idx as u16;
+            avail(&memory, 4, idx, idx % 4);
+            let chain = queue.pop().unwrap().unwrap();
+            assert_eq!(chain.id(), idx % 4);
+            queue.add_used(&chain, u32::from(idx)).unwrap();
+            let slot = DEVICE + 4 + 8 * u64::from(idx % 4);
+            assert_eq!(memory.read_u32(slot).unwrap(), u32::from(idx % 4));
+            assert_eq!(memory.read_u32(slot + 4).unwrap(), u32::from(idx));
+            assert_eq!(memory.read_u16(DEVICE + 2).unwrap(), idx.wrapping_add(1));
+        }
+        assert_eq!(queue.next_avail, 4);
+        assert_eq!(queue.pop(), Ok(None));
+    }
+
+    #[test]
+    fn packed_pop() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let mut queue = queue(&memory, 4, PACKED);
+        assert!(queue.is_packed());
+        assert_eq!(queue.pop(), Ok(None));
+
+        // the buffer id is the last descriptor's.
+        desc(&memory, DESC, 0, DATA, 4, 0, avail_flags(true, DESC_F_NEXT));
+        desc(
+            &memory,
+            DESC,
+            1,
+            DATA + 4,
+            4,
+            9,
+            avail_flags(true, DESC_F_WRITE),
+        );
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 9);
+        assert_eq!(
+            chain.descriptors(),
+            [descriptor(DATA, 4, false), descriptor(DATA + 4, 4, true)]
+        );
+        assert_eq!(queue.pop(), Ok(None));
+
+        queue.add_used(&chain, 4).unwrap();
+        assert_eq!(memory.read_u32(DESC + 8).unwrap(), 4);
+        assert_eq!(memory.read_u16(DESC + 12).unwrap(), 9);
+        assert_eq!(
+            memory.read_u16(DESC + 14).unwrap(),
+            DESC_F_AVAIL | DESC_F_USED | DESC_F_WRITE
+        );
+        assert_eq!(queue.next_used, 2);
+    }
+
+    #[test]
+    fn packed_wrap() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let mut queue = queue(&memory, 3, PACKED);
+
+        // laps of the ring, with chains of two descriptors straddling its
+        // end every other time.
+        let mut wrap = true;
+        let mut index = 0;
+        for lap in 0..6u16 {
+            let second = if index == 2 { !wrap } else { wrap };
+            desc(
+                &memory,
+                DESC,
+                index,
+                DATA,
+                1,
+                0,
+                avail_flags(wrap, DESC_F_NEXT),
+            );
+            desc(
+                &memory,
+                DESC,
+                (index + 1) % 3,
+                DATA,
+                1,
+                lap,
+                avail_flags(second, 0),
+            );
+            let chain = queue.pop().unwrap().unwrap();
+            assert_eq!(chain.id(), lap);
+            assert_eq!(chain.descriptors().len(), 2);
+            assert_eq!(queue.pop(), Ok(None));
+
+            queue.add_used(&chain, 0).unwrap();
+            let used = DESC + DESC_SIZE * u64::from(index);
+            let flags = if wrap { DESC_F_AVAIL | DESC_F_USED } else { 0 };
+            assert_eq!(memory.read_u16(used + 12).unwrap(), lap);
+            assert_eq!(memory.read_u16(used + 14).unwrap(), flags);
+
+            index += 2;
+            if index >= 3 {
+                index -= 3;
+                wrap = !wrap;
+            }
+            assert_eq!((queue.next_avail, queue.avail_wrap), (index, wrap));
+            assert_eq!((queue.next_used, queue.used_wrap), (index, wrap));
+        }
+
+        // a descriptor made available in the last lap isn't now.
+        desc(&memory, DESC, index, DATA, 1, 0, avail_flags(!wrap, 0));
+        assert_eq!(queue.pop(), Ok(None));
+    }
+
+    #[test]
+    fn packed_malformed() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let bad = |features: u64| {
+            let mut queue = self::queue(&memory, 4, PACKED | features);
+            error(queue.pop())
+        };
+
+        // a chain longer than the queue.
+        for index in 0..4 {
+            desc(
+                &memory,
+                DESC,
+                index,
+                DATA,
+                1,
+                0,
+                avail_flags(true, DESC_F_NEXT),
+            );
+        }
+        assert_eq!(bad(0), Errno::EINVAL);
+
+        // a chain running into a descriptor that isn't available.
+        desc(&memory, DESC, 1, DATA, 1, 0, avail_flags(false, 0));
+        assert_eq!(bad(0), Errno::EINVAL);
+
+        // readable after writable, and a buffer outside of memory.
+        desc(
+            &memory,
+            DESC,
+            0,
+            DATA,
+            1,
+            0,
+            avail_flags(true, DESC_F_WRITE | DESC_F_NEXT),
+        );
+        desc(&memory, DESC, 1, DATA, 1, 0, avail_flags(true, 0));
+        assert_eq!(bad(0), Errno::EINVAL);
+        desc(
+            &memory,
+            DESC,
+            0,
+            MEMORY_SIZE - 1,
+            2,
+            0,
+            avail_flags(true, 0),
+        );
+        assert_eq!(bad(0), Errno::EFAULT);
+
+        // indirect tables: every entry is part of the chain, in order.
+        desc(&memory, TABLE, 0, DATA, 8, 0, 0);
+        desc(&memory, TABLE, 1, DATA + 8, 8, 0, DESC_F_WRITE);
+        desc(
+            &memory,
+            DESC,
+            0,
+            TABLE,
+            32,
+            5,
+            avail_flags(true, DESC_F_INDIRECT),
+        );
+        assert_eq!(bad(0), Errno::EINVAL);
+        let mut queue = self::queue(&memory, 4, PACKED | INDIRECT);
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.id(), 5);
+        assert_eq!(
+            chain.descriptors(),
+            [descriptor(DATA, 8, false), descriptor(DATA + 8, 8, true)]
+        );
+
+        desc(&memory, TABLE, 1, TABLE, 32, 0, DESC_F_INDIRECT);
+        assert_eq!(bad(INDIRECT), Errno::EINVAL);
+        desc(
+            &memory,
+            DESC,
+            0,
+            TABLE,
+            24,
+            5,
+            avail_flags(true, DESC_F_INDIRECT),
+        );
+        assert_eq!(bad(INDIRECT), Errno::EINVAL);
+    }
+
+    #[test]
+    fn need_event_wraps() {
+        assert!(need_event(0, 1, 0));
+        assert!(!need_event(1, 1, 0));
+        assert!(need_event(5, 10, 3));
+        assert!(!need_event(10, 10, 3));
+        assert!(!need_event(2, 10, 3));
+        assert!(need_event(0xffff, 1, 0xfffe));
+        assert!(need_event(0, 1, 0xfffe));
+        assert!(!need_event(1, 1, 0xfffe));
+        assert!(!need_event(0xfffd, 1, 0xfffe));
+    }
+
+    #[test]
+    fn split_notification() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        desc(&memory, DESC, 0, DATA, 1, 0, 0);
+        let use_one = |queue: &mut Queue, idx: u16| {
+            avail(&memory, 4, idx, 0);
+            let chain = queue.pop().unwrap().unwrap();
+            queue.add_used(&chain, 0).unwrap();
+        };
+
+        let mut queue = self::queue(&memory, 4, 0);
+        use_one(&mut queue, 0);
+        assert_eq!(queue.needs_notification(), Ok(true));
+        memory.write_u16(DRIVER, AVAIL_F_NO_INTERRUPT).unwrap();
+        assert_eq!(queue.needs_notification(), Ok(false));
+        memory.write_u16(DRIVER, 0).unwrap();
+
+        queue.disable_notification().unwrap();
+        assert_eq!(memory.read_u16(DEVICE).unwrap(), USED_F_NO_NOTIFY);
+        assert_eq!(queue.enable_notification(), Ok(false));
+        assert_eq!(memory.read_u16(DEVICE).unwrap(), 0);
+        memory.write_u16(DRIVER + 2, 2).unwrap();
+        assert_eq!(queue.enable_notification(), Ok(true));
+
+        // the used event is at the end of the available ring, and the
+        // driver wants an interrupt once the used index passes it.
+        let used_event = DRIVER + 4 + 2 * 4;
+        let mut queue = self::queue(&memory, 4, EVENT_IDX);
+        queue.next_avail = 0xfffd;
+        queue.next_used = 0xfffd;
+        memory.write_u16(used_event, 0xfffe).unwrap();
+        use_one(&mut queue, 0xfffd);
+        assert_eq!(queue.needs_notification(), Ok(true));
+        use_one(&mut queue, 0xfffe);
+        assert_eq!(queue.needs_notification(), Ok(true));
+        use_one(&mut queue, 0xffff);
+        assert_eq!(queue.needs_notification(), Ok(false));
+        memory.write_u16(used_event, 1).unwrap();
+        use_one(&mut queue, 0);
+        assert_eq!(queue.needs_notification(), Ok(false));
+        use_one(&mut queue, 1);
+        use_one(&mut queue, 2);
+        assert_eq!(queue.needs_notification(), Ok(true));
+
+        let avail_event = DEVICE + 4 + 8 * 4;
+        assert_eq!(queue.enable_notification(), Ok(false));
+        assert_eq!(memory.read_u16(avail_event).unwrap(), 3);
+    }
+
+    #[test]
+    fn packed_notification() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let mut queue = self::queue(&memory, 4, PACKED | EVENT_IDX);
+        let mut wrap = true;
+        let mut use_one = |queue: &mut Queue| {
+            let index = queue.next_avail;
+            desc(&memory, DESC, index, DATA, 1, index, avail_flags(wrap, 0));
+            if index == 3 {
+                wrap = !wrap;
+            }
+            let chain = queue.pop().unwrap().unwrap();
+            queue.add_used(&chain, 0).unwrap();
+        };
+        let set_event = |flags: u16, off: u16, wrap: bool| {
+            memory.write_u16(DRIVER, off | (wrap as u16) << 15).unwrap();
+            memory.write_u16(DRIVER + 2, flags).unwrap();
+        };
+
+        for _ in 0..3 {
+            use_one(&mut queue);
+        }
+        assert_eq!(queue.needs_notification(), Ok(true));
+        set_event(EVENT_FLAG_DISABLE, 0, false);
+        assert_eq!(queue.needs_notification(), Ok(false));
+
+        // an event the used index passed before the last interrupt, from
+        // the first lap, with the ring position wrapping since.
+        set_event(EVENT_FLAG_DESC, 1, true);
+        use_one(&mut queue);
+        use_one(&mut queue);
+        assert_eq!((queue.next_used, queue.used_wrap), (1, false));
+        assert_eq!(queue.needs_notification(), Ok(false));
+
+        // one in the second lap, not yet passed, and then passed.
+        set_event(EVENT_FLAG_DESC, 2, false);
+        assert_eq!(queue.needs_notification(), Ok(false));
+        use_one(&mut queue);
+        assert_eq!(queue.needs_notification(), Ok(false));
+        use_one(&mut queue);
+        assert_eq!(queue.needs_notification(), Ok(true));
+
+        // one passed in the lap just ended, across the wrap.
+        set_event(EVENT_FLAG_DESC, 3, false);
+        use_one(&mut queue);
+        use_one(&mut queue);
+        assert_eq!((queue.next_used, queue.used_wrap), (1, true));
+        assert_eq!(queue.needs_notification(), Ok(true));
+
+        set_event(EVENT_FLAG_ENABLE, 0, false);
+        assert_eq!(queue.needs_notification(), Ok(true));
+
+        queue.disable_notification().unwrap();
+        assert_eq!(memory.read_u16(DEVICE + 2).unwrap(), EVENT_FLAG_DISABLE);
+        assert_eq!(queue.enable_notification(), Ok(false));
+        assert_eq!(memory.read_u16(DEVICE).unwrap(), 1 | 1 << 15);
+        assert_eq!(memory.read_u16(DEVICE + 2).unwrap(), EVENT_FLAG_DESC);
+    }
+
+    /// Pops chains off rings full of pseudo-random descriptors, checking
+    /// that what comes out is well formed, whatever goes in.
+    #[test]
+    fn random_rings() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for round in 0..2000 {
+            let features = [0, INDIRECT | EVENT_IDX, PACKED, PACKED | INDIRECT][round % 4];
+            let size = 8;
+            for table in &[DESC, TABLE] {
+                for index in 0..size {
+                    let r = random();
+                    // mostly in memory, sometimes not, and small flags and
+                    // indexes, to get chains rather than noise.
+                    let addr = r & 0x1_ffff;
+                    let len = (r >> 20) as u32 % 0x200;
+                    let a = (r >> 32) as u16 % 12;
+                    let b = (r >> 48) as u16 % 12;
+                    let (a, b) = if features & PACKED != 0 {
+                        (a, (b & 7) | if r & 1 << 19 != 0 { DESC_F_AVAIL } else { 0 })
+                    } else {
+                        (a & 7, b)
+                    };
+                    let len = if a & DESC_F_INDIRECT != 0 || b & DESC_F_INDIRECT != 0 {
+                        len & !0xf
+                    } else {
+                        len
+                    };
+                    let addr = if r & 1 << 18 != 0 { TABLE } else { addr };
+                    desc(&memory, *table, index, addr, len, a, b);
+                }
+            }
+            for slot in 0..u64::from(size) {
+                let head = (random() % 10) as u16;
+                memory.write_u16(DRIVER + 4 + 2 * slot, head).unwrap();
+            }
+            memory
+                .write_u16(DRIVER + 2, (random() % 12) as u16)
+                .unwrap();
+
+            let mut queue = self::queue(&memory, size, features);
+            for _ in 0..2 * size {
+                let chain = match queue.pop() {
+                    Ok(Some(chain)) => chain,
+                    Ok(None) => break,
+                    Err(nix::Error::Sys(errno)) => {
+                        assert!(errno == Errno::EINVAL || errno == Errno::EFAULT);
+                        break;
+                    }
+                    Err(e) => panic!("{}", e),
+                };
+
+                let descriptors = chain.descriptors();
+                assert!(!descriptors.is_empty());
+                let readable = descriptors.iter().take_while(|d| !d.writable).count();
+                assert!(descriptors[readable..].iter().all(|d| d.writable));
+                for d in descriptors {
+                    assert!(memory.contains(d.addr, u64::from(d.len)));
+                }
+                let mut reader = chain.reader(&memory);
+                io::copy(&mut reader, &mut io::sink()).unwrap();
+                let mut writer = chain.writer(&memory);
+                let available = writer.available();
+                io::copy(&mut io::repeat(0).take(available), &mut writer).unwrap();
+                queue.add_used(&chain, writer.written()).unwrap();
+                queue.needs_notification().unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! Dirty page tracking for memory slots.
+//!
+//! A memory slot registered with [`KVM_MEM_LOG_DIRTY_PAGES`] has its writes
+//! tracked by KVM.  [`DirtyBitmap`] wraps [`kvm_get_dirty_log`] for such a
+//! slot, and, if manual protection has been turned on with
+//! [`enable_manual_protect`], lets the caller re-protect the dirty pages in
+//! chunks with [`kvm_clear_dirty_log`] instead of all at once.
+//!
+//! Alternatively, with [`enable_dirty_ring`], KVM pushes each dirtied page
+//! onto a ring shared with userspace, one per vCPU.  [`DirtyRing`] maps and
+//! harvests such a ring.
+
+use super::consts::{
+    KVM_CAP_DIRTY_LOG_RING, KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2, KVM_DIRTY_GFN_F_DIRTY,
+    KVM_DIRTY_GFN_F_RESET, KVM_DIRTY_LOG_INITIALLY_SET, KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE,
+    KVM_DIRTY_LOG_PAGE_OFFSET,
+};
+use super::ctl::{
+    kvm_clear_dirty_log, kvm_enable_cap, kvm_get_dirty_log, ClearDirtyLog, DirtyGfn, DirtyLog,
+    DirtyLogValue, EnableCap, UserspaceMemoryRegion,
+};
+use libc;
+use nix;
+use nix::errno::Errno;
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The granularity of dirty tracking, in bytes.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Enables manual dirty log protection on the given VM.  After this,
+/// [`kvm_get_dirty_log`] only reports dirty pages, and each page stays
+/// writable until it is cleared with [`kvm_clear_dirty_log`] (or
+/// [`DirtyBitmap::clear`]).  If `initially_set` is true, dirty-logged slots
+/// created afterwards start with every page marked as dirty, which avoids
+/// write protecting the whole slot up front.
+///
+/// # Safety
+/// `fd` must be a VM file descriptor.
+///
+/// # Support
+/// This requires the [`KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2`] capability, and
+/// is available only on the VM file descriptor.
+pub unsafe fn enable_manual_protect(fd: RawFd, initially_set: bool) -> nix::Result<i32> {
+    let mut cap: EnableCap = ::std::mem::zeroed();
+    cap.cap = KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2;
+    cap.args[0] = KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE;
+    if initially_set {
+        cap.args[0] |= KVM_DIRTY_LOG_INITIALLY_SET;
+    }
+
+    kvm_enable_cap(fd, &cap)
+}
+
+/// The dirty bitmap of a single memory slot.  Bit `n` corresponds to the
+/// `n`th page of the slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirtyBitmap {
+    slot: u32,
+    pages: u64,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// Creates an empty bitmap sized for the given memory slot.
+    pub fn new(region: &UserspaceMemoryRegion) -> DirtyBitmap {
+        let pages = region.memory_size.div_ceil(PAGE_SIZE);
+        DirtyBitmap {
+            slot: region.slot,
+            pages,
+            bits: vec![0; pages.div_ceil(64) as usize],
+        }
+    }
+
+    /// The slot (including the address space, in bits 16-31) this bitmap
+    /// tracks.
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    /// The number of pages in the slot.
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    /// The raw bitmap, as returned by KVM.
+    pub fn bits(&self) -> &[u64] {
+        &self.bits
+    }
+
+    /// Replaces the contents of the bitmap with the pages dirtied since the
+    /// last fetch, returning the number of dirty pages.  Without manual
+    /// protection, this also write protects the entire slot again.
+    ///
+    /// # Safety
+    /// `fd` must be the file descriptor of the VM the bitmap's slot is in.
+    ///
+    /// # Support
+    /// See [`kvm_get_dirty_log`].  This is available only on the VM file
+    /// descriptor.
+    pub unsafe fn fetch(&mut self, fd: RawFd) -> nix::Result<u64> {
+        let log = DirtyLog {
+            slot: self.slot,
+            _pad: 0,
+            value: DirtyLogValue {
+                dirty_bitmap: self.bits.as_mut_ptr(),
+            },
+        };
+
+        kvm_get_dirty_log(fd, &log)?;
+        let count = self.count();
+        debug!(
+            "slot {:#x}: {} of {} pages dirty",
+            self.slot, count, self.pages
+        );
+        Ok(count)
+    }
+
+    /// The number of pages marked dirty in the bitmap.
+    pub fn count(&self) -> u64 {
+        self.bits.iter().map(|w| u64::from(w.count_ones())).sum()
+    }
+
+    /// Whether or not the given page is marked dirty.
+    pub fn is_dirty(&self, page: u64) -> bool {
+        page < self.pages && self.bits[(page / 64) as usize] & (1 << (page % 64)) != 0
+    }
+
+    /// Iterates over the indices of the dirty pages in the bitmap.
+    pub fn iter(&self) -> DirtyPages<'_> {
+        self.iter_from(0)
+    }
+
+    /// Iterates over the indices of the dirty pages in the bitmap, starting
+    /// at the given page.
+    pub fn iter_from(&self, page: u64) -> DirtyPages<'_> {
+        DirtyPages { bitmap: self, page }
+    }
+
+    /// Marks every page in the slot as dirty.
+    pub fn set_all(&mut self) {
+        for word in &mut self.bits {
+            *word = !0;
+        }
+
+        let tail = self.pages % 64;
+        if tail != 0 {
+            if let Some(last) = self.bits.last_mut() {
+                *last = (1 << tail) - 1;
+            }
+        }
+    }
+
+    /// Splits the slot into ranges of at most `chunk` pages, in the form
+    /// `(first_page, num_pages)`, suitable for [`DirtyBitmap::clear`].  The
+    /// chunk size is rounded up to a multiple of 64.
+    pub fn chunks(&self, chunk: u64) -> Vec<(u64, u64)> {
+        let chunk = chunk.max(1).div_ceil(64) * 64;
+        let mut out = vec![];
+        let mut first = 0;
+        while first < self.pages {
+            let num = chunk.min(self.pages - first);
+            out.push((first, num));
+            first += num;
+        }
+
+        out
+    }
+
+    /// Clears (and write protects again) the pages marked dirty in this
+    /// bitmap, in the range `first_page..first_page + num_pages`.  Pages
+    /// outside of the range are left alone; this lets migration code copy
+    /// and clear a large slot in pieces.  Returns `EINVAL` if the range is
+    /// not aligned the way KVM expects it to be, or if it lies outside of
+    /// the slot.
+    ///
+    /// # Safety
+    /// `fd` must be the file descriptor of the VM the bitmap's slot is in.
+    ///
+    /// # Support
+    /// See [`kvm_clear_dirty_log`].  This is available only on the VM file
+    /// descriptor, and only after [`enable_manual_protect`].
+    pub unsafe fn clear(&mut self, fd: RawFd, first_page: u64, num_pages: u64) -> nix::Result<i32> {
+        let end = first_page
+            .checked_add(num_pages)
+            .ok_or(nix::Error::Sys(Errno::EINVAL))?;
+        if !first_page.is_multiple_of(64)
+            || (!num_pages.is_multiple_of(64) && end != self.pages)
+            || end > self.pages
+            || num_pages > u64::from(u32::MAX)
+        {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let log = ClearDirtyLog {
+            slot: self.slot,
+            num_pages: num_pages as u32,
+            first_page,
+            value: DirtyLogValue {
+                dirty_bitmap: self.bits.as_mut_ptr().add((first_page / 64) as usize),
+            },
+        };
+
+        kvm_clear_dirty_log(fd, &log)
+    }
+}
+
+/// An iterator over the dirty pages of a [`DirtyBitmap`].
+pub struct DirtyPages<'a> {
+    bitmap: &'a DirtyBitmap,
+    page: u64,
+}
+
+impl<'a> Iterator for DirtyPages<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.page < self.bitmap.pages {
+            let word = self.bitmap.bits[(self.page / 64) as usize] >> (self.page % 64);
+            if word == 0 {
+                self.page = (self.page / 64 + 1) * 64;
+                continue;
+            }
+
+            let page = self.page + u64::from(word.trailing_zeros());
+            self.page = page + 1;
+            if page < self.bitmap.pages {
+                return Some(page);
+            }
+        }
+
+        None
+    }
+}
+
+/// Enables the per-vCPU dirty ring on the given VM, with each ring being
+/// `bytes` in size.  The size must be a power of two, at least a page, and
+/// no larger than the value [`kvm_check_extension`] returns for
+/// [`KVM_CAP_DIRTY_LOG_RING`].  This must be done before any vCPU is
+/// created.
+///
+/// While the ring is enabled, a vCPU whose ring is full exits with
+/// [`KVM_EXIT_DIRTY_RING_FULL`] (which
+/// [`MappedRun::run`](../run/struct.MappedRun.html#method.run) returns as
+/// `RunResult::DirtyRingFull`); userspace must harvest its ring, and call
+/// [`kvm_reset_dirty_rings`], before running it again.
+///
+/// # Safety
+/// `fd` must be a VM file descriptor.
+///
+/// # Support
+/// This requires the [`KVM_CAP_DIRTY_LOG_RING`] capability, and is
+/// available only on the VM file descriptor.
+pub unsafe fn enable_dirty_ring(fd: RawFd, bytes: u32) -> nix::Result<i32> {
+    let mut cap: EnableCap = ::std::mem::zeroed();
+    cap.cap = KVM_CAP_DIRTY_LOG_RING;
+    cap.args[0] = u64::from(bytes);
+
+    kvm_enable_cap(fd, &cap)
+}
+
+/// The dirty ring of a single vCPU, mapped into this process.  Entries are
+/// pushed by KVM and harvested in order; the ring is unmapped on drop.
+#[derive(Debug)]
+pub struct DirtyRing {
+    gfns: *mut DirtyGfn,
+    entries: u32,
+    next: u32,
+}
+
+unsafe impl Send for DirtyRing {}
+
+impl DirtyRing {
+    /// Maps the dirty ring of the given vCPU, with the ring being `bytes`
+    /// in size (as passed to [`enable_dirty_ring`]).
+    ///
+    /// # Safety
+    /// `fd` must be a vCPU file descriptor, of a VM whose dirty ring was
+    /// enabled with `bytes`; KVM writes to the mapping for as long as it
+    /// exists.
+    ///
+    /// # Support
+    /// This requires the [`KVM_CAP_DIRTY_LOG_RING`] capability to have been
+    /// enabled on the VM, and is available only on the vCPU file
+    /// descriptor.
+    pub unsafe fn map(fd: RawFd, bytes: u32) -> nix::Result<DirtyRing> {
+        let entries = bytes as usize / size_of::<DirtyGfn>();
+        if entries == 0 || !entries.is_power_of_two() {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as i64;
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            bytes as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            KVM_DIRTY_LOG_PAGE_OFFSET * page,
+        );
+
+        if addr == libc::MAP_FAILED {
+            return Err(nix::Error::Sys(Errno::last()));
+        }
+
+        Ok(DirtyRing {
+            gfns: addr as *mut DirtyGfn,
+            entries: entries as u32,
+            next: 0,
+        })
+    }
+
+    /// The number of entries in the ring.
+    pub fn entries(&self) -> u32 {
+        self.entries
+    }
+
+    /// Collects every dirty entry currently published in the ring, calling
+    /// `f` with the slot and page offset of each, and marks them as
+    /// harvested.  Returns the number of entries collected.  The pages are
+    /// not write protected again until [`kvm_reset_dirty_rings`] is called
+    /// on the VM.
+    pub fn harvest<F: FnMut(u32, u64)>(&mut self, mut f: F) -> u32 {
+        let mut count = 0;
+        loop {
+            let gfn = unsafe { self.gfns.add((self.next & (self.entries - 1)) as usize) };
+            let flags = unsafe { &*(ptr::addr_of_mut!((*gfn).flags) as *const AtomicU32) };
+
+            // KVM publishes the entry by setting the dirty flag last, so the
+            // rest of the entry is only valid once we've observed it.
+            if flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                break;
+            }
+
+            let (slot, offset) = unsafe { ((*gfn).slot, (*gfn).offset) };
+            f(slot, offset);
+            flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+            self.next = self.next.wrapping_add(1);
+            count += 1;
+        }
+
+        count
+    }
+}
+
+impl Drop for DirtyRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.gfns as *mut libc::c_void,
+                self.entries as usize * size_of::<DirtyGfn>(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap(pages: u64) -> DirtyBitmap {
+        DirtyBitmap::new(&UserspaceMemoryRegion {
+            slot: 1,
+            flags: 0,
+            guest_phys_addr: 0x10_0000,
+            memory_size: pages * PAGE_SIZE,
+            userspace_addr: 0,
+        })
+    }
+
+    #[test]
+    fn sizes() {
+        let mut region = UserspaceMemoryRegion {
+            slot: 1 << 16 | 2,
+            flags: 0,
+            guest_phys_addr: 0,
+            memory_size: 5 * PAGE_SIZE + 1,
+            userspace_addr: 0,
+        };
+        let partial = DirtyBitmap::new(&region);
+        assert_eq!(partial.slot(), 1 << 16 | 2);
+        assert_eq!(partial.pages(), 6);
+        assert_eq!(partial.bits(), &[0]);
+
+        region.memory_size = 129 * PAGE_SIZE;
+        assert_eq!(DirtyBitmap::new(&region).bits().len(), 3);
+        region.memory_size = 128 * PAGE_SIZE;
+        assert_eq!(DirtyBitmap::new(&region).bits().len(), 2);
+    }
+
+    #[test]
+    fn set_all() {
+        let mut bitmap = bitmap(130);
+        assert_eq!(bitmap.count(), 0);
+        bitmap.set_all();
+        // the bits past the end of the slot stay clear.
+        assert_eq!(bitmap.bits(), &[!0, !0, 0b11]);
+        assert_eq!(bitmap.count(), 130);
+        assert!(bitmap.is_dirty(129));
+        assert!(!bitmap.is_dirty(130));
+        assert!(!bitmap.is_dirty(u64::MAX));
+
+        let mut whole = self::bitmap(128);
+        whole.set_all();
+        assert_eq!(whole.bits(), &[!0, !0]);
+    }
+
+    #[test]
+    fn iter() {
+        let mut bitmap = bitmap(192);
+        bitmap.bits = vec![0b1001, 0, 1 << 63];
+        assert_eq!(bitmap.iter().collect::<Vec<_>>(), vec![0, 3, 191]);
+        assert_eq!(bitmap.iter_from(1).collect::<Vec<_>>(), vec![3, 191]);
+        assert_eq!(bitmap.iter_from(4).collect::<Vec<_>>(), vec![191]);
+        assert_eq!(bitmap.iter_from(191).collect::<Vec<_>>(), vec![191]);
+        assert_eq!(bitmap.iter_from(192).count(), 0);
+        assert_eq!(bitmap.count(), 3);
+
+        // stray bits past the end of the slot aren't pages.
+        let mut short = self::bitmap(100);
+        short.bits = vec![0, 1 << 40 | 1 << 35];
+        assert_eq!(short.iter().collect::<Vec<_>>(), vec![99]);
+    }
+
+    #[test]
+    fn chunks() {
+        let bitmap = bitmap(130);
+        assert_eq!(bitmap.chunks(100), vec![(0, 128), (128, 2)]);
+        assert_eq!(bitmap.chunks(0), vec![(0, 64), (64, 64), (128, 2)]);
+        assert_eq!(bitmap.chunks(1 << 20), vec![(0, 130)]);
+        assert_eq!(self::bitmap(0).chunks(64), vec![]);
+    }
+
+    #[test]
+    fn clear_checks_ranges() {
+        let mut bitmap = bitmap(130);
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        unsafe {
+            assert_eq!(bitmap.clear(-1, 1, 64), Err(einval));
+            assert_eq!(bitmap.clear(-1, 0, 63), Err(einval));
+            assert_eq!(bitmap.clear(-1, 128, 64), Err(einval));
+            assert_eq!(bitmap.clear(-1, 192, 0), Err(einval));
+            assert_eq!(bitmap.clear(-1, 64, u64::MAX - 63), Err(einval));
+
+            // these get as far as KVM.
+            let ebadf = nix::Error::Sys(Errno::EBADF);
+            for &(first, num) in &bitmap.chunks(64) {
+                assert_eq!(bitmap.clear(-1, first, num), Err(ebadf));
+            }
+            assert_eq!(bitmap.clear(-1, 0, 130), Err(ebadf));
+        }
+    }
+}
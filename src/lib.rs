@@ -1,14 +1,31 @@
 #[macro_use]
 extern crate nix;
 extern crate libc;
+#[cfg(feature = "serde")]
+extern crate serde;
 #[macro_use]
 extern crate log;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
+pub mod acpi;
 mod consts;
 mod ctl;
+pub mod devices;
+pub mod dirty;
+pub mod loader;
+pub mod memory;
+pub mod migration;
+pub mod mptable;
 pub mod run;
+pub mod snapshot;
+pub mod state;
+pub mod vm;
 pub mod x86;
 
 pub use self::consts::*;
 pub use self::ctl::*;
-pub use self::run::{Exit, Run};
+pub use self::run::{Exit, MappedRun, Run, RunResult, VmEvents};
+pub use self::vm::Vm;
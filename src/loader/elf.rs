@@ -0,0 +1,524 @@
+//! Loading ELF kernels, and booting them through the PVH entry point.
+//!
+//! [`load_segments`] copies the `PT_LOAD` segments of an ELF32 or ELF64
+//! image to their physical addresses.  [`load`] goes on to boot the kernel
+//! the way Xen's PVH boot protocol does, which Linux (as a `vmlinux`) and
+//! a number of unikernels support: the kernel names a 32-bit entry point
+//! in an `XEN_ELFNOTE_PHYS32_ENTRY` note, and is entered there in flat
+//! protected mode, with paging off and `ebx` pointing to an
+//! `hvm_start_info` structure at [`BOOT_INFO_ADDR`].
+
+use super::{
+    flat_gdt, in_ram, invalid, le, tables, write_cmdline, E820Entry, Entry, BOOT_INFO_ADDR,
+    CMDLINE_ADDR, CMDLINE_MAX, HIGH_MEMORY,
+};
+use memory::GuestMemory;
+use state::io_error;
+use std::convert::TryFrom;
+use std::io;
+use x86::mode::{CODE32_SELECTOR, DATA_SELECTOR};
+
+/// A loadable segment.
+pub const PT_LOAD: u32 = 1;
+/// A segment of notes.
+pub const PT_NOTE: u32 = 4;
+
+/// The type of the Xen note holding the 32-bit PVH entry point.
+pub const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+/// The `magic` of `hvm_start_info`.
+pub const HVM_START_MAGIC: u32 = 0x336e_c578;
+
+/// The most memory map entries that fit after `hvm_start_info`.
+pub const MEMMAP_MAX: usize = 128;
+
+const EM_386: u64 = 3;
+const EM_X86_64: u64 = 62;
+
+// the layout of the boot information, from `BOOT_INFO_ADDR`: the 56 byte
+// `hvm_start_info`, the module list, and the memory map.
+const MODLIST_OFFSET: u64 = 0x40;
+const MEMMAP_OFFSET: u64 = 0x80;
+
+/// What [`load_segments`] found in an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Elf {
+    /// Whether the image is ELF64, rather than ELF32.
+    pub elf64: bool,
+    /// The entry point in the ELF header.
+    pub entry: u64,
+    /// The 32-bit PVH entry point, if the image has one.
+    pub pvh_entry: Option<u64>,
+    /// The lowest physical address loaded.
+    pub start: u64,
+    /// The end of the highest physical address loaded.
+    pub end: u64,
+}
+
+/// A program header, reduced to what matters here.
+struct Segment {
+    kind: u32,
+    offset: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+/// Copies each `PT_LOAD` segment of an x86 ELF image into guest memory at
+/// its physical address, zeroing whatever part of it isn't in the file.
+/// `e820` is the memory map for the guest.  Fails with `InvalidData` if
+/// the image is malformed, or a segment is below [`HIGH_MEMORY`] (where
+/// the boot information goes) or isn't in RAM.
+pub fn load_segments(memory: &GuestMemory, image: &[u8], e820: &[E820Entry]) -> io::Result<Elf> {
+    if image.get(..4) != Some(&b"\x7fELF"[..]) {
+        return Err(invalid("not an ELF image"));
+    }
+    let elf64 = match le(image, 4, 1)? {
+        1 => false,
+        2 => true,
+        _ => return Err(invalid("unknown ELF class")),
+    };
+    if le(image, 5, 1)? != 1 {
+        return Err(invalid("not a little endian ELF image"));
+    }
+    match (le(image, 0x12, 2)?, elf64) {
+        (EM_386, false) | (EM_X86_64, true) => (),
+        _ => return Err(invalid("not an x86 ELF image")),
+    }
+
+    let (entry, phoff, phentsize, phnum) = if elf64 {
+        (
+            le(image, 0x18, 8)?,
+            le(image, 0x20, 8)?,
+            le(image, 0x36, 2)?,
+            le(image, 0x38, 2)?,
+        )
+    } else {
+        (
+            le(image, 0x18, 4)?,
+            le(image, 0x1c, 4)?,
+            le(image, 0x2a, 2)?,
+            le(image, 0x2c, 2)?,
+        )
+    };
+
+    let mut elf = Elf {
+        elf64,
+        entry,
+        pvh_entry: None,
+        start: u64::MAX,
+        end: 0,
+    };
+    for i in 0..phnum {
+        let at = i
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .filter(|&at| at < image.len() as u64)
+            .ok_or_else(|| invalid("a program header is outside of the image"))?;
+        let segment = program_header(image, at, elf64)?;
+        let data = usize::try_from(segment.offset)
+            .ok()
+            .and_then(|start| image.get(start..start.checked_add(segment.filesz as usize)?))
+            .ok_or_else(|| invalid("a segment is outside of the image"))?;
+
+        match segment.kind {
+            PT_LOAD if segment.memsz != 0 => {
+                if segment.filesz > segment.memsz {
+                    return Err(invalid("a segment is smaller than its data"));
+                }
+                if segment.paddr < HIGH_MEMORY {
+                    return Err(invalid("segments must be loaded at or above 1 MiB"));
+                }
+                if !in_ram(e820, segment.paddr, segment.memsz)
+                    || !memory.contains(segment.paddr, segment.memsz)
+                {
+                    return Err(invalid("a segment doesn't fit in guest memory"));
+                }
+                memory.write(segment.paddr, data).map_err(io_error)?;
+                memory
+                    .zero(
+                        segment.paddr + segment.filesz,
+                        segment.memsz - segment.filesz,
+                    )
+                    .map_err(io_error)?;
+
+                elf.start = elf.start.min(segment.paddr);
+                elf.end = elf.end.max(segment.paddr + segment.memsz);
+            }
+            PT_NOTE => {
+                if let Some(entry) = pvh_entry(data)? {
+                    elf.pvh_entry = Some(entry);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if elf.end == 0 {
+        return Err(invalid("the ELF image has nothing to load"));
+    }
+
+    Ok(elf)
+}
+
+/// Loads an ELF kernel with a PVH entry point, with a command line and
+/// optionally an initrd (passed as the first module), and returns the PVH
+/// entry.  `e820` is the memory map for the guest (see
+/// [`ram_map`](../fn.ram_map.html)).  Fails with `InvalidData` if the
+/// kernel has no `XEN_ELFNOTE_PHYS32_ENTRY` note, or the initrd, which
+/// goes right after the kernel, isn't in RAM there.
+pub fn load(
+    memory: &GuestMemory,
+    image: &[u8],
+    cmdline: &str,
+    initrd: Option<&[u8]>,
+    e820: &[E820Entry],
+) -> io::Result<Entry> {
+    let elf = load_segments(memory, image, e820)?;
+    let entry = elf
+        .pvh_entry
+        .ok_or_else(|| invalid("the kernel has no PVH entry point"))?;
+    if e820.len() > MEMMAP_MAX {
+        return Err(invalid("too many memory map entries for hvm_start_info"));
+    }
+
+    let info = BOOT_INFO_ADDR;
+    memory.zero(info, MEMMAP_OFFSET).map_err(io_error)?;
+    write_cmdline(memory, cmdline, CMDLINE_MAX)?;
+
+    let mut modules = 0;
+    if let Some(initrd) = initrd {
+        // right after the kernel, page aligned.
+        let addr = (elf.end + 0xfff) & !0xfff;
+        let len = initrd.len() as u64;
+        if !in_ram(e820, addr, len) || !memory.contains(addr, len) {
+            return Err(invalid("the initrd doesn't fit in RAM"));
+        }
+        memory.write(addr, initrd).map_err(io_error)?;
+
+        let module = info + MODLIST_OFFSET;
+        memory.write_u64(module, addr).map_err(io_error)?;
+        memory
+            .write_u64(module + 8, initrd.len() as u64)
+            .map_err(io_error)?;
+        modules = 1;
+    }
+
+    for (i, e) in e820.iter().enumerate() {
+        let at = info + MEMMAP_OFFSET + i as u64 * 24;
+        memory.write_u64(at, e.addr).map_err(io_error)?;
+        memory.write_u64(at + 8, e.size).map_err(io_error)?;
+        memory.write_u32(at + 16, e.kind).map_err(io_error)?;
+        memory.write_u32(at + 20, 0).map_err(io_error)?;
+    }
+
+    let fields = [
+        (0, HVM_START_MAGIC.into(), 4),
+        (4, 1, 4), // version
+        (12, modules, 4),
+        (16, info + MODLIST_OFFSET, 8),
+        (24, CMDLINE_ADDR, 8),
+        (40, info + MEMMAP_OFFSET, 8),
+        (48, e820.len() as u64, 4),
+    ];
+    for &(offset, value, size) in &fields {
+        let at = info + offset;
+        match size {
+            4 => memory.write_u32(at, value as u32),
+            _ => memory.write_u64(at, value),
+        }
+        .map_err(io_error)?;
+    }
+
+    let (gdt, cs, data) = flat_gdt(CODE32_SELECTOR, DATA_SELECTOR, false)?;
+    let mut boot = tables(memory, gdt, cs, data)?;
+    boot.regs.rip = entry;
+    boot.regs.rbx = info;
+    Ok(boot)
+}
+
+/// An ELF32 image for tests, entered at `entry`, with a `PT_LOAD` segment
+/// for each `(paddr, data, memsz)`, and a `PT_NOTE` segment holding `notes`
+/// unless it is empty.  Each segment's data is 16-byte aligned in the file.
+#[cfg(test)]
+pub(crate) fn test_image(entry: u32, segments: &[(u32, &[u8], u32)], notes: &[u8]) -> Vec<u8> {
+    let mut headers = segments
+        .iter()
+        .map(|&(paddr, data, memsz)| (PT_LOAD, paddr, data, memsz))
+        .collect::<Vec<_>>();
+    if !notes.is_empty() {
+        headers.push((PT_NOTE, 0, notes, 0));
+    }
+
+    let mut image = vec![0; 52 + 32 * headers.len()];
+    image[..7].copy_from_slice(b"\x7fELF\x01\x01\x01");
+    image[0x10] = 2; // executable
+    image[0x12] = EM_386 as u8;
+    image[0x14] = 1;
+    image[0x18..0x1c].copy_from_slice(&entry.to_le_bytes());
+    image[0x1c] = 52;
+    image[0x28] = 52;
+    image[0x2a] = 32;
+    image[0x2c] = headers.len() as u8;
+    for (i, &(kind, paddr, data, memsz)) in headers.iter().enumerate() {
+        let offset = (image.len() + 15) & !15;
+        image.resize(offset, 0);
+        image.extend_from_slice(data);
+
+        let fields = [kind, offset as u32, paddr, paddr, data.len() as u32, memsz];
+        for (j, field) in fields.iter().enumerate() {
+            let at = 52 + 32 * i + 4 * j;
+            image[at..at + 4].copy_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    image
+}
+
+fn program_header(image: &[u8], at: u64, elf64: bool) -> io::Result<Segment> {
+    Ok(if elf64 {
+        Segment {
+            kind: le(image, at, 4)? as u32,
+            offset: le(image, at + 0x08, 8)?,
+            paddr: le(image, at + 0x18, 8)?,
+            filesz: le(image, at + 0x20, 8)?,
+            memsz: le(image, at + 0x28, 8)?,
+        }
+    } else {
+        Segment {
+            kind: le(image, at, 4)? as u32,
+            offset: le(image, at + 0x04, 4)?,
+            paddr: le(image, at + 0x0c, 4)?,
+            filesz: le(image, at + 0x10, 4)?,
+            memsz: le(image, at + 0x14, 4)?,
+        }
+    })
+}
+
+/// Looks for the PVH entry point in a segment of notes.
+fn pvh_entry(notes: &[u8]) -> io::Result<Option<u64>> {
+    let align = |n: u64| (n + 3) & !3;
+    let mut at = 0;
+    while at + 12 <= notes.len() as u64 {
+        let namesz = le(notes, at, 4)?;
+        let descsz = le(notes, at + 4, 4)?;
+        let kind = le(notes, at + 8, 4)? as u32;
+        let name = at + 12;
+        let desc = name + align(namesz);
+
+        let is_xen = notes.get(name as usize..(name + namesz) as usize) == Some(&b"Xen\0"[..]);
+        if is_xen && kind == XEN_ELFNOTE_PHYS32_ENTRY {
+            return match descsz {
+                4 | 8 => le(notes, desc, descsz as usize).map(Some),
+                _ => Err(invalid("malformed PVH entry note")),
+            };
+        }
+
+        at = desc + align(descsz);
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ram_map, E820_RAM};
+    use super::*;
+    use memory::anonymous_memory;
+
+    const MEMORY_SIZE: u64 = 16 << 20;
+    const KERNEL: u32 = 0x10_0000;
+
+    /// A note with the name `name` and the type `kind`.
+    fn note(name: &[u8], kind: u32, desc: &[u8]) -> Vec<u8> {
+        let mut note = vec![];
+        for &field in &[name.len() as u32, desc.len() as u32, kind] {
+            note.extend_from_slice(&field.to_le_bytes());
+        }
+        for part in &[name, desc] {
+            note.extend_from_slice(part);
+            note.resize((note.len() + 3) & !3, 0);
+        }
+        note
+    }
+
+    /// The PVH entry point note.
+    fn pvh_note(entry: u32) -> Vec<u8> {
+        note(b"Xen\0", XEN_ELFNOTE_PHYS32_ENTRY, &entry.to_le_bytes())
+    }
+
+    /// A kernel of a page of text and a page of bss, entered through PVH
+    /// at `KERNEL + 0x10`.
+    fn kernel() -> Vec<u8> {
+        let mut notes = note(b"GNU\0", 3, &[0; 20]);
+        notes.extend_from_slice(&pvh_note(KERNEL + 0x10));
+        test_image(KERNEL, &[(KERNEL, b"kernel text", 0x2000)], &notes)
+    }
+
+    fn fails(result: io::Result<Elf>) {
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn segments() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        memory.write(u64::from(KERNEL), &[0xff; 0x2000]).unwrap();
+        memory.write(0x20_0000, &[0xff; 0x10]).unwrap();
+        let image = test_image(
+            KERNEL + 4,
+            &[(KERNEL, b"text", 0x1000), (0x20_0000, b"data", 0x10)],
+            &[],
+        );
+        let elf = load_segments(&memory, &image, &e820).unwrap();
+        assert_eq!(
+            elf,
+            Elf {
+                elf64: false,
+                entry: u64::from(KERNEL) + 4,
+                pvh_entry: None,
+                start: u64::from(KERNEL),
+                end: 0x20_0010,
+            }
+        );
+
+        // what isn't in the file is zeroed, and nothing past the segment is.
+        let mut text = [0; 0x1004];
+        memory.read(u64::from(KERNEL), &mut text).unwrap();
+        assert_eq!(&text[..4], b"text");
+        assert!(text[4..0x1000].iter().all(|&b| b == 0));
+        assert_eq!(&text[0x1000..], &[0xff; 4]);
+        let mut data = [0; 0x10];
+        memory.read(0x20_0000, &mut data).unwrap();
+        assert_eq!(&data[..4], b"data");
+
+        let elf = load_segments(&memory, &kernel(), &e820).unwrap();
+        assert_eq!(elf.pvh_entry, Some(u64::from(KERNEL) + 0x10));
+        assert_eq!(elf.end, u64::from(KERNEL) + 0x2000);
+    }
+
+    #[test]
+    fn bad_images() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let image = kernel();
+        let with = |at: usize, byte: u8| {
+            let mut image = image.clone();
+            image[at] = byte;
+            image
+        };
+
+        fails(load_segments(&memory, b"\x7fELG", &e820));
+        fails(load_segments(&memory, &with(4, 3), &e820));
+        fails(load_segments(&memory, &with(5, 2), &e820));
+        fails(load_segments(&memory, &with(0x12, EM_X86_64 as u8), &e820));
+        fails(load_segments(&memory, &image[..image.len() - 1], &e820));
+        fails(load_segments(&memory, &image[..0x40], &e820));
+        fails(load_segments(&memory, &test_image(0, &[], &[]), &e820));
+
+        // the program headers must be in the image.
+        let mut far = image.clone();
+        far[0x1c..0x20].copy_from_slice(&u32::MAX.to_le_bytes());
+        fails(load_segments(&memory, &far, &e820));
+
+        // segments must be in RAM above 1 MiB, and no smaller than their data.
+        let outside = test_image(0, &[(MEMORY_SIZE as u32 - 8, b"0123456789", 16)], &[]);
+        fails(load_segments(&memory, &outside, &e820));
+        let low = test_image(0, &[(KERNEL - 0x1000, b"text", 4)], &[]);
+        fails(load_segments(&memory, &low, &e820));
+        let one_page = [E820Entry {
+            addr: u64::from(KERNEL),
+            size: 0x1000,
+            kind: E820_RAM,
+        }];
+        let past_ram = test_image(0, &[(KERNEL, b"text", 0x2000)], &[]);
+        fails(load_segments(&memory, &past_ram, &one_page));
+        let short = test_image(0, &[(KERNEL, b"0123456789", 8)], &[]);
+        fails(load_segments(&memory, &short, &e820));
+
+        let short_desc = note(b"Xen\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0; 2]);
+        let malformed = test_image(0, &[(KERNEL, b"text", 4)], &short_desc);
+        fails(load_segments(&memory, &malformed, &e820));
+        // the entry note is Xen's.
+        let other = note(b"Xem\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0; 4]);
+        let image = test_image(0, &[(KERNEL, b"text", 4)], &other);
+        assert_eq!(
+            load_segments(&memory, &image, &e820).unwrap().pvh_entry,
+            None
+        );
+    }
+
+    #[test]
+    fn load_pvh() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let boot = load(&memory, &kernel(), "console=ttyS0", Some(b"initrd"), &e820).unwrap();
+        assert_eq!(boot.regs.rip, u64::from(KERNEL) + 0x10);
+        assert_eq!(boot.regs.rbx, BOOT_INFO_ADDR);
+        assert_eq!(boot.cr3, None);
+        assert_eq!(boot.cs.l, 0);
+        assert_eq!(boot.cs.db, 1);
+
+        let info = BOOT_INFO_ADDR;
+        let read32 = |addr| memory.read_u32(addr).unwrap();
+        let read64 = |addr| memory.read_u64(addr).unwrap();
+        assert_eq!(read32(info), HVM_START_MAGIC);
+        assert_eq!(read32(info + 4), 1);
+        assert_eq!(read32(info + 12), 1);
+        assert_eq!(read64(info + 24), CMDLINE_ADDR);
+        assert_eq!(read32(info + 48), e820.len() as u32);
+
+        // the initrd is the first module, on the page after the bss.
+        let module = read64(info + 16);
+        let initrd = u64::from(KERNEL) + 0x2000;
+        assert_eq!(read64(module), initrd);
+        assert_eq!(read64(module + 8), 6);
+        let mut data = [0; 6];
+        memory.read(initrd, &mut data).unwrap();
+        assert_eq!(&data, b"initrd");
+
+        let memmap = read64(info + 40);
+        for (i, e) in e820.iter().enumerate() {
+            let at = memmap + 24 * i as u64;
+            assert_eq!((read64(at), read64(at + 8)), (e.addr, e.size));
+            assert_eq!(read32(at + 16), e.kind);
+        }
+
+        let mut cmdline = [0; 14];
+        memory.read(CMDLINE_ADDR, &mut cmdline).unwrap();
+        assert_eq!(&cmdline, b"console=ttyS0\0");
+    }
+
+    #[test]
+    fn load_checks() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let no_entry = test_image(KERNEL, &[(KERNEL, b"text", 4)], &[]);
+        let err = load(&memory, &no_entry, "", None, &e820).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let too_many = vec![e820[0]; MEMMAP_MAX + 1];
+        assert!(load(&memory, &kernel(), "", None, &too_many).is_err());
+
+        // an initrd past the end of RAM, though it is in guest memory.
+        let initrd = vec![0; 0x10_0000];
+        let ram = [
+            E820Entry {
+                addr: 0,
+                size: 0x9_fc00,
+                kind: E820_RAM,
+            },
+            E820Entry {
+                addr: 0x10_0000,
+                size: 0x10_0000,
+                kind: E820_RAM,
+            },
+        ];
+        let err = load(&memory, &kernel(), "", Some(&initrd), &ram).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(load(&memory, &kernel(), "", Some(&initrd[..0xe000]), &ram).is_ok());
+        assert!(load(&memory, &kernel(), "", Some(&initrd), &e820).is_ok());
+
+        let beyond = vec![0; MEMORY_SIZE as usize];
+        assert!(load(&memory, &kernel(), "", Some(&beyond), &e820).is_err());
+    }
+}
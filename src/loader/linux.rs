@@ -0,0 +1,482 @@
+//! Loading a Linux `bzImage`, following the x86 boot protocol.
+//!
+//! The kernel is entered at its 64-bit entry point, in long mode, as
+//! described in the kernel's `Documentation/x86/boot.rst`: `rsi` points to
+//! the `boot_params` (the "zero page") at [`BOOT_INFO_ADDR`], the low 4 GiB
+//! are identity mapped, and the segments are flat, with `cs` at
+//! [`BOOT_CS`] and the rest at [`BOOT_DS`].  This needs a kernel speaking
+//! version 2.12 of the protocol or later, built for x86-64.
+
+use super::{
+    flat_gdt, identity_map, in_ram, invalid, place_high, tables, write_cmdline, E820Entry, Entry,
+    BOOT_INFO_ADDR, CMDLINE_ADDR, HIGH_MEMORY,
+};
+use memory::GuestMemory;
+use state::io_error;
+use std::io;
+
+/// The code segment selector the kernel expects.
+pub const BOOT_CS: u16 = 0x10;
+/// The data segment selector the kernel expects.
+pub const BOOT_DS: u16 = 0x18;
+
+/// The oldest protocol version with a 64-bit entry point.
+pub const MIN_VERSION: u16 = 0x20c;
+
+/// The protected mode kernel is loaded high (always set for a `bzImage`).
+pub const LOADED_HIGH: u8 = 1 << 0;
+/// The kernel has the 64-bit entry point, at 0x200 into it.
+pub const XLF_KERNEL_64: u16 = 1 << 0;
+/// The kernel and the initrd may be loaded above 4 GiB.
+pub const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+
+/// The most E820 entries that fit in `boot_params`.
+pub const E820_MAX: usize = 128;
+
+const BOOT_FLAG: u16 = 0xaa55;
+const HEADER_MAGIC: &[u8; 4] = b"HdrS";
+/// Where the setup header starts, both in the image and in `boot_params`.
+const HEADER_START: usize = 0x1f1;
+/// The end of the fields read from the header.
+const HEADER_MIN_END: usize = 0x264;
+const ENTRY_64: u64 = 0x200;
+
+// offsets into `boot_params`.
+const EXT_RAMDISK_IMAGE: u64 = 0x0c0;
+const EXT_RAMDISK_SIZE: u64 = 0x0c4;
+const EXT_CMD_LINE_PTR: u64 = 0x0c8;
+const E820_ENTRIES: u64 = 0x1e8;
+const TYPE_OF_LOADER: u64 = 0x210;
+const CODE32_START: u64 = 0x214;
+const RAMDISK_IMAGE: u64 = 0x218;
+const RAMDISK_SIZE: u64 = 0x21c;
+const CMD_LINE_PTR: u64 = 0x228;
+const E820_TABLE: u64 = 0x2d0;
+const BOOT_PARAMS_SIZE: u64 = 0x1000;
+
+/// The fields of a `bzImage`'s setup header that matter for loading it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetupHeader {
+    /// The size of the real mode setup code, in 512-byte sectors, not
+    /// counting the boot sector.
+    pub setup_sects: u8,
+    /// The boot protocol version, e.g. `0x20f` for 2.15.
+    pub version: u16,
+    pub loadflags: u8,
+    pub xloadflags: u16,
+    /// Whether the kernel may be loaded somewhere other than
+    /// `pref_address`.
+    pub relocatable: bool,
+    pub kernel_alignment: u32,
+    /// Where the kernel prefers to be loaded.
+    pub pref_address: u64,
+    /// The memory the kernel needs from where it's loaded, to decompress
+    /// and initialize itself.
+    pub init_size: u32,
+    /// The longest command line, not counting the terminating NUL.
+    pub cmdline_size: u32,
+    /// The highest address the initrd may occupy.
+    pub initrd_addr_max: u32,
+    /// The length of the setup header in the image, from its start at
+    /// offset 0x1f1.
+    pub len: usize,
+}
+
+impl SetupHeader {
+    /// Parses the setup header of a `bzImage`.  Fails with `InvalidData`
+    /// if the image isn't one, or doesn't support the 64-bit entry point.
+    pub fn parse(image: &[u8]) -> io::Result<SetupHeader> {
+        if image.len() < HEADER_MIN_END
+            || le16(image, 0x1fe) != BOOT_FLAG
+            || &image[0x202..0x206] != HEADER_MAGIC
+        {
+            return Err(invalid("not a bzImage"));
+        }
+
+        // the header ends where the jump at its start lands.
+        let len = 0x202 + usize::from(image[0x201]) - HEADER_START;
+        let header = SetupHeader {
+            setup_sects: match image[0x1f1] {
+                0 => 4,
+                n => n,
+            },
+            version: le16(image, 0x206),
+            loadflags: image[0x211],
+            xloadflags: le16(image, 0x236),
+            relocatable: image[0x234] != 0,
+            kernel_alignment: le32(image, 0x230),
+            pref_address: u64::from(le32(image, 0x258)) | u64::from(le32(image, 0x25c)) << 32,
+            init_size: le32(image, 0x260),
+            cmdline_size: le32(image, 0x238),
+            initrd_addr_max: le32(image, 0x22c),
+            len,
+        };
+
+        if header.version < MIN_VERSION
+            || header.xloadflags & XLF_KERNEL_64 == 0
+            || HEADER_START + len < HEADER_MIN_END
+        {
+            return Err(invalid("the kernel has no 64-bit entry point"));
+        }
+        if header.loadflags & LOADED_HIGH == 0 {
+            return Err(invalid("the kernel is a zImage, which isn't supported"));
+        }
+        if header.kernel_offset() > image.len() || HEADER_START + len > image.len() {
+            return Err(invalid("the bzImage is truncated"));
+        }
+
+        Ok(header)
+    }
+
+    /// The offset of the protected mode kernel in the image.
+    pub fn kernel_offset(&self) -> usize {
+        (usize::from(self.setup_sects) + 1) * 512
+    }
+
+    /// Where the protected mode kernel gets loaded: `pref_address`, unless
+    /// the kernel is relocatable, in which case it is the first address at
+    /// or above [`HIGH_MEMORY`] aligned to `kernel_alignment`.
+    pub fn load_addr(&self) -> u64 {
+        if self.relocatable {
+            let align = u64::from(self.kernel_alignment).max(1);
+            HIGH_MEMORY.div_ceil(align) * align
+        } else {
+            self.pref_address
+        }
+    }
+}
+
+/// Loads a `bzImage`, with a command line and optionally an initrd, and
+/// returns the 64-bit entry.  `e820` is the memory map for the guest (see
+/// [`ram_map`](../fn.ram_map.html)); the initrd is put as high in RAM as
+/// the kernel allows, above the kernel if it fits there.  Fails with
+/// `InvalidData` if the kernel is to be loaded below [`HIGH_MEMORY`],
+/// where the boot information and command line go, if the `init_size`
+/// bytes it needs there aren't all RAM, or if they go past 4 GiB, since
+/// `code32_start` is 32 bits.
+pub fn load(
+    memory: &GuestMemory,
+    image: &[u8],
+    cmdline: &str,
+    initrd: Option<&[u8]>,
+    e820: &[E820Entry],
+) -> io::Result<Entry> {
+    let header = SetupHeader::parse(image)?;
+    if e820.len() > E820_MAX {
+        return Err(invalid("too many E820 entries for boot_params"));
+    }
+
+    let kernel = &image[header.kernel_offset()..];
+    let load = header.load_addr();
+    let kernel_size = (kernel.len() as u64).max(u64::from(header.init_size));
+    let kernel_end = load
+        .checked_add(kernel_size)
+        .ok_or_else(|| invalid("the kernel doesn't fit in guest memory"))?;
+    // the boot information and the command line are all below 1 MiB.
+    if load < HIGH_MEMORY {
+        return Err(invalid("the kernel must be loaded at or above 1 MiB"));
+    }
+    if kernel_end > 1 << 32 {
+        return Err(invalid("the kernel must be loaded below 4 GiB"));
+    }
+    if !in_ram(e820, load, kernel_size) || !memory.contains(load, kernel_size) {
+        return Err(invalid("the kernel doesn't fit in guest memory"));
+    }
+    memory.write(load, kernel).map_err(io_error)?;
+
+    let params = BOOT_INFO_ADDR;
+    memory.zero(params, BOOT_PARAMS_SIZE).map_err(io_error)?;
+    memory
+        .write(
+            params + HEADER_START as u64,
+            &image[HEADER_START..HEADER_START + header.len],
+        )
+        .map_err(io_error)?;
+    // an undefined boot loader.
+    memory
+        .write_u8(params + TYPE_OF_LOADER, 0xff)
+        .map_err(io_error)?;
+    memory
+        .write_u32(params + CODE32_START, load as u32)
+        .map_err(io_error)?;
+
+    write_cmdline(memory, cmdline, u64::from(header.cmdline_size) + 1)?;
+    memory
+        .write_u32(params + CMD_LINE_PTR, CMDLINE_ADDR as u32)
+        .map_err(io_error)?;
+    memory
+        .write_u32(params + EXT_CMD_LINE_PTR, (CMDLINE_ADDR >> 32) as u32)
+        .map_err(io_error)?;
+
+    if let Some(initrd) = initrd {
+        let size = initrd.len() as u64;
+        let high = u64::from(header.initrd_addr_max) + 1;
+        let addr = place_high(e820, size, kernel_end, high)
+            .or_else(|| place_high(e820, size, HIGH_MEMORY, load.min(high)))
+            .ok_or_else(|| invalid("the initrd doesn't fit in guest memory"))?;
+        memory.write(addr, initrd).map_err(io_error)?;
+
+        let fields = [
+            (RAMDISK_IMAGE, addr),
+            (RAMDISK_SIZE, size),
+            (EXT_RAMDISK_IMAGE, addr >> 32),
+            (EXT_RAMDISK_SIZE, size >> 32),
+        ];
+        for &(offset, value) in &fields {
+            memory
+                .write_u32(params + offset, value as u32)
+                .map_err(io_error)?;
+        }
+    }
+
+    memory
+        .write_u8(params + E820_ENTRIES, e820.len() as u8)
+        .map_err(io_error)?;
+    for (i, entry) in e820.iter().enumerate() {
+        let at = params + E820_TABLE + i as u64 * 20;
+        memory.write_u64(at, entry.addr).map_err(io_error)?;
+        memory.write_u64(at + 8, entry.size).map_err(io_error)?;
+        memory.write_u32(at + 16, entry.kind).map_err(io_error)?;
+    }
+
+    let (gdt, cs, data) = flat_gdt(BOOT_CS, BOOT_DS, true)?;
+    let mut entry = tables(memory, gdt, cs, data)?;
+    entry.cr3 = Some(identity_map(memory)?);
+    entry.regs.rip = load + ENTRY_64;
+    entry.regs.rsi = params;
+    Ok(entry)
+}
+
+fn le16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+fn le32(image: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ram_map, E820_RAM, E820_RESERVED};
+    use super::*;
+    use memory::anonymous_memory;
+
+    const MEMORY_SIZE: u64 = 64 << 20;
+    const KERNEL: &[u8] = b"\x90\x90\xf4 not really a kernel";
+
+    /// A `bzImage` with one setup sector, relocatable, aligned to 2 MiB.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 1024];
+        image[0x1f1] = 1;
+        image[0x1fe..0x200].copy_from_slice(&BOOT_FLAG.to_le_bytes());
+        image[0x200] = 0xeb;
+        image[0x201] = 0x66;
+        image[0x202..0x206].copy_from_slice(HEADER_MAGIC);
+        image[0x206..0x208].copy_from_slice(&0x20fu16.to_le_bytes());
+        image[0x211] = LOADED_HIGH;
+        image[0x22c..0x230].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+        image[0x230..0x234].copy_from_slice(&0x20_0000u32.to_le_bytes());
+        image[0x234] = 1;
+        let xloadflags = XLF_KERNEL_64 | XLF_CAN_BE_LOADED_ABOVE_4G;
+        image[0x236..0x238].copy_from_slice(&xloadflags.to_le_bytes());
+        image[0x238..0x23c].copy_from_slice(&2047u32.to_le_bytes());
+        image[0x258..0x260].copy_from_slice(&0x100_0000u64.to_le_bytes());
+        image[0x260..0x264].copy_from_slice(&0x10_0000u32.to_le_bytes());
+        image.extend_from_slice(KERNEL);
+        image
+    }
+
+    fn set32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn parse_header() {
+        let header = SetupHeader::parse(&image()).unwrap();
+        assert_eq!(
+            header,
+            SetupHeader {
+                setup_sects: 1,
+                version: 0x20f,
+                loadflags: LOADED_HIGH,
+                xloadflags: XLF_KERNEL_64 | XLF_CAN_BE_LOADED_ABOVE_4G,
+                relocatable: true,
+                kernel_alignment: 0x20_0000,
+                pref_address: 0x100_0000,
+                init_size: 0x10_0000,
+                cmdline_size: 2047,
+                initrd_addr_max: 0x7fff_ffff,
+                len: 0x77,
+            }
+        );
+        assert_eq!(header.kernel_offset(), 1024);
+
+        // a setup_sects of 0 means 4.
+        let mut image = image();
+        image[0x1f1] = 0;
+        image.resize(5 * 512, 0);
+        assert_eq!(SetupHeader::parse(&image).unwrap().kernel_offset(), 5 * 512);
+    }
+
+    #[test]
+    fn parse_rejects() {
+        let rejected = |change: &dyn Fn(&mut Vec<u8>)| {
+            let mut image = image();
+            change(&mut image);
+            SetupHeader::parse(&image).unwrap_err().kind()
+        };
+
+        assert_eq!(rejected(&|i| i[0x1fe] = 0), io::ErrorKind::InvalidData);
+        assert_eq!(rejected(&|i| i[0x202] = b'h'), io::ErrorKind::InvalidData);
+        assert_eq!(rejected(&|i| i.truncate(0x263)), io::ErrorKind::InvalidData);
+        // protocol 2.11 has no 64-bit entry point.
+        assert_eq!(rejected(&|i| i[0x206] = 0x0b), io::ErrorKind::InvalidData);
+        assert_eq!(rejected(&|i| i[0x236] = 0), io::ErrorKind::InvalidData);
+        // a header too short to hold the fields read.
+        assert_eq!(rejected(&|i| i[0x201] = 0x60), io::ErrorKind::InvalidData);
+        assert_eq!(rejected(&|i| i[0x211] = 0), io::ErrorKind::InvalidData);
+        assert_eq!(rejected(&|i| i[0x1f1] = 8), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn load_address() {
+        let mut header = SetupHeader::parse(&image()).unwrap();
+        assert_eq!(header.load_addr(), 0x20_0000);
+        header.kernel_alignment = 0x1000;
+        assert_eq!(header.load_addr(), HIGH_MEMORY);
+        header.kernel_alignment = 0;
+        assert_eq!(header.load_addr(), HIGH_MEMORY);
+
+        header.relocatable = false;
+        assert_eq!(header.load_addr(), 0x100_0000);
+    }
+
+    #[test]
+    fn load_kernel() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let image = image();
+        let entry = load(&memory, &image, "console=ttyS0", None, &e820).unwrap();
+
+        let load = 0x20_0000;
+        assert_eq!(entry.regs.rip, load + ENTRY_64);
+        assert_eq!(entry.regs.rsi, BOOT_INFO_ADDR);
+        assert!(entry.cr3.is_some());
+        assert_eq!(entry.cs.selector, BOOT_CS);
+        assert_eq!(entry.data.selector, BOOT_DS);
+
+        let mut kernel = vec![0; KERNEL.len()];
+        memory.read(load, &mut kernel).unwrap();
+        assert_eq!(kernel, KERNEL);
+
+        let params = BOOT_INFO_ADDR;
+        let mut header = vec![0; 0x77];
+        memory
+            .read(params + HEADER_START as u64, &mut header)
+            .unwrap();
+        assert_eq!(header[..0x1f], image[HEADER_START..HEADER_START + 0x1f]);
+        assert_eq!(memory.read_u8(params + TYPE_OF_LOADER), Ok(0xff));
+        assert_eq!(memory.read_u32(params + CODE32_START), Ok(load as u32));
+        assert_eq!(
+            memory.read_u32(params + CMD_LINE_PTR),
+            Ok(CMDLINE_ADDR as u32)
+        );
+        let mut cmdline = [0; 14];
+        memory.read(CMDLINE_ADDR, &mut cmdline).unwrap();
+        assert_eq!(&cmdline, b"console=ttyS0\0");
+        assert_eq!(memory.read_u32(params + RAMDISK_IMAGE), Ok(0));
+
+        assert_eq!(memory.read_u8(params + E820_ENTRIES), Ok(e820.len() as u8));
+        let last = params + E820_TABLE + (e820.len() as u64 - 1) * 20;
+        assert_eq!(memory.read_u64(last), Ok(e820[e820.len() - 1].addr));
+        assert_eq!(memory.read_u64(last + 8), Ok(e820[e820.len() - 1].size));
+        assert_eq!(memory.read_u32(last + 16), Ok(E820_RAM));
+    }
+
+    #[test]
+    fn initrd_placement() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let initrd = vec![0xa5; 0x1800];
+        let placed = |image: &[u8]| {
+            load(&memory, image, "", Some(&initrd), &e820)?;
+            let addr = memory.read_u32(BOOT_INFO_ADDR + RAMDISK_IMAGE).unwrap();
+            let size = memory.read_u32(BOOT_INFO_ADDR + RAMDISK_SIZE).unwrap();
+            assert_eq!(size, 0x1800);
+            let mut read = vec![0; initrd.len()];
+            memory.read(u64::from(addr), &mut read).unwrap();
+            assert_eq!(read, initrd);
+            Ok::<_, io::Error>(u64::from(addr))
+        };
+
+        // as high as memory goes.
+        let mut image = image();
+        assert_eq!(placed(&image).unwrap(), MEMORY_SIZE - 0x2000);
+
+        // below initrd_addr_max, which is the last byte it may use.
+        set32(&mut image, 0x22c, 0x7f_ffff);
+        assert_eq!(placed(&image).unwrap(), 0x80_0000 - 0x2000);
+        set32(&mut image, 0x22c, 0x7f_f000);
+        assert_eq!(placed(&image).unwrap(), 0x7f_d000);
+
+        // below the kernel, if it doesn't fit above it.
+        image[0x234] = 0;
+        set32(&mut image, 0x258, 0x200_0000);
+        set32(&mut image, 0x22c, 0x20f_ffff);
+        assert_eq!(placed(&image).unwrap(), 0x200_0000 - 0x2000);
+
+        set32(&mut image, 0x22c, 0xf_ffff);
+        assert!(placed(&image).is_err());
+    }
+
+    #[test]
+    fn kernel_must_be_in_ram() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let fails = |image: &[u8]| {
+            let err = load(&memory, image, "", None, &e820).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        };
+
+        let mut image = image();
+        set32(&mut image, 0x260, (MEMORY_SIZE - 0x20_0000) as u32 + 1);
+        fails(&image);
+        set32(&mut image, 0x260, (MEMORY_SIZE - 0x20_0000) as u32);
+        assert!(load(&memory, &image, "", None, &e820).is_ok());
+
+        // where the boot information and command line go, and in the
+        // legacy hole.
+        image[0x234] = 0;
+        set32(&mut image, 0x258, CMDLINE_ADDR as u32);
+        fails(&image);
+        set32(&mut image, 0x258, 0xa_0000);
+        fails(&image);
+
+        // in memory, but not in RAM.
+        set32(&mut image, 0x258, 0x100_0000);
+        set32(&mut image, 0x260, 0x1000);
+        assert!(load(&memory, &image, "", None, &e820).is_ok());
+        let reserved = [E820Entry {
+            kind: E820_RESERVED,
+            ..e820[e820.len() - 1]
+        }];
+        assert!(load(&memory, &image, "", None, &reserved).is_err());
+
+        // anywhere past 4 GiB, even in RAM.
+        set32(&mut image, 0x25c, 1);
+        let high = [E820Entry {
+            addr: 1 << 32,
+            size: 1 << 30,
+            kind: E820_RAM,
+        }];
+        let err = load(&memory, &image, "", None, &high).unwrap_err();
+        assert_eq!(err.to_string(), "the kernel must be loaded below 4 GiB");
+        set32(&mut image, 0x25c, 0);
+        set32(&mut image, 0x258, 0xffff_f000);
+        set32(&mut image, 0x260, 0x2000);
+        let err = load(&memory, &image, "", None, &high).unwrap_err();
+        assert_eq!(err.to_string(), "the kernel must be loaded below 4 GiB");
+    }
+}
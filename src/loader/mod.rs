@@ -0,0 +1,209 @@
+//! Loading guest kernels into guest memory.
+//!
+//! Each loader writes the kernel, and whatever boot information its boot
+//! protocol calls for, into guest memory, and returns an [`Entry`]: the
+//! registers to start the boot vCPU with.  The loaders share the layout of
+//! low memory described by the `*_ADDR` constants here; the kernel itself
+//! goes at [`HIGH_MEMORY`] or above.
+
+pub use super::memory::{
+    E820Entry, E820_ACPI, E820_NVS, E820_RAM, E820_RESERVED, E820_UNUSABLE, HIGH_MEMORY,
+    LOW_MEMORY_END,
+};
+
+use super::memory::{GuestMemory, MemoryMap};
+use super::state::io_error;
+use super::x86::gdt::{write_tss, Gdt, Idt};
+use super::x86::mode;
+use super::x86::paging::{PageSize, PageTables, PTE_RW};
+use super::x86::{Dtable, Regs, Segment, Sregs};
+use nix;
+use std::convert::TryFrom;
+use std::io;
+
+pub mod elf;
+pub mod linux;
+pub mod multiboot;
+
+/// Where the GDT goes.
+pub const GDT_ADDR: u64 = 0x500;
+/// Where the TSS goes.
+pub const TSS_ADDR: u64 = 0x580;
+/// Where the (empty) IDT goes.
+pub const IDT_ADDR: u64 = 0x600;
+/// Where the boot information for the kernel goes; what it holds depends
+/// on the boot protocol.
+pub const BOOT_INFO_ADDR: u64 = 0x7000;
+/// Where the page tables go, for kernels entered in long mode.
+pub const PAGE_TABLES_ADDR: u64 = 0x9000;
+/// The space set aside for the page tables.
+pub const PAGE_TABLES_SIZE: u64 = 0x7000;
+/// Where the kernel command line goes.
+pub const CMDLINE_ADDR: u64 = 0x20000;
+/// The space set aside for the command line, including its terminating
+/// NUL.
+pub const CMDLINE_MAX: u64 = 0x10000;
+/// A memory map with each range of guest memory as RAM, less the legacy
+/// VGA and BIOS areas between [`LOW_MEMORY_END`] and [`HIGH_MEMORY`].  For
+/// anything more involved, build a [`MemoryMap`] and use
+/// [`MemoryMap::e820`].  Fails as [`MemoryMap::from_memory`] does.
+pub fn ram_map(memory: &GuestMemory) -> nix::Result<Vec<E820Entry>> {
+    MemoryMap::from_memory(memory).map(|map| map.e820())
+}
+
+/// How to start the boot vCPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The general purpose registers, including `rip`.
+    pub regs: Regs,
+    pub gdt: Dtable,
+    pub idt: Dtable,
+    pub cs: Segment,
+    /// The segment for `ds`, `es`, `fs`, `gs`, and `ss`.
+    pub data: Segment,
+    pub tr: Segment,
+    /// The page tables, for a long mode entry; for a protected mode entry,
+    /// paging is off.
+    pub cr3: Option<u64>,
+}
+
+impl Entry {
+    /// Sets up the special registers, as read from the vCPU, for the entry.
+    pub fn apply(&self, sregs: &mut Sregs) {
+        match self.cr3 {
+            Some(cr3) => mode::long_mode(sregs, self.gdt, cr3),
+            None => mode::protected_mode(sregs, self.gdt),
+        }
+
+        sregs.idt = self.idt;
+        sregs.cs = self.cs;
+        sregs.ds = self.data;
+        sregs.es = self.data;
+        sregs.fs = self.data;
+        sregs.gs = self.data;
+        sregs.ss = self.data;
+        sregs.tr = self.tr;
+    }
+}
+
+/// Writes the GDT, the TSS, and an empty IDT at their addresses, and
+/// returns a protected mode entry (with no registers set) whose selectors
+/// are where `gdt` put them.  `gdt` must already hold `cs` and `data`; the
+/// TSS is added here.
+pub(crate) fn tables(
+    memory: &GuestMemory,
+    mut gdt: Gdt,
+    cs: Segment,
+    data: Segment,
+) -> io::Result<Entry> {
+    let mut tr = mode::tss_segment(0, TSS_ADDR);
+    tr.selector = gdt.push(&tr).map_err(io_error)?;
+
+    write_tss(memory, TSS_ADDR).map_err(io_error)?;
+    Ok(Entry {
+        regs: Regs {
+            rflags: 2,
+            ..Regs::default()
+        },
+        gdt: gdt.write(memory, GDT_ADDR).map_err(io_error)?,
+        idt: Idt::new(cs.l != 0)
+            .write(memory, IDT_ADDR)
+            .map_err(io_error)?,
+        cs,
+        data,
+        tr,
+        cr3: None,
+    })
+}
+
+/// Identity maps the low 4 GiB with 2 MiB pages, and returns `cr3`.
+pub(crate) fn identity_map(memory: &GuestMemory) -> io::Result<u64> {
+    let mut tables =
+        PageTables::new(memory, PAGE_TABLES_ADDR, PAGE_TABLES_SIZE, false).map_err(io_error)?;
+    tables
+        .identity(0, 4 << 30, PageSize::Size2M, PTE_RW)
+        .map_err(io_error)?;
+    Ok(tables.cr3())
+}
+
+/// Writes the command line, NUL terminated, at [`CMDLINE_ADDR`].  `max`
+/// is the most bytes the kernel accepts, including the NUL.
+pub(crate) fn write_cmdline(memory: &GuestMemory, cmdline: &str, max: u64) -> io::Result<()> {
+    let len = cmdline.len() as u64 + 1;
+    if cmdline.contains('\0') || len > max.min(CMDLINE_MAX) {
+        return Err(invalid("command line is too long for the kernel"));
+    }
+
+    memory
+        .write(CMDLINE_ADDR, cmdline.as_bytes())
+        .map_err(io_error)?;
+    memory.write_u8(CMDLINE_ADDR + len - 1, 0).map_err(io_error)
+}
+
+/// The highest page aligned address in RAM where `size` bytes fit, at or
+/// above `low` and ending at or below `high`.
+pub(crate) fn place_high(e820: &[E820Entry], size: u64, low: u64, high: u64) -> Option<u64> {
+    e820.iter()
+        .filter(|e| e.kind == E820_RAM)
+        .filter_map(|e| {
+            let end = (e.addr + e.size).min(high);
+            let start = end.checked_sub(size)? & !0xfff;
+            if start >= e.addr.max(low) {
+                Some(start)
+            } else {
+                None
+            }
+        })
+        .max()
+}
+
+/// Whether `size` bytes at `addr` lie within one RAM range of the memory
+/// map.
+pub(crate) fn in_ram(e820: &[E820Entry], addr: u64, size: u64) -> bool {
+    addr.checked_add(size).is_some_and(|end| {
+        e820.iter()
+            .any(|e| e.kind == E820_RAM && e.addr <= addr && end <= e.addr + e.size)
+    })
+}
+
+/// A GDT with a flat data segment, and a flat code segment which is 64-bit
+/// if `long` is set, at the given selectors.  Entries in between are left
+/// null.
+pub(crate) fn flat_gdt(code: u16, data: u16, long: bool) -> io::Result<(Gdt, Segment, Segment)> {
+    let cs = mode::code_segment(code, long);
+    let ds = mode::data_segment(data);
+    // a null entry, which is only one entry wide even in long mode.
+    let null = Segment {
+        s: 1,
+        ..mode::unusable_segment()
+    };
+
+    let mut gdt = Gdt::new(long);
+    for selector in (8..=code.max(data)).step_by(8) {
+        gdt.push(match selector {
+            s if s == code => &cs,
+            s if s == data => &ds,
+            _ => &null,
+        })
+        .map_err(io_error)?;
+    }
+
+    Ok((gdt, cs, ds))
+}
+
+/// Reads a little endian integer of `size` bytes (at most 8) at `offset`
+/// into an image, failing with `InvalidData` if the image is too short.
+pub(crate) fn le(image: &[u8], offset: u64, size: usize) -> io::Result<u64> {
+    let bytes = usize::try_from(offset)
+        .ok()
+        .and_then(|start| image.get(start..start.checked_add(size)?))
+        .ok_or_else(|| invalid("the image is truncated"))?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &b| value << 8 | u64::from(b)))
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,708 @@
+//! Loading kernels that follow the Multiboot or Multiboot2 specifications.
+//!
+//! [`load`] looks for a Multiboot2 header in the first 32 KiB of the image,
+//! and then for a Multiboot header in the first 8 KiB; whichever is found
+//! decides what the kernel is handed.  The image is loaded as the header's
+//! address fields say, or as an ELF image if it has none; modules follow
+//! the kernel, each page aligned.  The kernel is entered in flat protected
+//! mode, with paging off, the boot loader magic in `eax`, and `ebx`
+//! pointing to the boot information at [`BOOT_INFO_ADDR`].
+
+use super::elf::load_segments;
+use super::{
+    flat_gdt, in_ram, invalid, le, tables, E820Entry, Entry, BOOT_INFO_ADDR, E820_RAM, HIGH_MEMORY,
+    PAGE_TABLES_ADDR,
+};
+use memory::GuestMemory;
+use state::io_error;
+use std::io;
+use x86::mode::{CODE32_SELECTOR, DATA_SELECTOR};
+
+/// The magic at the start of a Multiboot header.
+pub const MULTIBOOT_HEADER_MAGIC: u32 = 0x1bad_b002;
+/// The magic in `eax` for a Multiboot kernel.
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2bad_b002;
+/// The magic at the start of a Multiboot2 header.
+pub const MULTIBOOT2_HEADER_MAGIC: u32 = 0xe852_50d6;
+/// The magic in `eax` for a Multiboot2 kernel.
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+/// Header flag: the address fields of the header are valid.
+pub const MULTIBOOT_AOUT_KLUDGE: u32 = 1 << 16;
+
+// Multiboot information flags.
+const INFO_MEMORY: u32 = 1 << 0;
+const INFO_CMDLINE: u32 = 1 << 2;
+const INFO_MODS: u32 = 1 << 3;
+const INFO_MEM_MAP: u32 = 1 << 6;
+const INFO_BOOT_LOADER_NAME: u32 = 1 << 9;
+const INFO_SIZE: usize = 88;
+
+// Multiboot2 header tags.
+const HEADER_TAG_END: u64 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u64 = 1;
+const HEADER_TAG_ADDRESS: u64 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u64 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u64 = 4;
+const HEADER_TAG_MODULE_ALIGN: u64 = 6;
+const HEADER_TAG_OPTIONAL: u64 = 1;
+
+// Multiboot2 information tags.
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_MMAP: u32 = 6;
+
+const BOOT_LOADER_NAME: &str = "kvm-sys";
+/// The space set aside for the boot information.
+const INFO_MAX: u64 = PAGE_TABLES_ADDR - BOOT_INFO_ADDR;
+
+/// A module loaded along with the kernel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Module<'a> {
+    pub data: &'a [u8],
+    /// The string the kernel sees for the module; usually its command
+    /// line.
+    pub cmdline: &'a str,
+}
+
+/// Which specification a kernel was booted with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Version {
+    Multiboot,
+    Multiboot2,
+}
+
+/// The address fields of a header.
+struct Addresses {
+    header_addr: u64,
+    load_addr: u64,
+    load_end_addr: u64,
+    bss_end_addr: u64,
+    entry_addr: Option<u64>,
+}
+
+/// Finds the Multiboot2 or Multiboot header in an image, returning the
+/// version and its offset.
+pub fn find(image: &[u8]) -> Option<(Version, usize)> {
+    let search = |magic: u32, limit: usize, align: usize, words: usize| {
+        (0..image.len().min(limit))
+            .step_by(align)
+            .find(|&at| checksum_ok(image, at, magic, words))
+    };
+
+    search(MULTIBOOT2_HEADER_MAGIC, 32 * 1024, 8, 4)
+        .map(|at| (Version::Multiboot2, at))
+        .or_else(|| {
+            search(MULTIBOOT_HEADER_MAGIC, 8 * 1024, 4, 3).map(|at| (Version::Multiboot, at))
+        })
+}
+
+/// Loads a Multiboot or Multiboot2 kernel, with a command line and
+/// modules, and returns its entry.  `e820` is the memory map for the guest
+/// (see [`ram_map`](../fn.ram_map.html)).  Fails with `InvalidData` if the
+/// image has no valid header, asks for something that isn't supported,
+/// such as a framebuffer, or would be loaded below [`HIGH_MEMORY`] or
+/// outside of RAM, whether from its address fields or as an ELF image.
+pub fn load(
+    memory: &GuestMemory,
+    image: &[u8],
+    cmdline: &str,
+    modules: &[Module],
+    e820: &[E820Entry],
+) -> io::Result<Entry> {
+    let (version, offset) = find(image).ok_or_else(|| invalid("no Multiboot header"))?;
+    let (addresses, entry_addr) = match version {
+        Version::Multiboot => (header(image, offset)?, None),
+        Version::Multiboot2 => header2(image, offset)?,
+    };
+
+    let (entry, end) = match addresses {
+        Some(addresses) => load_addresses(memory, image, offset as u64, &addresses, e820)?,
+        None => {
+            let elf = load_segments(memory, image, e820)?;
+            (elf.entry, elf.end)
+        }
+    };
+    let entry = entry_addr.unwrap_or(entry);
+
+    // modules go after the kernel, page aligned, in RAM below 4 GiB.
+    let page_up = |addr: u64| addr.checked_add(0xfff).map(|a| a & !0xfff);
+    let mut placed = vec![];
+    let mut next = page_up(end);
+    for module in modules {
+        let size = module.data.len() as u64;
+        let addr = next
+            .filter(|&addr| in_ram(e820, addr, size) && addr + size <= 1 << 32)
+            .ok_or_else(|| invalid("a module doesn't fit in RAM"))?;
+        memory.write(addr, module.data).map_err(io_error)?;
+        placed.push((addr, addr + size, module.cmdline));
+        next = page_up(addr + size);
+    }
+
+    let (info, magic) = match version {
+        Version::Multiboot => (info(cmdline, &placed, e820), MULTIBOOT_BOOTLOADER_MAGIC),
+        Version::Multiboot2 => (info2(cmdline, &placed, e820), MULTIBOOT2_BOOTLOADER_MAGIC),
+    };
+    if info.len() as u64 > INFO_MAX {
+        return Err(invalid("too much boot information"));
+    }
+    memory.write(BOOT_INFO_ADDR, &info).map_err(io_error)?;
+
+    let (gdt, cs, data) = flat_gdt(CODE32_SELECTOR, DATA_SELECTOR, false)?;
+    let mut boot = tables(memory, gdt, cs, data)?;
+    boot.regs.rip = entry;
+    boot.regs.rax = u64::from(magic);
+    boot.regs.rbx = BOOT_INFO_ADDR;
+    Ok(boot)
+}
+
+/// Whether the `words` 32-bit words at `at`, starting with `magic`, add up
+/// to zero.
+fn checksum_ok(image: &[u8], at: usize, magic: u32, words: usize) -> bool {
+    let word = |i: usize| le(image, (at + i * 4) as u64, 4).map(|w| w as u32);
+    match (0..words).map(word).collect::<io::Result<Vec<_>>>() {
+        Ok(w) => w[0] == magic && w.iter().fold(0u32, |sum, &x| sum.wrapping_add(x)) == 0,
+        Err(_) => false,
+    }
+}
+
+/// Reads the address fields of a Multiboot header, if it has them.
+fn header(image: &[u8], at: usize) -> io::Result<Option<Addresses>> {
+    let at = at as u64;
+    let flags = le(image, at + 4, 4)? as u32;
+    // bits 0 to 15 are requirements; beyond page aligned modules and
+    // memory information, which are always given, they can't be met.
+    if flags & 0xfffc != 0 {
+        return Err(invalid("the kernel needs unsupported Multiboot features"));
+    }
+    if flags & MULTIBOOT_AOUT_KLUDGE == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(Addresses {
+        header_addr: le(image, at + 12, 4)?,
+        load_addr: le(image, at + 16, 4)?,
+        load_end_addr: le(image, at + 20, 4)?,
+        bss_end_addr: le(image, at + 24, 4)?,
+        entry_addr: Some(le(image, at + 28, 4)?),
+    }))
+}
+
+/// Reads the address tag and the entry address tag of a Multiboot2 header,
+/// if it has them, checking that every tag the kernel needs is supported.
+/// The entry address applies whether or not there is an address tag.
+fn header2(image: &[u8], at: usize) -> io::Result<(Option<Addresses>, Option<u64>)> {
+    let start = at as u64;
+    if le(image, start + 4, 4)? != 0 {
+        return Err(invalid("the Multiboot2 header isn't for i386"));
+    }
+    let end = start + le(image, start + 8, 4)?;
+
+    let mut addresses = None;
+    let mut entry = None;
+    let mut tag = start + 16;
+    while tag + 8 <= end {
+        let kind = le(image, tag, 2)?;
+        let optional = le(image, tag + 2, 2)? & HEADER_TAG_OPTIONAL != 0;
+        let size = le(image, tag + 4, 4)?;
+        match kind {
+            HEADER_TAG_END => break,
+            HEADER_TAG_INFORMATION_REQUEST if !optional => {
+                for i in 0..size.saturating_sub(8) / 4 {
+                    match le(image, tag + 8 + i * 4, 4)? as u32 {
+                        TAG_CMDLINE | TAG_BOOT_LOADER_NAME | TAG_MODULE | TAG_BASIC_MEMINFO
+                        | TAG_MMAP => (),
+                        _ => return Err(invalid("the kernel needs unsupported boot information")),
+                    }
+                }
+            }
+            HEADER_TAG_ADDRESS => {
+                addresses = Some(Addresses {
+                    header_addr: le(image, tag + 8, 4)?,
+                    load_addr: le(image, tag + 12, 4)?,
+                    load_end_addr: le(image, tag + 16, 4)?,
+                    bss_end_addr: le(image, tag + 20, 4)?,
+                    entry_addr: None,
+                })
+            }
+            HEADER_TAG_ENTRY_ADDRESS => entry = Some(le(image, tag + 8, 4)?),
+            // modules are page aligned anyway, and the console is serial.
+            HEADER_TAG_INFORMATION_REQUEST | HEADER_TAG_CONSOLE_FLAGS | HEADER_TAG_MODULE_ALIGN => {
+            }
+            _ if optional => {}
+            _ => return Err(invalid("the kernel needs unsupported Multiboot2 features")),
+        }
+
+        tag += (size.max(8) + 7) & !7;
+    }
+
+    Ok((addresses, entry))
+}
+
+/// Loads an image as its header's address fields say, returning its entry
+/// point and end.
+fn load_addresses(
+    memory: &GuestMemory,
+    image: &[u8],
+    header_offset: u64,
+    a: &Addresses,
+    e820: &[E820Entry],
+) -> io::Result<(u64, u64)> {
+    let inconsistent = || invalid("the Multiboot header addresses are inconsistent");
+    // a load address of -1 means the image is loaded from its start.
+    let (offset, load_addr) = if a.load_addr == u64::from(u32::MAX) {
+        let load_addr = a.header_addr.checked_sub(header_offset);
+        (0, load_addr.ok_or_else(inconsistent)?)
+    } else {
+        let before = a.header_addr.checked_sub(a.load_addr);
+        let offset = before.and_then(|before| header_offset.checked_sub(before));
+        (offset.ok_or_else(inconsistent)?, a.load_addr)
+    };
+
+    let len = match a.load_end_addr {
+        0 => (image.len() as u64).saturating_sub(offset),
+        end => end
+            .checked_sub(load_addr)
+            .ok_or_else(|| invalid("the Multiboot load end is before its start"))?,
+    };
+    let data = image
+        .get(offset as usize..offset.saturating_add(len) as usize)
+        .ok_or_else(|| invalid("the image is truncated"))?;
+    let end = load_addr
+        .checked_add(len)
+        .ok_or_else(inconsistent)?
+        .max(a.bss_end_addr);
+    if load_addr < HIGH_MEMORY {
+        return Err(invalid("the kernel must be loaded at or above 1 MiB"));
+    }
+    if !in_ram(e820, load_addr, end - load_addr) || !memory.contains(load_addr, end - load_addr) {
+        return Err(invalid("the kernel doesn't fit in guest memory"));
+    }
+
+    memory.write(load_addr, data).map_err(io_error)?;
+    memory
+        .zero(load_addr + len, end - load_addr - len)
+        .map_err(io_error)?;
+    Ok((a.entry_addr.unwrap_or(load_addr), end))
+}
+
+/// The KiB of RAM at 0 and at 1 MiB, for the basic memory information.
+fn mem_lower_upper(e820: &[E820Entry]) -> (u32, u32) {
+    let ram_at = |addr: u64| {
+        e820.iter()
+            .find(|e| e.kind == E820_RAM && e.addr <= addr && addr < e.addr + e.size)
+            .map_or(0, |e| e.addr + e.size - addr)
+    };
+
+    let lower = ram_at(0).min(640 * 1024) / 1024;
+    let upper = ram_at(HIGH_MEMORY).min(u64::from(u32::MAX) * 1024) / 1024;
+    (lower as u32, upper as u32)
+}
+
+/// Builds the Multiboot information structure, followed by the module
+/// list, the memory map, and the strings, for [`BOOT_INFO_ADDR`].
+fn info(cmdline: &str, modules: &[(u64, u64, &str)], e820: &[E820Entry]) -> Vec<u8> {
+    let mut out = vec![0; INFO_SIZE];
+    let addr = |out: &Vec<u8>| (BOOT_INFO_ADDR + out.len() as u64) as u32;
+
+    let mods = addr(&out);
+    out.resize(out.len() + modules.len() * 16, 0);
+    let mmap = addr(&out);
+    for e in e820 {
+        push32(&mut out, 20);
+        push64(&mut out, e.addr);
+        push64(&mut out, e.size);
+        push32(&mut out, e.kind);
+    }
+    let mmap_length = addr(&out) - mmap;
+
+    for (i, &(start, end, string)) in modules.iter().enumerate() {
+        let at = (mods - BOOT_INFO_ADDR as u32) as usize + i * 16;
+        let string_addr = push_str(&mut out, string);
+        put32(&mut out, at, start as u32);
+        put32(&mut out, at + 4, end as u32);
+        put32(&mut out, at + 8, string_addr);
+    }
+    let cmdline = push_str(&mut out, cmdline);
+    let name = push_str(&mut out, BOOT_LOADER_NAME);
+
+    let (lower, upper) = mem_lower_upper(e820);
+    let flags = INFO_MEMORY | INFO_CMDLINE | INFO_MODS | INFO_MEM_MAP | INFO_BOOT_LOADER_NAME;
+    let fields = [
+        (0, flags),
+        (4, lower),
+        (8, upper),
+        (16, cmdline),
+        (20, modules.len() as u32),
+        (24, mods),
+        (44, mmap_length),
+        (48, mmap),
+        (64, name),
+    ];
+    for &(offset, value) in &fields {
+        put32(&mut out, offset, value);
+    }
+
+    out
+}
+
+/// Builds the Multiboot2 boot information, for [`BOOT_INFO_ADDR`].
+fn info2(cmdline: &str, modules: &[(u64, u64, &str)], e820: &[E820Entry]) -> Vec<u8> {
+    // the total size, and a reserved field.
+    let mut out = vec![0; 8];
+
+    tag_str(&mut out, TAG_CMDLINE, &[], cmdline);
+    tag_str(&mut out, TAG_BOOT_LOADER_NAME, &[], BOOT_LOADER_NAME);
+    for &(start, end, string) in modules {
+        tag_str(&mut out, TAG_MODULE, &[start as u32, end as u32], string);
+    }
+
+    let (lower, upper) = mem_lower_upper(e820);
+    tag(&mut out, TAG_BASIC_MEMINFO, |out| {
+        push32(out, lower);
+        push32(out, upper);
+    });
+    tag(&mut out, TAG_MMAP, |out| {
+        push32(out, 24);
+        push32(out, 0);
+        for e in e820 {
+            push64(out, e.addr);
+            push64(out, e.size);
+            push32(out, e.kind);
+            push32(out, 0);
+        }
+    });
+    tag(&mut out, TAG_END, |_| ());
+
+    let len = out.len() as u32;
+    put32(&mut out, 0, len);
+    out
+}
+
+/// Appends a Multiboot2 tag, padded to 8 bytes, whose contents `f` writes.
+fn tag<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, kind: u32, f: F) {
+    let start = out.len();
+    push32(out, kind);
+    push32(out, 0);
+    f(out);
+
+    let size = (out.len() - start) as u32;
+    put32(out, start + 4, size);
+    let padded = (out.len() + 7) & !7;
+    out.resize(padded, 0);
+}
+
+/// Appends a Multiboot2 tag of some 32-bit words and a string.
+fn tag_str(out: &mut Vec<u8>, kind: u32, words: &[u32], string: &str) {
+    tag(out, kind, |out| {
+        for &w in words {
+            push32(out, w);
+        }
+        out.extend_from_slice(string.as_bytes());
+        out.push(0);
+    });
+}
+
+/// Appends a NUL terminated string, returning its guest address.
+fn push_str(out: &mut Vec<u8>, string: &str) -> u32 {
+    let addr = (BOOT_INFO_ADDR + out.len() as u64) as u32;
+    out.extend_from_slice(string.as_bytes());
+    out.push(0);
+    addr
+}
+
+fn push32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(out: &mut [u8], at: usize, value: u32) {
+    out[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::elf::test_image;
+    use super::super::ram_map;
+    use super::*;
+    use memory::anonymous_memory;
+
+    const MEMORY_SIZE: u64 = 16 << 20;
+    const KERNEL: u64 = 0x10_0000;
+
+    /// A Multiboot header with the address fields, for a kernel loaded
+    /// from the start of the image at [`KERNEL`], where the header is.
+    fn multiboot(bss_end: u32, entry: u32) -> Vec<u8> {
+        let flags = MULTIBOOT_AOUT_KLUDGE;
+        let checksum = 0u32
+            .wrapping_sub(MULTIBOOT_HEADER_MAGIC)
+            .wrapping_sub(flags);
+        let mut image = vec![];
+        let kernel = KERNEL as u32;
+        for &word in &[
+            MULTIBOOT_HEADER_MAGIC,
+            flags,
+            checksum,
+            kernel,
+            kernel,
+            0,
+            bss_end,
+            entry,
+        ] {
+            push32(&mut image, word);
+        }
+        image.extend_from_slice(b"kernel text");
+        image
+    }
+
+    /// A Multiboot2 header with the given tags, the end tag following them.
+    fn multiboot2(tags: &[(u16, &[u32])]) -> Vec<u8> {
+        let mut header = vec![];
+        push32(&mut header, MULTIBOOT2_HEADER_MAGIC);
+        push32(&mut header, 0);
+        push32(&mut header, 0);
+        push32(&mut header, 0);
+        for &(kind, words) in tags.iter().chain(&[(HEADER_TAG_END as u16, &[][..])]) {
+            let start = header.len();
+            header.extend_from_slice(&kind.to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes());
+            push32(&mut header, 8 + 4 * words.len() as u32);
+            for &w in words {
+                push32(&mut header, w);
+            }
+            header.resize((header.len() + 7) & !7, 0);
+            assert!(header.len() - start >= 8);
+        }
+
+        let len = header.len() as u32;
+        put32(&mut header, 8, len);
+        let checksum = 0u32.wrapping_sub(MULTIBOOT2_HEADER_MAGIC).wrapping_sub(len);
+        put32(&mut header, 12, checksum);
+        header
+    }
+
+    fn read32(memory: &GuestMemory, addr: u64) -> u32 {
+        memory.read_u32(addr).unwrap()
+    }
+
+    #[test]
+    fn find_header() {
+        assert_eq!(find(&multiboot(0, 0)), Some((Version::Multiboot, 0)));
+
+        let mut image = vec![0; 0x40];
+        image.extend_from_slice(&multiboot2(&[]));
+        image.extend_from_slice(&multiboot(0, 0));
+        assert_eq!(find(&image), Some((Version::Multiboot2, 0x40)));
+
+        // a bad checksum, or a misaligned header, isn't found.
+        image[0x4c] ^= 1;
+        assert_eq!(find(&image), Some((Version::Multiboot, 0x40 + 24)));
+        let mut image = vec![0; 2];
+        image.extend_from_slice(&multiboot(0, 0));
+        assert_eq!(find(&image), None);
+    }
+
+    #[test]
+    fn load_multiboot() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        memory.write(KERNEL + 0x20, &[0xff; 0x100]).unwrap();
+        let image = multiboot(KERNEL as u32 + 0x100, KERNEL as u32 + 0x20);
+        let modules = [
+            Module {
+                data: b"first",
+                cmdline: "one",
+            },
+            Module {
+                data: &[7; 0x1001],
+                cmdline: "two",
+            },
+        ];
+        let boot = load(&memory, &image, "console=ttyS0", &modules, &e820).unwrap();
+        assert_eq!(boot.regs.rip, KERNEL + 0x20);
+        assert_eq!(boot.regs.rax, u64::from(MULTIBOOT_BOOTLOADER_MAGIC));
+        assert_eq!(boot.regs.rbx, BOOT_INFO_ADDR);
+        assert_eq!(boot.cr3, None);
+
+        let mut kernel = vec![0; image.len()];
+        memory.read(KERNEL, &mut kernel).unwrap();
+        assert_eq!(kernel, image);
+        // the bss is zeroed.
+        assert_eq!(memory.read_u64(KERNEL + 0xf8), Ok(0));
+        assert_eq!(memory.read_u8(KERNEL + 0x100), Ok(0xff));
+
+        let info = BOOT_INFO_ADDR;
+        assert_eq!(read32(&memory, info) & INFO_MODS, INFO_MODS);
+        assert_eq!(read32(&memory, info + 4), 640);
+        assert_eq!(
+            read32(&memory, info + 8),
+            (MEMORY_SIZE - HIGH_MEMORY) as u32 / 1024
+        );
+        assert_eq!(read32(&memory, info + 20), 2);
+
+        // the modules follow the kernel, each page aligned.
+        let mods = u64::from(read32(&memory, info + 24));
+        let first = KERNEL + 0x1000;
+        let second = first + 0x1000;
+        assert_eq!(read32(&memory, mods), first as u32);
+        assert_eq!(read32(&memory, mods + 4), first as u32 + 5);
+        assert_eq!(read32(&memory, mods + 16), second as u32);
+        assert_eq!(read32(&memory, mods + 20), second as u32 + 0x1001);
+        let mut name = [0; 4];
+        memory
+            .read(u64::from(read32(&memory, mods + 24)), &mut name)
+            .unwrap();
+        assert_eq!(&name, b"two\0");
+        assert_eq!(memory.read_u8(second + 0x1000), Ok(7));
+    }
+
+    #[test]
+    fn modules_need_ram() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let image = multiboot(0, KERNEL as u32);
+        let module = [Module {
+            data: &[0; 0x2000],
+            cmdline: "",
+        }];
+
+        // backed by memory, but not RAM as far as the guest is told.
+        let e820 = [E820Entry {
+            addr: KERNEL,
+            size: 0x2000,
+            kind: E820_RAM,
+        }];
+        assert!(load(&memory, &image, "", &module, &e820).is_err());
+        let e820 = [E820Entry {
+            size: 0x3000,
+            ..e820[0]
+        }];
+        assert!(load(&memory, &image, "", &module, &e820).is_ok());
+        let e820 = ram_map(&anonymous_memory(KERNEL + 0x2000)).unwrap();
+        assert!(load(&memory, &image, "", &module, &e820).is_err());
+    }
+
+    #[test]
+    fn inconsistent_addresses() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        // changes the address fields of a header 0x40 into the image.
+        let with = |fields: &[(usize, u32)]| {
+            let mut image = vec![0; 0x40];
+            image.extend_from_slice(&multiboot(0, KERNEL as u32));
+            for &(offset, value) in fields {
+                put32(&mut image, 0x40 + offset, value);
+            }
+            load(&memory, &image, "", &[], &e820).map(|boot| boot.regs.rip)
+        };
+        let kernel = KERNEL as u32;
+
+        // the image loaded from its start at 1 MiB.
+        assert_eq!(with(&[(12, kernel + 0x40)]).unwrap(), KERNEL);
+        assert_eq!(with(&[(12, kernel + 0x40), (16, !0)]).unwrap(), KERNEL);
+        // from before the start of the image.
+        assert!(with(&[(12, kernel + 0x80)]).is_err());
+        assert!(with(&[(12, 0x20), (16, !0)]).is_err());
+        // the header before the load address, or the end before the start.
+        assert!(with(&[(12, kernel - 1)]).is_err());
+        assert!(with(&[(12, kernel + 0x40), (20, kernel - 1)]).is_err());
+        // below 1 MiB, or not in memory.
+        assert!(with(&[(12, 0x8_0040), (16, 0x8_0000)]).is_err());
+        assert!(with(&[(12, kernel + 0x40), (24, MEMORY_SIZE as u32 + 1)]).is_err());
+    }
+
+    #[test]
+    fn multiboot2_entry_tag() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let header = multiboot2(&[(HEADER_TAG_ENTRY_ADDRESS as u16, &[0x10_0010])]);
+        let text = [0x90; 0x20];
+        let image = test_image(
+            0x10_0000,
+            &[(0x10_0000, &header, 0x100), (0x20_0000, &text, 0x20)],
+            &[],
+        );
+        assert_eq!(find(&image).map(|f| f.0), Some(Version::Multiboot2));
+
+        // without an address tag, the image is loaded as an ELF, but entered
+        // where the tag says.
+        let boot = load(&memory, &image, "", &[], &e820).unwrap();
+        assert_eq!(boot.regs.rip, 0x10_0010);
+        assert_eq!(boot.regs.rax, u64::from(MULTIBOOT2_BOOTLOADER_MAGIC));
+        assert_eq!(memory.read_u8(0x20_001f), Ok(0x90));
+
+        let header = multiboot2(&[]);
+        let image = test_image(0x10_0000, &[(0x10_0000, &header, 0x100)], &[]);
+        let boot = load(&memory, &image, "", &[], &e820).unwrap();
+        assert_eq!(boot.regs.rip, 0x10_0000);
+    }
+
+    #[test]
+    fn elf_segments_need_ram() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let header = multiboot2(&[]);
+        let elf = |paddr: u32| test_image(paddr, &[(paddr, &header, 0x2000)], &[]);
+        assert!(load(&memory, &elf(0x10_0000), "", &[], &e820).is_ok());
+
+        // below 1 MiB, where the boot information goes.
+        assert!(load(&memory, &elf(0x8_0000), "", &[], &e820).is_err());
+        // backed by memory, but not RAM as far as the guest is told.
+        let e820 = [E820Entry {
+            addr: KERNEL,
+            size: 0x1000,
+            kind: E820_RAM,
+        }];
+        assert!(load(&memory, &elf(0x10_0000), "", &[], &e820).is_err());
+    }
+
+    #[test]
+    fn multiboot2_info() {
+        let memory = anonymous_memory(MEMORY_SIZE);
+        let e820 = ram_map(&memory).unwrap();
+        let header = multiboot2(&[(HEADER_TAG_INFORMATION_REQUEST as u16, &[TAG_MMAP])]);
+        let image = test_image(0x10_0000, &[(0x10_0000, &header, 0x100)], &[]);
+        let module = [Module {
+            data: b"module",
+            cmdline: "mod",
+        }];
+        load(&memory, &image, "cmdline", &module, &e820).unwrap();
+
+        // walk the tags.
+        let info = BOOT_INFO_ADDR;
+        let total = u64::from(read32(&memory, info));
+        let mut tags = vec![];
+        let mut at = info + 8;
+        while at < info + total {
+            let kind = read32(&memory, at);
+            tags.push(kind);
+            if kind == TAG_MODULE {
+                assert_eq!(read32(&memory, at + 8), 0x10_1000);
+                assert_eq!(read32(&memory, at + 12), 0x10_1006);
+            }
+            at += (u64::from(read32(&memory, at + 4)) + 7) & !7;
+        }
+        assert_eq!(at, info + total);
+        assert_eq!(
+            tags,
+            vec![
+                TAG_CMDLINE,
+                TAG_BOOT_LOADER_NAME,
+                TAG_MODULE,
+                TAG_BASIC_MEMINFO,
+                TAG_MMAP,
+                TAG_END
+            ]
+        );
+
+        // requests for information that isn't given are refused.
+        let header = multiboot2(&[(HEADER_TAG_INFORMATION_REQUEST as u16, &[8])]);
+        let image = test_image(0x10_0000, &[(0x10_0000, &header, 0x100)], &[]);
+        assert!(load(&memory, &image, "", &[], &e820).is_err());
+    }
+}
@@ -0,0 +1,743 @@
+//! Guest physical memory: access to it from the host, and a description of
+//! its layout for the guest.
+//!
+//! [`GuestMemory`] maps guest physical addresses to the host memory backing
+//! each memory slot.  Every access is checked against the slots; an access
+//! to an address that isn't backed by memory fails with `EFAULT`, the same
+//! as KVM does for a bad userspace address.  Accesses may span adjacent
+//! slots.
+//!
+//! [`MemoryMap`] describes what lives where in the guest physical address
+//! space: RAM, which comes from the memory slots, and the reserved ranges,
+//! ACPI tables, and MMIO holes declared on top of it.  It is what the boot
+//! loaders and firmware tables tell the guest about its memory, as an E820
+//! table.
+
+use super::ctl::{UserspaceMemoryRegion, KVM_MEM_READONLY};
+use super::vm::Vm;
+use nix;
+use nix::errno::Errno;
+use std::ptr;
+
+/// Usable RAM.
+pub const E820_RAM: u32 = 1;
+/// Reserved; not to be used by the guest.
+pub const E820_RESERVED: u32 = 2;
+/// RAM holding ACPI tables, which the guest may reclaim once it has read
+/// them.
+pub const E820_ACPI: u32 = 3;
+/// ACPI non-volatile storage.
+pub const E820_NVS: u32 = 4;
+/// Memory that is known to be bad.
+pub const E820_UNUSABLE: u32 = 5;
+
+/// The end of the low 640 KiB of RAM; the legacy VGA and BIOS areas follow.
+pub const LOW_MEMORY_END: u64 = 0xa_0000;
+/// The start of memory above the legacy areas.
+pub const HIGH_MEMORY: u64 = 0x10_0000;
+
+/// The start of the conventional hole below 4 GiB for PCI MMIO (BARs,
+/// ECAM, the IOAPIC and local APIC); RAM that doesn't fit below it goes
+/// above 4 GiB instead.
+pub const PCI_HOLE_START: u64 = 0xc000_0000;
+/// The end of the PCI hole.
+pub const PCI_HOLE_END: u64 = 0x1_0000_0000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Region {
+    guest_phys_addr: u64,
+    size: u64,
+    host: *mut u8,
+}
+
+/// The guest physical memory of a VM.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestMemory {
+    regions: Vec<Region>,
+}
+
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+impl GuestMemory {
+    /// Creates a guest memory map with no memory in it.
+    pub fn new() -> GuestMemory {
+        GuestMemory { regions: vec![] }
+    }
+
+    /// Creates a guest memory map from memory slots.
+    ///
+    /// # Safety
+    /// The `userspace_addr` of each slot must point to memory that is
+    /// valid for reads and writes for `memory_size` bytes, for as long as
+    /// the guest memory map (or any clone of it) is in use.
+    pub unsafe fn from_regions(regions: &[UserspaceMemoryRegion]) -> nix::Result<GuestMemory> {
+        let mut memory = GuestMemory::new();
+        for region in regions {
+            memory.add(
+                region.guest_phys_addr,
+                region.userspace_addr as *mut u8,
+                region.memory_size,
+            )?;
+        }
+
+        Ok(memory)
+    }
+
+    /// Adds host memory at the given guest physical address.  Fails with
+    /// `EEXIST` if it overlaps memory that is already in the map.
+    ///
+    /// # Safety
+    /// `host` must be valid for reads and writes for `size` bytes, for as
+    /// long as the guest memory map (or any clone of it) is in use.
+    pub unsafe fn add(
+        &mut self,
+        guest_phys_addr: u64,
+        host: *mut u8,
+        size: u64,
+    ) -> nix::Result<()> {
+        let end = guest_phys_addr
+            .checked_add(size)
+            .ok_or(nix::Error::Sys(Errno::EINVAL))?;
+        if self
+            .regions
+            .iter()
+            .any(|r| guest_phys_addr < r.guest_phys_addr + r.size && r.guest_phys_addr < end)
+        {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        self.regions.push(Region {
+            guest_phys_addr,
+            size,
+            host,
+        });
+        self.regions.sort_by_key(|r| r.guest_phys_addr);
+        Ok(())
+    }
+
+    /// The `(guest_phys_addr, size)` of each contiguous piece of memory, in
+    /// address order.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        self.regions
+            .iter()
+            .map(|r| (r.guest_phys_addr, r.size))
+            .collect()
+    }
+
+    /// Whether `len` bytes starting at `addr` are all backed by memory.
+    pub fn contains(&self, addr: u64, len: u64) -> bool {
+        self.walk(addr, len as usize, |_, _, _| ()).is_ok()
+    }
+
+    /// Copies guest memory starting at `addr` into `buf`.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> nix::Result<()> {
+        let dst = buf.as_mut_ptr();
+        self.walk(addr, buf.len(), |host, offset, len| unsafe {
+            ptr::copy_nonoverlapping(host, dst.add(offset), len)
+        })
+    }
+
+    /// Copies `buf` into guest memory starting at `addr`.
+    pub fn write(&self, addr: u64, buf: &[u8]) -> nix::Result<()> {
+        let src = buf.as_ptr();
+        self.walk(addr, buf.len(), |host, offset, len| unsafe {
+            ptr::copy_nonoverlapping(src.add(offset), host, len)
+        })
+    }
+
+    /// Sets `len` bytes of guest memory starting at `addr` to zero.
+    pub fn zero(&self, addr: u64, len: u64) -> nix::Result<()> {
+        self.walk(addr, len as usize, |host, _, len| unsafe {
+            ptr::write_bytes(host, 0, len)
+        })
+    }
+
+    pub fn read_u8(&self, addr: u64) -> nix::Result<u8> {
+        let mut buf = [0; 1];
+        self.read(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn read_u16(&self, addr: u64) -> nix::Result<u16> {
+        let mut buf = [0; 2];
+        self.read(addr, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn read_u32(&self, addr: u64) -> nix::Result<u32> {
+        let mut buf = [0; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> nix::Result<u64> {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn write_u8(&self, addr: u64, v: u8) -> nix::Result<()> {
+        self.write(addr, &[v])
+    }
+
+    pub fn write_u16(&self, addr: u64, v: u16) -> nix::Result<()> {
+        self.write(addr, &v.to_le_bytes())
+    }
+
+    pub fn write_u32(&self, addr: u64, v: u32) -> nix::Result<()> {
+        self.write(addr, &v.to_le_bytes())
+    }
+
+    pub fn write_u64(&self, addr: u64, v: u64) -> nix::Result<()> {
+        self.write(addr, &v.to_le_bytes())
+    }
+
+    /// Calls `f` with the host address, the offset into the access, and
+    /// the length of each piece of `addr..addr + len`, after checking that
+    /// all of it is backed by memory.
+    fn walk<F: FnMut(*mut u8, usize, usize)>(
+        &self,
+        addr: u64,
+        len: usize,
+        mut f: F,
+    ) -> nix::Result<()> {
+        let end = addr
+            .checked_add(len as u64)
+            .ok_or(nix::Error::Sys(Errno::EFAULT))?;
+
+        // check all of it first, so a failed write doesn't half happen.
+        let mut cur = addr;
+        while cur < end {
+            cur += self.piece(cur, end)?.2;
+        }
+
+        let mut cur = addr;
+        while cur < end {
+            let (region, offset, piece) = self.piece(cur, end)?;
+            f(
+                unsafe { region.host.add(offset as usize) },
+                (cur - addr) as usize,
+                piece as usize,
+            );
+            cur += piece;
+        }
+
+        Ok(())
+    }
+
+    /// The region containing `addr`, the offset of `addr` into it, and how
+    /// much of `addr..end` it covers.
+    fn piece(&self, addr: u64, end: u64) -> nix::Result<(&Region, u64, u64)> {
+        let region = self
+            .regions
+            .iter()
+            .find(|r| r.guest_phys_addr <= addr && addr - r.guest_phys_addr < r.size)
+            .ok_or(nix::Error::Sys(Errno::EFAULT))?;
+        let offset = addr - region.guest_phys_addr;
+        Ok((region, offset, (region.size - offset).min(end - addr)))
+    }
+}
+
+/// `size` bytes of anonymous memory at guest physical address 0, for
+/// tests.  The mapping is never unmapped, since clones of the map may
+/// outlive any one test's use of it.
+#[cfg(test)]
+pub(crate) fn anonymous_memory(size: u64) -> GuestMemory {
+    let mut memory = GuestMemory::new();
+    unsafe { memory.add(0, anonymous_mapping(size), size) }.unwrap();
+    memory
+}
+
+/// Maps `size` bytes of anonymous memory, which is never unmapped, for
+/// tests.
+#[cfg(test)]
+pub(crate) fn anonymous_mapping(size: u64) -> *mut u8 {
+    use libc;
+
+    let host = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(host, libc::MAP_FAILED);
+    host as *mut u8
+}
+
+/// An entry of the E820 memory map, which describes guest physical memory
+/// to the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    /// One of the `E820_*` constants.
+    pub kind: u32,
+}
+
+/// What a range of guest physical addresses is used for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Ram,
+    /// Reserved, such as firmware; it may or may not be backed by RAM.
+    Reserved,
+    /// ACPI tables, which the guest may reclaim as RAM.
+    Acpi,
+    /// ACPI non-volatile storage.
+    Nvs,
+    /// Device registers; never backed by RAM, and left out of the E820
+    /// table.
+    Mmio,
+}
+
+impl Kind {
+    /// The E820 type for the kind, if it appears in the E820 table.
+    pub fn e820(self) -> Option<u32> {
+        match self {
+            Kind::Ram => Some(E820_RAM),
+            Kind::Reserved => Some(E820_RESERVED),
+            Kind::Acpi => Some(E820_ACPI),
+            Kind::Nvs => Some(E820_NVS),
+            Kind::Mmio => None,
+        }
+    }
+}
+
+/// A range of guest physical addresses in a [`MemoryMap`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub addr: u64,
+    pub size: u64,
+    pub kind: Kind,
+}
+
+impl Range {
+    /// The address just past the range.
+    pub fn end(&self) -> u64 {
+        self.addr + self.size
+    }
+
+    fn overlaps(&self, addr: u64, end: u64) -> bool {
+        self.addr < end && addr < self.end()
+    }
+}
+
+/// The layout of the guest physical address space.
+///
+/// Ranges are added with a [`Kind`], and checked for conflicts as they
+/// are: MMIO may not overlap anything, RAM may not overlap RAM, and the
+/// other kinds may not overlap each other.  Reserved, ACPI, and NVS ranges
+/// may be declared on top of RAM, in which case they take precedence over
+/// it in the E820 table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    ranges: Vec<Range>,
+}
+
+impl MemoryMap {
+    /// Creates an empty memory map.
+    pub fn new() -> MemoryMap {
+        MemoryMap { ranges: vec![] }
+    }
+
+    /// Creates a memory map with the memory slots of the VM (in address
+    /// space 0) as RAM; read-only slots, which are usually ROM, are
+    /// reserved instead.  Fails with `EEXIST` if the slots overlap, or
+    /// `EINVAL` if one is empty.
+    pub fn from_vm(vm: &Vm) -> nix::Result<MemoryMap> {
+        let mut map = MemoryMap::new();
+        for region in vm.regions().iter().filter(|r| r.slot >> 16 == 0) {
+            let kind = if region.flags & KVM_MEM_READONLY != 0 {
+                Kind::Reserved
+            } else {
+                Kind::Ram
+            };
+            map.add(region.guest_phys_addr, region.memory_size, kind)?;
+        }
+
+        Ok(map)
+    }
+
+    /// Creates a memory map with all of the guest memory as RAM.  Fails
+    /// with `EINVAL` if a piece of it is empty.
+    pub fn from_memory(memory: &GuestMemory) -> nix::Result<MemoryMap> {
+        let mut map = MemoryMap::new();
+        for (addr, size) in memory.ranges() {
+            map.add(addr, size, Kind::Ram)?;
+        }
+
+        Ok(map)
+    }
+
+    /// Adds a range.  Fails with `EEXIST` if it conflicts with a range
+    /// already in the map (see [`MemoryMap::conflict`]), or `EINVAL` if it
+    /// is empty or runs past the end of the address space.
+    pub fn add(&mut self, addr: u64, size: u64, kind: Kind) -> nix::Result<()> {
+        if size == 0 || addr.checked_add(size).is_none() {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        if let Some(other) = self.conflict(addr, size, kind) {
+            warn!(
+                "{:?} at {:#x}+{:#x} conflicts with {:?} at {:#x}+{:#x}",
+                kind, addr, size, other.kind, other.addr, other.size
+            );
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+
+        self.ranges.push(Range { addr, size, kind });
+        self.ranges.sort_by_key(|r| r.addr);
+        Ok(())
+    }
+
+    /// Declares a reserved range.  See [`MemoryMap::add`].
+    pub fn reserve(&mut self, addr: u64, size: u64) -> nix::Result<()> {
+        self.add(addr, size, Kind::Reserved)
+    }
+
+    /// Declares the PCI hole, from [`PCI_HOLE_START`] to [`PCI_HOLE_END`],
+    /// as MMIO.  Fails with `EEXIST` if there is RAM in it.
+    pub fn add_pci_hole(&mut self) -> nix::Result<()> {
+        self.add(PCI_HOLE_START, PCI_HOLE_END - PCI_HOLE_START, Kind::Mmio)
+    }
+
+    /// The first range that a new range would conflict with, if any.
+    pub fn conflict(&self, addr: u64, size: u64, kind: Kind) -> Option<Range> {
+        let end = addr.saturating_add(size);
+        self.ranges
+            .iter()
+            .filter(|r| r.overlaps(addr, end))
+            .find(|r| {
+                let carves = (r.kind == Kind::Ram) != (kind == Kind::Ram);
+                r.kind == Kind::Mmio || kind == Kind::Mmio || !carves
+            })
+            .cloned()
+    }
+
+    /// The ranges, in address order.
+    pub fn ranges(&self) -> &[Range] {
+        &self.ranges
+    }
+
+    /// What the address is used for, if anything; declared ranges take
+    /// precedence over the RAM they're on.
+    pub fn kind(&self, addr: u64) -> Option<Kind> {
+        let mut found = self
+            .ranges
+            .iter()
+            .filter(|r| addr >= r.addr && addr - r.addr < r.size);
+        let first = found.next()?;
+        Some(match found.next() {
+            Some(r) if first.kind == Kind::Ram => r.kind,
+            _ => first.kind,
+        })
+    }
+
+    /// The E820 table for the map, in address order, with adjacent entries
+    /// of the same type merged.  RAM in the legacy VGA and BIOS areas,
+    /// between [`LOW_MEMORY_END`] and [`HIGH_MEMORY`], is left out, as are
+    /// MMIO ranges.
+    pub fn e820(&self) -> Vec<E820Entry> {
+        let declared = self
+            .ranges
+            .iter()
+            .filter(|r| r.kind != Kind::Ram)
+            .map(|r| (r.addr, r.end()))
+            .chain(Some((LOW_MEMORY_END, HIGH_MEMORY)))
+            .collect::<Vec<_>>();
+
+        let mut entries = vec![];
+        for range in &self.ranges {
+            match range.kind {
+                Kind::Ram => {
+                    for (start, end) in subtract((range.addr, range.end()), &declared) {
+                        entries.push(E820Entry {
+                            addr: start,
+                            size: end - start,
+                            kind: E820_RAM,
+                        });
+                    }
+                }
+                kind => {
+                    if let Some(e820) = kind.e820() {
+                        entries.push(E820Entry {
+                            addr: range.addr,
+                            size: range.size,
+                            kind: e820,
+                        });
+                    }
+                }
+            }
+        }
+
+        entries.sort_by_key(|e| e.addr);
+        let mut out: Vec<E820Entry> = vec![];
+        for entry in entries {
+            match out.last_mut() {
+                Some(last) if last.kind == entry.kind && last.addr + last.size == entry.addr => {
+                    last.size += entry.size
+                }
+                _ => out.push(entry),
+            }
+        }
+
+        out
+    }
+}
+
+/// Splits `size` bytes of RAM around the PCI hole, following the usual
+/// convention: as much as fits goes below [`PCI_HOLE_START`], and the rest
+/// at [`PCI_HOLE_END`].  Returns the `(guest_phys_addr, size)` of each
+/// piece, for the memory slots.
+pub fn ram_ranges(size: u64) -> Vec<(u64, u64)> {
+    let low = size.min(PCI_HOLE_START);
+    let mut out = vec![];
+    if low != 0 {
+        out.push((0, low));
+    }
+    if size > low {
+        out.push((PCI_HOLE_END, size - low));
+    }
+
+    out
+}
+
+/// The parts of `range` not covered by any of `holes`.
+fn subtract(range: (u64, u64), holes: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut pieces = vec![range];
+    for &(start, end) in holes {
+        pieces = pieces
+            .into_iter()
+            .flat_map(|(a, b)| vec![(a, b.min(start)), (a.max(end), b)])
+            .filter(|&(a, b)| a < b)
+            .collect();
+    }
+
+    pieces
+}
+
+impl Vm {
+    /// The guest memory of the VM (in address space 0), as registered
+    /// through [`Vm::set_user_memory_region`].  Changes to the memory slots
+    /// made afterwards aren't reflected in the returned map.  Fails with
+    /// `EEXIST` if the slots overlap, or `EINVAL` if one runs past the end
+    /// of the address space.
+    pub fn memory(&self) -> nix::Result<GuestMemory> {
+        let regions = self
+            .regions()
+            .iter()
+            .filter(|r| r.slot >> 16 == 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        // the requirements of `set_user_memory_region` cover this.
+        unsafe { GuestMemory::from_regions(&regions) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::test_vm;
+
+    /// Guest memory over two adjacent buffers, at `0x1000..0x2000` and
+    /// `0x2000..0x3000`, with a third at `0x4000..0x5000` past a hole.
+    fn split_memory(buffers: &mut [Vec<u8>; 3]) -> GuestMemory {
+        let mut memory = GuestMemory::new();
+        for (buffer, addr) in buffers.iter_mut().zip(&[0x2000, 0x1000, 0x4000]) {
+            unsafe { memory.add(*addr, buffer.as_mut_ptr(), buffer.len() as u64) }.unwrap();
+        }
+        memory
+    }
+
+    #[test]
+    fn read_write() {
+        let memory = anonymous_memory(0x1000);
+        memory.write(0x10, b"hello").unwrap();
+        let mut buf = [0; 5];
+        memory.read(0x10, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        memory.write_u8(0x20, 0x12).unwrap();
+        memory.write_u16(0x22, 0x3456).unwrap();
+        memory.write_u32(0x24, 0x789a_bcde).unwrap();
+        memory.write_u64(0x28, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(memory.read_u8(0x20), Ok(0x12));
+        assert_eq!(memory.read_u16(0x22), Ok(0x3456));
+        assert_eq!(memory.read_u32(0x24), Ok(0x789a_bcde));
+        assert_eq!(memory.read_u64(0x28), Ok(0x0102_0304_0506_0708));
+        // little endian.
+        assert_eq!(memory.read_u8(0x28), Ok(0x08));
+        assert_eq!(memory.read_u16(0x24), Ok(0xbcde));
+
+        memory.zero(0x21, 8).unwrap();
+        assert_eq!(memory.read_u64(0x21), Ok(0));
+        assert_eq!(memory.read_u8(0x20), Ok(0x12));
+        assert_eq!(memory.read_u8(0x29), Ok(0x07));
+    }
+
+    #[test]
+    fn spans_regions() {
+        let mut buffers = [vec![0; 0x1000], vec![0; 0x1000], vec![0; 0x1000]];
+        let memory = split_memory(&mut buffers);
+        assert_eq!(
+            memory.ranges(),
+            vec![(0x1000, 0x1000), (0x2000, 0x1000), (0x4000, 0x1000)]
+        );
+
+        memory.write_u64(0x1ffc, 0x1122_3344_5566_7788).unwrap();
+        assert_eq!(memory.read_u64(0x1ffc), Ok(0x1122_3344_5566_7788));
+        assert_eq!(&buffers[1][0xffc..], &[0x88, 0x77, 0x66, 0x55]);
+        assert_eq!(&buffers[0][..4], &[0x44, 0x33, 0x22, 0x11]);
+
+        assert!(memory.contains(0x1000, 0x2000));
+        assert!(memory.contains(0x4000, 0x1000));
+        assert!(!memory.contains(0x2ffc, 8));
+        assert!(!memory.contains(0x4ffc, 8));
+        assert!(memory.contains(0x4ffc, 4));
+    }
+
+    #[test]
+    fn outside_memory() {
+        let mut buffers = [vec![0; 0x1000], vec![0; 0x1000], vec![0; 0x1000]];
+        let memory = split_memory(&mut buffers);
+        let efault = nix::Error::Sys(Errno::EFAULT);
+
+        assert_eq!(memory.read_u8(0xfff), Err(efault));
+        assert_eq!(memory.read_u8(0x3000), Err(efault));
+        assert_eq!(memory.read_u32(0x2ffe), Err(efault));
+        assert_eq!(memory.read_u8(!0), Err(efault));
+        assert_eq!(memory.read(!0, &mut [0; 2]), Err(efault));
+
+        // a write running into the hole fails without writing anything.
+        assert_eq!(memory.write(0x2ffe, &[0xff; 4]), Err(efault));
+        assert_eq!(memory.zero(0xffe, 4), Err(efault));
+        assert_eq!(&buffers[1][0xffe..], &[0, 0]);
+
+        // empty accesses always succeed.
+        assert_eq!(memory.write(0x3000, &[]), Ok(()));
+    }
+
+    #[test]
+    fn add_checks_overlap() {
+        let mut buffers = [vec![0; 0x1000], vec![0; 0x1000], vec![0; 0x1000]];
+        let mut memory = split_memory(&mut buffers);
+        let mut other = vec![0u8; 0x1000];
+        let host = other.as_mut_ptr();
+
+        let eexist = Err(nix::Error::Sys(Errno::EEXIST));
+        assert_eq!(unsafe { memory.add(0x800, host, 0x1000) }, eexist);
+        assert_eq!(unsafe { memory.add(0x2fff, host, 2) }, eexist);
+        assert_eq!(unsafe { memory.add(0x3800, host, 0x1000) }, eexist);
+        assert_eq!(
+            unsafe { memory.add(!0xfff, host, 0x2000) },
+            Err(nix::Error::Sys(Errno::EINVAL))
+        );
+
+        // filling the hole exactly joins the pieces up.
+        assert_eq!(unsafe { memory.add(0x3000, host, 0x1000) }, Ok(()));
+        assert!(memory.contains(0x1000, 0x4000));
+        memory.write_u32(0x2ffe, 0xaabb_ccdd).unwrap();
+        assert_eq!(&other[..2], &[0xbb, 0xaa]);
+    }
+
+    #[test]
+    fn map_conflicts() {
+        let mut map = MemoryMap::new();
+        let eexist = nix::Error::Sys(Errno::EEXIST);
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        map.add(0, 0x8000_0000, Kind::Ram).unwrap();
+        map.add_pci_hole().unwrap();
+
+        // RAM may not overlap RAM, but other kinds may be declared on it.
+        assert_eq!(map.add(0x7fff_f000, 0x2000, Kind::Ram), Err(eexist));
+        map.reserve(0xf_0000, 0x1_0000).unwrap();
+        map.add(0x7fff_0000, 0x1_0000, Kind::Acpi).unwrap();
+        assert_eq!(map.add(0xf_8000, 0x1000, Kind::Nvs), Err(eexist));
+        assert_eq!(map.reserve(0x7fff_8000, 0x1000), Err(eexist));
+
+        // MMIO may not overlap anything.
+        assert_eq!(map.add(0x7fff_f000, 0x2000, Kind::Mmio), Err(eexist));
+        assert_eq!(map.reserve(0xfeff_c000, 0x4000), Err(eexist));
+        assert_eq!(map.add(0xbfff_f000, 0x2000, Kind::Ram), Err(eexist));
+        assert_eq!(
+            map.conflict(0xbfff_f000, 0x2000, Kind::Ram),
+            Some(Range {
+                addr: PCI_HOLE_START,
+                size: PCI_HOLE_END - PCI_HOLE_START,
+                kind: Kind::Mmio,
+            })
+        );
+        assert_eq!(map.conflict(0x8000_0000, 0x1000, Kind::Ram), None);
+
+        assert_eq!(map.add(0x1000, 0, Kind::Ram), Err(einval));
+        assert_eq!(map.reserve(!0xfff, 0x2000), Err(einval));
+
+        assert_eq!(map.kind(0x1000), Some(Kind::Ram));
+        assert_eq!(map.kind(0xf_0000), Some(Kind::Reserved));
+        assert_eq!(map.kind(0x7fff_ffff), Some(Kind::Acpi));
+        assert_eq!(map.kind(0xd000_0000), Some(Kind::Mmio));
+        assert_eq!(map.kind(0x8000_0000), None);
+        // up to the top of the address space.
+        map.reserve(!0xffff, 0xffff).unwrap();
+        assert_eq!(map.kind(!0x1), Some(Kind::Reserved));
+        assert_eq!(map.kind(u64::MAX), None);
+    }
+
+    #[test]
+    fn map_e820() {
+        let mut map = MemoryMap::new();
+        map.add(0, 0x8000_0000, Kind::Ram).unwrap();
+        map.add(PCI_HOLE_END + 0x2000_0000, 0x2000_0000, Kind::Ram)
+            .unwrap();
+        map.add(PCI_HOLE_END, 0x2000_0000, Kind::Ram).unwrap();
+        map.add(0x7ffe_0000, 0x1_0000, Kind::Nvs).unwrap();
+        map.add(0x7fff_0000, 0x1_0000, Kind::Acpi).unwrap();
+        map.reserve(0xf_0000, 0x1_0000).unwrap();
+        map.reserve(0xfeff_c000, 0x4000).unwrap();
+        map.add(0xd000_0000, 0x1000, Kind::Mmio).unwrap();
+
+        let entry = |addr, size, kind| E820Entry { addr, size, kind };
+        assert_eq!(
+            map.e820(),
+            vec![
+                entry(0, LOW_MEMORY_END, E820_RAM),
+                entry(0xf_0000, 0x1_0000, E820_RESERVED),
+                entry(HIGH_MEMORY, 0x7ffe_0000 - HIGH_MEMORY, E820_RAM),
+                entry(0x7ffe_0000, 0x1_0000, E820_NVS),
+                entry(0x7fff_0000, 0x1_0000, E820_ACPI),
+                entry(0xfeff_c000, 0x4000, E820_RESERVED),
+                entry(PCI_HOLE_END, 0x4000_0000, E820_RAM),
+            ]
+        );
+
+        let memory = anonymous_memory(0x20_0000);
+        assert_eq!(
+            MemoryMap::from_memory(&memory).unwrap().e820(),
+            vec![
+                entry(0, LOW_MEMORY_END, E820_RAM),
+                entry(HIGH_MEMORY, 0x10_0000, E820_RAM),
+            ]
+        );
+    }
+
+    #[test]
+    fn vm_memory() {
+        let (vm, _) = match test_vm(0x20_0000) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let map = MemoryMap::from_vm(&vm).unwrap();
+        assert_eq!(
+            map.ranges(),
+            &[Range {
+                addr: 0,
+                size: 0x20_0000,
+                kind: Kind::Ram,
+            }]
+        );
+        assert_eq!(vm.memory().unwrap().ranges(), vec![(0, 0x20_0000)]);
+    }
+}
@@ -0,0 +1,386 @@
+//! Live migration of a VM over a byte stream.
+//!
+//! The source side ([`Source`]) enables dirty logging on every memory slot,
+//! sends all of guest memory while the guest keeps running, and then sends
+//! whatever pages the guest dirtied in the meantime, over and over, until
+//! the remaining set is small ([`Source::precopy`]).  The caller then stops
+//! the vCPUs, and [`Source::complete`] sends the last dirty pages along with
+//! the CPU and device state.  The destination side ([`receive`]) loads all
+//! of this into a VM with the same memory layout.
+//!
+//! # Stream format
+//! All integers are little endian.  The stream starts with the magic bytes
+//! `KVMMIGR\0`, a `u32` version (currently 1), and the memory slot table: a
+//! `u32` count, then `slot: u32`, `flags: u32`, `guest_phys_addr: u64`, and
+//! `memory_size: u64` for each slot.  Records follow, each beginning with a
+//! one byte tag:
+//!
+//! - `1`, pages: `slot: u32`, `first_page: u64`, `count: u32`, and then
+//!   `count` whole pages of memory;
+//! - `2`, state: a [`VmState`], a `u32` vCPU count, and a [`VcpuState`]
+//!   for each vCPU, in the order of [`Vm::vcpus`];
+//! - `3`, end of stream.
+
+use super::ctl::{UserspaceMemoryRegion, KVM_MEM_LOG_DIRTY_PAGES};
+use super::dirty::{DirtyBitmap, PAGE_SIZE};
+use super::state::{io_error, read_u32, read_u64, write_u32, write_u64, VcpuState, VmState};
+use super::vm::{has_regions, host_memory, read_regions, write_regions, Vm};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"KVMMIGR\0";
+const VERSION: u32 = 1;
+
+const TAG_PAGES: u8 = 1;
+const TAG_STATE: u8 = 2;
+const TAG_END: u8 = 3;
+
+/// The most pages sent in a single pages record.
+const MAX_RUN: u64 = 256;
+
+/// How the pre-copy phase of a migration behaves.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PrecopyConfig {
+    /// The most passes over dirty memory to make after the initial copy.
+    pub max_iterations: u32,
+    /// Pre-copy stops once a pass finds this many dirty pages or fewer.
+    pub dirty_threshold: u64,
+    /// Whether manual dirty log protection has been enabled on the VM (see
+    /// [`enable_manual_protect`](../dirty/fn.enable_manual_protect.html)).
+    /// If it has, pages are re-protected in chunks right before they are
+    /// sent, rather than the whole slot at once.
+    pub manual_protect: bool,
+    /// The size of the chunks, in pages, when `manual_protect` is set.
+    pub chunk_pages: u64,
+}
+
+impl Default for PrecopyConfig {
+    fn default() -> PrecopyConfig {
+        PrecopyConfig {
+            max_iterations: 30,
+            dirty_threshold: 256,
+            manual_protect: false,
+            chunk_pages: 64 * 1024,
+        }
+    }
+}
+
+/// The sending side of a migration.
+pub struct Source<'a, W: Write> {
+    vm: &'a Vm,
+    out: W,
+    bitmaps: Vec<(UserspaceMemoryRegion, DirtyBitmap)>,
+    config: PrecopyConfig,
+    sent: u64,
+}
+
+impl<'a, W: Write> Source<'a, W> {
+    /// Starts a migration of the given VM, writing the stream header.  This
+    /// turns on dirty logging for every memory slot that doesn't already
+    /// have it, which is why it needs the VM mutably; the borrow is only
+    /// held for the duration of this call.
+    ///
+    /// # Safety
+    /// The memory slots of the VM must have been registered through
+    /// [`Vm::set_user_memory_region`], with its requirements upheld, and
+    /// must not change for the duration of the migration.
+    pub unsafe fn new(vm: &'a mut Vm, mut out: W, config: PrecopyConfig) -> io::Result<Self> {
+        let regions = vm.regions().to_vec();
+        for region in &regions {
+            if region.flags & KVM_MEM_LOG_DIRTY_PAGES == 0 {
+                let mut logged = *region;
+                logged.flags |= KVM_MEM_LOG_DIRTY_PAGES;
+                vm.set_user_memory_region(logged).map_err(io_error)?;
+            }
+        }
+
+        let vm: &'a Vm = vm;
+        out.write_all(MAGIC)?;
+        write_u32(&mut out, VERSION)?;
+        write_regions(&mut out, vm.regions())?;
+
+        let bitmaps = vm
+            .regions()
+            .iter()
+            .map(|r| (*r, DirtyBitmap::new(r)))
+            .collect();
+        Ok(Source {
+            vm,
+            out,
+            bitmaps,
+            config,
+            sent: 0,
+        })
+    }
+
+    /// The number of pages sent so far.
+    pub fn pages_sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Copies all of guest memory, and then iteratively copies the pages
+    /// dirtied during the previous pass, while the guest is running.
+    /// Returns the number of pages found dirty on the last pass, which
+    /// [`Source::complete`] will have to send with the guest stopped.
+    pub fn precopy(&mut self) -> io::Result<u64> {
+        // the first fetch arms tracking; everything is sent regardless.
+        for i in 0..self.bitmaps.len() {
+            unsafe { self.bitmaps[i].1.fetch(self.vm.fd()) }.map_err(io_error)?;
+            self.bitmaps[i].1.set_all();
+            self.send_dirty(i)?;
+        }
+
+        let mut dirty = 0;
+        for iteration in 0..self.config.max_iterations {
+            dirty = self.pass()?;
+            debug!("migration pass {}: {} pages dirty", iteration, dirty);
+            if dirty <= self.config.dirty_threshold {
+                break;
+            }
+        }
+
+        Ok(dirty)
+    }
+
+    /// Sends the remaining dirty pages and the state of the VM and all of
+    /// its vCPUs, including the MSRs with the given indices, and ends the
+    /// stream.  Every vCPU must have been stopped (i.e. no thread may be in
+    /// [`kvm_run`](../fn.kvm_run.html) for it) before calling this.
+    /// Returns the underlying writer.
+    pub fn complete(mut self, msrs: &[u32]) -> io::Result<W> {
+        self.pass()?;
+
+        let vm_state = unsafe { VmState::save(self.vm.fd()) }.map_err(io_error)?;
+        let vcpus = self
+            .vm
+            .vcpus()
+            .iter()
+            .map(|&fd| unsafe { VcpuState::save(fd, msrs) })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io_error)?;
+
+        self.out.write_all(&[TAG_STATE])?;
+        vm_state.write_to(&mut self.out)?;
+        write_u32(&mut self.out, vcpus.len() as u32)?;
+        for vcpu in &vcpus {
+            vcpu.write_to(&mut self.out)?;
+        }
+
+        self.out.write_all(&[TAG_END])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Fetches the dirty log of every slot and sends the dirty pages.
+    fn pass(&mut self) -> io::Result<u64> {
+        let mut dirty = 0;
+        for i in 0..self.bitmaps.len() {
+            dirty += unsafe { self.bitmaps[i].1.fetch(self.vm.fd()) }.map_err(io_error)?;
+            self.send_dirty(i)?;
+        }
+
+        Ok(dirty)
+    }
+
+    /// Sends the pages marked dirty in the bitmap of the given slot,
+    /// re-protecting them first if manual protection is in use.
+    fn send_dirty(&mut self, index: usize) -> io::Result<()> {
+        let chunks = if self.config.manual_protect {
+            self.bitmaps[index].1.chunks(self.config.chunk_pages)
+        } else {
+            vec![(0, self.bitmaps[index].1.pages())]
+        };
+
+        for (first, num) in chunks {
+            if self.config.manual_protect {
+                unsafe { self.bitmaps[index].1.clear(self.vm.fd(), first, num) }
+                    .map_err(io_error)?;
+            }
+
+            let runs = runs(&self.bitmaps[index].1, first, first + num);
+            for (start, count) in runs {
+                self.send_run(index, start, count)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_run(&mut self, index: usize, first: u64, count: u64) -> io::Result<()> {
+        let region = self.bitmaps[index].0;
+        let memory = unsafe { host_memory(&region) };
+        let start = (first * PAGE_SIZE) as usize;
+        let end = ((first + count) * PAGE_SIZE).min(region.memory_size) as usize;
+
+        self.out.write_all(&[TAG_PAGES])?;
+        write_u32(&mut self.out, region.slot)?;
+        write_u64(&mut self.out, first)?;
+        write_u32(&mut self.out, count as u32)?;
+        self.out.write_all(&memory[start..end])?;
+        self.sent += count;
+        Ok(())
+    }
+}
+
+/// Receives a migration stream into the given VM.  The VM must have the
+/// same memory slots (by slot id, address, and size) as the source, the
+/// same number of vCPUs, and, if the source had them, an in-kernel irqchip
+/// and PIT already created.  None of its vCPUs may be running.
+///
+/// # Safety
+/// The memory slots of the VM must have been registered through
+/// [`Vm::set_user_memory_region`], with its requirements upheld.
+pub unsafe fn receive<R: Read>(vm: &Vm, mut input: R) -> io::Result<()> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a migration stream"));
+    }
+    if read_u32(&mut input)? != VERSION {
+        return Err(invalid("unsupported migration stream version"));
+    }
+
+    let regions = read_regions(&mut input)?;
+    if !has_regions(vm, &regions) {
+        return Err(invalid("memory slots do not match the source"));
+    }
+
+    loop {
+        let mut tag = [0];
+        input.read_exact(&mut tag)?;
+        match tag[0] {
+            TAG_PAGES => {
+                let slot = read_u32(&mut input)?;
+                let first = read_u64(&mut input)?;
+                let count = u64::from(read_u32(&mut input)?);
+                let region = match vm.region(slot) {
+                    Some(r) if regions.iter().any(|s| s.slot == slot) => *r,
+                    _ => return Err(invalid("pages for an unknown memory slot")),
+                };
+
+                let start = first.saturating_mul(PAGE_SIZE);
+                let end = (first.saturating_add(count))
+                    .saturating_mul(PAGE_SIZE)
+                    .min(region.memory_size);
+                if start >= end {
+                    return Err(invalid("pages outside of their memory slot"));
+                }
+                input.read_exact(&mut host_memory(&region)[start as usize..end as usize])?;
+            }
+            TAG_STATE => {
+                let vm_state = VmState::read_from(&mut input)?;
+                let count = read_u32(&mut input)? as usize;
+                if count != vm.vcpus().len() {
+                    return Err(invalid("vCPU count does not match the source"));
+                }
+
+                for &fd in vm.vcpus() {
+                    VcpuState::read_from(&mut input)?
+                        .restore(fd)
+                        .map_err(io_error)?;
+                }
+                vm_state.restore(vm.fd()).map_err(io_error)?;
+            }
+            TAG_END => return Ok(()),
+            _ => return Err(invalid("unknown migration record")),
+        }
+    }
+}
+
+/// Splits the dirty pages of a bitmap in `first..end` into runs of
+/// consecutive pages, as `(first_page, count)`.
+fn runs(bitmap: &DirtyBitmap, first: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut out: Vec<(u64, u64)> = vec![];
+    for page in bitmap.iter_from(first).take_while(|&p| p < end) {
+        match out.last_mut() {
+            Some(&mut (start, ref mut count)) if start + *count == page && *count < MAX_RUN => {
+                *count += 1
+            }
+            _ => out.push((page, 1)),
+        }
+    }
+
+    out
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ctl::{
+        kvm_get_debugregs, kvm_get_vcpu_events, kvm_set_debugregs, kvm_set_vcpu_events,
+        KVM_VCPUEVENT_VALID_NMI_PENDING,
+    };
+    use std::mem::zeroed;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use vm::test_vm;
+    use x86::{kvm_get_regs, kvm_set_regs};
+
+    #[test]
+    fn migrate_over_socketpair() {
+        let size = 0x10_0000;
+        let (mut source, source_memory) = match test_vm(size) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let (dest, dest_memory) = test_vm(size).unwrap();
+
+        let pattern = (0..3 * PAGE_SIZE)
+            .map(|i| i as u8 ^ 0x5a)
+            .collect::<Vec<_>>();
+        source_memory.write(0x4000, &pattern).unwrap();
+        let fd = source.vcpus()[0];
+        unsafe {
+            let mut regs = zeroed();
+            kvm_get_regs(fd, &mut regs).unwrap();
+            regs.rax = 0x1234_5678;
+            regs.rip = 0xfff0;
+            kvm_set_regs(fd, &regs).unwrap();
+
+            let mut debugregs = zeroed();
+            kvm_get_debugregs(fd, &mut debugregs).unwrap();
+            debugregs.db = [0x1000, 0x2000, 0x3000, 0x4000];
+            debugregs.dr7 = 0x455;
+            kvm_set_debugregs(fd, &debugregs).unwrap();
+
+            let mut events = zeroed();
+            kvm_get_vcpu_events(fd, &mut events).unwrap();
+            events.nmi.pending = 1;
+            events.flags |= KVM_VCPUEVENT_VALID_NMI_PENDING;
+            kvm_set_vcpu_events(fd, &events).unwrap();
+        }
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let received = {
+            let dest = &dest;
+            thread::scope(|scope| {
+                let receiver = scope.spawn(move || unsafe { receive(dest, rx) });
+                let mut sender =
+                    unsafe { Source::new(&mut source, tx, PrecopyConfig::default()) }.unwrap();
+                sender.precopy().unwrap();
+                assert!(sender.pages_sent() >= size / PAGE_SIZE);
+                sender.complete(&[]).unwrap();
+                receiver.join().unwrap()
+            })
+        };
+        received.unwrap();
+
+        let mut copy = vec![0; pattern.len()];
+        dest_memory.read(0x4000, &mut copy).unwrap();
+        assert_eq!(copy, pattern);
+
+        let (from, to) = (source.vcpus()[0], dest.vcpus()[0]);
+        let saved = unsafe { VcpuState::save(from, &[]) }.unwrap();
+        let restored = unsafe { VcpuState::save(to, &[]) }.unwrap();
+        assert_eq!(restored.regs.rax, 0x1234_5678);
+        assert_eq!(restored.regs.rip, saved.regs.rip);
+        assert_eq!(restored.debugregs.db, [0x1000, 0x2000, 0x3000, 0x4000]);
+        assert_eq!(restored.debugregs, saved.debugregs);
+        assert_eq!(restored.events.nmi.pending, 1);
+        assert_eq!(restored.events, saved.events);
+        assert_eq!(restored.xcrs, saved.xcrs);
+    }
+}
@@ -0,0 +1,291 @@
+//! Generating Intel MultiProcessor Specification (version 1.4) tables for a
+//! guest.
+//!
+//! Guests without ACPI support, or booted without firmware, find their
+//! processors and interrupt routing through the MP floating pointer, which
+//! they look for in the first kilobyte of the EBDA, among other places.  The
+//! tables describe the in-kernel irqchip (see
+//! [`kvm_create_irqchip`](../fn.kvm_create_irqchip.html)) the same way the ACPI
+//! MADT does (see [`acpi`](../acpi/index.html)):
+//!
+//! - a processor for each vCPU, with APIC ids 0 and up, the first being the
+//!   bootstrap processor;
+//! - an ISA bus;
+//! - the IOAPIC, with the ISA IRQs on the pins of the same number, except
+//!   for IRQ 0 on pin 2 (and no IRQ 2, the cascade), and the 8259 on pin 0
+//!   as an ExtINT;
+//! - the 8259 on LINT0 of every local APIC, and the NMI on LINT1.
+//!
+//! [`write`] lays these out in guest memory, by default at [`MPTABLE_ADDR`].
+//! Both the floating pointer and the configuration table are checksummed.
+
+use super::acpi::{checksum, IOAPIC_ADDR, IOAPIC_ID, LAPIC_ADDR};
+use super::memory::{GuestMemory, LOW_MEMORY_END};
+use super::vm::Vm;
+use nix;
+use nix::errno::Errno;
+
+/// Where the tables go by default: the start of the EBDA, the last
+/// kilobyte of conventional memory.  The range from here to
+/// [`LOW_MEMORY_END`](../memory/constant.LOW_MEMORY_END.html) should be
+/// reserved in the guest's memory map.
+pub const MPTABLE_ADDR: u64 = 0x9_fc00;
+
+/// The specification revision, 1.4.
+pub const SPEC_REVISION: u8 = 4;
+
+const FLOATING_POINTER_SIZE: usize = 16;
+const HEADER_SIZE: usize = 44;
+const PROCESSOR_SIZE: usize = 20;
+const OEM_ID: [u8; 8] = *b"KVMSYS  ";
+const PRODUCT_ID: [u8; 12] = *b"KVMSYS      ";
+
+// entry types.
+const MP_PROCESSOR: u8 = 0;
+const MP_BUS: u8 = 1;
+const MP_IOAPIC: u8 = 2;
+const MP_INTSRC: u8 = 3;
+const MP_LINTSRC: u8 = 4;
+
+const CPU_ENABLED: u8 = 1 << 0;
+const CPU_BOOTPROCESSOR: u8 = 1 << 1;
+/// A family 6 processor.
+const CPU_SIGNATURE: u32 = 0x600;
+/// FPU and APIC.
+const CPU_FEATURES: u32 = 1 << 0 | 1 << 9;
+const LAPIC_VERSION: u8 = 0x14;
+const IOAPIC_VERSION: u8 = 0x11;
+const IOAPIC_ENABLED: u8 = 1;
+const ISA_BUS: u8 = 0;
+
+// interrupt types.
+const MP_INT: u8 = 0;
+const MP_NMI: u8 = 1;
+const MP_EXTINT: u8 = 3;
+/// All local APICs.
+const ALL_LAPICS: u8 = 0xff;
+
+/// The number of ISA IRQs routed through the IOAPIC.
+pub const ISA_IRQS: u8 = 16;
+
+/// The MP configuration table, with a processor entry for each of `cpus`
+/// vCPUs.
+pub fn config_table(cpus: u8) -> Vec<u8> {
+    let mut entries = vec![];
+    let mut count = 0u16;
+    let mut entry = |bytes: &[u8]| {
+        entries.extend_from_slice(bytes);
+        count += 1;
+    };
+
+    for id in 0..cpus {
+        let flags = match id {
+            0 => CPU_ENABLED | CPU_BOOTPROCESSOR,
+            _ => CPU_ENABLED,
+        };
+        let mut processor = vec![MP_PROCESSOR, id, LAPIC_VERSION, flags];
+        processor.extend_from_slice(&CPU_SIGNATURE.to_le_bytes());
+        processor.extend_from_slice(&CPU_FEATURES.to_le_bytes());
+        processor.resize(PROCESSOR_SIZE, 0);
+        entry(&processor);
+    }
+
+    entry(&[MP_BUS, ISA_BUS, b'I', b'S', b'A', b' ', b' ', b' ']);
+
+    let mut ioapic = vec![MP_IOAPIC, IOAPIC_ID, IOAPIC_VERSION, IOAPIC_ENABLED];
+    ioapic.extend_from_slice(&IOAPIC_ADDR.to_le_bytes());
+    entry(&ioapic);
+
+    // the flags of 0 take the bus's polarity and trigger mode.
+    entry(&[MP_INTSRC, MP_EXTINT, 0, 0, ISA_BUS, 0, IOAPIC_ID, 0]);
+    for irq in 0..ISA_IRQS {
+        // the PIT, on IRQ 0, is wired to pin 2; pin 0 is the 8259.
+        let pin = match irq {
+            0 => 2,
+            // pin 2 is taken by IRQ 0, and IRQ 2 is the 8259 cascade.
+            2 => continue,
+            irq => irq,
+        };
+        entry(&[MP_INTSRC, MP_INT, 0, 0, ISA_BUS, irq, IOAPIC_ID, pin]);
+    }
+
+    entry(&[MP_LINTSRC, MP_EXTINT, 0, 0, ISA_BUS, 0, ALL_LAPICS, 0]);
+    entry(&[MP_LINTSRC, MP_NMI, 0, 0, ISA_BUS, 0, ALL_LAPICS, 1]);
+
+    let mut out = b"PCMP".to_vec();
+    out.extend_from_slice(&((HEADER_SIZE + entries.len()) as u16).to_le_bytes());
+    out.push(SPEC_REVISION);
+    out.push(0);
+    out.extend_from_slice(&OEM_ID);
+    out.extend_from_slice(&PRODUCT_ID);
+    // no OEM table.
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&LAPIC_ADDR.to_le_bytes());
+    // no extended entries.
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&entries);
+
+    out[7] = checksum(&out);
+    out
+}
+
+/// The MP floating pointer, pointing to the configuration table.
+pub fn floating_pointer(config_table: u32) -> Vec<u8> {
+    let mut out = b"_MP_".to_vec();
+    out.extend_from_slice(&config_table.to_le_bytes());
+    // the length, in 16-byte units.
+    out.push(1);
+    out.push(SPEC_REVISION);
+    out.push(0);
+    // no default configuration, and no IMCR: the 8259 is in virtual wire
+    // mode.
+    out.extend_from_slice(&[0; 5]);
+
+    out[10] = checksum(&out);
+    out
+}
+
+/// Writes the tables for `cpus` vCPUs into guest memory, with the floating
+/// pointer at `addr` (which must be 16-byte aligned to be found by a scan)
+/// and the configuration table right after it.  Returns the end of the
+/// tables.  Fails with `EINVAL` for more vCPUs than there are APIC ids
+/// (254), or if the tables would end above 4 GiB.
+pub fn write(memory: &GuestMemory, addr: u64, cpus: usize) -> nix::Result<u64> {
+    if cpus == 0 || cpus >= usize::from(ALL_LAPICS) {
+        return Err(nix::Error::Sys(Errno::EINVAL));
+    }
+
+    let table_addr = addr + FLOATING_POINTER_SIZE as u64;
+    let table = config_table(cpus as u8);
+    let end = table_addr + table.len() as u64;
+    if end > 1 << 32 {
+        return Err(nix::Error::Sys(Errno::EINVAL));
+    }
+
+    memory.write(addr, &floating_pointer(table_addr as u32))?;
+    memory.write(table_addr, &table)?;
+    Ok(end)
+}
+
+impl Vm {
+    /// Writes the MP tables for the vCPUs of the VM at [`MPTABLE_ADDR`].
+    /// See [`write`].  Fails with `ENOSPC` if there are too many vCPUs for
+    /// the tables to fit in the EBDA (about 40).
+    pub fn write_mptable(&self, memory: &GuestMemory) -> nix::Result<u64> {
+        let cpus = self.vcpus().len();
+        let size = FLOATING_POINTER_SIZE + config_table(0).len() + PROCESSOR_SIZE * cpus;
+        if MPTABLE_ADDR + size as u64 > LOW_MEMORY_END {
+            return Err(nix::Error::Sys(Errno::ENOSPC));
+        }
+
+        write(memory, MPTABLE_ADDR, cpus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::anonymous_memory;
+    use super::*;
+
+    fn sum(bytes: &[u8]) -> u8 {
+        bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut b = [0; 4];
+        b.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(b)
+    }
+
+    #[test]
+    fn floating_pointer_fields() {
+        for &addr in &[0, MPTABLE_ADDR as u32 + 16, 0xdead_beef, !0] {
+            let fp = floating_pointer(addr);
+            assert_eq!(fp.len(), FLOATING_POINTER_SIZE);
+            assert_eq!(sum(&fp), 0);
+            assert_eq!(&fp[..4], b"_MP_");
+            assert_eq!(u32_at(&fp, 4), addr);
+            assert_eq!(fp[8], 1);
+            assert_eq!(fp[9], SPEC_REVISION);
+            assert_eq!(&fp[11..], &[0; 5]);
+        }
+    }
+
+    #[test]
+    fn config_table_fields() {
+        for cpus in 0..ALL_LAPICS {
+            let table = config_table(cpus);
+            assert_eq!(sum(&table), 0, "{} vCPUs", cpus);
+            assert_eq!(&table[..4], b"PCMP");
+            // the base table length covers the header and every entry.
+            assert_eq!(usize::from(u16_at(&table, 4)), table.len());
+            assert_eq!(table[6], SPEC_REVISION);
+            // the processors, the bus, the IOAPIC, 16 interrupt sources
+            // (the ExtINT and the ISA IRQs but 2), and 2 local ones.
+            assert_eq!(u16_at(&table, 34), u16::from(cpus) + 20);
+            assert_eq!(u32_at(&table, 36), LAPIC_ADDR);
+            assert_eq!(
+                table.len(),
+                HEADER_SIZE + PROCESSOR_SIZE * usize::from(cpus) + 8 * 20
+            );
+        }
+    }
+
+    #[test]
+    fn processors() {
+        let table = config_table(3);
+        let processor = |id: usize| &table[HEADER_SIZE + PROCESSOR_SIZE * id..][..PROCESSOR_SIZE];
+        assert_eq!(&processor(0)[..4], &[MP_PROCESSOR, 0, LAPIC_VERSION, 3]);
+        assert_eq!(&processor(1)[..4], &[MP_PROCESSOR, 1, LAPIC_VERSION, 1]);
+        assert_eq!(&processor(2)[..4], &[MP_PROCESSOR, 2, LAPIC_VERSION, 1]);
+        assert_eq!(u32_at(processor(2), 4), CPU_SIGNATURE);
+        assert_eq!(u32_at(processor(2), 8), CPU_FEATURES);
+        assert_eq!(table[HEADER_SIZE + PROCESSOR_SIZE * 3], MP_BUS);
+    }
+
+    #[test]
+    fn write_tables() {
+        let memory = anonymous_memory(LOW_MEMORY_END);
+        let end = write(&memory, MPTABLE_ADDR, 2).unwrap();
+        let table = config_table(2);
+        assert_eq!(end, MPTABLE_ADDR + 16 + table.len() as u64);
+
+        let mut read = vec![0; (end - MPTABLE_ADDR) as usize];
+        memory.read(MPTABLE_ADDR, &mut read).unwrap();
+        assert_eq!(read[..16], floating_pointer(MPTABLE_ADDR as u32 + 16)[..]);
+        assert_eq!(read[16..], table[..]);
+
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        assert_eq!(write(&memory, MPTABLE_ADDR, 0), Err(einval));
+        assert_eq!(write(&memory, MPTABLE_ADDR, 255), Err(einval));
+        assert_eq!(write(&memory, (1 << 32) - 0x80, 1), Err(einval));
+        assert_eq!(
+            write(&memory, LOW_MEMORY_END, 1),
+            Err(nix::Error::Sys(Errno::EFAULT))
+        );
+    }
+
+    #[test]
+    fn write_mptable_fits_ebda() {
+        let memory = anonymous_memory(LOW_MEMORY_END);
+        let mut vm = Vm::new(-1);
+        for _ in 0..40 {
+            vm.add_vcpu(-1);
+        }
+        let end = vm.write_mptable(&memory).unwrap();
+        assert!(end <= LOW_MEMORY_END);
+        assert_eq!(end, MPTABLE_ADDR + 16 + config_table(40).len() as u64);
+
+        vm.add_vcpu(-1);
+        assert_eq!(
+            vm.write_mptable(&memory),
+            Err(nix::Error::Sys(Errno::ENOSPC))
+        );
+    }
+}
@@ -1,5 +1,24 @@
+//! The `kvm_run` structure that a vCPU shares with userspace, and a loop
+//! that runs a vCPU on it.
+//!
+//! [`MappedRun`] maps a vCPU's `kvm_run`, and [`MappedRun::run`] runs the
+//! vCPU, handing port I/O and MMIO to a [`Bus`] of devices, until the
+//! guest or a device stops it, as reported by a [`RunResult`].  A device
+//! that stops the VM stops every vCPU that runs with the same
+//! [`VmEvents`].
+
 use super::consts;
+use super::ctl::{kvm_get_vcpu_mmap_size, kvm_run};
+use super::devices::{Bus, VmEvent};
+use libc;
+use nix;
+use nix::errno::Errno;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use std::fmt;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -296,9 +315,404 @@ impl fmt::Debug for ExitDebug {
             consts::KVM_EXIT_SYSTEM_EVENT => tuple.field(&unsafe { self.0.system_event }),
             consts::KVM_EXIT_S390_STSI => tuple.field(&unsafe { self.0.s390_stsi }),
             consts::KVM_EXIT_IOAPIC_EOI => tuple.field(&unsafe { self.0.eoi }),
+            // there's no payload; the vCPU's dirty ring needs harvesting.
+            consts::KVM_EXIT_DIRTY_RING_FULL => tuple.field(&"dirty ring full"),
             _ => tuple.field(&unsafe { self.0._pad.iter() }).field(&self.1),
         };
 
         tuple.finish()
     }
 }
+
+/// Why [`MappedRun::run`] stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunResult {
+    /// A device ended the run with a status code (see
+    /// [`DebugExit`](../devices/debug/struct.DebugExit.html)).
+    Exited(u32),
+    /// The guest reset the CPU, through a device or a `KVM_EXIT_SYSTEM_EVENT`.
+    Reset,
+    /// The guest shut down, through a triple fault or a
+    /// `KVM_EXIT_SYSTEM_EVENT`.
+    Shutdown,
+    /// The vCPU halted, which only exits to userspace without an in-kernel
+    /// irqchip.
+    Halted,
+    /// A signal interrupted the run.
+    Interrupted,
+    /// Some other system event, e.g. `KVM_SYSTEM_EVENT_CRASH`.
+    SystemEvent(u32),
+    /// The vCPU's dirty ring is full.  Its ring must be harvested (see
+    /// [`DirtyRing::harvest`](../dirty/struct.DirtyRing.html#method.harvest)),
+    /// and [`kvm_reset_dirty_rings`](../fn.kvm_reset_dirty_rings.html)
+    /// called, before running it again.
+    DirtyRingFull,
+    /// An exit the loop doesn't handle, left in [`Run`] for the caller.
+    Unhandled(u32),
+}
+
+impl From<VmEvent> for RunResult {
+    fn from(event: VmEvent) -> RunResult {
+        match event {
+            VmEvent::Reset => RunResult::Reset,
+            VmEvent::Exit(code) => RunResult::Exited(code),
+        }
+    }
+}
+
+/// The events the devices of a VM send, shared by the run loops of all of
+/// its vCPUs.
+///
+/// The first event stops every vCPU: the one whose access sent it returns
+/// it straight after the access, and the others are kicked out of
+/// `KVM_RUN` by setting their `immediate_exit`, and by sending their thread
+/// a signal whose handler does nothing.  The event is kept, so that runs
+/// started afterwards return it at once, until it is cleared; events sent
+/// in the meantime are dropped.
+pub struct VmEvents {
+    signal: Signal,
+    state: Mutex<EventState>,
+}
+
+struct EventState {
+    receiver: Receiver<VmEvent>,
+    event: Option<VmEvent>,
+    running: Vec<Running>,
+}
+
+// a vCPU in `MappedRun::run`, on `thread`.
+struct Running {
+    thread: libc::pthread_t,
+    run: *mut Run,
+}
+
+unsafe impl Send for Running {}
+
+extern "C" fn kick(_: libc::c_int) {}
+
+impl VmEvents {
+    /// Takes the events the devices send to `receiver`, kicking vCPUs with
+    /// `signal`.  This replaces the process's handler for `signal`, which
+    /// should be one that nothing else uses, e.g. `SIGUSR1`: it may also
+    /// interrupt a system call on a vCPU's thread just after its run.
+    pub fn new(receiver: Receiver<VmEvent>, signal: Signal) -> nix::Result<VmEvents> {
+        let action = SigAction::new(SigHandler::Handler(kick), SaFlags::empty(), SigSet::empty());
+        unsafe { signal::sigaction(signal, &action) }?;
+
+        Ok(VmEvents {
+            signal,
+            state: Mutex::new(EventState {
+                receiver,
+                event: None,
+                running: vec![],
+            }),
+        })
+    }
+
+    /// The event that stopped the vCPUs, if any.
+    pub fn event(&self) -> Option<VmEvent> {
+        self.state.lock().unwrap().event
+    }
+
+    /// Forgets the event that stopped the vCPUs, and any sent since, e.g.
+    /// once the VM has been reset, so that they can run again.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.event = None;
+        while state.receiver.try_recv().is_ok() {}
+    }
+
+    // takes the events sent so far, kicking every vCPU but the one on `own`
+    // if the first arrives; returns the event, if there is one.
+    fn poll(&self, state: &mut EventState, own: *mut Run) -> Option<VmEvent> {
+        while let Ok(event) = state.receiver.try_recv() {
+            if state.event.is_some() {
+                continue;
+            }
+
+            state.event = Some(event);
+            for running in state.running.iter().filter(|r| r.run != own) {
+                unsafe {
+                    ptr::write_volatile(&mut (*running.run).immediate_exit, 1);
+                    libc::pthread_kill(running.thread, self.signal as libc::c_int);
+                }
+            }
+        }
+
+        state.event
+    }
+
+    // registers the vCPU on `run` as running on this thread, unless there
+    // already is an event.
+    fn enter(&self, run: *mut Run) -> Option<VmEvent> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(event) = self.poll(&mut state, run) {
+            return Some(event);
+        }
+
+        state.running.push(Running {
+            thread: unsafe { libc::pthread_self() },
+            run,
+        });
+        None
+    }
+
+    fn leave(&self, run: *mut Run) {
+        let mut state = self.state.lock().unwrap();
+        state.running.retain(|r| r.run != run);
+        unsafe { ptr::write_volatile(&mut (*run).immediate_exit, 0) };
+    }
+
+    fn check(&self, run: *mut Run) -> Option<VmEvent> {
+        let mut state = self.state.lock().unwrap();
+        self.poll(&mut state, run)
+    }
+}
+
+/// The `kvm_run` of a single vCPU, mapped into this process.  It is
+/// unmapped on drop.
+#[derive(Debug)]
+pub struct MappedRun {
+    fd: RawFd,
+    run: *mut Run,
+    size: usize,
+}
+
+unsafe impl Send for MappedRun {}
+
+impl MappedRun {
+    /// Maps the `kvm_run` of the vCPU `fd`, with the size from the system
+    /// file descriptor `kvm`.
+    ///
+    /// # Safety
+    /// `kvm` must be the system file descriptor, and `fd` a vCPU file
+    /// descriptor; KVM writes to the mapping for as long as it exists.
+    ///
+    /// # Support
+    /// See [`kvm_get_vcpu_mmap_size`].
+    pub unsafe fn map(kvm: RawFd, fd: RawFd) -> nix::Result<MappedRun> {
+        let size = kvm_get_vcpu_mmap_size(kvm)? as usize;
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+
+        if addr == libc::MAP_FAILED {
+            return Err(nix::Error::Sys(Errno::last()));
+        }
+
+        Ok(MappedRun {
+            fd,
+            run: addr as *mut Run,
+            size,
+        })
+    }
+
+    /// The vCPU file descriptor.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// The mapped structure, e.g. for [`Bus::handle_io`].
+    pub fn as_ptr(&self) -> *mut Run {
+        self.run
+    }
+
+    /// The mapped structure.
+    pub fn get(&self) -> &Run {
+        unsafe { &*self.run }
+    }
+
+    /// The mapped structure, to change its inputs.
+    pub fn get_mut(&mut self) -> &mut Run {
+        unsafe { &mut *self.run }
+    }
+
+    /// Runs the vCPU until it stops for a reason in [`RunResult`], handing
+    /// port I/O and MMIO exits to `bus` (unclaimed accesses are dropped),
+    /// and checking `events` after each for a device asking to reset or
+    /// exit.  Returns at once if a device already has, and is kicked out if
+    /// one does during another vCPU's access.  Fails if [`kvm_run`] does,
+    /// other than by being interrupted.
+    ///
+    /// # Support
+    /// See [`kvm_run`].
+    pub fn run(&mut self, bus: &Bus, events: &VmEvents) -> nix::Result<RunResult> {
+        if let Some(event) = events.enter(self.run) {
+            return Ok(event.into());
+        }
+
+        let result = self.run_until_event(bus, events);
+        events.leave(self.run);
+        result
+    }
+
+    fn run_until_event(&mut self, bus: &Bus, events: &VmEvents) -> nix::Result<RunResult> {
+        loop {
+            match unsafe { kvm_run(self.fd) } {
+                Ok(_) => {}
+                Err(nix::Error::Sys(Errno::EINTR)) => {
+                    return Ok(events
+                        .event()
+                        .map_or(RunResult::Interrupted, RunResult::from));
+                }
+                Err(e) => return Err(e),
+            }
+
+            let run = self.get_mut();
+            match run.exit_reason {
+                consts::KVM_EXIT_IO => {
+                    unsafe { bus.handle_io(run) };
+                }
+                consts::KVM_EXIT_MMIO => {
+                    bus.handle_mmio(run);
+                }
+                consts::KVM_EXIT_HLT => return Ok(RunResult::Halted),
+                consts::KVM_EXIT_SHUTDOWN => return Ok(RunResult::Shutdown),
+                consts::KVM_EXIT_INTR => return Ok(RunResult::Interrupted),
+                consts::KVM_EXIT_DIRTY_RING_FULL => return Ok(RunResult::DirtyRingFull),
+                consts::KVM_EXIT_SYSTEM_EVENT => {
+                    return Ok(match unsafe { run.exit.system_event }.kind {
+                        consts::KVM_SYSTEM_EVENT_RESET => RunResult::Reset,
+                        consts::KVM_SYSTEM_EVENT_SHUTDOWN => RunResult::Shutdown,
+                        kind => RunResult::SystemEvent(kind),
+                    });
+                }
+                reason => return Ok(RunResult::Unhandled(reason)),
+            }
+
+            if let Some(event) = events.check(self.run) {
+                return Ok(event.into());
+            }
+        }
+    }
+}
+
+impl Drop for MappedRun {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.run as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::debug::{DebugExit, EXIT_PORT, EXIT_PORTS};
+    use devices::i8042::{COMMAND_PORT, DATA_PORT, I8042};
+    use devices::Space;
+    use nix::fcntl::{self, OFlag};
+    use nix::sys::stat::Mode;
+    use std::mem;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use vm::test_vm;
+    use x86::mode::real_mode;
+    use x86::{kvm_get_sregs, kvm_set_regs, kvm_set_sregs, Regs, Sregs};
+
+    // mov dx, 0x501; mov al, 0x2a; out dx, al; hlt
+    const EXIT: [u8; 7] = [0xba, 0x01, 0x05, 0xb0, 0x2a, 0xee, 0xf4];
+
+    // a bus with the exit port and the i8042, and the events they send.
+    fn bus() -> (Bus, VmEvents) {
+        let (tx, rx) = mpsc::channel();
+        let mut bus = Bus::new();
+        let exit = Arc::new(Mutex::new(DebugExit::new(tx.clone())));
+        bus.insert(exit, Space::Io, u64::from(EXIT_PORT), EXIT_PORTS)
+            .unwrap();
+        let i8042 = Arc::new(Mutex::new(I8042::new(tx, None, None)));
+        bus.insert(i8042.clone(), Space::Io, u64::from(DATA_PORT), 1)
+            .unwrap();
+        bus.insert(i8042, Space::Io, u64::from(COMMAND_PORT), 1)
+            .unwrap();
+        (bus, VmEvents::new(rx, Signal::SIGUSR1).unwrap())
+    }
+
+    // maps the vCPU's `kvm_run`, with it about to run at `rip` in real mode.
+    fn start(vcpu: RawFd, rip: u64) -> MappedRun {
+        let mut sregs: Sregs = unsafe { mem::zeroed() };
+        unsafe { kvm_get_sregs(vcpu, &mut sregs) }.unwrap();
+        real_mode(&mut sregs);
+        unsafe { kvm_set_sregs(vcpu, &sregs) }.unwrap();
+        let regs = Regs {
+            rip,
+            rflags: 2,
+            ..Regs::default()
+        };
+        unsafe { kvm_set_regs(vcpu, &regs) }.unwrap();
+
+        let kvm = fcntl::open("/dev/kvm", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty()).unwrap();
+        unsafe { MappedRun::map(kvm, vcpu) }.unwrap()
+    }
+
+    #[test]
+    fn exit() {
+        let (vm, memory) = match test_vm(0x10_0000) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let (bus, events) = bus();
+        memory.write(0x1000, &EXIT).unwrap();
+        let mut run = start(vm.vcpus()[0], 0x1000);
+
+        assert_eq!(run.run(&bus, &events), Ok(RunResult::Exited(0x55)));
+        assert_eq!(events.event(), Some(VmEvent::Exit(0x55)));
+        // until it is cleared, the event stops any run at once.
+        assert_eq!(run.run(&bus, &events), Ok(RunResult::Exited(0x55)));
+        assert_eq!(run.get().immediate_exit, 0);
+        events.clear();
+        assert_eq!(events.event(), None);
+        assert_eq!(run.run(&bus, &events), Ok(RunResult::Halted));
+    }
+
+    #[test]
+    fn reset() {
+        let (vm, memory) = match test_vm(0x10_0000) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let (bus, events) = bus();
+        // mov al, 0xfe; out 0x64, al; hlt
+        memory
+            .write(0x1000, &[0xb0, 0xfe, 0xe6, 0x64, 0xf4])
+            .unwrap();
+        let mut run = start(vm.vcpus()[0], 0x1000);
+
+        assert_eq!(run.run(&bus, &events), Ok(RunResult::Reset));
+        assert_eq!(events.event(), Some(VmEvent::Reset));
+    }
+
+    #[test]
+    fn kicks_other_vcpus() {
+        let (mut vm, memory) = match test_vm(0x10_0000) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let spinner = unsafe { vm.create_vcpu(1) }.unwrap();
+        let (bus, events) = bus();
+        // the spinner: mov byte [0x3000], 1; jmp $
+        memory
+            .write(0x2000, &[0xc6, 0x06, 0x00, 0x30, 0x01, 0xeb, 0xfe])
+            .unwrap();
+        // waits for the spinner: cmp byte [0x3000], 1; jne $-5; then exits.
+        memory
+            .write(0x1000, &[0x80, 0x3e, 0x00, 0x30, 0x01, 0x75, 0xf9])
+            .unwrap();
+        memory.write(0x1007, &EXIT).unwrap();
+        let mut exiter = start(vm.vcpus()[0], 0x1000);
+        let mut spinner = start(spinner, 0x2000);
+
+        thread::scope(|scope| {
+            let spun = scope.spawn(|| spinner.run(&bus, &events));
+            assert_eq!(exiter.run(&bus, &events), Ok(RunResult::Exited(0x55)));
+            assert_eq!(spun.join().unwrap(), Ok(RunResult::Exited(0x55)));
+        });
+        assert_eq!(spinner.get().immediate_exit, 0);
+    }
+}
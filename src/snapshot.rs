@@ -0,0 +1,329 @@
+//! Saving a stopped VM to a file, and restoring it later.
+//!
+//! # Format
+//! A snapshot is a single stream; all integers are little endian.  It is
+//! made of the following blocks, in order:
+//!
+//! 1. The header: the magic bytes `KVMSNAP\0`, then a `u32` format
+//!    version (currently 1), and a `u32` of flags (currently always 0).
+//! 2. The capability fingerprint: a `u32` count, then `cap: i32` and
+//!    `value: i32` for each capability in [`FINGERPRINT`], as returned by
+//!    [`kvm_check_extension`] on the VM file descriptor.
+//! 3. The memory slot table: a `u32` count, then `slot: u32`, `flags: u32`,
+//!    `guest_phys_addr: u64`, and `memory_size: u64` for each slot.
+//! 4. The vCPU count, as a `u32`.
+//! 5. The contents of each memory slot, in the order of the table, each
+//!    exactly `memory_size` bytes long.
+//! 6. The vCPU state blocks: a [`VcpuState`] for each vCPU, in the order
+//!    of [`Vm::vcpus`].
+//! 7. The VM state block: a [`VmState`], holding the irqchip, PIT, and
+//!    kvmclock state.
+//! 8. The trailer: the magic bytes `KVMSNAP\0` again.
+//!
+//! Everything that must match the VM being restored into comes before the
+//! memory, so that a snapshot that doesn't fit is rejected before anything
+//! is written.
+//!
+//! The state blocks hold the kernel structures as they are laid out in
+//! memory; see the [`state`](../state/index.html) module.
+
+use super::consts::{
+    KVM_CAP_ADJUST_CLOCK, KVM_CAP_IRQCHIP, KVM_CAP_MP_STATE, KVM_CAP_PIT_STATE2, KVM_CAP_XCRS,
+    KVM_CAP_XSAVE,
+};
+use super::ctl::kvm_check_extension;
+use super::state::{io_error, msr_index_list, read_u32, write_u32, VcpuState, VmState};
+use super::vm::{has_regions, host_memory, read_regions, write_regions, Vm};
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"KVMSNAP\0";
+const VERSION: u32 = 1;
+
+/// The capabilities recorded in a snapshot.  Each of these changes what
+/// state the snapshot holds, or whether it can be loaded back into KVM; a
+/// snapshot is only restored into a VM where each is supported if, and
+/// only if, it was supported when the snapshot was taken.
+pub const FINGERPRINT: [i32; 6] = [
+    KVM_CAP_IRQCHIP,
+    KVM_CAP_PIT_STATE2,
+    KVM_CAP_XSAVE,
+    KVM_CAP_XCRS,
+    KVM_CAP_ADJUST_CLOCK,
+    KVM_CAP_MP_STATE,
+];
+
+impl Vm {
+    /// Writes a snapshot of the VM, including every MSR that KVM supports
+    /// saving (see [`msr_index_list`]) for each vCPU.  None of the vCPUs
+    /// may be running.
+    ///
+    /// # Safety
+    /// The memory slots of the VM must have been registered through
+    /// [`Vm::set_user_memory_region`], with its requirements upheld.
+    ///
+    /// # Support
+    /// This reads the MSR list through `/dev/kvm`, the state of the VM
+    /// through its file descriptor (see [`VmState::save`]), and the state
+    /// of each vCPU through its own (see [`VcpuState::save`]).
+    pub unsafe fn snapshot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let msrs = saved_msrs()?;
+        out.write_all(MAGIC)?;
+        write_u32(out, VERSION)?;
+        write_u32(out, 0)?;
+
+        write_u32(out, FINGERPRINT.len() as u32)?;
+        for (cap, value) in fingerprint(self)? {
+            write_u32(out, cap as u32)?;
+            write_u32(out, value as u32)?;
+        }
+
+        write_regions(out, self.regions())?;
+        write_u32(out, self.vcpus().len() as u32)?;
+        for region in self.regions() {
+            out.write_all(host_memory(region))?;
+        }
+
+        for &fd in self.vcpus() {
+            VcpuState::save(fd, &msrs)
+                .map_err(io_error)?
+                .write_to(out)?;
+        }
+
+        VmState::save(self.fd()).map_err(io_error)?.write_to(out)?;
+        out.write_all(MAGIC)?;
+        out.flush()
+    }
+
+    /// Restores a snapshot written by [`Vm::snapshot`] into this VM.  The VM
+    /// must have the same memory slots (by slot id, address, and size) and
+    /// the same number of vCPUs as the one the snapshot was taken of, and
+    /// must already have an in-kernel irqchip and PIT if that one did.
+    /// Fails with `InvalidData` if the snapshot is malformed, or if the
+    /// capabilities in its fingerprint aren't supported the same way here
+    /// (or are missing from it).
+    ///
+    /// The header, fingerprint, slot table and vCPU count are all checked
+    /// before anything is written.  A failure after that, e.g. a truncated
+    /// snapshot, or state that KVM refuses, leaves the memory and the vCPUs
+    /// partly restored, and the VM in an undefined state.
+    ///
+    /// # Safety
+    /// The memory slots of the VM must have been registered through
+    /// [`Vm::set_user_memory_region`], with its requirements upheld.
+    ///
+    /// # Support
+    /// This writes the state of the VM through its file descriptor (see
+    /// [`VmState::restore`]), and the state of each vCPU through its own
+    /// (see [`VcpuState::restore`]).
+    pub unsafe fn restore<R: Read>(&self, mut input: R) -> io::Result<()> {
+        read_magic(&mut input)?;
+        if read_u32(&mut input)? != VERSION {
+            return Err(invalid("unsupported snapshot version"));
+        }
+        if read_u32(&mut input)? != 0 {
+            return Err(invalid("unsupported snapshot flags"));
+        }
+
+        let mut ours = fingerprint(self)?;
+        let count = read_u32(&mut input)?;
+        for _ in 0..count {
+            let cap = read_u32(&mut input)? as i32;
+            let value = read_u32(&mut input)? as i32;
+            // capabilities we don't know about can't matter to us.
+            if let Some(i) = ours.iter().position(|&(c, _)| c == cap) {
+                let (_, here) = ours.swap_remove(i);
+                if (here > 0) != (value > 0) {
+                    warn!(
+                        "capability {} was {} in the snapshot, {} here",
+                        cap, value, here
+                    );
+                    return Err(invalid("snapshot capabilities differ from this host"));
+                }
+            }
+        }
+        if let Some(&(cap, _)) = ours.first() {
+            warn!("capability {} is missing from the snapshot", cap);
+            return Err(invalid("snapshot capabilities differ from this host"));
+        }
+
+        let regions = read_regions(&mut input)?;
+        if !has_regions(self, &regions) {
+            return Err(invalid("memory slots do not match the snapshot"));
+        }
+        let count = read_u32(&mut input)? as usize;
+        if count != self.vcpus().len() {
+            return Err(invalid("vCPU count does not match the snapshot"));
+        }
+
+        for region in &regions {
+            let ours = self
+                .region(region.slot)
+                .ok_or_else(|| invalid("memory slots do not match the snapshot"))?;
+            input.read_exact(host_memory(ours))?;
+        }
+        for &fd in self.vcpus() {
+            VcpuState::read_from(&mut input)?
+                .restore(fd)
+                .map_err(io_error)?;
+        }
+
+        VmState::read_from(&mut input)?
+            .restore(self.fd())
+            .map_err(io_error)?;
+        read_magic(&mut input)
+    }
+}
+
+/// Checks each capability in [`FINGERPRINT`] on the VM.
+unsafe fn fingerprint(vm: &Vm) -> io::Result<Vec<(i32, i32)>> {
+    FINGERPRINT
+        .iter()
+        .map(|&cap| Ok((cap, kvm_check_extension(vm.fd(), cap).map_err(io_error)?)))
+        .collect()
+}
+
+/// The MSRs that KVM supports saving, read through `/dev/kvm` since the
+/// list is only available on the system file descriptor.
+unsafe fn saved_msrs() -> io::Result<Vec<u32>> {
+    let kvm = fcntl::open("/dev/kvm", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())
+        .map_err(io_error)?;
+    let msrs = msr_index_list(kvm);
+    let _ = unistd::close(kvm);
+    msrs.map_err(io_error)
+}
+
+fn read_magic<R: Read>(input: &mut R) -> io::Result<()> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a snapshot, or a truncated one"));
+    }
+
+    Ok(())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ctl::MsrEntry;
+    use state::{get_msrs, set_msrs};
+    use std::mem::zeroed;
+    use vm::test_vm;
+    use x86::{kvm_get_regs, kvm_set_regs};
+
+    const SIZE: u64 = 0x10_0000;
+    const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+
+    /// A snapshot of a VM with some memory, registers, and an MSR set.
+    fn take_snapshot() -> Option<Vec<u8>> {
+        let (vm, memory) = test_vm(SIZE)?;
+        memory.write(0x1000, b"snapshot").unwrap();
+        memory.write_u64(SIZE - 8, 0x1122_3344_5566_7788).unwrap();
+        let fd = vm.vcpus()[0];
+        unsafe {
+            let mut regs = zeroed();
+            kvm_get_regs(fd, &mut regs).unwrap();
+            regs.rbx = 0xdead_beef;
+            regs.rip = 0x1000;
+            kvm_set_regs(fd, &regs).unwrap();
+            let msr = MsrEntry {
+                index: MSR_IA32_SYSENTER_CS,
+                reserved: 0,
+                data: 0x10,
+            };
+            set_msrs(fd, &[msr]).unwrap();
+        }
+
+        let mut out = vec![];
+        unsafe { vm.snapshot(&mut out) }.unwrap();
+        Some(out)
+    }
+
+    #[test]
+    fn save_restore() {
+        let snapshot = match take_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        assert_eq!(&snapshot[..8], MAGIC);
+        assert_eq!(&snapshot[snapshot.len() - 8..], MAGIC);
+
+        let (vm, memory) = test_vm(SIZE).unwrap();
+        unsafe { vm.restore(&snapshot[..]) }.unwrap();
+
+        let mut buf = [0; 8];
+        memory.read(0x1000, &mut buf).unwrap();
+        assert_eq!(&buf, b"snapshot");
+        assert_eq!(memory.read_u64(SIZE - 8), Ok(0x1122_3344_5566_7788));
+
+        let fd = vm.vcpus()[0];
+        unsafe {
+            let mut regs = zeroed();
+            kvm_get_regs(fd, &mut regs).unwrap();
+            assert_eq!((regs.rbx, regs.rip), (0xdead_beef, 0x1000));
+            let msrs = get_msrs(fd, &[MSR_IA32_SYSENTER_CS]).unwrap();
+            assert_eq!(msrs[0].data, 0x10);
+        }
+    }
+
+    #[test]
+    fn restore_rejects() {
+        let snapshot = match take_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let (vm, memory) = test_vm(SIZE).unwrap();
+        let restore = |changes: &[(usize, u32)]| {
+            let mut bytes = snapshot.clone();
+            for &(at, value) in changes {
+                bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            unsafe { vm.restore(&bytes[..]) }.map_err(|e| e.kind())
+        };
+
+        let invalid = Err(io::ErrorKind::InvalidData);
+        assert_eq!(restore(&[(8, VERSION + 1)]), invalid);
+        assert_eq!(restore(&[(12, 1)]), invalid);
+
+        // the fingerprint starts at 20, with KVM_CAP_IRQCHIP first.
+        assert_eq!(&snapshot[20..24], &KVM_CAP_IRQCHIP.to_le_bytes());
+        let irqchip = unsafe { kvm_check_extension(vm.fd(), KVM_CAP_IRQCHIP) }.unwrap();
+        assert_eq!(restore(&[(24, (irqchip <= 0) as u32)]), invalid);
+        assert_eq!(restore(&[(20, 0x7fff_ffff)]), invalid);
+        // an unknown capability is ignored, as long as ours are all there.
+        let count = FINGERPRINT.len() as u32;
+        let mut extra = snapshot[..16].to_vec();
+        extra.extend_from_slice(&(count + 1).to_le_bytes());
+        extra.extend_from_slice(&0x7fff_ffffu32.to_le_bytes());
+        extra.extend_from_slice(&1u32.to_le_bytes());
+        extra.extend_from_slice(&snapshot[20..]);
+        unsafe { vm.restore(&extra[..]) }.unwrap();
+
+        // the slot table follows, with the size of slot 0 after the count,
+        // its id, flags, and address.
+        let slots = 20 + 8 * FINGERPRINT.len();
+        assert_eq!(restore(&[(slots + 20, SIZE as u32 / 2)]), invalid);
+        // then the vCPU count, checked before the memory is written.
+        let vcpus = slots + 4 + 24;
+        assert_eq!(&snapshot[vcpus..vcpus + 4], &1u32.to_le_bytes());
+        memory.write(0x1000, b"untouche").unwrap();
+        assert_eq!(restore(&[(vcpus, 2)]), invalid);
+        let mut buf = [0; 8];
+        memory.read(0x1000, &mut buf).unwrap();
+        assert_eq!(&buf, b"untouche");
+
+        let truncated = &snapshot[..snapshot.len() - 4];
+        assert_eq!(
+            unsafe { vm.restore(truncated) }.map_err(|e| e.kind()),
+            Err(io::ErrorKind::UnexpectedEof)
+        );
+        unsafe { vm.restore(&snapshot[..]) }.unwrap();
+    }
+}
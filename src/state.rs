@@ -0,0 +1,565 @@
+//! Saving and restoring the complete state of vCPUs and of the VM itself.
+//!
+//! [`VcpuState`] and [`VmState`] collect everything KVM keeps on behalf of
+//! an x86 guest that userspace can read back: registers, FPU/xsave state,
+//! extended control and debug registers, pending events, MSRs, the local
+//! APIC, the in-kernel irqchip and PIT, and the kvmclock.
+//! Both can be written to and read from a byte stream; the encoding is the
+//! raw in-memory layout of the kernel structures, in host byte order, so it
+//! is only meaningful between hosts of the same architecture.
+
+use super::consts::{KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE};
+use super::ctl::{
+    kvm_get_clock, kvm_get_debugregs, kvm_get_fpu, kvm_get_irqchip, kvm_get_lapic,
+    kvm_get_mp_state, kvm_get_msr_index_list, kvm_get_msrs, kvm_get_pit2, kvm_get_vcpu_events,
+    kvm_get_xcrs, kvm_get_xsave, kvm_set_clock, kvm_set_debugregs, kvm_set_fpu, kvm_set_irqchip,
+    kvm_set_lapic, kvm_set_mp_state, kvm_set_msrs, kvm_set_pit2, kvm_set_vcpu_events, kvm_set_xcrs,
+    kvm_set_xsave, ClockData, DebugRegs, Fpu, Irqchip, LapicState, MpState, MsrEntry, MsrList,
+    Msrs, PitState2, VcpuEvents, Xcrs, Xsave, KVM_VCPUEVENT_VALID_NMI_PENDING,
+    KVM_VCPUEVENT_VALID_SIPI_VECTOR,
+};
+use super::x86::{kvm_get_regs, kvm_get_sregs, kvm_set_regs, kvm_set_sregs, Regs, Sregs};
+use nix;
+use nix::errno::Errno;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::mem::{size_of, zeroed};
+use std::os::unix::io::RawFd;
+use std::slice;
+
+/// Returns the list of MSRs that KVM supports saving and restoring, which
+/// is the list usually passed to [`VcpuState::save`].
+///
+/// # Safety
+/// `kvm` must be the system file descriptor.
+///
+/// # Support
+/// See [`kvm_get_msr_index_list`].  This is available only on the system
+/// file descriptor.
+pub unsafe fn msr_index_list(kvm: RawFd) -> nix::Result<Vec<u32>> {
+    let mut capacity = 256;
+    loop {
+        // the header is the same size as an index, so reserve one extra.
+        let mut buf = vec![0u32; capacity + 1];
+        let list = buf.as_mut_ptr() as *mut MsrList;
+        (*list).nmsrs = capacity as u32;
+        match kvm_get_msr_index_list(kvm, list) {
+            Ok(_) => {
+                let len = (*list).nmsrs as usize;
+                buf.truncate(len + 1);
+                buf.remove(0);
+                return Ok(buf);
+            }
+            // KVM tells us how many there are when the buffer is too small.
+            Err(nix::Error::Sys(Errno::E2BIG)) => capacity = (*list).nmsrs as usize,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads the given MSRs from a vCPU.  MSRs that KVM refuses to read are
+/// skipped (with a warning), rather than failing the whole read.
+///
+/// # Safety
+/// `fd` must be a vCPU file descriptor.
+///
+/// # Support
+/// See [`kvm_get_msrs`].  This is available only on the vCPU file
+/// descriptor.
+pub unsafe fn get_msrs(fd: RawFd, indices: &[u32]) -> nix::Result<Vec<MsrEntry>> {
+    let mut out = Vec::with_capacity(indices.len());
+    let mut rest = indices;
+    while !rest.is_empty() {
+        let mut buf = MsrBuffer::new(rest.iter().map(|&index| MsrEntry {
+            index,
+            reserved: 0,
+            data: 0,
+        }));
+        let read = kvm_get_msrs(fd, buf.as_mut_ptr())? as usize;
+        out.extend_from_slice(&buf.entries_mut()[..read]);
+        if read < rest.len() {
+            warn!("could not read msr {:#x}; skipping it", rest[read]);
+            rest = &rest[read + 1..];
+        } else {
+            rest = &[];
+        }
+    }
+
+    Ok(out)
+}
+
+/// Writes the given MSRs to a vCPU.  MSRs that KVM refuses to write (for
+/// example, because the guest's CPUID doesn't advertise them) are skipped,
+/// with a warning.
+///
+/// # Safety
+/// `fd` must be a vCPU file descriptor.
+///
+/// # Support
+/// See [`kvm_set_msrs`].  This is available only on the vCPU file
+/// descriptor.
+pub unsafe fn set_msrs(fd: RawFd, entries: &[MsrEntry]) -> nix::Result<()> {
+    let mut rest = entries;
+    while !rest.is_empty() {
+        let mut buf = MsrBuffer::new(rest.iter().cloned());
+        let written = kvm_set_msrs(fd, buf.as_mut_ptr())? as usize;
+        if written < rest.len() {
+            warn!(
+                "could not write msr {:#x}; skipping it",
+                rest[written].index
+            );
+            rest = &rest[written + 1..];
+        } else {
+            rest = &[];
+        }
+    }
+
+    Ok(())
+}
+
+/// Backing storage for a `kvm_msrs` structure with trailing entries.  The
+/// header is a single `u64`, followed by two `u64`s per entry.
+struct MsrBuffer(Vec<u64>);
+
+impl MsrBuffer {
+    fn new<I: ExactSizeIterator<Item = MsrEntry>>(entries: I) -> MsrBuffer {
+        let mut buf = vec![0u64; 1 + entries.len() * 2];
+        unsafe {
+            (*(buf.as_mut_ptr() as *mut Msrs)).nmsrs = entries.len() as u32;
+        }
+        let mut out = MsrBuffer(buf);
+        for (slot, entry) in out.entries_mut().iter_mut().zip(entries) {
+            *slot = entry;
+        }
+
+        out
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut Msrs {
+        self.0.as_mut_ptr() as *mut Msrs
+    }
+
+    fn entries_mut(&mut self) -> &mut [MsrEntry] {
+        let len = (self.0.len() - 1) / 2;
+        unsafe { slice::from_raw_parts_mut(self.0.as_mut_ptr().add(1) as *mut MsrEntry, len) }
+    }
+}
+
+/// The state of a single x86 vCPU.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VcpuState {
+    pub regs: Regs,
+    pub sregs: Sregs,
+    pub fpu: Fpu,
+    /// Only present if the host supports [`kvm_get_xsave`].
+    pub xsave: Option<Xsave>,
+    /// Only present if the host supports [`kvm_get_xcrs`].
+    pub xcrs: Option<Xcrs>,
+    pub debugregs: DebugRegs,
+    /// The pending and injected exceptions, interrupts and NMIs.
+    pub events: VcpuEvents,
+    /// Only present if the VM has an in-kernel local APIC.
+    pub lapic: Option<LapicState>,
+    pub mp_state: MpState,
+    pub msrs: Vec<MsrEntry>,
+}
+
+impl VcpuState {
+    /// Reads the state of the given vCPU, including the MSRs with the given
+    /// indices (usually the result of [`msr_index_list`]).  The vCPU must
+    /// not be running.
+    ///
+    /// # Safety
+    /// `fd` must be a vCPU file descriptor.
+    ///
+    /// # Support
+    /// This is available only on the vCPU file descriptor.
+    pub unsafe fn save(fd: RawFd, msrs: &[u32]) -> nix::Result<VcpuState> {
+        let mut regs: Regs = zeroed();
+        let mut sregs: Sregs = zeroed();
+        let mut fpu: Fpu = zeroed();
+        let mut mp_state: MpState = zeroed();
+        kvm_get_regs(fd, &mut regs)?;
+        kvm_get_sregs(fd, &mut sregs)?;
+        kvm_get_fpu(fd, &mut fpu)?;
+        kvm_get_mp_state(fd, &mut mp_state)?;
+
+        let mut xsave: Xsave = zeroed();
+        let xsave = optional(kvm_get_xsave(fd, &mut xsave))?.map(|_| xsave);
+        let mut xcrs: Xcrs = zeroed();
+        let xcrs = optional(kvm_get_xcrs(fd, &mut xcrs))?.map(|_| xcrs);
+        let mut debugregs: DebugRegs = zeroed();
+        kvm_get_debugregs(fd, &mut debugregs)?;
+        let mut events: VcpuEvents = zeroed();
+        kvm_get_vcpu_events(fd, &mut events)?;
+        let mut lapic: LapicState = zeroed();
+        let lapic = optional(kvm_get_lapic(fd, &mut lapic))?.map(|_| lapic);
+
+        Ok(VcpuState {
+            regs,
+            sregs,
+            fpu,
+            xsave,
+            xcrs,
+            debugregs,
+            events,
+            lapic,
+            mp_state,
+            msrs: get_msrs(fd, msrs)?,
+        })
+    }
+
+    /// Writes this state into the given vCPU.  The vCPU must not be
+    /// running.
+    ///
+    /// # Safety
+    /// `fd` must be a vCPU file descriptor.
+    ///
+    /// # Support
+    /// This is available only on the vCPU file descriptor.
+    pub unsafe fn restore(&self, fd: RawFd) -> nix::Result<()> {
+        kvm_set_sregs(fd, &self.sregs)?;
+        kvm_set_regs(fd, &self.regs)?;
+        kvm_set_fpu(fd, &self.fpu)?;
+        if let Some(ref xsave) = self.xsave {
+            kvm_set_xsave(fd, xsave)?;
+        }
+        if let Some(ref xcrs) = self.xcrs {
+            kvm_set_xcrs(fd, xcrs)?;
+        }
+        set_msrs(fd, &self.msrs)?;
+        kvm_set_mp_state(fd, &self.mp_state)?;
+        if let Some(ref lapic) = self.lapic {
+            kvm_set_lapic(fd, lapic)?;
+        }
+
+        // the events go after the MP state and the local APIC, which they
+        // depend on (a latched INIT, for one).  KVM leaves the NMI and SIPI
+        // flags out when reading, but both are part of the state we saved.
+        let mut events = self.events;
+        events.flags |= KVM_VCPUEVENT_VALID_NMI_PENDING | KVM_VCPUEVENT_VALID_SIPI_VECTOR;
+        kvm_set_vcpu_events(fd, &events)?;
+        kvm_set_debugregs(fd, &self.debugregs)?;
+
+        Ok(())
+    }
+
+    /// Writes this state to a stream.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        unsafe {
+            write_pod(w, &self.regs)?;
+            write_pod(w, &self.sregs)?;
+            write_pod(w, &self.fpu)?;
+            write_optional(w, self.xsave.as_ref())?;
+            write_optional(w, self.xcrs.as_ref())?;
+            write_pod(w, &self.debugregs)?;
+            write_pod(w, &self.events)?;
+            write_optional(w, self.lapic.as_ref())?;
+            write_pod(w, &self.mp_state)?;
+        }
+        write_msrs(w, &self.msrs)
+    }
+
+    /// Reads a state previously written with [`VcpuState::write_to`].
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<VcpuState> {
+        unsafe {
+            Ok(VcpuState {
+                regs: read_pod(r)?,
+                sregs: read_pod(r)?,
+                fpu: read_pod(r)?,
+                xsave: read_optional(r)?,
+                xcrs: read_optional(r)?,
+                debugregs: read_pod(r)?,
+                events: read_pod(r)?,
+                lapic: read_optional(r)?,
+                mp_state: read_pod(r)?,
+                msrs: read_msrs(r)?,
+            })
+        }
+    }
+}
+
+/// The state of the VM-wide devices KVM emulates.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VmState {
+    /// The master PIC, slave PIC, and IOAPIC, in that order.  Only present
+    /// if the VM has an in-kernel irqchip.
+    pub irqchip: Option<[Irqchip; 3]>,
+    /// Only present if the VM has an in-kernel PIT.
+    pub pit: Option<PitState2>,
+    pub clock: ClockData,
+}
+
+impl VmState {
+    /// Reads the state of the given VM.  The vCPUs must not be running.
+    ///
+    /// # Safety
+    /// `fd` must be a VM file descriptor.
+    ///
+    /// # Support
+    /// This is available only on the VM file descriptor.
+    pub unsafe fn save(fd: RawFd) -> nix::Result<VmState> {
+        let mut chips: [Irqchip; 3] = zeroed();
+        let ids = [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ];
+        let mut present = true;
+        for (chip, &id) in chips.iter_mut().zip(ids.iter()) {
+            chip.chip_id = id;
+            present = present && optional(kvm_get_irqchip(fd, chip))?.is_some();
+        }
+        let irqchip = if present { Some(chips) } else { None };
+
+        let mut pit: PitState2 = zeroed();
+        let pit = optional(kvm_get_pit2(fd, &mut pit))?.map(|_| pit);
+        let mut clock: ClockData = zeroed();
+        kvm_get_clock(fd, &mut clock)?;
+        Ok(VmState {
+            irqchip,
+            pit,
+            clock,
+        })
+    }
+
+    /// Writes this state into the given VM.  The in-kernel irqchip and PIT
+    /// must already have been created, if they are present in the state.
+    ///
+    /// # Safety
+    /// `fd` must be a VM file descriptor.
+    ///
+    /// # Support
+    /// This is available only on the VM file descriptor.
+    pub unsafe fn restore(&self, fd: RawFd) -> nix::Result<()> {
+        if let Some(ref chips) = self.irqchip {
+            for chip in chips {
+                kvm_set_irqchip(fd, chip)?;
+            }
+        }
+        if let Some(ref pit) = self.pit {
+            kvm_set_pit2(fd, pit)?;
+        }
+
+        // the flags describe how the clock was read; none of them apply when
+        // setting it.
+        let mut clock = self.clock;
+        clock.flags = 0;
+        kvm_set_clock(fd, &clock)?;
+        Ok(())
+    }
+
+    /// Writes this state to a stream.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        unsafe {
+            write_optional(w, self.irqchip.as_ref())?;
+            write_optional(w, self.pit.as_ref())?;
+            write_pod(w, &self.clock)
+        }
+    }
+
+    /// Reads a state previously written with [`VmState::write_to`].
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<VmState> {
+        unsafe {
+            Ok(VmState {
+                irqchip: read_optional(r)?,
+                pit: read_optional(r)?,
+                clock: read_pod(r)?,
+            })
+        }
+    }
+}
+
+fn write_msrs<W: Write>(w: &mut W, msrs: &[MsrEntry]) -> io::Result<()> {
+    write_u32(w, msrs.len() as u32)?;
+    for msr in msrs {
+        unsafe { write_pod(w, msr)? };
+    }
+
+    Ok(())
+}
+
+fn read_msrs<R: Read>(r: &mut R) -> io::Result<Vec<MsrEntry>> {
+    let len = read_u32(r)?;
+    (0..len).map(|_| unsafe { read_pod(r) }).collect()
+}
+
+/// Maps the errors KVM returns when a device isn't present to `None`.
+fn optional(res: nix::Result<i32>) -> nix::Result<Option<i32>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(nix::Error::Sys(Errno::ENXIO)) | Err(nix::Error::Sys(Errno::EINVAL)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn io_error(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        other => io::Error::other(other),
+    }
+}
+
+pub(crate) fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+pub(crate) fn write_u64<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+pub(crate) fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Writes the raw bytes of a value.  `T` must have no padding bytes that
+/// are left uninitialized; the kernel structures spell out their padding.
+pub(crate) unsafe fn write_pod<W: Write, T: Copy>(w: &mut W, v: &T) -> io::Result<()> {
+    w.write_all(slice::from_raw_parts(
+        v as *const T as *const u8,
+        size_of::<T>(),
+    ))
+}
+
+/// Reads the raw bytes of a value.  `T` must be valid for any bit pattern.
+pub(crate) unsafe fn read_pod<R: Read, T: Copy>(r: &mut R) -> io::Result<T> {
+    let mut v: T = zeroed();
+    r.read_exact(slice::from_raw_parts_mut(
+        &mut v as *mut T as *mut u8,
+        size_of::<T>(),
+    ))?;
+    Ok(v)
+}
+
+unsafe fn write_optional<W: Write, T: Copy>(w: &mut W, v: Option<&T>) -> io::Result<()> {
+    match v {
+        Some(v) => {
+            w.write_all(&[1])?;
+            write_pod(w, v)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+unsafe fn read_optional<R: Read, T: Copy>(r: &mut R) -> io::Result<Option<T>> {
+    let mut flag = [0];
+    r.read_exact(&mut flag)?;
+    match flag[0] {
+        0 => Ok(None),
+        1 => read_pod(r).map(Some),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid optional state marker",
+        )),
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use bincode;
+    use serde_json;
+
+    /// The stream encoding of a state, to compare them by.
+    fn encode<F: Fn(&mut Vec<u8>) -> io::Result<()>>(write_to: F) -> Vec<u8> {
+        let mut out = vec![];
+        write_to(&mut out).unwrap();
+        out
+    }
+
+    fn vcpu_state() -> VcpuState {
+        let mut state: VcpuState = unsafe {
+            VcpuState {
+                regs: zeroed(),
+                sregs: zeroed(),
+                fpu: zeroed(),
+                xsave: Some(zeroed()),
+                xcrs: Some(zeroed()),
+                debugregs: zeroed(),
+                events: zeroed(),
+                lapic: Some(zeroed()),
+                mp_state: zeroed(),
+                msrs: vec![],
+            }
+        };
+        state.regs.rip = 0xfff0;
+        state.sregs.cr0 = 0x6000_0010;
+        state.sregs.interrupt_bitmap[3] = 1 << 7;
+        state.fpu.xmm[15] = [0xa5; 16];
+        for (i, word) in state.xsave.as_mut().unwrap().region.iter_mut().enumerate() {
+            *word = (i as u32).wrapping_mul(0x9e37_79b9);
+        }
+        for (i, byte) in state.lapic.as_mut().unwrap().regs.iter_mut().enumerate() {
+            *byte = i as u8 ^ 0x3c;
+        }
+        state.xcrs.as_mut().unwrap().nr_xcrs = 1;
+        state.xcrs.as_mut().unwrap().xcrs[0].value = 7;
+        state.debugregs.dr7 = 0x400;
+        state.events.nmi.masked = 1;
+        state.msrs = (0..40)
+            .map(|i| MsrEntry {
+                index: 0xc000_0080 + i,
+                reserved: 0,
+                data: u64::from(i) << 40 | 0xffff,
+            })
+            .collect();
+        state
+    }
+
+    #[test]
+    fn vcpu_state_round_trip() {
+        let state = vcpu_state();
+        let expected = encode(|w| state.write_to(w));
+
+        let json = serde_json::to_string(&state).unwrap();
+        let back: VcpuState = serde_json::from_str(&json).unwrap();
+        assert_eq!(encode(|w| back.write_to(w)), expected);
+
+        let binary = bincode::serialize(&state).unwrap();
+        let back: VcpuState = bincode::deserialize(&binary).unwrap();
+        assert_eq!(encode(|w| back.write_to(w)), expected);
+
+        // a truncated array is an error rather than zero filled.
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["lapic"]["regs"].as_array_mut().unwrap().pop();
+        assert!(serde_json::from_value::<VcpuState>(value).is_err());
+    }
+
+    #[test]
+    fn vm_state_round_trip() {
+        let mut state: VmState = unsafe { zeroed() };
+        let mut chips: [Irqchip; 3] = unsafe { zeroed() };
+        for (i, chip) in chips.iter_mut().enumerate() {
+            chip.chip_id = i as u32;
+            for (j, byte) in chip.chip.iter_mut().enumerate() {
+                *byte = (i + j) as u8;
+            }
+        }
+        state.irqchip = Some(chips);
+        let mut pit: PitState2 = unsafe { zeroed() };
+        pit.channels[0].count = 0x1_0000;
+        pit.channels[2].mode = 3;
+        state.pit = Some(pit);
+        state.clock.clock = 123_456_789;
+        let expected = encode(|w| state.write_to(w));
+
+        let json = serde_json::to_string(&state).unwrap();
+        let back: VmState = serde_json::from_str(&json).unwrap();
+        assert_eq!(encode(|w| back.write_to(w)), expected);
+
+        let binary = bincode::serialize(&state).unwrap();
+        let back: VmState = bincode::deserialize(&binary).unwrap();
+        assert_eq!(encode(|w| back.write_to(w)), expected);
+    }
+}
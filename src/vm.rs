@@ -0,0 +1,193 @@
+//! A record of a virtual machine's file descriptors and memory slots.
+//!
+//! The raw ioctls in this crate take nothing but file descriptors, which
+//! leaves it up to the caller to remember which vCPUs and memory slots a VM
+//! has.  Higher level operations (such as migration) need that bookkeeping;
+//! [`Vm`] keeps it, as long as the VM is set up through it.
+
+use super::ctl::{
+    kvm_create_vcpu, kvm_create_vm, kvm_set_user_memory_region, UserspaceMemoryRegion,
+};
+use super::state::{read_u32, read_u64, write_u32, write_u64};
+use nix;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::slice;
+
+/// A virtual machine, along with its vCPUs and memory slots.  This does not
+/// take ownership of any of the file descriptors; they are not closed when
+/// this is dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vm {
+    fd: RawFd,
+    vcpus: Vec<RawFd>,
+    regions: Vec<UserspaceMemoryRegion>,
+}
+
+impl Vm {
+    /// Wraps an existing VM file descriptor, with no known vCPUs or memory
+    /// slots.
+    pub fn new(fd: RawFd) -> Vm {
+        Vm {
+            fd,
+            vcpus: vec![],
+            regions: vec![],
+        }
+    }
+
+    /// Creates a new VM, of machine type 0, from the system file descriptor.
+    ///
+    /// # Safety
+    /// `kvm` must be the system file descriptor.
+    ///
+    /// # Support
+    /// See [`kvm_create_vm`].  This is available only on the system file
+    /// descriptor.
+    pub unsafe fn create(kvm: RawFd) -> nix::Result<Vm> {
+        kvm_create_vm(kvm, 0).map(Vm::new)
+    }
+
+    /// The VM file descriptor.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Creates a new vCPU with the given id, and records it.  vCPUs are
+    /// kept in the order they were created or added.
+    ///
+    /// # Safety
+    /// The file descriptor this record wraps must be a VM file descriptor.
+    ///
+    /// # Support
+    /// See [`kvm_create_vcpu`].
+    pub unsafe fn create_vcpu(&mut self, id: i32) -> nix::Result<RawFd> {
+        let fd = kvm_create_vcpu(self.fd, id)?;
+        self.vcpus.push(fd);
+        Ok(fd)
+    }
+
+    /// Records a vCPU that was created outside of this VM record.
+    pub fn add_vcpu(&mut self, fd: RawFd) {
+        self.vcpus.push(fd);
+    }
+
+    /// The file descriptors of the vCPUs of the VM.
+    pub fn vcpus(&self) -> &[RawFd] {
+        &self.vcpus
+    }
+
+    /// Creates, modifies, or (with a `memory_size` of 0) deletes a memory
+    /// slot, and records the change.
+    ///
+    /// # Safety
+    /// The `userspace_addr` of the region must point to memory that stays
+    /// mapped, and valid for reads and writes, for `memory_size` bytes for
+    /// as long as the slot exists; other parts of this crate access guest
+    /// memory through it.
+    ///
+    /// # Support
+    /// See [`kvm_set_user_memory_region`].
+    pub unsafe fn set_user_memory_region(
+        &mut self,
+        region: UserspaceMemoryRegion,
+    ) -> nix::Result<i32> {
+        let out = kvm_set_user_memory_region(self.fd, &region)?;
+        self.regions.retain(|r| r.slot != region.slot);
+        if region.memory_size != 0 {
+            self.regions.push(region);
+            self.regions.sort_by_key(|r| r.guest_phys_addr);
+        }
+
+        Ok(out)
+    }
+
+    /// The memory slots of the VM, in order of guest physical address.
+    pub fn regions(&self) -> &[UserspaceMemoryRegion] {
+        &self.regions
+    }
+
+    /// The memory slot with the given slot id, if it exists.
+    pub fn region(&self, slot: u32) -> Option<&UserspaceMemoryRegion> {
+        self.regions.iter().find(|r| r.slot == slot)
+    }
+}
+
+/// Writes a memory slot table: a `u32` count, then `slot: u32`,
+/// `flags: u32`, `guest_phys_addr: u64`, and `memory_size: u64` for each
+/// slot, all little endian.
+pub(crate) fn write_regions<W: Write>(
+    w: &mut W,
+    regions: &[UserspaceMemoryRegion],
+) -> io::Result<()> {
+    write_u32(w, regions.len() as u32)?;
+    for region in regions {
+        write_u32(w, region.slot)?;
+        write_u32(w, region.flags)?;
+        write_u64(w, region.guest_phys_addr)?;
+        write_u64(w, region.memory_size)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a slot table written by `write_regions`.  The `userspace_addr` of
+/// each is left as 0, since it means nothing outside of the source process.
+pub(crate) fn read_regions<R: Read>(r: &mut R) -> io::Result<Vec<UserspaceMemoryRegion>> {
+    let count = read_u32(r)?;
+    (0..count)
+        .map(|_| {
+            Ok(UserspaceMemoryRegion {
+                slot: read_u32(r)?,
+                flags: read_u32(r)?,
+                guest_phys_addr: read_u64(r)?,
+                memory_size: read_u64(r)?,
+                userspace_addr: 0,
+            })
+        })
+        .collect()
+}
+
+/// Whether every slot in the table exists in the VM, at the same address
+/// and with the same size.
+pub(crate) fn has_regions(vm: &Vm, regions: &[UserspaceMemoryRegion]) -> bool {
+    regions.iter().all(|region| match vm.region(region.slot) {
+        Some(r) => {
+            r.guest_phys_addr == region.guest_phys_addr && r.memory_size == region.memory_size
+        }
+        None => false,
+    })
+}
+
+/// The host memory backing a memory slot.
+pub(crate) unsafe fn host_memory<'a>(region: &UserspaceMemoryRegion) -> &'a mut [u8] {
+    slice::from_raw_parts_mut(
+        region.userspace_addr as *mut u8,
+        region.memory_size as usize,
+    )
+}
+
+/// A VM with one vCPU, and `size` bytes of anonymous memory in slot 0 at
+/// guest physical address 0, for tests; `None` if KVM isn't available.
+/// None of it is ever torn down.
+#[cfg(test)]
+pub(crate) fn test_vm(size: u64) -> Option<(Vm, super::memory::GuestMemory)> {
+    use super::memory::{anonymous_mapping, GuestMemory};
+    use nix::fcntl::{self, OFlag};
+    use nix::sys::stat::Mode;
+
+    let kvm = fcntl::open("/dev/kvm", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty()).ok()?;
+    unsafe {
+        let mut vm = Vm::create(kvm).unwrap();
+        vm.set_user_memory_region(UserspaceMemoryRegion {
+            slot: 0,
+            flags: 0,
+            guest_phys_addr: 0,
+            memory_size: size,
+            userspace_addr: anonymous_mapping(size) as u64,
+        })
+        .unwrap();
+        vm.create_vcpu(0).unwrap();
+        let memory = GuestMemory::from_regions(vm.regions()).unwrap();
+        Some((vm, memory))
+    }
+}
@@ -0,0 +1,593 @@
+//! Encoding segment descriptors, and laying out descriptor tables in guest
+//! memory.
+//!
+//! KVM caches the segment registers as [`Segment`]s, and the descriptor
+//! table registers as [`Dtable`]s, but the guest reads the tables in its
+//! own memory whenever it reloads a segment register.  The helpers here
+//! turn [`Segment`]s into the descriptors the processor reads (and back),
+//! and write the tables into guest memory, so the two agree.
+//!
+//! Descriptors are 8 bytes, except for system segments (such as the TSS)
+//! and gates in long mode, which are 16.
+
+use super::mode::{
+    code_segment, data_segment, dtable, tss_segment, CODE32_SELECTOR, CODE64_SELECTOR,
+    DATA_SELECTOR, TSS_LIMIT, TSS_SELECTOR,
+};
+use super::{Dtable, Segment, Sregs};
+use memory::GuestMemory;
+use nix;
+use nix::errno::Errno;
+
+/// A 32-bit interrupt gate.  In long mode, this is a 64-bit one.
+pub const GATE_TYPE_INTERRUPT: u8 = 0xe;
+/// A 32-bit trap gate.  In long mode, this is a 64-bit one.
+pub const GATE_TYPE_TRAP: u8 = 0xf;
+
+/// The most entries a descriptor table can have, as its limit is 16 bits.
+pub const MAX_ENTRIES: usize = 0x2000;
+
+/// The size of a task state segment with no I/O permission bitmap.
+pub const TSS_SIZE: u64 = TSS_LIMIT as u64 + 1;
+
+/// The offset of the GDT from the address given to [`setup`].
+pub const GDT_OFFSET: u64 = 0;
+/// The offset of the TSS from the address given to [`setup`].
+pub const TSS_OFFSET: u64 = 0x80;
+/// The offset of the IDT from the address given to [`setup`].
+pub const IDT_OFFSET: u64 = 0x100;
+/// The guest memory used by [`setup`], with no IDT entries.
+pub const TABLES_SIZE: u64 = IDT_OFFSET;
+
+/// Encodes a code, data, or (legacy) system segment as an 8-byte
+/// descriptor.  An unusable segment encodes as the null descriptor.  If
+/// `g` is set, the low 12 bits of the limit are dropped, as they aren't
+/// representable.
+pub fn encode(segment: &Segment) -> u64 {
+    if segment.unusable != 0 {
+        return 0;
+    }
+
+    let limit = u64::from(if segment.g != 0 {
+        segment.limit >> 12
+    } else {
+        segment.limit
+    });
+    let base = segment.base;
+
+    (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | u64::from(segment.kind & 0xf) << 40
+        | u64::from(segment.s & 1) << 44
+        | u64::from(segment.dpl & 3) << 45
+        | u64::from(segment.present & 1) << 47
+        | (limit >> 16 & 0xf) << 48
+        | u64::from(segment.avl & 1) << 52
+        | u64::from(segment.l & 1) << 53
+        | u64::from(segment.db & 1) << 54
+        | u64::from(segment.g & 1) << 55
+        | (base >> 24 & 0xff) << 56
+}
+
+/// Encodes a system segment (such as a TSS or an LDT) as the 16-byte
+/// descriptor used in long mode, low half first.
+pub fn encode_system(segment: &Segment) -> [u64; 2] {
+    if segment.unusable != 0 {
+        return [0, 0];
+    }
+
+    [encode(segment), segment.base >> 32]
+}
+
+/// Decodes an 8-byte descriptor, as it would be loaded with the given
+/// selector.  A descriptor that isn't present decodes as unusable.
+pub fn decode(descriptor: u64, selector: u16) -> Segment {
+    let bit = |n: u32| (descriptor >> n & 1) as u8;
+    let raw = (descriptor & 0xffff) as u32 | ((descriptor >> 48 & 0xf) as u32) << 16;
+    let g = bit(55);
+
+    Segment {
+        base: (descriptor >> 16 & 0xff_ffff) | (descriptor >> 56 & 0xff) << 24,
+        limit: if g != 0 { raw << 12 | 0xfff } else { raw },
+        selector,
+        kind: (descriptor >> 40 & 0xf) as u8,
+        present: bit(47),
+        dpl: (descriptor >> 45 & 3) as u8,
+        db: bit(54),
+        s: bit(44),
+        l: bit(53),
+        g,
+        avl: bit(52),
+        unusable: bit(47) ^ 1,
+        padding: 0,
+    }
+}
+
+/// Decodes a 16-byte system segment descriptor, as it would be loaded with
+/// the given selector.
+pub fn decode_system(descriptor: [u64; 2], selector: u16) -> Segment {
+    let mut segment = decode(descriptor[0], selector);
+    segment.base |= (descriptor[1] & 0xffff_ffff) << 32;
+    segment
+}
+
+/// A global descriptor table, as it is being built.  The first entry is
+/// always the null descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gdt {
+    entries: Vec<u64>,
+    long: bool,
+}
+
+impl Gdt {
+    /// Creates a table holding only the null descriptor.  If `long` is set,
+    /// system segments take up two entries, as they do in long mode.
+    pub fn new(long: bool) -> Gdt {
+        Gdt {
+            entries: vec![0],
+            long,
+        }
+    }
+
+    /// Appends the descriptor for a segment, returning its selector.  The
+    /// `selector` of the segment itself is ignored.  Fails with `ENOSPC` if
+    /// the descriptor doesn't fit in [`MAX_ENTRIES`].
+    pub fn push(&mut self, segment: &Segment) -> nix::Result<u16> {
+        let wide = self.long && segment.s == 0;
+        if self.entries.len() + if wide { 2 } else { 1 } > MAX_ENTRIES {
+            return Err(nix::Error::Sys(Errno::ENOSPC));
+        }
+
+        let selector = (self.entries.len() << 3) as u16;
+        if wide {
+            self.entries.extend_from_slice(&encode_system(segment));
+        } else {
+            self.entries.push(encode(segment));
+        }
+
+        Ok(selector)
+    }
+
+    /// The segment the descriptor for the given selector decodes to, if
+    /// the selector is within the table.
+    pub fn segment(&self, selector: u16) -> Option<Segment> {
+        let index = usize::from(selector >> 3);
+        let low = *self.entries.get(index)?;
+        let segment = decode(low, selector);
+        if self.long && segment.s == 0 && segment.present != 0 {
+            let high = *self.entries.get(index + 1)?;
+            Some(decode_system([low, high], selector))
+        } else {
+            Some(segment)
+        }
+    }
+
+    /// The raw descriptors.
+    pub fn entries(&self) -> &[u64] {
+        &self.entries
+    }
+
+    /// The size of the table in bytes.
+    pub fn size(&self) -> u64 {
+        self.entries.len() as u64 * 8
+    }
+
+    /// Writes the table into guest memory at `addr`, returning the value
+    /// for [`Sregs::gdt`].
+    pub fn write(&self, memory: &GuestMemory, addr: u64) -> nix::Result<Dtable> {
+        write_entries(memory, addr, &self.entries)
+    }
+}
+
+/// An interrupt or trap gate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Gate {
+    /// The address of the handler.
+    pub offset: u64,
+    /// The code segment selector of the handler.
+    pub selector: u16,
+    /// [`GATE_TYPE_INTERRUPT`] or [`GATE_TYPE_TRAP`].
+    pub kind: u8,
+    /// The privilege level needed to invoke the gate with `int`.
+    pub dpl: u8,
+    /// The interrupt stack table index (long mode only), or 0 for none.
+    pub ist: u8,
+    pub present: bool,
+}
+
+impl Gate {
+    /// Encodes the gate as the 16-byte descriptor used in long mode, low
+    /// half first.
+    pub fn encode_long(&self) -> [u64; 2] {
+        [
+            self.encode() | u64::from(self.ist & 7) << 32,
+            self.offset >> 32,
+        ]
+    }
+
+    /// Encodes the gate as an 8-byte protected mode descriptor.
+    pub fn encode(&self) -> u64 {
+        (self.offset & 0xffff)
+            | u64::from(self.selector) << 16
+            | u64::from(self.kind & 0xf) << 40
+            | u64::from(self.dpl & 3) << 45
+            | u64::from(self.present) << 47
+            | (self.offset >> 16 & 0xffff) << 48
+    }
+
+    /// Decodes an 8-byte protected mode gate descriptor.
+    pub fn decode(descriptor: u64) -> Gate {
+        Gate {
+            offset: (descriptor & 0xffff) | (descriptor >> 48 & 0xffff) << 16,
+            selector: (descriptor >> 16) as u16,
+            kind: (descriptor >> 40 & 0xf) as u8,
+            dpl: (descriptor >> 45 & 3) as u8,
+            ist: 0,
+            present: descriptor >> 47 & 1 != 0,
+        }
+    }
+
+    /// Decodes a 16-byte long mode gate descriptor.
+    pub fn decode_long(descriptor: [u64; 2]) -> Gate {
+        let mut gate = Gate::decode(descriptor[0]);
+        gate.offset |= (descriptor[1] & 0xffff_ffff) << 32;
+        gate.ist = (descriptor[0] >> 32 & 7) as u8;
+        gate
+    }
+}
+
+/// An interrupt descriptor table, as it is being built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Idt {
+    gates: Vec<Option<Gate>>,
+    long: bool,
+}
+
+impl Idt {
+    /// Creates a table with no vectors.  If `long` is set, gates are
+    /// encoded the way they are in long mode.
+    pub fn new(long: bool) -> Idt {
+        Idt {
+            gates: vec![],
+            long,
+        }
+    }
+
+    /// Sets the gate for a vector, growing the table to cover it.  Vectors
+    /// below it that haven't been set are left not present.
+    pub fn set(&mut self, vector: u8, gate: Gate) {
+        let index = usize::from(vector);
+        if self.gates.len() <= index {
+            self.gates.resize(index + 1, None);
+        }
+
+        self.gates[index] = Some(gate);
+    }
+
+    /// The gate for a vector, if it has been set.
+    pub fn gate(&self, vector: u8) -> Option<Gate> {
+        self.gates.get(usize::from(vector)).and_then(|&g| g)
+    }
+
+    /// The size of the table in bytes.
+    pub fn size(&self) -> u64 {
+        self.gates.len() as u64 * if self.long { 16 } else { 8 }
+    }
+
+    /// Writes the table into guest memory at `addr`, returning the value
+    /// for [`Sregs::idt`].
+    pub fn write(&self, memory: &GuestMemory, addr: u64) -> nix::Result<Dtable> {
+        let mut entries = vec![];
+        for gate in &self.gates {
+            match (gate, self.long) {
+                (Some(g), true) => entries.extend_from_slice(&g.encode_long()),
+                (Some(g), false) => entries.push(g.encode()),
+                (None, true) => entries.extend_from_slice(&[0, 0]),
+                (None, false) => entries.push(0),
+            }
+        }
+
+        write_entries(memory, addr, &entries)
+    }
+}
+
+/// Writes a task state segment with no I/O permission bitmap, and all of
+/// its stack pointers zero, into guest memory at `addr`.
+pub fn write_tss(memory: &GuestMemory, addr: u64) -> nix::Result<()> {
+    memory.zero(addr, TSS_SIZE)?;
+    // the I/O map base is past the limit, so there is no bitmap.
+    memory.write_u16(addr + 0x66, TSS_SIZE as u16)
+}
+
+/// The descriptor table registers, and the task register, for tables
+/// written by [`setup`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tables {
+    pub gdt: Dtable,
+    pub idt: Dtable,
+    pub tr: Segment,
+}
+
+impl Tables {
+    /// Points the registers at the tables.  This is meant to be done after
+    /// [`mode::protected_mode`](../mode/fn.protected_mode.html) or
+    /// [`mode::long_mode`](../mode/fn.long_mode.html), which set up the
+    /// segment registers to match.
+    pub fn apply(&self, sregs: &mut Sregs) {
+        sregs.gdt = self.gdt;
+        sregs.idt = self.idt;
+        sregs.tr = self.tr;
+    }
+}
+
+/// Writes a GDT, a TSS, and an empty IDT into guest memory at `addr`,
+/// which should be 8-byte aligned, using [`TABLES_SIZE`] bytes.  The GDT
+/// has the layout described by the `*_SELECTOR` constants in
+/// [`mode`](../mode/index.html); its TSS descriptor is marked busy, to
+/// match the task register.
+pub fn setup(memory: &GuestMemory, addr: u64, long: bool) -> nix::Result<Tables> {
+    let tss = addr + TSS_OFFSET;
+    let tr = tss_segment(TSS_SELECTOR, tss);
+
+    let mut gdt = Gdt::new(long);
+    // the selectors follow from the order; see the constants in `mode`.
+    gdt.push(&code_segment(CODE64_SELECTOR, true))?;
+    gdt.push(&data_segment(DATA_SELECTOR))?;
+    gdt.push(&code_segment(CODE32_SELECTOR, false))?;
+    gdt.push(&tr)?;
+    debug_assert!(gdt.size() <= TSS_OFFSET);
+
+    write_tss(memory, tss)?;
+    Ok(Tables {
+        gdt: gdt.write(memory, addr + GDT_OFFSET)?,
+        idt: Idt::new(long).write(memory, addr + IDT_OFFSET)?,
+        tr,
+    })
+}
+
+/// Fails with `EINVAL` if there are more than [`MAX_ENTRIES`], as the limit
+/// couldn't describe them.
+fn write_entries(memory: &GuestMemory, addr: u64, entries: &[u64]) -> nix::Result<Dtable> {
+    if entries.len() > MAX_ENTRIES {
+        return Err(nix::Error::Sys(Errno::EINVAL));
+    }
+
+    let bytes = entries
+        .iter()
+        .flat_map(|e| e.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    memory.write(addr, &bytes)?;
+    // an empty table has a limit of 0, the same as one with a single byte.
+    Ok(dtable(addr, bytes.len().saturating_sub(1) as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mode::unusable_segment;
+    use super::*;
+    use memory::anonymous_memory;
+
+    fn gate(offset: u64) -> Gate {
+        Gate {
+            offset,
+            selector: CODE64_SELECTOR,
+            kind: GATE_TYPE_INTERRUPT,
+            dpl: 3,
+            ist: 0,
+            present: true,
+        }
+    }
+
+    #[test]
+    fn segment_round_trip() {
+        // the flat segments, as the processor documents them.
+        assert_eq!(encode(&code_segment(0, true)), 0x00af_9b00_0000_ffff);
+        assert_eq!(encode(&code_segment(0, false)), 0x00cf_9b00_0000_ffff);
+        assert_eq!(encode(&data_segment(0)), 0x00cf_9300_0000_ffff);
+
+        let odd = Segment {
+            base: 0xdead_beef,
+            limit: 0xa_bcde,
+            selector: 0x2b,
+            kind: 0x5,
+            present: 1,
+            dpl: 3,
+            db: 1,
+            s: 1,
+            l: 0,
+            g: 0,
+            avl: 1,
+            unusable: 0,
+            padding: 0,
+        };
+        for segment in &[
+            code_segment(CODE64_SELECTOR, true),
+            code_segment(CODE32_SELECTOR, false),
+            data_segment(DATA_SELECTOR),
+            tss_segment(TSS_SELECTOR, 0x1234_5678),
+            odd,
+        ] {
+            assert_eq!(decode(encode(segment), segment.selector), *segment);
+        }
+
+        // with g set, the low 12 bits of the limit read back as ones.
+        let coarse = Segment {
+            limit: 0x1234_5000,
+            g: 1,
+            ..odd
+        };
+        assert_eq!(decode(encode(&coarse), 0x2b).limit, 0x1234_5fff);
+    }
+
+    #[test]
+    fn unusable_round_trip() {
+        assert_eq!(encode(&unusable_segment()), 0);
+        assert_eq!(encode_system(&unusable_segment()), [0, 0]);
+
+        let absent = Segment {
+            present: 0,
+            ..data_segment(DATA_SELECTOR)
+        };
+        let segment = decode(encode(&absent), DATA_SELECTOR);
+        assert_eq!(segment.unusable, 1);
+        assert_eq!(decode(0, 0).unusable, 1);
+    }
+
+    #[test]
+    fn system_round_trip() {
+        let tss = tss_segment(TSS_SELECTOR, 0xffff_8000_1234_5678);
+        let descriptor = encode_system(&tss);
+        assert_eq!(descriptor[1], 0xffff_8000);
+        assert_eq!(decode_system(descriptor, TSS_SELECTOR), tss);
+        // the low half alone has only the low 32 bits of the base.
+        assert_eq!(decode(descriptor[0], TSS_SELECTOR).base, 0x1234_5678);
+    }
+
+    #[test]
+    fn gate_round_trip() {
+        let protected = gate(0x8765_4321);
+        assert_eq!(protected.encode(), 0x8765_ee00_0008_4321);
+        assert_eq!(Gate::decode(protected.encode()), protected);
+
+        let long = Gate {
+            ist: 5,
+            kind: GATE_TYPE_TRAP,
+            ..gate(0xffff_ffff_8765_4321)
+        };
+        assert_eq!(long.encode_long()[1], 0xffff_ffff);
+        assert_eq!(Gate::decode_long(long.encode_long()), long);
+        // the IST only exists in long mode.
+        assert_eq!(Gate::decode(long.encode_long()[0]).ist, 0);
+
+        let absent = Gate {
+            present: false,
+            ..protected
+        };
+        assert!(!Gate::decode(absent.encode()).present);
+    }
+
+    #[test]
+    fn gdt_layout() {
+        for &long in &[false, true] {
+            let mut gdt = Gdt::new(long);
+            let tss = tss_segment(0, 0x1_0000_2000);
+            assert_eq!(gdt.push(&data_segment(0)), Ok(0x08));
+            assert_eq!(gdt.push(&tss), Ok(0x10));
+            assert_eq!(
+                gdt.push(&code_segment(0, long)),
+                Ok(if long { 0x20 } else { 0x18 })
+            );
+            assert_eq!(gdt.size(), if long { 0x28 } else { 0x20 });
+
+            assert_eq!(gdt.segment(0x08), Some(data_segment(0x08)));
+            let base = if long { 0x1_0000_2000 } else { 0x2000 };
+            assert_eq!(gdt.segment(0x10), Some(tss_segment(0x10, base)));
+            assert_eq!(gdt.segment(0x40), None);
+            assert_eq!(gdt.segment(0).map(|s| s.unusable), Some(1));
+        }
+    }
+
+    #[test]
+    fn gdt_full() {
+        let mut gdt = Gdt::new(true);
+        for _ in 1..MAX_ENTRIES - 1 {
+            gdt.push(&data_segment(0)).unwrap();
+        }
+
+        // a system segment needs two entries, but only one is left.
+        let enospc = Err(nix::Error::Sys(Errno::ENOSPC));
+        assert_eq!(gdt.push(&tss_segment(0, 0)), enospc);
+        assert_eq!(gdt.push(&data_segment(0)), Ok(0xfff8));
+        assert_eq!(gdt.push(&data_segment(0)), enospc);
+        assert_eq!(gdt.entries().len(), MAX_ENTRIES);
+
+        // a full table has the largest limit, rather than wrapping to 0.
+        let memory = anonymous_memory(0x1_0000);
+        let table = gdt.write(&memory, 0).unwrap();
+        assert_eq!(table.limit, 0xffff);
+        assert_eq!(memory.read_u64(0xfff8), Ok(encode(&data_segment(0))));
+
+        let mut entries = gdt.entries().to_vec();
+        entries.push(0);
+        assert_eq!(
+            write_entries(&memory, 0, &entries),
+            Err(nix::Error::Sys(Errno::EINVAL))
+        );
+    }
+
+    #[test]
+    fn idt_write() {
+        let memory = anonymous_memory(0x1000);
+        for &long in &[false, true] {
+            let mut idt = Idt::new(long);
+            assert_eq!(idt.write(&memory, 0x100).unwrap().limit, 0);
+
+            idt.set(3, gate(0x1_2345_6789));
+            assert_eq!(idt.gate(3), Some(gate(0x1_2345_6789)));
+            assert_eq!(idt.gate(2), None);
+            assert_eq!(idt.gate(4), None);
+
+            let width = if long { 16 } else { 8 };
+            let table = idt.write(&memory, 0x100).unwrap();
+            assert_eq!(table.base, 0x100);
+            assert_eq!(u64::from(table.limit), 4 * width - 1);
+            assert_eq!(idt.size(), 4 * width);
+
+            let low = memory.read_u64(0x100 + 3 * width).unwrap();
+            let read = if long {
+                let high = memory.read_u64(0x108 + 3 * width).unwrap();
+                Gate::decode_long([low, high])
+            } else {
+                Gate::decode(low)
+            };
+            let offset = if long { 0x1_2345_6789 } else { 0x2345_6789 };
+            assert_eq!(read, gate(offset));
+            assert_eq!(memory.read_u64(0x100 + 2 * width), Ok(0));
+        }
+    }
+
+    #[test]
+    fn tss() {
+        let memory = anonymous_memory(0x1000);
+        memory.write(0, &[0xff; 0x100]).unwrap();
+        write_tss(&memory, 0x10).unwrap();
+
+        let mut tss = [0; TSS_SIZE as usize];
+        memory.read(0x10, &mut tss).unwrap();
+        assert_eq!(u16::from_le_bytes([tss[0x66], tss[0x67]]), 0x68);
+        assert!(tss[..0x66].iter().all(|&b| b == 0));
+        assert_eq!(memory.read_u8(0x10 + TSS_SIZE), Ok(0xff));
+    }
+
+    #[test]
+    fn setup_tables() {
+        let memory = anonymous_memory(0x2000);
+        for &long in &[false, true] {
+            let tables = setup(&memory, 0x1000, long).unwrap();
+            assert_eq!(tables.gdt.base, 0x1000 + GDT_OFFSET);
+            assert_eq!(tables.idt, dtable(0x1000 + IDT_OFFSET, 0));
+            assert_eq!(tables.tr, tss_segment(TSS_SELECTOR, 0x1000 + TSS_OFFSET));
+
+            // the descriptors in memory match the selectors in `mode`.
+            let mut entries = vec![0; (usize::from(tables.gdt.limit) + 1) / 8];
+            for (i, entry) in entries.iter_mut().enumerate() {
+                *entry = memory.read_u64(0x1000 + i as u64 * 8).unwrap();
+            }
+            assert_eq!(entries.len(), if long { 6 } else { 5 });
+            assert_eq!(entries[0], 0);
+            assert_eq!(
+                decode(entries[1], CODE64_SELECTOR),
+                code_segment(CODE64_SELECTOR, true)
+            );
+            assert_eq!(
+                decode(entries[2], DATA_SELECTOR),
+                data_segment(DATA_SELECTOR)
+            );
+            assert_eq!(
+                decode(entries[3], CODE32_SELECTOR),
+                code_segment(CODE32_SELECTOR, false)
+            );
+            assert_eq!(decode(entries[4], TSS_SELECTOR), tables.tr);
+        }
+    }
+}
@@ -1,14 +1,25 @@
+// Array sizes in the ioctl structs are spelled the way the kernel headers
+// spell them.
+#![allow(clippy::manual_div_ceil)]
+
 use super::ctl::{ehandle, KVMIO};
 use libc::ioctl;
 use nix;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::mem::size_of;
 use std::os::unix::io::RawFd;
 
+pub mod gdt;
+pub mod mode;
+pub mod paging;
+
 /// The registers.  Note that this definition only works for x64 hosts and
 /// guests; we'll assume that this is the case.  If this a problem, we'll
 /// extract this behavior out to be more platform independent.
 #[repr(C)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_regs`.
 pub struct Regs {
     pub rax: u64,
@@ -33,6 +44,7 @@ pub struct Regs {
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_segment`.
 pub struct Segment {
     pub base: u64,
@@ -48,6 +60,7 @@ pub struct Segment {
     pub g: u8,
     pub avl: u8,
     pub unusable: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub padding: u8,
 }
 
@@ -59,15 +72,18 @@ impl Default for Segment {
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `kvm_dtable`.
 pub struct Dtable {
     pub base: u64,
     pub limit: u16,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub padding: [u16; 3],
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// From the struct `sregs`.
 pub struct Sregs {
     pub cs: Segment,
@@ -132,6 +148,10 @@ pub unsafe fn kvm_set_regs(fd: RawFd, regs: *const Regs) -> nix::Result<i32> {
 /// Reads special registers from the vCPU.  This assumes that the
 /// vCPU is x86-based.
 ///
+/// # Safety
+/// `fd` must be a vCPU file descriptor, and `regs` must be valid for
+/// writes of a `Sregs`.
+///
 /// # Support
 /// This ioctl is supported only by x86 and ppc, and is a basic
 /// capability.  This is available only on the vCPU file descriptor.
@@ -142,6 +162,10 @@ pub unsafe fn kvm_get_sregs(fd: RawFd, regs: *mut Sregs) -> nix::Result<i32> {
 /// Writes special registers to the vCPU.  This assumes that the
 /// vCPU is x86-based.
 ///
+/// # Safety
+/// `fd` must be a vCPU file descriptor, and `regs` must be valid for
+/// reads of a `Sregs`.
+///
 /// # Support
 /// This ioctl is supported only by x86 and ppc, and is a basic
 /// capability.  This is available only on the vCPU file descriptor.
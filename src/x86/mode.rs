@@ -0,0 +1,368 @@
+//! Setting up the special registers for each of the x86 operating modes.
+//!
+//! Each of the helpers here takes the special registers as read from the
+//! vCPU (with [`kvm_get_sregs`](../fn.kvm_get_sregs.html)), and changes
+//! only what is needed to enter the mode; the result can then be written
+//! back with [`kvm_set_sregs`](../fn.kvm_set_sregs.html).  The segment
+//! selectors used match the GDT layout described by the `*_SELECTOR`
+//! constants.
+
+use super::{Dtable, Segment, Sregs};
+
+/// Protection enable.
+pub const CR0_PE: u64 = 1 << 0;
+/// Monitor coprocessor.
+pub const CR0_MP: u64 = 1 << 1;
+/// x87 emulation.
+pub const CR0_EM: u64 = 1 << 2;
+/// Task switched.
+pub const CR0_TS: u64 = 1 << 3;
+/// Extension type; hardwired to 1.
+pub const CR0_ET: u64 = 1 << 4;
+/// Numeric error.
+pub const CR0_NE: u64 = 1 << 5;
+/// Write protect.
+pub const CR0_WP: u64 = 1 << 16;
+/// Alignment mask.
+pub const CR0_AM: u64 = 1 << 18;
+/// Not write-through.
+pub const CR0_NW: u64 = 1 << 29;
+/// Cache disable.
+pub const CR0_CD: u64 = 1 << 30;
+/// Paging.
+pub const CR0_PG: u64 = 1 << 31;
+
+/// Virtual-8086 mode extensions.
+pub const CR4_VME: u64 = 1 << 0;
+/// Protected-mode virtual interrupts.
+pub const CR4_PVI: u64 = 1 << 1;
+/// Time stamp disable.
+pub const CR4_TSD: u64 = 1 << 2;
+/// Debugging extensions.
+pub const CR4_DE: u64 = 1 << 3;
+/// Page size extensions.
+pub const CR4_PSE: u64 = 1 << 4;
+/// Physical address extension.
+pub const CR4_PAE: u64 = 1 << 5;
+/// Machine-check enable.
+pub const CR4_MCE: u64 = 1 << 6;
+/// Page global enable.
+pub const CR4_PGE: u64 = 1 << 7;
+/// Performance-monitoring counter enable.
+pub const CR4_PCE: u64 = 1 << 8;
+/// OS support for `FXSAVE` and `FXRSTOR`.
+pub const CR4_OSFXSR: u64 = 1 << 9;
+/// OS support for unmasked SIMD floating-point exceptions.
+pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+/// User-mode instruction prevention.
+pub const CR4_UMIP: u64 = 1 << 11;
+/// 57-bit linear addresses (5-level paging).
+pub const CR4_LA57: u64 = 1 << 12;
+/// VMX enable.
+pub const CR4_VMXE: u64 = 1 << 13;
+/// SMX enable.
+pub const CR4_SMXE: u64 = 1 << 14;
+/// `FSGSBASE` enable.
+pub const CR4_FSGSBASE: u64 = 1 << 16;
+/// PCID enable.
+pub const CR4_PCIDE: u64 = 1 << 17;
+/// `XSAVE` and processor extended states enable.
+pub const CR4_OSXSAVE: u64 = 1 << 18;
+/// Supervisor-mode execution prevention.
+pub const CR4_SMEP: u64 = 1 << 20;
+/// Supervisor-mode access prevention.
+pub const CR4_SMAP: u64 = 1 << 21;
+/// Protection keys for user-mode pages.
+pub const CR4_PKE: u64 = 1 << 22;
+
+/// System call extensions.
+pub const EFER_SCE: u64 = 1 << 0;
+/// Long mode enable.
+pub const EFER_LME: u64 = 1 << 8;
+/// Long mode active.
+pub const EFER_LMA: u64 = 1 << 10;
+/// No-execute enable.
+pub const EFER_NXE: u64 = 1 << 11;
+
+/// The selector of the 64-bit code segment; this is GDT entry 1.
+pub const CODE64_SELECTOR: u16 = 0x08;
+/// The selector of the flat data segment; this is GDT entry 2.
+pub const DATA_SELECTOR: u16 = 0x10;
+/// The selector of the flat 32-bit code segment; this is GDT entry 3.
+pub const CODE32_SELECTOR: u16 = 0x18;
+/// The selector of the task state segment; this is GDT entry 4 (and 5, in
+/// long mode, where the descriptor is 16 bytes).
+pub const TSS_SELECTOR: u16 = 0x20;
+
+/// An execute/read, accessed code segment.
+pub const SEGMENT_TYPE_CODE: u8 = 0xb;
+/// A read/write, accessed data segment.
+pub const SEGMENT_TYPE_DATA: u8 = 0x3;
+/// A busy 32-bit (or, in long mode, 64-bit) task state segment.
+pub const SEGMENT_TYPE_TSS_BUSY: u8 = 0xb;
+
+/// The size of a task state segment with no I/O permission bitmap.
+pub const TSS_LIMIT: u32 = 0x67;
+
+/// A flat 4 GiB code segment.  If `long` is set, this is a 64-bit code
+/// segment; otherwise, it is a 32-bit one.
+pub fn code_segment(selector: u16, long: bool) -> Segment {
+    Segment {
+        base: 0,
+        limit: 0xffff_ffff,
+        selector,
+        kind: SEGMENT_TYPE_CODE,
+        present: 1,
+        dpl: 0,
+        db: if long { 0 } else { 1 },
+        s: 1,
+        l: if long { 1 } else { 0 },
+        g: 1,
+        avl: 0,
+        unusable: 0,
+        padding: 0,
+    }
+}
+
+/// A flat 4 GiB data segment.
+pub fn data_segment(selector: u16) -> Segment {
+    Segment {
+        base: 0,
+        limit: 0xffff_ffff,
+        selector,
+        kind: SEGMENT_TYPE_DATA,
+        present: 1,
+        dpl: 0,
+        db: 1,
+        s: 1,
+        l: 0,
+        g: 1,
+        avl: 0,
+        unusable: 0,
+        padding: 0,
+    }
+}
+
+/// A busy task state segment at the given address.
+pub fn tss_segment(selector: u16, base: u64) -> Segment {
+    Segment {
+        base,
+        limit: TSS_LIMIT,
+        selector,
+        kind: SEGMENT_TYPE_TSS_BUSY,
+        present: 1,
+        dpl: 0,
+        db: 0,
+        s: 0,
+        l: 0,
+        g: 0,
+        avl: 0,
+        unusable: 0,
+        padding: 0,
+    }
+}
+
+/// A real mode segment, as it looks after a `mov` of the given selector
+/// into it.
+pub fn real_segment(selector: u16, code: bool) -> Segment {
+    Segment {
+        base: u64::from(selector) << 4,
+        limit: 0xffff,
+        selector,
+        kind: if code {
+            SEGMENT_TYPE_CODE
+        } else {
+            SEGMENT_TYPE_DATA
+        },
+        present: 1,
+        dpl: 0,
+        db: 0,
+        s: 1,
+        l: 0,
+        g: 0,
+        avl: 0,
+        unusable: 0,
+        padding: 0,
+    }
+}
+
+/// An unusable segment, as the LDT is when it isn't in use.
+pub fn unusable_segment() -> Segment {
+    Segment {
+        unusable: 1,
+        ..Segment::default()
+    }
+}
+
+/// Puts the registers into 16-bit real mode, with all of the segments
+/// (including `cs`) using selector 0, and paging turned off.
+pub fn real_mode(sregs: &mut Sregs) {
+    sregs.cs = real_segment(0, true);
+    let data = real_segment(0, false);
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+    sregs.tr = Segment {
+        limit: 0xffff,
+        kind: SEGMENT_TYPE_TSS_BUSY,
+        present: 1,
+        ..Segment::default()
+    };
+    sregs.ldt = unusable_segment();
+    sregs.gdt = dtable(0, 0xffff);
+    sregs.idt = dtable(0, 0x3ff);
+
+    sregs.cr0 &= !(CR0_PE | CR0_PG);
+    sregs.cr0 |= CR0_ET;
+    sregs.cr4 &= !(CR4_PAE | CR4_LA57 | CR4_PCIDE);
+    sregs.efer &= !(EFER_LME | EFER_LMA);
+}
+
+/// Puts the registers into 32-bit protected mode, with flat 4 GiB code and
+/// data segments, and paging turned off.  `gdt` describes where the GDT
+/// lives in guest memory; it must hold the descriptors for the
+/// `*_SELECTOR` constants.
+pub fn protected_mode(sregs: &mut Sregs, gdt: Dtable) {
+    sregs.cs = code_segment(CODE32_SELECTOR, false);
+    flat_data(sregs);
+    sregs.tr = tss_segment(TSS_SELECTOR, 0);
+    sregs.ldt = unusable_segment();
+    sregs.gdt = gdt;
+
+    sregs.cr0 |= CR0_PE | CR0_ET;
+    sregs.cr0 &= !CR0_PG;
+    sregs.cr4 &= !(CR4_PAE | CR4_LA57 | CR4_PCIDE);
+    sregs.efer &= !(EFER_LME | EFER_LMA);
+}
+
+/// Puts the registers into 64-bit long mode, with a 64-bit code segment,
+/// flat data segments, and 4-level paging rooted at `cr3`.  `gdt` describes
+/// where the GDT lives in guest memory; it must hold the descriptors for
+/// the `*_SELECTOR` constants.  For 5-level paging, set [`CR4_LA57`]
+/// afterwards; for page tables using the NX bit, set [`EFER_NXE`].
+pub fn long_mode(sregs: &mut Sregs, gdt: Dtable, cr3: u64) {
+    sregs.cs = code_segment(CODE64_SELECTOR, true);
+    flat_data(sregs);
+    sregs.tr = tss_segment(TSS_SELECTOR, 0);
+    sregs.ldt = unusable_segment();
+    sregs.gdt = gdt;
+
+    sregs.cr3 = cr3;
+    sregs.cr4 &= !CR4_LA57;
+    sregs.cr4 |= CR4_PAE;
+    sregs.cr0 |= CR0_PE | CR0_PG | CR0_ET;
+    sregs.efer |= EFER_LME | EFER_LMA;
+}
+
+/// A descriptor table register value.
+pub fn dtable(base: u64, limit: u16) -> Dtable {
+    Dtable {
+        base,
+        limit,
+        padding: [0; 3],
+    }
+}
+
+fn flat_data(sregs: &mut Sregs) {
+    let data = data_segment(DATA_SELECTOR);
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{kvm_get_sregs, kvm_set_sregs};
+    use super::*;
+    use std::mem;
+    use vm::test_vm;
+
+    const GDT: u64 = 0x500;
+
+    /// Special registers as a 5-level paging kernel left them.
+    fn la57_sregs() -> Sregs {
+        let mut sregs: Sregs = unsafe { mem::zeroed() };
+        sregs.cr0 = CR0_PE | CR0_PG | CR0_ET | CR0_WP;
+        sregs.cr4 = CR4_PAE | CR4_LA57 | CR4_PCIDE | CR4_OSFXSR;
+        sregs.efer = EFER_LME | EFER_LMA | EFER_NXE;
+        sregs
+    }
+
+    #[test]
+    fn real() {
+        let mut sregs = la57_sregs();
+        real_mode(&mut sregs);
+        assert_eq!(sregs.cr0, CR0_ET | CR0_WP);
+        assert_eq!(sregs.cr4, CR4_OSFXSR);
+        assert_eq!(sregs.efer, EFER_NXE);
+        assert_eq!(sregs.cs, real_segment(0, true));
+        assert_eq!(sregs.ss, real_segment(0, false));
+        assert_eq!(sregs.ldt.unusable, 1);
+        assert_eq!(sregs.idt, dtable(0, 0x3ff));
+    }
+
+    #[test]
+    fn protected() {
+        let mut sregs = la57_sregs();
+        protected_mode(&mut sregs, dtable(GDT, 0x27));
+        assert_eq!(sregs.cr0, CR0_PE | CR0_ET | CR0_WP);
+        assert_eq!(sregs.cr4, CR4_OSFXSR);
+        assert_eq!(sregs.efer, EFER_NXE);
+        assert_eq!(sregs.cs, code_segment(CODE32_SELECTOR, false));
+        assert_eq!((sregs.cs.db, sregs.cs.l), (1, 0));
+        assert_eq!(sregs.ds, data_segment(DATA_SELECTOR));
+        assert_eq!(sregs.tr, tss_segment(TSS_SELECTOR, 0));
+        assert_eq!(sregs.gdt, dtable(GDT, 0x27));
+    }
+
+    #[test]
+    fn long() {
+        // 4-level paging, whatever the registers had before.
+        let mut sregs = la57_sregs();
+        long_mode(&mut sregs, dtable(GDT, 0x2f), 0x9000);
+        assert_eq!(sregs.cr0, CR0_PE | CR0_PG | CR0_ET | CR0_WP);
+        assert_eq!(sregs.cr4, CR4_PAE | CR4_PCIDE | CR4_OSFXSR);
+        assert_eq!(sregs.efer, EFER_LME | EFER_LMA | EFER_NXE);
+        assert_eq!(sregs.cr3, 0x9000);
+        assert_eq!(sregs.cs, code_segment(CODE64_SELECTOR, true));
+        assert_eq!((sregs.cs.db, sregs.cs.l), (0, 1));
+        assert_eq!(sregs.es, data_segment(DATA_SELECTOR));
+
+        let mut sregs: Sregs = unsafe { mem::zeroed() };
+        long_mode(&mut sregs, dtable(GDT, 0x2f), 0x9000);
+        assert_eq!(sregs.cr4, CR4_PAE);
+        assert_eq!(sregs.efer, EFER_LME | EFER_LMA);
+    }
+
+    #[test]
+    fn kvm_accepts() {
+        let (vm, _memory) = match test_vm(0x10_0000) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let vcpu = vm.vcpus()[0];
+        let mut sregs: Sregs = unsafe { mem::zeroed() };
+        unsafe { kvm_get_sregs(vcpu, &mut sregs) }.unwrap();
+
+        let set = |sregs: &Sregs| {
+            unsafe { kvm_set_sregs(vcpu, sregs) }.unwrap();
+            let mut read: Sregs = unsafe { mem::zeroed() };
+            unsafe { kvm_get_sregs(vcpu, &mut read) }.unwrap();
+            assert_eq!(
+                (read.cr0, read.cr3, read.cr4, read.efer),
+                (sregs.cr0, sregs.cr3, sregs.cr4, sregs.efer)
+            );
+            assert_eq!((read.cs.l, read.cs.db), (sregs.cs.l, sregs.cs.db));
+        };
+        long_mode(&mut sregs, dtable(GDT, 0x2f), 0x9000);
+        set(&sregs);
+        protected_mode(&mut sregs, dtable(GDT, 0x27));
+        set(&sregs);
+        real_mode(&mut sregs);
+        set(&sregs);
+    }
+}
@@ -0,0 +1,793 @@
+//! Building 4-level and 5-level page tables for 64-bit guests, and walking
+//! the guest's own page tables.
+//!
+//! A [`PageTables`] allocates its tables, one page each, out of an area of
+//! guest memory set aside for them, and writes the entries straight into
+//! guest memory as mappings are added.  Once everything is mapped,
+//! [`PageTables::cr3`] is the value for [`Sregs::cr3`]; pass it to
+//! [`mode::long_mode`](../mode/fn.long_mode.html).  Tables with 5 levels
+//! also need [`CR4_LA57`] set, and mappings using [`PTE_NX`] need
+//! [`EFER_NXE`]; [`PageTables::apply`] takes care of both.
+//!
+//! [`translate`] goes the other way: it walks whatever page tables the
+//! special registers point to, in any of the x86 paging modes, the same way
+//! the processor would.  Unlike [`kvm_translate`](../../fn.kvm_translate.html)
+//! it needs no vCPU, so it also works on the registers of a snapshot.
+
+use super::mode::{CR0_PG, CR4_LA57, CR4_PAE, CR4_PSE, EFER_LMA, EFER_NXE};
+use super::Sregs;
+use ctl::Translation;
+use memory::GuestMemory;
+use nix;
+use nix::errno::Errno;
+
+/// The entry maps a page, or points to a table.
+pub const PTE_PRESENT: u64 = 1 << 0;
+/// Writes are allowed.
+pub const PTE_RW: u64 = 1 << 1;
+/// User-mode accesses are allowed.
+pub const PTE_US: u64 = 1 << 2;
+/// Write-through caching.
+pub const PTE_PWT: u64 = 1 << 3;
+/// Caching disabled.
+pub const PTE_PCD: u64 = 1 << 4;
+/// Set by the processor when the entry is used.
+pub const PTE_ACCESSED: u64 = 1 << 5;
+/// Set by the processor when the page is written.
+pub const PTE_DIRTY: u64 = 1 << 6;
+/// The entry maps a 2 MiB or 1 GiB page, rather than pointing to a table.
+pub const PTE_PS: u64 = 1 << 7;
+/// The translation is global.
+pub const PTE_GLOBAL: u64 = 1 << 8;
+/// Instruction fetches are not allowed; needs [`EFER_NXE`].
+pub const PTE_NX: u64 = 1 << 63;
+
+/// The bits of an entry holding the physical address it points to.
+pub const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The end of the physical address space an entry can point into.
+const MAX_PHYS: u64 = 1 << 52;
+
+/// The size of a page table, and of the smallest page.
+pub const TABLE_SIZE: u64 = 4096;
+
+/// The size of the pages a mapping is made with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    /// Needs the `pdpe1gb` CPUID feature in the guest.
+    Size1G,
+}
+
+impl PageSize {
+    /// The size of the page in bytes.
+    pub fn bytes(self) -> u64 {
+        1 << self.shift()
+    }
+
+    /// The number of the low address bits that are the offset into the
+    /// page.
+    pub fn shift(self) -> u32 {
+        match self {
+            PageSize::Size4K => 12,
+            PageSize::Size2M => 21,
+            PageSize::Size1G => 30,
+        }
+    }
+
+    /// The level of the table that maps pages of this size, where the page
+    /// table is level 1.
+    fn level(self) -> u32 {
+        (self.shift() - 12) / 9 + 1
+    }
+}
+
+/// A set of page tables, being built in guest memory.
+#[derive(Debug)]
+pub struct PageTables<'a> {
+    memory: &'a GuestMemory,
+    levels: u32,
+    root: u64,
+    next: u64,
+    end: u64,
+    nx: bool,
+}
+
+impl<'a> PageTables<'a> {
+    /// Starts page tables with nothing mapped, allocating the tables from
+    /// the `size` bytes of guest memory at `area`, which must be page
+    /// aligned.  If `la57` is set, the tables have 5 levels; otherwise,
+    /// they have 4.  The top level table is allocated right away.
+    pub fn new(memory: &'a GuestMemory, area: u64, size: u64, la57: bool) -> nix::Result<Self> {
+        if !area.is_multiple_of(TABLE_SIZE) {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+        let end = area
+            .checked_add(size)
+            .ok_or(nix::Error::Sys(Errno::EINVAL))?;
+
+        let mut tables = PageTables {
+            memory,
+            levels: if la57 { 5 } else { 4 },
+            root: 0,
+            next: area,
+            end,
+            nx: false,
+        };
+        tables.root = tables.alloc()?;
+        Ok(tables)
+    }
+
+    /// Maps `len` bytes at the virtual address `virt` to the physical
+    /// address `phys`, using pages of the given size.  All three must be
+    /// multiples of the page size, and the virtual range must be
+    /// canonical.  `flags` are any of [`PTE_RW`], [`PTE_US`], [`PTE_NX`],
+    /// and the caching and [`PTE_GLOBAL`] bits; they apply to the pages,
+    /// while the tables leading to them allow everything.
+    ///
+    /// Fails with `EINVAL` for a bad range, `EEXIST` if part of the range
+    /// is already mapped, `ENOMEM` if the area for the tables runs out, or
+    /// `EFAULT` if the tables aren't backed by guest memory.  On failure,
+    /// the part of the range before the problem stays mapped.
+    pub fn map(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        len: u64,
+        size: PageSize,
+        flags: u64,
+    ) -> nix::Result<()> {
+        let page = size.bytes();
+        let bits = 12 + 9 * self.levels;
+        let aligned =
+            virt.is_multiple_of(page) && phys.is_multiple_of(page) && len.is_multiple_of(page);
+        let last = match virt.checked_add(len) {
+            Some(end) if len != 0 => end - 1,
+            _ => return Err(nix::Error::Sys(Errno::EINVAL)),
+        };
+        if !aligned
+            || !canonical(virt, bits)
+            || !canonical(last, bits)
+            // the range may not cross the hole in the middle.
+            || (virt ^ last) >> (bits - 1) != 0
+            || phys.checked_add(len).is_none_or(|end| end > MAX_PHYS)
+        {
+            return Err(nix::Error::Sys(Errno::EINVAL));
+        }
+
+        let flags = flags & (PTE_RW | PTE_US | PTE_PWT | PTE_PCD | PTE_GLOBAL | PTE_NX);
+        let leaf = PTE_PRESENT | flags | if size == PageSize::Size4K { 0 } else { PTE_PS };
+        self.nx |= flags & PTE_NX != 0;
+
+        let mut offset = 0;
+        while offset < len {
+            let entry = self.entry(virt + offset, size.level())?;
+            if self.memory.read_u64(entry)? & PTE_PRESENT != 0 {
+                return Err(nix::Error::Sys(Errno::EEXIST));
+            }
+            self.memory.write_u64(entry, (phys + offset) | leaf)?;
+            offset += page;
+        }
+
+        Ok(())
+    }
+
+    /// Maps `len` bytes at `addr` to the same physical address.  See
+    /// [`PageTables::map`].
+    pub fn identity(&mut self, addr: u64, len: u64, size: PageSize, flags: u64) -> nix::Result<()> {
+        self.map(addr, addr, len, size, flags)
+    }
+
+    /// The value for [`Sregs::cr3`].
+    pub fn cr3(&self) -> u64 {
+        self.root
+    }
+
+    /// The number of levels, 4 or 5.
+    pub fn levels(&self) -> u32 {
+        self.levels
+    }
+
+    /// The number of bytes of the area used by tables so far.
+    pub fn used(&self) -> u64 {
+        self.next - self.root
+    }
+
+    /// Points `cr3` at the tables, and sets [`CR4_LA57`] and [`EFER_NXE`]
+    /// if the tables need them.  This is meant to be done after
+    /// [`mode::long_mode`](../mode/fn.long_mode.html).
+    pub fn apply(&self, sregs: &mut Sregs) {
+        sregs.cr3 = self.cr3();
+        if self.levels == 5 {
+            sregs.cr4 |= CR4_LA57;
+        }
+        if self.nx {
+            sregs.efer |= EFER_NXE;
+        }
+    }
+
+    /// The address of the entry for `virt` in the table at `level`,
+    /// creating the tables above it as needed.
+    fn entry(&mut self, virt: u64, level: u32) -> nix::Result<u64> {
+        let mut table = self.root;
+        for l in (level + 1..=self.levels).rev() {
+            let entry = table + index(virt, l) * 8;
+            let value = self.memory.read_u64(entry)?;
+            table = if value & PTE_PRESENT == 0 {
+                let next = self.alloc()?;
+                self.memory
+                    .write_u64(entry, next | PTE_PRESENT | PTE_RW | PTE_US)?;
+                next
+            } else if value & PTE_PS != 0 {
+                // a larger page already covers this address.
+                return Err(nix::Error::Sys(Errno::EEXIST));
+            } else {
+                value & PTE_ADDR_MASK
+            };
+        }
+
+        Ok(table + index(virt, level) * 8)
+    }
+
+    /// Allocates and zeroes a table.
+    fn alloc(&mut self) -> nix::Result<u64> {
+        if self.end - self.next < TABLE_SIZE {
+            return Err(nix::Error::Sys(Errno::ENOMEM));
+        }
+
+        let table = self.next;
+        self.memory.zero(table, TABLE_SIZE)?;
+        self.next += TABLE_SIZE;
+        Ok(table)
+    }
+}
+
+/// How the processor translates virtual addresses, as set up by the
+/// control registers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Paging is off; virtual addresses are physical ones.
+    Disabled,
+    /// 32-bit paging, with 2 levels of 4-byte entries.
+    Bits32,
+    /// PAE paging, with 3 levels of 8-byte entries.
+    Pae,
+    /// 4-level paging.
+    Level4,
+    /// 5-level paging.
+    Level5,
+}
+
+impl PagingMode {
+    /// The paging mode the special registers select.
+    pub fn from_sregs(sregs: &Sregs) -> PagingMode {
+        if sregs.cr0 & CR0_PG == 0 {
+            PagingMode::Disabled
+        } else if sregs.cr4 & CR4_PAE == 0 {
+            PagingMode::Bits32
+        } else if sregs.efer & EFER_LMA == 0 {
+            PagingMode::Pae
+        } else if sregs.cr4 & CR4_LA57 == 0 {
+            PagingMode::Level4
+        } else {
+            PagingMode::Level5
+        }
+    }
+
+    /// The number of levels of tables.
+    pub fn levels(self) -> u32 {
+        match self {
+            PagingMode::Disabled => 0,
+            PagingMode::Bits32 => 2,
+            PagingMode::Pae => 3,
+            PagingMode::Level4 => 4,
+            PagingMode::Level5 => 5,
+        }
+    }
+}
+
+/// A successful translation of a virtual address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    /// The physical address the virtual address translates to.
+    pub phys: u64,
+    /// The size of the page it lies in.
+    pub page_size: u64,
+    /// The level of the entry that mapped the page, where the page table is
+    /// level 1; 0 if paging is off.
+    pub level: u32,
+    /// Whether every level allows writes.  Note that supervisor writes
+    /// ignore this unless [`CR0_WP`](../mode/constant.CR0_WP.html) is set.
+    pub writable: bool,
+    /// Whether every level allows user-mode accesses.
+    pub user: bool,
+    /// Whether no level forbids instruction fetches.
+    pub executable: bool,
+}
+
+/// Why a virtual address doesn't translate.  Levels are numbered as in
+/// [`Mapping::level`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// The address isn't canonical for the paging mode.
+    NonCanonical,
+    /// The entry at this level isn't present.
+    NotPresent { level: u32 },
+    /// The entry at this level has a reserved bit set.
+    Reserved { level: u32 },
+    /// The table at this level, at the given physical address, isn't
+    /// backed by guest memory.
+    Unbacked { level: u32, addr: u64 },
+}
+
+/// Translates a virtual address through the page tables that the special
+/// registers point to, the way the processor would.
+pub fn translate(sregs: &Sregs, memory: &GuestMemory, virt: u64) -> Result<Mapping, Fault> {
+    let mode = PagingMode::from_sregs(sregs);
+    let nxe = sregs.efer & EFER_NXE != 0;
+    let (mut table, bits, virt) = match mode {
+        PagingMode::Disabled => {
+            return Ok(Mapping {
+                phys: virt & 0xffff_ffff,
+                page_size: TABLE_SIZE,
+                level: 0,
+                writable: true,
+                user: true,
+                executable: true,
+            })
+        }
+        PagingMode::Bits32 => (sregs.cr3 & 0xffff_f000, 10, virt & 0xffff_ffff),
+        PagingMode::Pae => (sregs.cr3 & 0xffff_ffe0, 9, virt & 0xffff_ffff),
+        PagingMode::Level4 | PagingMode::Level5 => {
+            if !canonical(virt, 12 + 9 * mode.levels()) {
+                return Err(Fault::NonCanonical);
+            }
+            (sregs.cr3 & PTE_ADDR_MASK, 9, virt)
+        }
+    };
+
+    let mut mapping = Mapping {
+        phys: 0,
+        page_size: 0,
+        level: 0,
+        writable: true,
+        user: true,
+        executable: true,
+    };
+    for level in (1..=mode.levels()).rev() {
+        let shift = 12 + bits * (level - 1);
+        let index = virt >> shift & ((1 << bits) - 1);
+        let entry = if bits == 10 {
+            let addr = table + index * 4;
+            memory
+                .read_u32(addr)
+                .map(u64::from)
+                .map_err(|_| Fault::Unbacked { level, addr: table })?
+        } else {
+            memory
+                .read_u64(table + index * 8)
+                .map_err(|_| Fault::Unbacked { level, addr: table })?
+        };
+
+        if entry & PTE_PRESENT == 0 {
+            return Err(Fault::NotPresent { level });
+        }
+        if entry & PTE_NX != 0 && !nxe {
+            return Err(Fault::Reserved { level });
+        }
+
+        // the PAE PDPTEs have no permission bits, and can't map pages.
+        let pdpte = mode == PagingMode::Pae && level == 3;
+        if pdpte && entry & (PTE_RW | PTE_US | PTE_PS | PTE_NX) != 0 {
+            return Err(Fault::Reserved { level });
+        }
+        if !pdpte {
+            mapping.writable &= entry & PTE_RW != 0;
+            mapping.user &= entry & PTE_US != 0;
+            mapping.executable &= entry & PTE_NX == 0;
+        }
+
+        let large = level > 1 && entry & PTE_PS != 0;
+        let large = large && (bits == 9 || sregs.cr4 & CR4_PSE != 0);
+        if large && level > 3 {
+            return Err(Fault::Reserved { level });
+        }
+        if level == 1 || large {
+            let size = 1u64 << shift;
+            let base = if bits == 10 && large {
+                // PSE-36: bits 13 and up hold physical address bits 32 and up.
+                (entry & 0xffc0_0000) | (entry >> 13 & 0xff) << 32
+            } else if bits == 10 {
+                entry & 0xffff_f000
+            } else {
+                entry & PTE_ADDR_MASK & !(size - 1)
+            };
+
+            mapping.phys = base | (virt & (size - 1));
+            mapping.page_size = size;
+            mapping.level = level;
+            return Ok(mapping);
+        }
+
+        table = if bits == 10 {
+            entry & 0xffff_f000
+        } else {
+            entry & PTE_ADDR_MASK
+        };
+    }
+
+    // the last level always maps a page, so this is only reached with no
+    // levels at all.
+    Err(Fault::NotPresent { level: 0 })
+}
+
+/// Translates a virtual address like [`translate`], in the form that
+/// [`kvm_translate`](../../fn.kvm_translate.html) returns.  Note that KVM
+/// on x86 always reports a translation as writable and not user mode, so
+/// only `physical_address` and `valid` are comparable with its results,
+/// and then only for canonical addresses, which KVM doesn't check for.
+pub fn translation(sregs: &Sregs, memory: &GuestMemory, virt: u64) -> Translation {
+    let mapping = translate(sregs, memory, virt).ok();
+    Translation {
+        linear_address: virt,
+        physical_address: mapping.map_or(u64::MAX, |m| m.phys),
+        valid: mapping.is_some() as u8,
+        writable: mapping.is_some_and(|m| m.writable) as u8,
+        usermode: mapping.is_some_and(|m| m.user) as u8,
+        _pad: [0; 5],
+    }
+}
+
+/// The index into the table at `level` for a virtual address.
+fn index(virt: u64, level: u32) -> u64 {
+    virt >> (12 + 9 * (level - 1)) & 0x1ff
+}
+
+/// Whether the bits of `addr` above `bits - 1` are all copies of that bit.
+fn canonical(addr: u64, bits: u32) -> bool {
+    let shift = 64 - bits;
+    ((addr << shift) as i64 >> shift) as u64 == addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mode::{dtable, long_mode};
+    use super::super::{kvm_get_sregs, kvm_set_sregs};
+    use super::*;
+    use ctl::kvm_translate;
+    use memory::anonymous_memory;
+    use std::mem;
+    use vm::test_vm;
+
+    const AREA: u64 = 0x10_0000;
+    const AREA_SIZE: u64 = 0x10_0000;
+
+    /// The special registers for long mode on the given tables.
+    fn sregs(tables: &PageTables) -> Sregs {
+        let mut sregs: Sregs = unsafe { mem::zeroed() };
+        long_mode(&mut sregs, dtable(0, 0), 0);
+        tables.apply(&mut sregs);
+        sregs
+    }
+
+    fn mapping(phys: u64, page_size: u64, level: u32, flags: u64) -> Mapping {
+        Mapping {
+            phys,
+            page_size,
+            level,
+            writable: flags & PTE_RW != 0,
+            user: flags & PTE_US != 0,
+            executable: flags & PTE_NX == 0,
+        }
+    }
+
+    /// Maps a 4K, a 2M, and a 1G page with different permissions, at
+    /// `base` and up, and checks that they translate back.
+    fn round_trip(la57: bool, base: u64) {
+        let memory = anonymous_memory(AREA + AREA_SIZE);
+        let mut tables = PageTables::new(&memory, AREA, AREA_SIZE, la57).unwrap();
+        assert_eq!(tables.levels(), if la57 { 5 } else { 4 });
+
+        let small = base + 0x1000;
+        let large = base + (2 << 20);
+        let huge = base + (1 << 30);
+        tables
+            .map(small, 0x5000, 0x2000, PageSize::Size4K, PTE_RW)
+            .unwrap();
+        tables
+            .map(
+                large,
+                0x4000_0000,
+                2 << 20,
+                PageSize::Size2M,
+                PTE_US | PTE_NX,
+            )
+            .unwrap();
+        tables
+            .map(
+                huge,
+                0x80_0000_0000,
+                1 << 30,
+                PageSize::Size1G,
+                PTE_RW | PTE_US,
+            )
+            .unwrap();
+
+        let sregs = sregs(&tables);
+        assert_eq!(sregs.cr3, AREA);
+        assert_eq!(sregs.cr4 & CR4_LA57 != 0, la57);
+        assert_ne!(sregs.efer & EFER_NXE, 0);
+        assert_eq!(
+            PagingMode::from_sregs(&sregs),
+            if la57 {
+                PagingMode::Level5
+            } else {
+                PagingMode::Level4
+            }
+        );
+
+        let walk = |virt| translate(&sregs, &memory, virt);
+        assert_eq!(walk(small + 0x123), Ok(mapping(0x5123, 1 << 12, 1, PTE_RW)));
+        assert_eq!(
+            walk(small + 0x1fff),
+            Ok(mapping(0x6fff, 1 << 12, 1, PTE_RW))
+        );
+        assert_eq!(
+            walk(large + 0x12_3456),
+            Ok(mapping(0x4012_3456, 2 << 20, 2, PTE_US | PTE_NX))
+        );
+        assert_eq!(
+            walk(huge + 0x1234_5678),
+            Ok(mapping(0x80_1234_5678, 1 << 30, 3, PTE_RW | PTE_US))
+        );
+
+        // the neighbours of the mappings aren't mapped.
+        assert_eq!(walk(base), Err(Fault::NotPresent { level: 1 }));
+        assert_eq!(walk(small + 0x2000), Err(Fault::NotPresent { level: 1 }));
+        assert_eq!(walk(large + (2 << 20)), Err(Fault::NotPresent { level: 2 }));
+        assert_eq!(walk(huge + (1 << 30)), Err(Fault::NotPresent { level: 3 }));
+        let top = tables.levels();
+        let other = base ^ (1 << (12 + 9 * (top - 1)));
+        assert_eq!(walk(other), Err(Fault::NotPresent { level: top }));
+
+        // one table per level, which the larger pages share.
+        assert_eq!(tables.used(), u64::from(top) * TABLE_SIZE);
+
+        let t = translation(&sregs, &memory, small + 0x10);
+        assert_eq!(
+            (t.physical_address, t.valid, t.writable, t.usermode),
+            (0x5010, 1, 1, 0)
+        );
+        let t = translation(&sregs, &memory, base);
+        assert_eq!((t.physical_address, t.valid), (u64::MAX, 0));
+    }
+
+    #[test]
+    fn round_trip_4_level() {
+        round_trip(false, 0);
+        round_trip(false, 0xffff_8000_0000_0000);
+    }
+
+    #[test]
+    fn round_trip_5_level() {
+        round_trip(true, 0);
+        round_trip(true, 0x00ff_0000_0000_0000);
+        round_trip(true, 0xff00_0000_0000_0000);
+    }
+
+    #[test]
+    fn canonical_addresses() {
+        let memory = anonymous_memory(AREA + AREA_SIZE);
+        for &la57 in &[false, true] {
+            let mut tables = PageTables::new(&memory, AREA, AREA_SIZE, la57).unwrap();
+            let bits = if la57 { 57 } else { 48 };
+            let hole = 1u64 << (bits - 1);
+            let einval = nix::Error::Sys(Errno::EINVAL);
+            assert_eq!(
+                tables.identity(hole, 0x1000, PageSize::Size4K, 0),
+                Err(einval)
+            );
+            // the range may not cross the hole, even though both ends are
+            // canonical.
+            let below = hole - 0x1000;
+            assert_eq!(
+                tables.map(below, 0, 0x2000, PageSize::Size4K, 0),
+                Err(einval)
+            );
+            tables.map(below, 0, 0x1000, PageSize::Size4K, 0).unwrap();
+
+            let sregs = sregs(&tables);
+            assert_eq!(translate(&sregs, &memory, hole), Err(Fault::NonCanonical));
+            assert_eq!(
+                translate(&sregs, &memory, !(hole - 1)),
+                Err(Fault::NotPresent {
+                    level: tables.levels()
+                })
+            );
+            assert_eq!(translate(&sregs, &memory, below).map(|m| m.phys), Ok(0));
+        }
+    }
+
+    #[test]
+    fn map_errors() {
+        let memory = anonymous_memory(AREA + AREA_SIZE);
+        let einval = nix::Error::Sys(Errno::EINVAL);
+        let eexist = nix::Error::Sys(Errno::EEXIST);
+        assert_eq!(
+            PageTables::new(&memory, AREA + 8, AREA_SIZE, false).map(|t| t.cr3()),
+            Err(einval)
+        );
+
+        let mut tables = PageTables::new(&memory, AREA, AREA_SIZE, false).unwrap();
+        assert_eq!(tables.identity(0x1000, 0, PageSize::Size4K, 0), Err(einval));
+        assert_eq!(
+            tables.identity(0x1000, 0x800, PageSize::Size4K, 0),
+            Err(einval)
+        );
+        assert_eq!(
+            tables.map(0x20_0000, 0x1000, 2 << 20, PageSize::Size2M, 0),
+            Err(einval)
+        );
+        assert_eq!(
+            tables.map(0, MAX_PHYS, 0x1000, PageSize::Size4K, 0),
+            Err(einval)
+        );
+
+        tables.identity(0, 2 << 20, PageSize::Size2M, 0).unwrap();
+        tables
+            .identity(0x4000_0000, 0x1000, PageSize::Size4K, 0)
+            .unwrap();
+        // within the 2M page, over the 4K page, or over its page directory.
+        assert_eq!(
+            tables.identity(0x1000, 0x1000, PageSize::Size4K, 0),
+            Err(eexist)
+        );
+        assert_eq!(
+            tables.identity(0, 2 << 20, PageSize::Size2M, 0),
+            Err(eexist)
+        );
+        assert_eq!(
+            tables.identity(0x4000_0000, 0x1000, PageSize::Size4K, 0),
+            Err(eexist)
+        );
+        assert_eq!(
+            tables.identity(0x4000_0000, 1 << 30, PageSize::Size1G, 0),
+            Err(eexist)
+        );
+
+        // a failure part of the way through keeps what was mapped.
+        assert_eq!(
+            tables.identity(0x3fe0_0000, 4 << 20, PageSize::Size2M, 0),
+            Err(eexist)
+        );
+        let sregs = sregs(&tables);
+        assert_eq!(
+            translate(&sregs, &memory, 0x3fe0_0000).map(|m| m.level),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn area_runs_out() {
+        let memory = anonymous_memory(AREA + AREA_SIZE);
+        let mut tables = PageTables::new(&memory, AREA, 2 * TABLE_SIZE, false).unwrap();
+        tables.identity(0, 1 << 30, PageSize::Size1G, 0).unwrap();
+        assert_eq!(
+            tables.identity(1 << 30, 2 << 20, PageSize::Size2M, 0),
+            Err(nix::Error::Sys(Errno::ENOMEM))
+        );
+
+        let empty = PageTables::new(&memory, AREA, TABLE_SIZE - 1, false);
+        assert_eq!(empty.map(|t| t.cr3()), Err(nix::Error::Sys(Errno::ENOMEM)));
+    }
+
+    #[test]
+    fn permissions() {
+        let memory = anonymous_memory(AREA + AREA_SIZE);
+        let mut tables = PageTables::new(&memory, AREA, AREA_SIZE, false).unwrap();
+        tables.identity(0, 0x1000, PageSize::Size4K, 0).unwrap();
+        tables
+            .identity(0x1000, 0x1000, PageSize::Size4K, PTE_RW | PTE_US | PTE_NX)
+            .unwrap();
+
+        let mut sregs = sregs(&tables);
+        let read_only = translate(&sregs, &memory, 0).unwrap();
+        assert!(!read_only.writable && !read_only.user && read_only.executable);
+        let data = translate(&sregs, &memory, 0x1000).unwrap();
+        assert!(data.writable && data.user && !data.executable);
+
+        // a table entry that takes away a permission takes it away for all
+        // of the pages below it.
+        let pml4e = memory.read_u64(AREA).unwrap();
+        memory.write_u64(AREA, pml4e & !PTE_US).unwrap();
+        assert!(!translate(&sregs, &memory, 0x1000).unwrap().user);
+
+        // NX is a reserved bit unless EFER.NXE is set.
+        sregs.efer &= !EFER_NXE;
+        assert_eq!(
+            translate(&sregs, &memory, 0x1000),
+            Err(Fault::Reserved { level: 1 })
+        );
+        assert!(translate(&sregs, &memory, 0).is_ok());
+    }
+
+    #[test]
+    fn unbacked_tables() {
+        let memory = anonymous_memory(AREA + AREA_SIZE);
+        let mut tables = PageTables::new(&memory, AREA, AREA_SIZE, false).unwrap();
+        tables.identity(0, 0x1000, PageSize::Size4K, 0).unwrap();
+        let mut sregs = sregs(&tables);
+
+        memory.write_u64(AREA, (1 << 40) | PTE_PRESENT).unwrap();
+        assert_eq!(
+            translate(&sregs, &memory, 0),
+            Err(Fault::Unbacked {
+                level: 3,
+                addr: 1 << 40
+            })
+        );
+        sregs.cr3 = 1 << 40;
+        assert_eq!(
+            translate(&sregs, &memory, 0),
+            Err(Fault::Unbacked {
+                level: 4,
+                addr: 1 << 40
+            })
+        );
+    }
+
+    #[test]
+    fn matches_kvm_translate() {
+        let (vm, memory) = match test_vm(AREA + AREA_SIZE) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let vcpu = vm.vcpus()[0];
+
+        // without setting up the guest's CPUID, KVM allows neither 1G pages
+        // nor NX.
+        let mut tables = PageTables::new(&memory, AREA, AREA_SIZE, false).unwrap();
+        tables
+            .map(0x1000, 0x3000, 0x2000, PageSize::Size4K, PTE_RW)
+            .unwrap();
+        tables
+            .map(
+                0xffff_8000_0000_0000,
+                0x20_0000,
+                4 << 20,
+                PageSize::Size2M,
+                0,
+            )
+            .unwrap();
+
+        let mut sregs: Sregs = unsafe { mem::zeroed() };
+        unsafe { kvm_get_sregs(vcpu, &mut sregs) }.unwrap();
+        long_mode(&mut sregs, dtable(0, 0), 0);
+        tables.apply(&mut sregs);
+        unsafe { kvm_set_sregs(vcpu, &sregs) }.unwrap();
+
+        for &virt in &[
+            0,
+            0x1000,
+            0x2abc,
+            0x3000,
+            0xffff_8000_0000_0000,
+            0xffff_8000_0032_1000,
+            0xffff_8000_0040_0000,
+        ] {
+            let ours = translation(&sregs, &memory, virt);
+            let mut theirs = Translation {
+                linear_address: virt,
+                ..ours
+            };
+            unsafe { kvm_translate(vcpu, &mut theirs) }.unwrap();
+            assert_eq!(
+                (theirs.valid, theirs.physical_address),
+                (ours.valid, ours.physical_address),
+                "{:#x}",
+                virt
+            );
+        }
+    }
+}